@@ -0,0 +1,10 @@
+//! Compiles the input with and without optimizations, and loads the bytecode
+//! back.
+
+extern crate fuzz;
+extern crate vm;
+
+fn main() {
+    let input = fuzz::input().expect("input to be readable");
+    let _ = vm::compile_and_check(&input);
+}
@@ -0,0 +1,11 @@
+//! Parses and resolves the input.
+
+extern crate fuzz;
+extern crate parser;
+
+fn main() {
+    let input = fuzz::input().expect("input to be readable");
+    if let Ok(mut stmts) = parser::parse_bytes(&input) {
+        let _ = parser::resolve_with_warnings(&mut stmts);
+    }
+}
@@ -0,0 +1,26 @@
+//! Fuzz targets for the parser and the VM's compiler.
+//!
+//! Each target is a binary that reads one input, from the file it is given or
+//! from stdin, and passes it to an entry point that returns errors for
+//! invalid scripts rather than panicking. So a crash is always a bug. They
+//! can be run under AFL, which instruments them with `cargo afl build`:
+//!
+//! ```text
+//! cargo afl build -p fuzz
+//! afl-fuzz -i integration/lox-tests -o findings target/debug/fuzz_compile @@
+//! ```
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Reads the input to fuzz with, from the file named by the first argument
+/// if there is one and stdin otherwise.
+pub fn input() -> io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    match env::args_os().nth(1) {
+        Some(path) => File::open(path)?.read_to_end(&mut input)?,
+        None => io::stdin().read_to_end(&mut input)?,
+    };
+    Ok(input)
+}
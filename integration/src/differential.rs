@@ -0,0 +1,390 @@
+//! Differential testing, which runs random programs through two binaries and
+//! checks that they print the same thing.
+//!
+//! The programs are generated to be valid and to finish without errors, so
+//! that any difference in what the binaries print is a difference in what
+//! they take the program to mean. Each program is decided by a seed, so a
+//! failure can be reproduced by generating it again.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+/// How deeply expressions are nested.
+const MAX_EXPR_DEPTH: usize = 3;
+/// How deeply blocks, loops and functions are nested.
+const MAX_BLOCK_DEPTH: usize = 3;
+const STRINGS: &[&str] = &["", "a", "lox", "hello", " ", "12"];
+
+/// Runs the program for each seed with both binaries, and panics with the
+/// program if they print different things or either of them fails.
+pub fn check<I: IntoIterator<Item = u64>>(first: &str, second: &str, seeds: I) {
+    fs::metadata(first).expect("Could not locate binary");
+    fs::metadata(second).expect("Could not locate binary");
+    for seed in seeds {
+        let source = program(seed);
+        let path = env::temp_dir().join(format!("rlox-differential-{}-{}.lox", ::std::process::id(), seed));
+        fs::write(&path, &source).expect("Could not write program");
+        let run = |binary: &str| {
+            let output = Command::new(binary).arg(&path).output().expect("Failed to execute process");
+            let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            assert!(output.status.success(), "{} failed on seed {}:\n{}\n{}", binary, seed, stderr, source);
+            stdout
+        };
+        let (expected, got) = (run(first), run(second));
+        let _ = fs::remove_file(&path);
+        assert!(expected == got, r"
+===============================================================================
+Seed {}
+
+{}
+===============================================================================
+{}
+
+{}
+===============================================================================
+{}
+
+{}
+===============================================================================
+", seed, source, first, expected, second, got);
+    }
+}
+
+/// Generates a random program that prints some values and finishes without
+/// errors.
+pub fn program(seed: u64) -> String {
+    let mut gen = Generator {
+        rng: Rng(seed),
+        out: String::new(),
+        indent: 0,
+        scopes: vec![Vec::new()],
+        functions: Vec::new(),
+        classes: Vec::new(),
+        names: 0,
+        block_depth: 0,
+    };
+    let count = 8 + gen.below(12);
+    for _ in 0..count {
+        gen.stmt();
+    }
+    gen.out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Bool,
+    String,
+    /// An instance of one of the generated classes.
+    Instance,
+}
+
+#[derive(Debug, Clone)]
+struct Var {
+    name: String,
+    ty: Type,
+    /// Loop counters can't be assigned to, so that every loop finishes.
+    assignable: bool,
+}
+
+struct Generator {
+    rng: Rng,
+    out: String,
+    indent: usize,
+    scopes: Vec<Vec<Var>>,
+    /// The names and arities of the functions in scope, which all return
+    /// numbers.
+    functions: Vec<(String, usize, usize)>,
+    /// The classes in scope, along with the scope they were declared in.
+    classes: Vec<(String, usize)>,
+    names: usize,
+    block_depth: usize,
+}
+
+impl Generator {
+    fn below(&mut self, n: usize) -> usize {
+        (self.rng.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn name(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{}{}", prefix, self.names)
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn declare(&mut self, name: String, ty: Type, assignable: bool) {
+        self.scopes.last_mut().expect("a scope").push(Var { name, ty, assignable });
+    }
+
+    fn vars(&self, ty: Type, assignable: bool) -> Vec<String> {
+        self.scopes.iter()
+            .flat_map(|scope| scope.iter())
+            .filter(|var| var.ty == ty && (var.assignable || !assignable))
+            .map(|var| var.name.clone())
+            .collect()
+    }
+
+    fn pick(&mut self, names: &[String]) -> String {
+        let i = self.below(names.len());
+        names[i].clone()
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+        self.indent += 1;
+        self.block_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+        let depth = self.scopes.len();
+        self.functions.retain(|f| f.2 < depth);
+        self.classes.retain(|c| c.1 < depth);
+        self.indent -= 1;
+        self.block_depth -= 1;
+    }
+
+    fn block(&mut self) {
+        self.begin_scope();
+        let count = 1 + self.below(4);
+        for _ in 0..count {
+            self.stmt();
+        }
+        self.end_scope();
+    }
+
+    fn stmt(&mut self) {
+        let nested = self.block_depth < MAX_BLOCK_DEPTH;
+        match self.below(10) {
+            0 | 1 => {
+                let ty = self.any_type();
+                let init = self.expr(ty, 0);
+                let name = self.name("v");
+                self.line(&format!("var {} = {};", name, init));
+                self.declare(name, ty, true);
+            },
+            2 | 3 => {
+                let ty = self.any_type();
+                let value = self.expr(ty, 0);
+                self.line(&format!("print {};", value));
+            },
+            4 => {
+                let ty = self.any_type();
+                let vars = self.vars(ty, true);
+                if vars.is_empty() {
+                    return self.stmt();
+                }
+                let var = self.pick(&vars);
+                let value = self.expr(ty, 0);
+                self.line(&format!("{} = {};", var, value));
+            },
+            5 if nested => {
+                let cond = self.expr(Type::Bool, 0);
+                self.line(&format!("if ({}) {{", cond));
+                self.block();
+                if self.chance(50) {
+                    self.line("} else {");
+                    self.block();
+                }
+                self.line("}");
+            },
+            6 if nested => {
+                let counter = self.name("i");
+                let end = self.below(4);
+                if self.chance(50) {
+                    self.line(&format!("for (var {0} = 0; {0} < {1}; {0} = {0} + 1) {{", counter, end));
+                    self.scopes.push(vec![Var { name: counter, ty: Type::Number, assignable: false }]);
+                    self.block();
+                    self.scopes.pop();
+                } else {
+                    self.line(&format!("var {} = 0;", counter));
+                    self.line(&format!("while ({} < {}) {{", counter, end));
+                    self.scopes.push(vec![Var { name: counter.clone(), ty: Type::Number, assignable: false }]);
+                    self.block();
+                    self.scopes.pop();
+                    self.line(&format!("  {0} = {0} + 1;", counter));
+                    self.declare(counter, Type::Number, false);
+                }
+                self.line("}");
+            },
+            7 if nested => self.function(),
+            8 if nested => self.class(),
+            8 | 9 => {
+                let instances = self.vars(Type::Instance, false);
+                if instances.is_empty() {
+                    return self.stmt();
+                }
+                let instance = self.pick(&instances);
+                let n = self.expr(Type::Number, 1);
+                self.line(&format!("{}.add({});", instance, n));
+            },
+            _ if nested => {
+                self.line("{");
+                self.block();
+                self.line("}");
+            },
+            _ => {
+                let value = self.expr(Type::Number, 0);
+                self.line(&format!("print {};", value));
+            },
+        }
+    }
+
+    fn function(&mut self) {
+        let name = self.name("f");
+        let arity = self.below(3);
+        let params = (0..arity).map(|_| self.name("p")).collect::<Vec<_>>();
+        self.line(&format!("fun {}({}) {{", name, params.join(", ")));
+        self.begin_scope();
+        for param in params {
+            self.declare(param, Type::Number, true);
+        }
+        let count = self.below(3);
+        for _ in 0..count {
+            self.stmt();
+        }
+        let value = self.expr(Type::Number, 0);
+        self.line(&format!("return {};", value));
+        self.end_scope();
+        self.line("}");
+        // Functions can only call the ones declared before them, so none of
+        // them recurse.
+        let depth = self.scopes.len() - 1;
+        self.functions.push((name, arity, depth));
+    }
+
+    fn class(&mut self) {
+        let name = self.name("C");
+        let superclass = if self.classes.is_empty() || self.chance(50) {
+            None
+        } else {
+            let i = self.below(self.classes.len());
+            Some(self.classes[i].0.clone())
+        };
+        match superclass {
+            Some(ref superclass) => {
+                self.line(&format!("class {} < {} {{", name, superclass));
+                let scale = 1 + self.below(3);
+                self.line(&format!("  get() {{ return super.get() * {}; }}", scale));
+            },
+            None => {
+                self.line(&format!("class {} {{", name));
+                self.line("  init(v) { this.v = v; }");
+                self.line("  get() { return this.v; }");
+                let op = if self.chance(50) { "+" } else { "-" };
+                self.line(&format!("  add(n) {{ this.v = this.v {} n; return this; }}", op));
+            },
+        }
+        self.line("}");
+        let depth = self.scopes.len() - 1;
+        self.classes.push((name, depth));
+    }
+
+    fn any_type(&mut self) -> Type {
+        match self.below(8) {
+            0..=3 => Type::Number,
+            4 | 5 => Type::Bool,
+            6 => Type::String,
+            _ if !self.classes.is_empty() => Type::Instance,
+            _ => Type::Number,
+        }
+    }
+
+    fn expr(&mut self, ty: Type, depth: usize) -> String {
+        let leaf = depth >= MAX_EXPR_DEPTH || self.chance(30);
+        let vars = self.vars(ty, false);
+        if ty == Type::Instance {
+            let i = self.below(self.classes.len());
+            let class = self.classes[i].0.clone();
+            let value = self.expr(Type::Number, depth + 1);
+            return format!("{}({})", class, value);
+        }
+        if leaf || self.chance(20) {
+            if !vars.is_empty() && self.chance(60) {
+                return self.pick(&vars);
+            }
+            return self.literal(ty);
+        }
+        let a = depth + 1;
+        match ty {
+            Type::Number => match self.below(9) {
+                0 => format!("{} + {}", self.expr(ty, a), self.expr(ty, a)),
+                1 => format!("{} - {}", self.expr(ty, a), self.expr(ty, a)),
+                2 => format!("({}) * {}", self.expr(ty, a), self.expr(ty, a)),
+                // Dividing by a literal never divides by zero.
+                3 => format!("({}) / {}", self.expr(ty, a), 1 + self.below(8)),
+                4 => format!("-({})", self.expr(ty, a)),
+                5 if !self.functions.is_empty() => {
+                    let i = self.below(self.functions.len());
+                    let (name, arity, _) = self.functions[i].clone();
+                    let args = (0..arity).map(|_| self.expr(ty, a)).collect::<Vec<_>>();
+                    format!("{}({})", name, args.join(", "))
+                },
+                6 if !self.vars(Type::Instance, false).is_empty() => {
+                    let instances = self.vars(Type::Instance, false);
+                    format!("{}.get()", self.pick(&instances))
+                },
+                7 => format!("({} and {} or {})", self.expr(Type::Bool, a), self.expr(ty, a), self.expr(ty, a)),
+                _ => format!("({})", self.expr(ty, a)),
+            },
+            Type::Bool => match self.below(8) {
+                0 => format!("{} < {}", self.expr(Type::Number, a), self.expr(Type::Number, a)),
+                1 => format!("{} >= {}", self.expr(Type::Number, a), self.expr(Type::Number, a)),
+                2 => {
+                    let ty = self.any_type();
+                    let ty = if ty == Type::Instance { Type::Number } else { ty };
+                    format!("{} == {}", self.expr(ty, a), self.expr(ty, a))
+                },
+                3 => format!("{} != {}", self.expr(Type::String, a), self.expr(Type::String, a)),
+                4 => format!("!({})", self.expr(ty, a)),
+                5 => format!("({} and {})", self.expr(ty, a), self.expr(ty, a)),
+                6 => format!("({} or {})", self.expr(ty, a), self.expr(ty, a)),
+                _ => format!("({})", self.expr(ty, a)),
+            },
+            Type::String => match self.below(3) {
+                0 | 1 => format!("{} + {}", self.expr(ty, a), self.expr(ty, a)),
+                _ => format!("({})", self.expr(ty, a)),
+            },
+            Type::Instance => unreachable!(),
+        }
+    }
+
+    fn literal(&mut self, ty: Type) -> String {
+        match ty {
+            Type::Number => match self.below(6) {
+                0 => format!("{}.5", self.below(10)),
+                1 => "0.25".to_owned(),
+                _ => self.below(20).to_string(),
+            },
+            Type::Bool => if self.chance(50) { "true" } else { "false" }.to_owned(),
+            Type::String => format!("{:?}", STRINGS[self.below(STRINGS.len())]),
+            Type::Instance => unreachable!(),
+        }
+    }
+}
+
+/// splitmix64, which is plenty for picking what to generate.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
@@ -1,15 +1,26 @@
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::env;
 
+pub mod differential;
+
 const EXPECT: &str = "expect: ";
 const EXPECT_ERR: &str = "expect runtime error: ";
+const EXPECT_EXIT: &str = "expect exit: ";
+const INPUT: &str = "input: ";
+const BUDGET_INSTRUCTIONS: &str = "budget instructions: ";
+const BUDGET_ALLOCATIONS: &str = "budget allocations: ";
 const EXPECT_PARSE_ERR: &str = "Error at";
 const LINE_PARSE_ERR: &str = "[line";
 
 const ERR_LOG: &str = "[error]: ";
+const STATS_INSTRUCTIONS: &str = "[stats] instructions: ";
+const STATS_ALLOCATIONS: &str = "[stats] allocations: ";
+
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
 
 #[macro_export]
 macro_rules! define_integration_tests (
@@ -28,16 +39,19 @@ macro_rules! define_integration_tests (
             undefined
         );
 
-        define_test_mod!(block, empty, scope);
+        define_test_mod!(block, empty, pop_locals, scope, unclosed);
 
         define_test_mod!(bool, equality, not);
 
         define_test_mod!(call, bool, nil, num, object, string);
 
         define_test_mod!(class,
-            empty, inherited_method, local_reference_self, reference_self);
+            empty, inherited_method, local_reference_self, reference_self,
+            expression, expression_factory, to_string, to_string_arity,
+            to_string_error);
 
         define_test_mod!(closure,
+            assign_in_called_closure,
             assign_to_closure,
             assign_to_shadowed_later,
             close_over_function_parameter,
@@ -58,17 +72,21 @@ macro_rules! define_integration_tests (
             only_line_comment_and_line,
             unicode);
 
+        define_test_mod!(conversion, num, str, type_of);
+
         define_test_mod!(constructor,
             arguments,
             call_init_explicitly,
             default,
             default_arguments,
             early_return,
+            early_return_from_loop,
             extra_arguments,
             init_not_method,
             missing_arguments,
             return_in_nested_function,
-            return_value);
+            return_value,
+            inherited_init);
 
         // TODO: These are only used in the earlier parsing chapters,
         // and should be moved to equivalent parsing unit tests.
@@ -80,6 +98,9 @@ macro_rules! define_integration_tests (
         define_test_mod!(field,
             call_function_field,
             call_nonfunction_field,
+            delete,
+            delete_bad_name,
+            enumerate,
             get_and_set_method,
             get_on_bool,
             get_on_class,
@@ -87,10 +108,13 @@ macro_rules! define_integration_tests (
             get_on_nil,
             get_on_num,
             get_on_string,
+            getattr_on_num,
+            getattr_undefined,
             many,
             method,
             method_binds_this,
             on_instance,
+            reflect,
             set_evaluation_order,
             set_on_bool,
             set_on_class,
@@ -98,11 +122,16 @@ macro_rules! define_integration_tests (
             set_on_nil,
             set_on_num,
             set_on_string,
+            setattr_bad_name,
+            setattr_on_num,
             undefined);
 
         define_test_mod!(for_statement,
+            break_in_closure_loop,
             class_in_body,
+            closure_assigns_variable,
             closure_in_body,
+            closure_per_iteration,
             fun_in_body,
             return_closure,
             return_inside,
@@ -115,8 +144,10 @@ macro_rules! define_integration_tests (
 
         define_test_mod!(function,
             body_must_be_block,
+            closure_arity,
             empty_body,
             extra_arguments,
+            fall_off_end,
             local_mutual_recursion,
             local_recursion,
             missing_arguments,
@@ -125,6 +156,7 @@ macro_rules! define_integration_tests (
             parameters,
             print,
             recursion,
+            tail_call,
             too_many_arguments,
             too_many_parameters);
 
@@ -146,10 +178,13 @@ macro_rules! define_integration_tests (
             inherit_from_number,
             inherit_methods,
             parenthesized_superclass,
-            set_fields_from_base_class);
+            set_fields_from_base_class,
+            unbound_method);
 
         define_test_mod!(limit,
             loop_too_large,
+            many_constants,
+            nested_too_deeply,
             reuse_constants,
             stack_overflow,
             too_many_constants,
@@ -168,9 +203,28 @@ macro_rules! define_integration_tests (
             extra_arguments,
             missing_arguments,
             not_found,
+            polymorphic_call_site,
             refer_to_name,
             too_many_arguments,
-            too_many_parameters);
+            too_many_parameters,
+            bound_as_value,
+            bound_keeps_receiver,
+            unbound,
+            unbound_arity,
+            unbound_bad_receiver);
+
+        define_test_mod!(mixin,
+            conflict,
+            expression,
+            inherited_methods,
+            methods,
+            missing_name,
+            not_a_class,
+            over_superclass,
+            own_method_wins,
+            with_as_name);
+
+        define_test_mod!(native, arity, clock, now, random, random_bad_bounds, read_line, read_number, seed_random);
 
         define_test_mod!(nil, literal);
 
@@ -195,10 +249,14 @@ macro_rules! define_integration_tests (
             equals,
             equals_class,
             equals_method,
+            equals_string,
+            equals_zero,
             greater_nonnum_num,
             greater_num_nonnum,
             greater_or_equal_nonnum_num,
             greater_or_equal_num_nonnum,
+            is,
+            is_not_class,
             less_nonnum_num,
             less_num_nonnum,
             less_or_equal_nonnum_num,
@@ -217,15 +275,18 @@ macro_rules! define_integration_tests (
 
         define_test_mod!(print, missing_argument);
 
-        define_test_mod!(regression, regression);
+        define_test_mod!(regression, gc_temporaries, regression);
 
         define_test_mod!(return_statement,
             after_else,
             after_if,
             after_while,
             at_top_level,
+            exit_code,
             in_function,
             in_method,
+            nested_frames,
+            return_in_block,
             return_nil_if_no_value);
 
         // TODO: These are only used in the earlier parsing chapters,
@@ -240,9 +301,15 @@ macro_rules! define_integration_tests (
         //     whitespace);
 
         define_test_mod!(string,
+            bad_character_code,
+            case,
+            chr_ord,
             error_after_multiline,
+            index_of,
+            length,
             literals,
             multiline,
+            substr,
             unterminated);
 
         define_test_mod!(super_keyword,
@@ -265,7 +332,8 @@ macro_rules! define_integration_tests (
             super_in_top_level_function,
             super_without_dot,
             super_without_name,
-            this_in_superclass_method);
+            this_in_superclass_method,
+            init_returns_this);
 
         define_test_mod!(this,
             closure,
@@ -300,8 +368,10 @@ macro_rules! define_integration_tests (
             use_this_as_var);
 
         define_test_mod!(while_statement,
+            break_with_locals,
             class_in_body,
             closure_in_body,
+            comparison_condition,
             fun_in_body,
             return_closure,
             return_inside,
@@ -310,39 +380,92 @@ macro_rules! define_integration_tests (
     );
 );
 
+/// Benchmarks that fail when they execute more instructions or allocate more
+/// objects than the budgets they declare. Only the VM counts these, so only
+/// its tests define them.
+#[macro_export]
+macro_rules! define_perf_tests (
+    () => (
+        pub use $crate::execute_perf_test;
+
+        define_test_mod!(@run execute_perf_test, perf,
+            arithmetic,
+            closures,
+            fib,
+            method_calls,
+            string_concat);
+    );
+);
+
 #[macro_export]
 macro_rules! define_test_mod (
     ($mod:ident, $($testcase:ident),+) => (
+        define_test_mod!(@run execute_test, $mod, $($testcase),+);
+    );
+    (@run $run:ident, $mod:ident, $($testcase:ident),+) => (
         mod $mod {
             use crate::BINARY;
-            use super::execute_test;
+            use super::$run;
 
             const PATH: &str = concat!("integration/lox-tests/", stringify!($mod));
-            define_tests!($($testcase),+);
+            define_tests!(@run $run, $($testcase),+);
         }
     );
 );
 
 #[macro_export]
 macro_rules! define_tests (
-    ($testcase:ident) => (
+    (@run $run:ident, $testcase:ident) => (
         #[test]
         fn $testcase() {
             let filename = concat!(stringify!($testcase), ".lox");
-            execute_test(BINARY, PATH, filename);
+            $run(BINARY, PATH, filename);
         }
     );
-    ($testcase:ident, $($rest:ident),*) => (
+    (@run $run:ident, $testcase:ident, $($rest:ident),*) => (
         #[test]
         fn $testcase() {
             let filename = concat!(stringify!($testcase), ".lox");
-            execute_test(BINARY, PATH, filename);
+            $run(BINARY, PATH, filename);
         }
-        define_tests!($($rest),*);
+        define_tests!(@run $run, $($rest),*);
     );
 );
 
 pub fn execute_test(binary: &str, mod_path: &str, filename: &str) {
+    run_test(binary, &[], mod_path, filename);
+}
+
+/// Runs a test like `execute_test`, and then checks the statistics the binary
+/// reports against the budgets in the test file.
+pub fn execute_perf_test(binary: &str, mod_path: &str, filename: &str) {
+    let (budgets, stderr) = run_test(binary, &["--stats"], mod_path, filename);
+    assert!(budgets.instructions.is_some() || budgets.allocations.is_some(),
+            "perf test has no budgets");
+    let measured = |prefix: &str| -> usize {
+        let line = stderr.lines().find(|l| l.starts_with(prefix)).expect("binary to report stats");
+        line[prefix.len()..].trim().parse().expect("stat to be an integer")
+    };
+    if let Some(budget) = budgets.instructions {
+        let used = measured(STATS_INSTRUCTIONS);
+        assert!(used <= budget, "executed {} instructions, over the budget of {}", used, budget);
+    }
+    if let Some(budget) = budgets.allocations {
+        let used = measured(STATS_ALLOCATIONS);
+        assert!(used <= budget, "allocated {} objects, over the budget of {}", used, budget);
+    }
+}
+
+/// The most a perf test may use of each statistic.
+#[derive(Debug, Default)]
+struct Budgets {
+    instructions: Option<usize>,
+    allocations: Option<usize>,
+}
+
+/// Runs the test file with the binary, passing it `flags`, and checks its
+/// output. Returns the budgets declared in the file and the binary's stderr.
+fn run_test(binary: &str, flags: &[&str], mod_path: &str, filename: &str) -> (Budgets, String) {
     fs::metadata(binary).expect("Could not locate binary");
 
     let mut path = env::current_dir().unwrap();
@@ -363,8 +486,24 @@ pub fn execute_test(binary: &str, mod_path: &str, filename: &str) {
 
     let mut expected_out = String::new();
     let mut expected_err = String::new();
+    let mut expected_exit = None;
+    // Lines to feed to the script's stdin.
+    let mut input = String::new();
+    let mut budgets = Budgets::default();
     for expect in expects {
-        if expect.starts_with(EXPECT) {
+        if expect.starts_with(BUDGET_INSTRUCTIONS) {
+            let budget = expect[BUDGET_INSTRUCTIONS.len()..].trim().parse();
+            budgets.instructions = Some(budget.expect("budget to be an integer"));
+        } else if expect.starts_with(BUDGET_ALLOCATIONS) {
+            let budget = expect[BUDGET_ALLOCATIONS.len()..].trim().parse();
+            budgets.allocations = Some(budget.expect("budget to be an integer"));
+        } else if expect.starts_with(INPUT) {
+            input.push_str(&expect[INPUT.len()..]);
+            input.push('\n');
+        } else if expect.starts_with(EXPECT_EXIT) {
+            let code = expect[EXPECT_EXIT.len()..].trim().parse::<i32>();
+            expected_exit = Some(code.expect("exit code to be an integer"));
+        } else if expect.starts_with(EXPECT) {
             let o = &expect[EXPECT.len()..].trim();
             expected_out.push_str(o);
             expected_out.push('\n');
@@ -387,17 +526,39 @@ pub fn execute_test(binary: &str, mod_path: &str, filename: &str) {
     expected_out = expected_out.replace("\\n", "\n");
     expected_err = expected_err.replace("\\n", "\n");
 
-    let output =
+    let mut child =
         Command::new(binary)
-            .args(&[path])
-            .output()
+            .args(flags)
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
             .expect("Failed to execute process");
+    {
+        // Dropping stdin closes it, so that reading past the input hits EOF.
+        let mut stdin = child.stdin.take().expect("stdin to be piped");
+        // The script may exit without reading everything.
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let output = child.wait_with_output().expect("Failed to execute process");
 
-    if output.status.success() {
+    let status = output.status;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if let Some(code) = expected_exit {
+       assert_eq!(Some(code), status.code(), "unexpected exit code");
+       let output = String::from_utf8_lossy(&output.stdout);
+       assert_eq!(expected_out, output);
+    } else if status.success() {
+       assert!(expected_err.is_empty(), "expected an error but the script succeeded:\n{}", expected_err.trim());
+       assert!(!stderr.contains(ERR_LOG), "unexpected error:\n{}", stderr.trim());
        let output = String::from_utf8_lossy(&output.stdout);
        assert_eq!(expected_out, output);
     } else {
-       let output = String::from_utf8_lossy(&output.stderr);
+       // Without an error to match, the prefix check below would pass
+       // whatever the script printed.
+       assert!(!expected_err.is_empty(), "unexpected failure ({}):\n{}", status, stderr.trim());
+       let output = &strip_positions(&stderr);
        // TODO: Make this more intelligent with backtraces.
        // We check the prefix because the testcases do not include trace information.
        assert!(output.starts_with(&expected_err), r"
@@ -411,11 +572,31 @@ Got Output
 {}
 ===============================================================================
 ", expected_err.trim(), output.trim());
+       let expected_code = if expected_err.contains("Parse: ") { EX_DATAERR } else { EX_SOFTWARE };
+       assert_eq!(Some(expected_code), status.code(), "unexpected exit code");
     };
+    (budgets, stderr)
+}
+
+/// Removes the `[line 12:8]` that ends each parse error, since the test cases
+/// only say which line an error is on, and not always that.
+fn strip_positions(stderr: &str) -> String {
+    let mut stripped = String::with_capacity(stderr.len());
+    for line in stderr.lines() {
+        let end = match line.rfind(" [line ") {
+            Some(idx) if line.ends_with(']') && line[idx + 7..line.len() - 1].contains(':') => idx,
+            _ => line.len(),
+        };
+        stripped.push_str(&line[..end]);
+        stripped.push('\n');
+    }
+    stripped
 }
 
 fn line_filter(mut line: String) -> Option<String> {
     line.find("// expect")
+        .or_else(|| line.find("// input"))
+        .or_else(|| line.find("// budget"))
         .or_else(|| line.find("// Error at"))
         .or_else(|| line.find("// [line"))
         .map(|idx| line.split_off(idx + 3)) // remove the comment
@@ -0,0 +1,372 @@
+use parser::ast::*;
+
+/// Dumps a resolved program either as an indented tree or as JSON.
+///
+/// Both formats are rendered from the same intermediate `Node` so that they
+/// always agree on field names and ordering.
+pub struct AstDump {
+    json: bool,
+}
+
+impl AstDump {
+    pub fn tree() -> Self {
+        AstDump { json: false }
+    }
+
+    pub fn json() -> Self {
+        AstDump { json: true }
+    }
+
+    pub fn dump(&self, stmts: &[Stmt]) -> String {
+        let nodes: Vec<Node> = stmts.iter().map(stmt_node).collect();
+        let mut out = String::new();
+        if self.json {
+            push_json_list(&mut out, &nodes);
+        } else {
+            for node in &nodes {
+                push_tree(&mut out, node, None, 0);
+            }
+            // Drop the trailing newline, println! adds its own.
+            out.pop();
+        }
+        out
+    }
+}
+
+enum Attr {
+    Str(String),
+    Num(f64),
+    Int(usize),
+    Bool(bool),
+    Nil,
+}
+
+enum Child {
+    One(Node),
+    Many(Vec<Node>),
+}
+
+struct Node {
+    kind: &'static str,
+    attrs: Vec<(&'static str, Attr)>,
+    children: Vec<(&'static str, Child)>,
+}
+
+impl Node {
+    fn new(kind: &'static str) -> Self {
+        Node {
+            kind,
+            attrs: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn attr(mut self, name: &'static str, attr: Attr) -> Self {
+        self.attrs.push((name, attr));
+        self
+    }
+
+    fn str_attr<S: Into<String>>(self, name: &'static str, s: S) -> Self {
+        self.attr(name, Attr::Str(s.into()))
+    }
+
+    fn line(self, expr: &Expr) -> Self {
+        self.attr("line", Attr::Int(expr.line()))
+    }
+
+    fn child(mut self, name: &'static str, node: Node) -> Self {
+        self.children.push((name, Child::One(node)));
+        self
+    }
+
+    fn children(mut self, name: &'static str, nodes: Vec<Node>) -> Self {
+        self.children.push((name, Child::Many(nodes)));
+        self
+    }
+}
+
+fn var_node(kind: &'static str, var: &Variable) -> Node {
+    let node = Node::new(kind).str_attr("name", var.name());
+    match var.scope() {
+        Scope::Global => node.str_attr("scope", "global"),
+        Scope::Local(depth) => {
+            node.str_attr("scope", "local")
+                .attr("depth", Attr::Int(depth))
+                .attr("upvalue", Attr::Bool(var.is_upvalue()))
+        },
+    }
+}
+
+fn function_node(name: Option<&str>, decl: &FunctionDecl) -> Node {
+    let mut node = Node::new("Function");
+    if let Some(name) = name {
+        node = node.str_attr("name", name);
+    }
+    let params = decl.parameters.iter()
+        .map(|p| Node::new("Param").str_attr("name", p.name()))
+        .collect();
+    let body = decl.body.iter().map(stmt_node).collect();
+    node.attr("method", Attr::Bool(decl.method))
+        .children("parameters", params)
+        .children("body", body)
+}
+
+fn function_stmt_node(function: &FunctionStmt) -> Node {
+    function_node(Some(function.var.name()), &function.declaration.borrow())
+}
+
+fn stmt_node(stmt: &Stmt) -> Node {
+    match *stmt {
+        Stmt::Expr(ref expr) => Node::new("Expression").child("expr", expr_node(expr)),
+        Stmt::Print(ref expr) => Node::new("Print").child("expr", expr_node(expr)),
+        Stmt::Var(ref var, ref init) => {
+            var_node("Var", var).child("initializer", expr_node(init))
+        },
+        Stmt::Block(ref stmts) => {
+            Node::new("Block").children("body", stmts.iter().map(stmt_node).collect())
+        },
+        Stmt::If(ref cond, ref then_clause, ref else_clause) => {
+            let node = Node::new("If")
+                .child("condition", expr_node(cond))
+                .child("then", stmt_node(then_clause));
+            match *else_clause {
+                Some(ref else_clause) => node.child("else", stmt_node(else_clause)),
+                None => node,
+            }
+        },
+        Stmt::While(ref cond, ref body) => {
+            Node::new("While")
+                .child("condition", expr_node(cond))
+                .child("body", stmt_node(body))
+        },
+        Stmt::ForBody(ref for_body) => {
+            let node = var_node("ForBody", &for_body.var)
+                .child("body", stmt_node(&for_body.body));
+            match for_body.increment {
+                Some(ref increment) => node.child("increment", expr_node(increment)),
+                None => node,
+            }
+        },
+        Stmt::Break(_) => Node::new("Break"),
+        Stmt::Function(ref function) => function_stmt_node(function),
+        Stmt::Return(ref expr) => {
+            let node = Node::new("Return");
+            match *expr {
+                Some(ref expr) => node.child("value", expr_node(expr)),
+                None => node,
+            }
+        },
+        Stmt::Class(ref class) => class_node(class),
+    }
+}
+
+fn class_node(class: &Class) -> Node {
+    let mut node = var_node("Class", &class.var);
+    if let Some(ref superclass) = class.superclass {
+        node = node.child("superclass", var_node("Var", superclass));
+    }
+    node.children("mixins", class.mixins.iter().map(|m| var_node("Var", m)).collect())
+        .children("methods", class.methods.iter().map(function_stmt_node).collect())
+        .children("class_methods", class.class_methods.iter().map(function_stmt_node).collect())
+}
+
+fn expr_node(expr: &Expr) -> Node {
+    let node = match expr.node {
+        ExprKind::Logical(ref logical) => {
+            Node::new("Logical")
+                .str_attr("operator", logical.operator.to_str())
+                .child("lhs", expr_node(&logical.lhs))
+                .child("rhs", expr_node(&logical.rhs))
+        },
+        ExprKind::Binary(ref binary) => {
+            Node::new("Binary")
+                .str_attr("operator", binary.operator.to_str())
+                .child("lhs", expr_node(&binary.lhs))
+                .child("rhs", expr_node(&binary.rhs))
+        },
+        ExprKind::Is(ref value, ref class) => {
+            Node::new("Is")
+                .child("value", expr_node(value))
+                .child("class", expr_node(class))
+        },
+        ExprKind::Unary(ref unary) => {
+            Node::new("Unary")
+                .str_attr("operator", unary.operator.to_str())
+                .child("operand", expr_node(&unary.unary))
+        },
+        ExprKind::Call(ref call) => {
+            Node::new("Call")
+                .child("callee", expr_node(&call.callee))
+                .children("arguments", call.arguments.iter().map(expr_node).collect())
+        },
+        ExprKind::Grouping(ref inner) => Node::new("Grouping").child("expr", expr_node(inner)),
+        ExprKind::Literal(ref lit) => {
+            let value = match *lit {
+                Literal::Number(n) => Attr::Num(n),
+                Literal::String(ref s) => Attr::Str(s.clone()),
+                Literal::True => Attr::Bool(true),
+                Literal::False => Attr::Bool(false),
+                Literal::Nil => Attr::Nil,
+            };
+            Node::new("Literal").attr("value", value)
+        },
+        ExprKind::Var(ref var) => var_node("Var", var),
+        ExprKind::Assign(ref var, ref value) => {
+            var_node("Assign", var).child("value", expr_node(value))
+        },
+        ExprKind::Get(ref object, ref name) => {
+            Node::new("Get")
+                .str_attr("name", name.as_str())
+                .child("object", expr_node(object))
+        },
+        ExprKind::Set(ref object, ref name, ref value) => {
+            Node::new("Set")
+                .str_attr("name", name.as_str())
+                .child("object", expr_node(object))
+                .child("value", expr_node(value))
+        },
+        ExprKind::This(ref var, _) => var_node("This", var),
+        ExprKind::Super(ref var, _, ref method) => {
+            var_node("Super", var).str_attr("method", method.as_str())
+        },
+        ExprKind::Function(ref decl) => function_node(None, &decl.borrow()),
+        ExprKind::Class(ref class) => class_node(class),
+    };
+    node.line(expr)
+}
+
+fn push_tree(out: &mut String, node: &Node, label: Option<&str>, indent: usize) {
+    push_indent(out, indent);
+    if let Some(label) = label {
+        out.push_str(label);
+        out.push_str(": ");
+    }
+    out.push_str(node.kind);
+    for &(name, ref attr) in &node.attrs {
+        out.push(' ');
+        out.push_str(name);
+        out.push('=');
+        push_attr(out, attr);
+    }
+    out.push('\n');
+    for &(name, ref child) in &node.children {
+        match *child {
+            Child::One(ref child) => push_tree(out, child, Some(name), indent + 1),
+            Child::Many(ref children) if children.is_empty() => {},
+            Child::Many(ref children) => {
+                push_indent(out, indent + 1);
+                out.push_str(name);
+                out.push_str(":\n");
+                for child in children {
+                    push_tree(out, child, None, indent + 2);
+                }
+            },
+        }
+    }
+}
+
+fn push_indent(out: &mut String, indent: usize) {
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+fn push_attr(out: &mut String, attr: &Attr) {
+    match *attr {
+        Attr::Str(ref s) => push_json_str(out, s),
+        Attr::Num(n) => out.push_str(&n.to_string()),
+        Attr::Int(i) => out.push_str(&i.to_string()),
+        Attr::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+        Attr::Nil => out.push_str("nil"),
+    }
+}
+
+fn push_json(out: &mut String, node: &Node) {
+    out.push_str("{\"kind\":");
+    push_json_str(out, node.kind);
+    for &(name, ref attr) in &node.attrs {
+        out.push(',');
+        push_json_str(out, name);
+        out.push(':');
+        match *attr {
+            Attr::Nil => out.push_str("null"),
+            ref attr => push_attr(out, attr),
+        }
+    }
+    for &(name, ref child) in &node.children {
+        out.push(',');
+        push_json_str(out, name);
+        out.push(':');
+        match *child {
+            Child::One(ref child) => push_json(out, child),
+            Child::Many(ref children) => push_json_list(out, children),
+        }
+    }
+    out.push('}');
+}
+
+fn push_json_list(out: &mut String, nodes: &[Node]) {
+    out.push('[');
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_json(out, node);
+    }
+    out.push(']');
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn resolved(prog: &str) -> Vec<Stmt> {
+        let mut stmts = parser::parse(prog).unwrap();
+        parser::resolve(&mut stmts).unwrap();
+        stmts
+    }
+
+    #[test]
+    fn tree() {
+        let stmts = resolved("{ var a = 1; print a + \"x\"; }");
+        let expected = "\
+Block
+  body:
+    Var name=\"a\" scope=\"local\" depth=0 upvalue=false
+      initializer: Literal value=1 line=1
+    Print
+      expr: Binary operator=\"+\" line=1
+        lhs: Var name=\"a\" scope=\"local\" depth=0 upvalue=false line=1
+        rhs: Literal value=\"x\" line=1";
+        assert_eq!(expected, AstDump::tree().dump(&stmts));
+    }
+
+    #[test]
+    fn json() {
+        let stmts = resolved("print nil;\nfun f(a) { return \"a\\b\"; }");
+        let expected = concat!(
+            r#"[{"kind":"Print","expr":{"kind":"Literal","value":null,"line":1}},"#,
+            r#"{"kind":"Function","name":"f","method":false,"#,
+            r#""parameters":[{"kind":"Param","name":"a"}],"#,
+            r#""body":[{"kind":"Return","value":{"kind":"Literal","value":"a\\b","line":2}}]}]"#,
+        );
+        assert_eq!(expected, AstDump::json().dump(&stmts));
+    }
+}
@@ -0,0 +1,167 @@
+//! The engines that can run a script: the tree-walk interpreter in this
+//! crate, and the bytecode VM from the `vm` crate. Both run the same resolved
+//! statements, so a script can be compared on the two.
+
+use std::str::FromStr;
+
+use failure;
+use parser::ast::Stmt;
+use vm::gc::value::Variant;
+use vm::{Program, VM};
+
+use eval::Interpreter;
+use value::Value;
+
+#[derive(Debug)]
+pub enum EngineError {
+    /// The script couldn't be compiled, for each of these reasons.
+    Compile(Vec<failure::Error>),
+    /// The script failed while it was running.
+    Runtime(failure::Error),
+}
+
+pub type Result<T> = ::std::result::Result<T, EngineError>;
+
+pub trait Engine {
+    /// Runs statements that have already been resolved.
+    fn run(&mut self, stmts: &[Stmt]) -> Result<()>;
+
+    /// The status code that the last script asked to exit with, by returning
+    /// a number at the top level.
+    fn exit_code(&self) -> Option<i32>;
+}
+
+/// Which engine to run scripts with, as given by `--engine`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineKind {
+    Ast,
+    Vm,
+}
+
+impl EngineKind {
+    pub fn engine(self) -> Box<dyn Engine> {
+        match self {
+            EngineKind::Ast => Box::new(AstEngine::new()),
+            EngineKind::Vm => Box::new(VmEngine::new()),
+        }
+    }
+}
+
+impl Default for EngineKind {
+    fn default() -> Self {
+        EngineKind::Ast
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = failure::Error;
+
+    fn from_str(name: &str) -> ::std::result::Result<Self, Self::Err> {
+        match name {
+            "ast" => Ok(EngineKind::Ast),
+            "vm" => Ok(EngineKind::Vm),
+            _ => Err(format_err!("Unknown engine '{}', expected 'ast' or 'vm'.", name)),
+        }
+    }
+}
+
+pub struct AstEngine {
+    interpreter: Interpreter,
+    exit_code: Option<i32>,
+}
+
+impl AstEngine {
+    pub fn new() -> Self {
+        AstEngine {
+            interpreter: Interpreter::new(),
+            exit_code: None,
+        }
+    }
+}
+
+impl Engine for AstEngine {
+    fn run(&mut self, stmts: &[Stmt]) -> Result<()> {
+        match self.interpreter.interpret(stmts) {
+            Ok(value) => {
+                self.exit_code = match value {
+                    Value::Number(n) => Some(n as i32),
+                    _ => None,
+                };
+                Ok(())
+            },
+            Err(err) => Err(EngineError::Runtime(err.into())),
+        }
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+pub struct VmEngine {
+    vm: VM,
+    exit_code: Option<i32>,
+}
+
+impl VmEngine {
+    pub fn new() -> Self {
+        VmEngine {
+            vm: VM::new(),
+            exit_code: None,
+        }
+    }
+}
+
+impl Engine for VmEngine {
+    fn run(&mut self, stmts: &[Stmt]) -> Result<()> {
+        let program = Program::from_ast(stmts)
+            .map_err(|errors| EngineError::Compile(errors.into_iter().map(Into::into).collect()))?;
+        match self.vm.run_program(&program) {
+            Ok(value) => {
+                self.exit_code = match value.decode() {
+                    Variant::Float(n) => Some(n as i32),
+                    _ => None,
+                };
+                Ok(())
+            },
+            Err(traceback) => Err(EngineError::Runtime(format_err!("{}", traceback))),
+        }
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn run(kind: EngineKind, source: &str) -> Result<Option<i32>> {
+        let mut stmts = parser::parse(source).unwrap();
+        parser::resolve(&mut stmts).unwrap();
+        let mut engine = kind.engine();
+        engine.run(&stmts)?;
+        Ok(engine.exit_code())
+    }
+
+    #[test]
+    fn parses_engine_names() {
+        assert_eq!("ast".parse::<EngineKind>().unwrap(), EngineKind::Ast);
+        assert_eq!("vm".parse::<EngineKind>().unwrap(), EngineKind::Vm);
+        assert!("tree".parse::<EngineKind>().is_err());
+    }
+
+    #[test]
+    fn engines_agree() {
+        for &kind in &[EngineKind::Ast, EngineKind::Vm] {
+            assert_eq!(run(kind, "var a = 40; return a + 2;").unwrap(), Some(42));
+            assert_eq!(run(kind, "return \"done\";").unwrap(), None);
+            match run(kind, "nil();") {
+                Err(EngineError::Runtime(err)) => assert!(err.to_string().contains("Can only call functions and classes.")),
+                res => panic!("expected a runtime error, got {:?}", res),
+            }
+        }
+    }
+}
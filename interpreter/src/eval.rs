@@ -1,6 +1,11 @@
+use std::collections::HashSet;
+use std::time::Instant;
+
 use environment::Environment;
+use native;
 use parser::ast::*;
 use value::Value;
+use vm::random::Rng;
 
 #[derive(Debug, Fail)]
 pub enum RuntimeError {
@@ -35,12 +40,46 @@ pub enum RuntimeError {
     SuperNotAClass,
     #[fail(display = "Stack overflow.")]
     StackOverflow,
+    #[fail(display = "Allocation limit exceeded.")]
+    AllocationLimit,
+    #[fail(display = "Expected a {} instance as the receiver.", _0)]
+    BadReceiver(String),
+    #[fail(display = "{}.", _0)]
+    BadArgument(&'static str),
+    #[fail(display = "Failed to read input: {}.", _0)]
+    Input(String),
+    /// Reading or writing the file at a path failed.
+    #[fail(display = "Could not access '{}': {}.", _0, _1)]
+    #[cfg_attr(not(feature = "io"), allow(dead_code))]
+    File(String, String),
+    #[fail(display = "Mixin must be a class.")]
+    MixinNotAClass,
+    #[fail(display = "Method '{}' is defined by more than one mixin.", _0)]
+    MixinConflict(String),
+    #[fail(display = "Right operand of 'is' must be a class.")]
+    IsNotAClass,
 }
 
 pub type Result<T> = ::std::result::Result<T, RuntimeError>;
 
 const MAX_STACK_SIZE: usize = 256;
 
+/// Bounds on how much memory a single value may take up, so that a runaway
+/// script fails with a runtime error rather than exhausting the host.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum length in bytes of any string produced at runtime.
+    pub max_string_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_string_len: 1 << 28,
+        }
+    }
+}
+
 pub struct Interpreter {
     globals: Environment,
 
@@ -50,23 +89,50 @@ pub struct Interpreter {
     // Value to be Sync, which is quite complicated due to closures.
     retvals: Vec<Value>,
     stack_size: usize,
+    limits: Limits,
+    start_time: Instant,
+    rng: Rng,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
+        Interpreter::with_limits(Limits::default())
+    }
+
+    /// When the interpreter was created, which `clock()` counts from.
+    pub fn start_time(&self) -> Instant {
+        self.start_time
+    }
+
+    /// The generator used by `random` and `randomRange`, which `seedRandom`
+    /// reseeds.
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
         let mut globals = Environment::new();
         globals.set_at("clock", Value::builtin_clock(), 0);
+        native::define_natives(&mut globals);
 
         Interpreter {
             globals,
             retvals: Vec::new(),
             stack_size: 0,
+            limits,
+            start_time: Instant::now(),
+            rng: Rng::from_time(),
         }
     }
 
+    /// Evaluates `executable` in the global scope. A `return` at the top level
+    /// stops the script early, and the value it returns is the result.
     pub fn interpret<E: Eval>(&mut self, executable: E) -> Result<Value> {
         let mut globals = self.globals.clone();
-        executable.eval(self, &mut globals)
+        match executable.eval(self, &mut globals) {
+            Err(RuntimeError::Return) => Ok(self.pop_return()),
+            res => res,
+        }
     }
 
     pub fn interpret_within<E: Eval>(&mut self, environment: &mut Environment, executable: E) -> Result<Value> {
@@ -112,6 +178,7 @@ impl Eval for Stmt {
             Stmt::Expr(ref inner) => { inner.eval(interpreter, env)?; }
             Stmt::Print(ref inner) => {
                 let evald = inner.eval(interpreter, env)?;
+                let evald = printed(interpreter, evald)?;
                 println!("{}", evald);
             },
             Stmt::Var(ref var, ref expr) => {
@@ -145,7 +212,22 @@ impl Eval for Stmt {
                     }?;
                 }
             },
-            Stmt::Break => return Err(RuntimeError::Break),
+            Stmt::ForBody(ref for_body) => {
+                let name = for_body.var.name();
+                let value = interpreter.lookup(env, &for_body.var)
+                    .expect("the loop variable to be defined");
+                let mut iteration = env.extend();
+                iteration.set_at(name, value, 0);
+                let res = for_body.body.eval(interpreter, &mut iteration);
+                let value = iteration.get_at(name, 0)
+                    .expect("the copy of the loop variable to be defined");
+                interpreter.assign(env, &for_body.var, value);
+                res?;
+                if let Some(ref increment) = for_body.increment {
+                    increment.eval(interpreter, env)?;
+                }
+            },
+            Stmt::Break(_) => return Err(RuntimeError::Break),
             Stmt::Return(ref expr) => {
                 let retval =
                     expr.as_ref()
@@ -155,21 +237,7 @@ impl Eval for Stmt {
                 return Err(RuntimeError::Return);
             },
             Stmt::Class(ref class_decl) => {
-                // TODO: Clean this up.
-                let class = if let Some(ref sc_var) = class_decl.superclass {
-                    let superclass = match interpreter.lookup(env, sc_var) {
-                        Some(v) => v.clone(),
-                        None => return Err(RuntimeError::UndefinedVariable(sc_var.name().into())),
-                    };
-                    if superclass.clone().into_class().is_none() {
-                        return Err(RuntimeError::SuperNotAClass);
-                    }
-                    let mut env = env.extend();
-                    env.set_at("super", superclass.clone(), 0);
-                    Value::new_class(class_decl.var.name(), class_decl.methods.clone(), env, Some(superclass))
-                } else {
-                    Value::new_class(class_decl.var.name(), class_decl.methods.clone(), env.clone(), None)
-                };
+                let class = class_value(class_decl, interpreter, env)?;
                 debug!("{:?}", &class_decl.var);
                 interpreter.assign(env, &class_decl.var, class);
                 return Ok(Value::Void);
@@ -179,6 +247,70 @@ impl Eval for Stmt {
     }
 }
 
+/// What `print` shows for `value`, which for an instance whose class has a
+/// `toString` method is whatever that returns.
+fn printed(interpreter: &mut Interpreter, value: Value) -> Result<Value> {
+    let method = match value {
+        Value::Instance(ref instance) => instance.get_method("toString"),
+        _ => None,
+    };
+    match method {
+        Some(Value::Callable(ref method)) => {
+            if method.arity() != 0 {
+                return Err(RuntimeError::BadArity {
+                    got: 0,
+                    expected: method.arity(),
+                });
+            }
+            method.call(interpreter, Vec::new())
+        },
+        _ => Ok(value),
+    }
+}
+
+fn class_value(class_decl: &Class, interpreter: &mut Interpreter, env: &mut Environment) -> Result<Value> {
+    let mixins = mixins(class_decl, interpreter, env)?;
+    // TODO: Clean this up.
+    if let Some(ref sc_var) = class_decl.superclass {
+        let superclass = match interpreter.lookup(env, sc_var) {
+            Some(v) => v.clone(),
+            None => return Err(RuntimeError::UndefinedVariable(sc_var.name().into())),
+        };
+        if superclass.clone().into_class().is_none() {
+            return Err(RuntimeError::SuperNotAClass);
+        }
+        let mut env = env.extend();
+        env.set_at("super", superclass.clone(), 0);
+        Ok(Value::new_class(class_decl.var.name(), class_decl.methods.clone(), env, Some(superclass), mixins))
+    } else {
+        Ok(Value::new_class(class_decl.var.name(), class_decl.methods.clone(), env.clone(), None, mixins))
+    }
+}
+
+/// Looks up the mixins of a class, checking that no two of them provide a
+/// method that the class doesn't define itself.
+fn mixins(class_decl: &Class, interpreter: &mut Interpreter, env: &mut Environment) -> Result<Vec<Value>> {
+    let mut mixins = Vec::new();
+    let mut provided = HashSet::new();
+    for var in &class_decl.mixins {
+        let mixin = match interpreter.lookup(env, var) {
+            Some(v) => v,
+            None => return Err(RuntimeError::UndefinedVariable(var.name().into())),
+        };
+        let class = mixin.clone().into_class().ok_or(RuntimeError::MixinNotAClass)?;
+        for name in class.method_names() {
+            if class_decl.methods.iter().any(|m| m.var.name() == name) {
+                continue;
+            }
+            if !provided.insert(name.clone()) {
+                return Err(RuntimeError::MixinConflict(name));
+            }
+        }
+        mixins.push(mixin);
+    }
+    Ok(mixins)
+}
+
 impl Eval for Expr {
     fn eval(&self, interpreter: &mut Interpreter, env: &mut Environment) -> Result<Value> {
         match self.node {
@@ -187,6 +319,14 @@ impl Eval for Expr {
             ExprKind::Binary(ref inner) => inner.eval(interpreter, env),
             ExprKind::Unary(ref inner) => inner.eval(interpreter, env),
             ExprKind::Literal(ref inner) => inner.eval(interpreter, env),
+            ExprKind::Is(ref value, ref class) => {
+                let value = value.eval(interpreter, env)?;
+                let class = class.eval(interpreter, env)?.into_class().ok_or(RuntimeError::IsNotAClass)?;
+                match value {
+                    Value::Instance(ref instance) => Ok(instance.is_instance_of(&class).into()),
+                    _ => Ok(false.into()),
+                }
+            },
             ExprKind::Var(ref var) => {
                 match interpreter.lookup(env, var) {
                     None => return Err(RuntimeError::UndefinedVariable(var.name().into())),
@@ -205,12 +345,15 @@ impl Eval for Expr {
             },
             ExprKind::Call(ref inner) => inner.eval(interpreter, env),
             ExprKind::Get(ref expr, ref property) => {
-                if let Value::Instance(ref mut instance) = expr.eval(interpreter, env)? {
-                    instance.get(property)
-                        .ok_or_else(|| RuntimeError::UndefinedProperty(property.clone()))
-                } else {
-                    Err(RuntimeError::BadAccess)
-                }
+                let object = expr.eval(interpreter, env)?;
+                let value = match object {
+                    Value::Instance(ref instance) => instance.get(property),
+                    object => match object.into_class() {
+                        Some(class) => class.unbound_method(property),
+                        None => return Err(RuntimeError::BadAccess),
+                    },
+                };
+                value.ok_or_else(|| RuntimeError::UndefinedProperty(property.clone()))
             },
             ExprKind::Set(ref lhs, ref name, ref value) => {
                 let mut instance = lhs.eval(interpreter, env)?;
@@ -254,6 +397,7 @@ impl Eval for Expr {
             ExprKind::Function(ref declaration) => {
                 Ok(Value::new_lambda(declaration.clone(), env.clone()))
             },
+            ExprKind::Class(ref class_decl) => class_value(class_decl, interpreter, env),
         }
     }
 }
@@ -315,6 +459,9 @@ impl Eval for Binary {
         match *op {
             BinaryOperator::Plus => match (lhs, rhs) {
                 (Value::String(lhs), Value::String(rhs)) => {
+                    if lhs.len() + rhs.len() > interpreter.limits.max_string_len {
+                        return Err(RuntimeError::AllocationLimit);
+                    }
                     let mut res = lhs.clone();
                     res.push_str(&rhs);
                     return Ok(Value::String(res));
@@ -410,3 +557,21 @@ impl<'a> Eval for &'a [Stmt]
         Ok(Value::Void)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    #[test]
+    fn string_limit() {
+        let mut stmts = parser::parse("var s = \"ab\"; while (true) s = s + s;").unwrap();
+        parser::resolve(&mut stmts).unwrap();
+
+        let mut interpreter = Interpreter::with_limits(Limits { max_string_len: 64 });
+        match interpreter.interpret(&stmts[..]) {
+            Err(RuntimeError::AllocationLimit) => {},
+            res => panic!("expected allocation limit error, got {:?}", res),
+        }
+    }
+}
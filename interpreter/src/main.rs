@@ -6,31 +6,64 @@ extern crate log;
 extern crate failure;
 
 extern crate parser;
+extern crate vm;
 
 use std::env;
 use std::io::prelude::*;
 use std::fs::File;
 
-use eval::Interpreter;
+use engine::{EngineError, EngineKind};
 use repl::Repl;
 use pretty_printer::PrettyPrinter;
+use ast_dump::AstDump;
 
+mod engine;
 mod environment;
 mod eval;
 mod value;
+mod native;
 mod repl;
 mod pretty_printer;
+mod ast_dump;
+
+// Exit codes from sysexits.h, following the reference implementation.
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
 
 fn main() {
     env_logger::init();
 
-    let mut args = env::args();
-    let _ = args.next();
+    let mut args = env::args().skip(1).peekable();
+    let mut engine = EngineKind::default();
+    if args.peek().map_or(false, |arg| arg == "--engine" || arg.starts_with("--engine=")) {
+        let flag = args.next().unwrap();
+        let name = match flag.find('=') {
+            Some(i) => Some(flag[i + 1..].to_owned()),
+            None => args.next(),
+        };
+        engine = match name.map(|name| name.parse()) {
+            Some(Ok(engine)) => engine,
+            Some(Err(err)) => {
+                eprintln!("[error]: {}", err);
+                ::std::process::exit(1);
+            },
+            None => {
+                eprintln!("[error]: Missing argument to --engine");
+                ::std::process::exit(1);
+            },
+        };
+    }
 
     if let Some(arg) = args.next() {
         let res = match &arg[..] {
             "help" => {
-                println!("Usage: rlox [script]");
+                println!("Usage: rlox [--engine=ast|vm] [script]");
+                println!("       rlox print <script>        - Pretty print a script.");
+                println!("       rlox ast [--json] <script> - Dump the resolved AST of a script.");
+                println!("       rlox --tokens <script>     - Dump the tokens of a script.");
+                println!();
+                println!("Scripts are run by the tree-walk interpreter, or with --engine=vm by");
+                println!("compiling them to bytecode for the VM. The REPL always uses the interpreter.");
                 ::std::process::exit(0);
             },
             "print" => {
@@ -41,7 +74,30 @@ fn main() {
                 }
                 pretty_print(&arg.unwrap())
             },
-            sourcefile => execute(sourcefile),
+            "ast" => {
+                let mut json = false;
+                let mut filename = None;
+                for arg in args {
+                    match &arg[..] {
+                        "--json" => json = true,
+                        _ => filename = Some(arg),
+                    }
+                }
+                if filename.is_none() {
+                    eprintln!("[error]: Missing argument to ast");
+                    ::std::process::exit(1);
+                }
+                dump_ast(&filename.unwrap(), json)
+            },
+            "--tokens" => {
+                let arg = args.next();
+                if arg.is_none() {
+                    eprintln!("[error]: Missing argument to --tokens");
+                    ::std::process::exit(1);
+                }
+                dump_tokens(&arg.unwrap())
+            },
+            sourcefile => execute(sourcefile, engine),
         };
         if let Err(err) = res {
             eprintln!("[error]: {}", err);
@@ -65,7 +121,7 @@ fn show_errors<E: failure::Fail>(errors: Vec<E>) -> ! {
     for err in errors {
         eprintln!("[error]: Parse: {}", err);
     }
-    ::std::process::exit(1);
+    ::std::process::exit(EX_DATAERR);
 }
 
 fn pretty_print(filename: &str) -> Result<(), failure::Error> {
@@ -78,12 +134,51 @@ fn pretty_print(filename: &str) -> Result<(), failure::Error> {
     Ok(())
 }
 
-fn execute(filename: &str) -> Result<(), failure::Error> {
+fn dump_ast(filename: &str, json: bool) -> Result<(), failure::Error> {
+    let mut file = File::open(filename)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut stmts = report_and_bail!(parser::parse(&contents));
+    report_and_bail!(parser::resolve(&mut stmts));
+    let dump = if json { AstDump::json() } else { AstDump::tree() };
+    println!("{}", dump.dump(&stmts));
+    Ok(())
+}
+
+fn dump_tokens(filename: &str) -> Result<(), failure::Error> {
+    let mut file = File::open(filename)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let tokens = report_and_bail!(parser::tokenize(&contents));
+    for token in tokens {
+        let position = format!("{}:{}", token.position.line, token.position.column);
+        println!("{:<8} {:<14} {}", position, token.kind, token.text);
+    }
+    Ok(())
+}
+
+fn execute(filename: &str, engine: EngineKind) -> Result<(), failure::Error> {
     let mut file = File::open(filename)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
     let mut stmts = report_and_bail!(parser::parse(&contents));
     report_and_bail!(parser::resolve(&mut stmts));
-    Interpreter::new().interpret(&stmts[..])?;
+    let mut engine = engine.engine();
+    match engine.run(&stmts[..]) {
+        // A script that returns a number exits with it as the status code.
+        Ok(()) => if let Some(code) = engine.exit_code() {
+            ::std::process::exit(code);
+        },
+        Err(EngineError::Compile(errors)) => {
+            for err in errors {
+                eprintln!("[error]: Compile: {}", err);
+            }
+            ::std::process::exit(EX_DATAERR);
+        },
+        Err(EngineError::Runtime(err)) => {
+            eprintln!("[error]: {}", err);
+            ::std::process::exit(EX_SOFTWARE);
+        },
+    }
     Ok(())
 }
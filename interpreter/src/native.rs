@@ -0,0 +1,344 @@
+//! Global functions implemented in Rust, other than `clock`.
+//!
+//! These match the natives of the bytecode VM, so that scripts behave the same
+//! with either backend.
+
+use std::io;
+
+use environment::Environment;
+use eval::{Interpreter, Result, RuntimeError};
+use value::{LoxInstance, Value};
+
+pub fn define_natives(globals: &mut Environment) {
+    globals.set_at("fieldCount", Value::native("fieldCount", 1, field_count), 0);
+    globals.set_at("fieldName", Value::native("fieldName", 2, field_name), 0);
+    globals.set_at("deleteField", Value::native("deleteField", 2, delete_field), 0);
+    globals.set_at("getattr", Value::native("getattr", 2, getattr), 0);
+    globals.set_at("setattr", Value::native("setattr", 3, setattr), 0);
+    globals.set_at("hasattr", Value::native("hasattr", 2, hasattr), 0);
+    globals.set_at("len", Value::native("len", 1, len), 0);
+    globals.set_at("substr", Value::native("substr", 3, substr), 0);
+    globals.set_at("indexOf", Value::native("indexOf", 2, index_of), 0);
+    globals.set_at("upper", Value::native("upper", 1, upper), 0);
+    globals.set_at("lower", Value::native("lower", 1, lower), 0);
+    globals.set_at("chr", Value::native("chr", 1, chr), 0);
+    globals.set_at("ord", Value::native("ord", 1, ord), 0);
+    globals.set_at("type", Value::native("type", 1, type_of), 0);
+    globals.set_at("str", Value::native("str", 1, str), 0);
+    globals.set_at("num", Value::native("num", 1, num), 0);
+    globals.set_at("random", Value::native("random", 0, random), 0);
+    globals.set_at("randomRange", Value::native("randomRange", 2, random_range), 0);
+    globals.set_at("seedRandom", Value::native("seedRandom", 1, seed_random), 0);
+    globals.set_at("now", Value::native("now", 0, now), 0);
+    globals.set_at("readLine", Value::native("readLine", 0, read_line), 0);
+    globals.set_at("readNumber", Value::native("readNumber", 0, read_number), 0);
+    #[cfg(feature = "io")]
+    {
+        globals.set_at("readFile", Value::native("readFile", 1, read_file), 0);
+        globals.set_at("writeFile", Value::native("writeFile", 2, write_file), 0);
+        globals.set_at("appendFile", Value::native("appendFile", 2, append_file), 0);
+    }
+    #[cfg(feature = "process")]
+    {
+        globals.set_at("env", Value::native("env", 1, env), 0);
+        globals.set_at("exit", Value::native("exit", 1, exit), 0);
+        globals.set_at("cwd", Value::native("cwd", 0, cwd), 0);
+    }
+}
+
+/// Reads a line from stdin without its line ending, or None at the end of
+/// the input.
+fn line() -> Result<Option<String>> {
+    let mut line = String::new();
+    let read = io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| RuntimeError::Input(e.to_string()))?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+/// The next line of input, or nil at the end of the input.
+fn read_line(_: &mut Interpreter, _: Vec<Value>) -> Result<Value> {
+    Ok(line()?.map_or(Value::Nil, Value::String))
+}
+
+/// Reads a line and parses it as a number, returning nil at the end of the
+/// input or if the line isn't a number.
+fn read_number(_: &mut Interpreter, _: Vec<Value>) -> Result<Value> {
+    let number = line()?.and_then(|l| l.trim().parse::<f64>().ok());
+    Ok(number.map_or(Value::Nil, Value::Number))
+}
+
+/// The milliseconds since the unix epoch.
+fn now(_: &mut Interpreter, _: Vec<Value>) -> Result<Value> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let epoch_time =
+        SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("[FATAL] failed to get system time");
+    let millis = epoch_time.as_secs() * 1000 + u64::from(epoch_time.subsec_millis());
+    Ok(Value::Number(millis as f64))
+}
+
+fn string<'a>(value: &'a Value, error: &'static str) -> Result<&'a str> {
+    match *value {
+        Value::String(ref s) => Ok(s),
+        _ => Err(RuntimeError::BadArgument(error)),
+    }
+}
+
+/// A number argument that can be used as an index or a length.
+fn whole_number(value: &Value, error: &'static str) -> Result<usize> {
+    match *value {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Err(RuntimeError::BadArgument(error)),
+    }
+}
+
+/// The number of characters in a string.
+fn len(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let s = string(&args[0], "Argument must be a string")?;
+    Ok(Value::Number(s.chars().count() as f64))
+}
+
+/// The `len` characters starting at `start`, or fewer if the string ends
+/// first.
+fn substr(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let s = string(&args[0], "Argument must be a string")?;
+    let start = whole_number(&args[1], "Start must be a whole number")?;
+    let len = whole_number(&args[2], "Length must be a whole number")?;
+    Ok(Value::String(s.chars().skip(start).take(len).collect()))
+}
+
+/// The character index where `needle` first appears, or -1 if it doesn't.
+fn index_of(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let s = string(&args[0], "Argument must be a string")?;
+    let needle = string(&args[1], "Argument must be a string")?;
+    let index = s.find(needle).map_or(-1.0, |i| s[..i].chars().count() as f64);
+    Ok(Value::Number(index))
+}
+
+fn upper(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::String(string(&args[0], "Argument must be a string")?.to_uppercase()))
+}
+
+fn lower(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::String(string(&args[0], "Argument must be a string")?.to_lowercase()))
+}
+
+/// The string of the single character with the given code point.
+fn chr(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let c = match args[0] {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= f64::from(u32::max_value()) => {
+            ::std::char::from_u32(n as u32)
+        },
+        _ => None,
+    };
+    match c {
+        Some(c) => Ok(Value::String(c.to_string())),
+        None => Err(RuntimeError::BadArgument("Invalid character code")),
+    }
+}
+
+/// The code point of a single character string.
+fn ord(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let mut chars = string(&args[0], "Argument must be a single character")?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Value::Number(c as u32 as f64)),
+        _ => Err(RuntimeError::BadArgument("Argument must be a single character")),
+    }
+}
+
+fn type_of(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::String(args[0].type_name().to_owned()))
+}
+
+/// The value as `print` would show it.
+fn str(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::String(args[0].to_string()))
+}
+
+/// Parses a string as a number, returning nil if it isn't one. Numbers are
+/// returned as they are.
+fn num(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(match args[0] {
+        Value::Number(n) => Value::Number(n),
+        Value::String(ref s) => {
+            s.trim().parse::<f64>().ok().filter(|n| n.is_finite()).map_or(Value::Nil, Value::Number)
+        },
+        _ => Value::Nil,
+    })
+}
+
+/// A number from 0 up to but not including 1.
+fn random(interpreter: &mut Interpreter, _: Vec<Value>) -> Result<Value> {
+    Ok(Value::Number(interpreter.rng().next_f64()))
+}
+
+/// A whole number from `lo` up to but not including `hi`.
+fn random_range(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match (&args[0], &args[1]) {
+        (&Value::Number(lo), &Value::Number(hi)) if lo.fract() == 0.0 && hi.fract() == 0.0 => {
+            if lo >= hi {
+                return Err(RuntimeError::BadArgument("Range must not be empty"));
+            }
+            Ok(Value::Number(lo + (interpreter.rng().next_f64() * (hi - lo)).floor()))
+        },
+        _ => Err(RuntimeError::BadArgument("Range bounds must be whole numbers")),
+    }
+}
+
+/// Restarts the generator, so that it produces the same numbers every time
+/// it is given the same seed.
+fn seed_random(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match args[0] {
+        Value::Number(seed) => {
+            interpreter.rng().seed(seed.to_bits());
+            Ok(Value::Nil)
+        },
+        _ => Err(RuntimeError::BadArgument("Seed must be a number")),
+    }
+}
+
+#[cfg(feature = "io")]
+fn read_file(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    use std::fs;
+
+    let path = string(&args[0], "Path must be a string")?.to_owned();
+    let contents = fs::read_to_string(&path).map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    Ok(Value::String(contents))
+}
+
+/// Replaces the contents of a file, creating it if it doesn't exist.
+#[cfg(feature = "io")]
+fn write_file(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    use std::fs;
+
+    let path = string(&args[0], "Path must be a string")?.to_owned();
+    let contents = string(&args[1], "Contents must be a string")?;
+    fs::write(&path, contents).map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    Ok(Value::Nil)
+}
+
+/// Adds to the end of a file, creating it if it doesn't exist.
+#[cfg(feature = "io")]
+fn append_file(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let path = string(&args[0], "Path must be a string")?.to_owned();
+    let contents = string(&args[1], "Contents must be a string")?;
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    Ok(Value::Nil)
+}
+
+/// The value of an environment variable, or nil if it isn't set.
+#[cfg(feature = "process")]
+fn env(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let name = string(&args[0], "Variable name must be a string")?;
+    Ok(::std::env::var(name).map_or(Value::Nil, Value::String))
+}
+
+/// Exits the process straight away with the given status code.
+#[cfg(feature = "process")]
+fn exit(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match args[0] {
+        Value::Number(code) if code.fract() == 0.0 => {
+            use std::io::Write;
+
+            let _ = io::stdout().flush();
+            ::std::process::exit(code as i32)
+        },
+        _ => Err(RuntimeError::BadArgument("Exit code must be a whole number")),
+    }
+}
+
+/// The current working directory, or nil if it can't be found.
+#[cfg(feature = "process")]
+fn cwd(_: &mut Interpreter, _: Vec<Value>) -> Result<Value> {
+    Ok(::std::env::current_dir()
+        .map_or(Value::Nil, |dir| Value::String(dir.to_string_lossy().into_owned())))
+}
+
+fn instance(value: &Value) -> Result<&LoxInstance> {
+    match *value {
+        Value::Instance(ref instance) => Ok(instance),
+        _ => Err(RuntimeError::BadPropertyAccess),
+    }
+}
+
+fn field_count(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::Number(instance(&args[0])?.field_names().len() as f64))
+}
+
+/// The name of the `n`th field of an instance, counting in alphabetical
+/// order, so that scripts can loop over the fields up to `fieldCount`.
+fn field_name(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match args[1] {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => {
+            Ok(instance(&args[0])?.field_names()
+                .into_iter()
+                .nth(n as usize)
+                .map_or(Value::Nil, Value::String))
+        },
+        _ => Err(RuntimeError::BadArgument("Field index must be a whole number")),
+    }
+}
+
+/// Removes a field from an instance, returning whether it had one.
+fn delete_field(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match args[1] {
+        Value::String(ref name) => Ok(instance(&args[0])?.remove(name).is_some().into()),
+        _ => Err(RuntimeError::BadArgument("Field name must be a string")),
+    }
+}
+
+fn property_name(value: &Value) -> Result<&str> {
+    match *value {
+        Value::String(ref name) => Ok(name),
+        _ => Err(RuntimeError::BadArgument("Property name must be a string")),
+    }
+}
+
+/// Reads a property whose name is only known at runtime, like `obj.name`.
+fn getattr(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let name = property_name(&args[1])?;
+    match args[0] {
+        Value::Instance(ref instance) => {
+            instance.get(name).ok_or_else(|| RuntimeError::UndefinedProperty(name.to_owned()))
+        },
+        _ => Err(RuntimeError::BadAccess),
+    }
+}
+
+/// Sets a field whose name is only known at runtime, returning the value
+/// like an assignment does.
+fn setattr(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let name = property_name(&args[1])?;
+    instance(&args[0])?.set(name, args[2].clone());
+    Ok(args[2].clone())
+}
+
+/// Whether a value has a field or method of the given name. Anything other
+/// than an instance has none.
+fn hasattr(_: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let name = property_name(&args[1])?;
+    match args[0] {
+        Value::Instance(ref instance) => Ok(instance.get(name).is_some().into()),
+        _ => Ok(false.into()),
+    }
+}
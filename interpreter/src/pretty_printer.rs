@@ -1,4 +1,4 @@
-use parser::ast::{Stmt, Expr, ExprKind, Literal};
+use parser::ast::{Class, Stmt, Expr, ExprKind, Literal};
 
 pub struct PrettyPrinter {
     inner: String,
@@ -61,7 +61,16 @@ impl PrettyPrinter {
                 }
                 self.newline(indent).push_char('}');
             },
-            Stmt::Break => {
+            // Printed the way it is desugared, like the rest of the loop.
+            Stmt::ForBody(ref for_body) => {
+                self.push_char('{');
+                self.push_stmt(&for_body.body, indent + indent_size, true);
+                if let Some(ref increment) = for_body.increment {
+                    self.newline(indent + indent_size).push_expr(increment).push_char(';');
+                }
+                self.newline(indent).push_char('}');
+            },
+            Stmt::Break(_) => {
                 self.push("break;");
             },
             Stmt::If(ref cond, ref then_clause, ref else_clause) => {
@@ -114,11 +123,18 @@ impl PrettyPrinter {
                 if let Some(ref superclass) = cls.superclass {
                     self.push(" < ").push(superclass.name());
                 }
+                self.mixins(cls);
                 self.push("{}");
             },
         }
     }
 
+    fn mixins(&mut self, cls: &Class) {
+        for (i, mixin) in cls.mixins.iter().enumerate() {
+            self.push(if i == 0 { " with " } else { ", " }).push(mixin.name());
+        }
+    }
+
     fn newline(&mut self, indent: usize) -> &mut Self {
         self.push_char('\n');
         for _ in ::std::iter::repeat(' ').take(indent) {
@@ -139,6 +155,9 @@ impl PrettyPrinter {
                 self.push_expr(&logical.lhs).push_char(' ').push(op.to_str())
                     .push_char(' ').push_expr(&logical.rhs);
             },
+            ExprKind::Is(ref value, ref class) => {
+                self.push_expr(value).push(" is ").push_expr(class);
+            },
             ExprKind::Grouping(ref group) => {
                 self.push_char('(').push_expr(group).push_char(')');
             },
@@ -183,6 +202,14 @@ impl PrettyPrinter {
             },
             ExprKind::This(_, _) => { self.push("this"); },
             ExprKind::Super(_, _, ref method) => { self.push("super").push_char('.').push(method); },
+            ExprKind::Class(ref cls) => {
+                self.push("class");
+                if let Some(ref superclass) = cls.superclass {
+                    self.push(" < ").push(superclass.name());
+                }
+                self.mixins(cls);
+                self.push("{}");
+            },
             ExprKind::Function(ref function) => {
                 let decl = function.borrow();
                 for param in &decl.parameters {
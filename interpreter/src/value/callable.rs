@@ -1,7 +1,7 @@
 use std::fmt::Debug;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use value::Value;
 use value::LoxInstance;
@@ -16,7 +16,11 @@ use eval::Interpreter;
 pub enum Callable {
     Function(LoxFunction),
     Clock,
+    Native(Native),
     Class(LoxClassHandle),
+    /// A method taken from a class rather than an instance, which is called
+    /// with its receiver as the first argument.
+    Method(LoxClassHandle, LoxFunction),
 }
 
 impl Callable {
@@ -28,9 +32,9 @@ impl Callable {
         Callable::Function(LoxFunction::new_lambda(declaration, env))
     }
 
-    pub fn new_class(name: &str, methods: Vec<FunctionStmt>, env: Environment, superclass: Option<LoxClassHandle>) -> Self {
+    pub fn new_class(name: &str, methods: Vec<FunctionStmt>, env: Environment, superclass: Option<LoxClassHandle>, mixins: Vec<LoxClassHandle>) -> Self {
         Callable::Class(LoxClassHandle {
-            class: Rc::new(LoxClass::new(name, methods, env, superclass))
+            class: Rc::new(LoxClass::new(name, methods, env, superclass, mixins))
         })
     }
 
@@ -38,7 +42,18 @@ impl Callable {
         match *self {
             Callable::Function(ref fun) => fun.call(interpreter, arguments),
             Callable::Clock => clock(interpreter, arguments),
+            Callable::Native(ref native) => (native.function)(interpreter, arguments),
             Callable::Class(ref cls) => cls.call(interpreter, arguments),
+            Callable::Method(ref cls, ref fun) => {
+                let mut arguments = arguments.into_iter();
+                match arguments.next() {
+                    Some(Value::Instance(ref receiver)) if receiver.is_instance_of(cls) => {
+                        let bound = fun.bind(Value::Instance(receiver.clone()));
+                        bound.call(interpreter, arguments.collect())
+                    },
+                    _ => Err(RuntimeError::BadReceiver(cls.name().to_owned())),
+                }
+            },
         }
     }
 
@@ -46,7 +61,9 @@ impl Callable {
         match *self {
             Callable::Function(ref fun) => fun.arity(),
             Callable::Clock => 0,
+            Callable::Native(ref native) => native.arity,
             Callable::Class(ref cls) => cls.arity(),
+            Callable::Method(_, ref fun) => fun.arity() + 1,
         }
     }
 }
@@ -54,7 +71,7 @@ impl Callable {
 impl Debug for Callable {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match *self {
-            Callable::Function(ref fun) => {
+            Callable::Function(ref fun) | Callable::Method(_, ref fun) => {
                 if let Some(ref name) = fun.name {
                     write!(f, "<fn {}>", name)
                 } else {
@@ -64,6 +81,9 @@ impl Debug for Callable {
             Callable::Clock => {
                 write!(f, "<builtin 'clock'>")
             },
+            Callable::Native(ref native) => {
+                write!(f, "<builtin '{}'>", native.name)
+            },
             Callable::Class(ref cls) => {
                 write!(f, "{}", cls.name())
             },
@@ -124,10 +144,15 @@ impl LoxFunction {
         }
         // Evaluate body
         match interpreter.interpret_within(&mut env, decl.body.as_slice()) {
-            Err(RuntimeError::Return) => {
+            // An initializer can only `return;`, which still returns `this`.
+            Err(RuntimeError::Return) if !self.is_initializer() => {
                 let retval = interpreter.pop_return();
                 return Ok(retval);
             },
+            Err(RuntimeError::Return) => {
+                interpreter.pop_return();
+                Ok(Value::Nil)
+            },
             val => val,
         }?;
         if self.is_initializer() {
@@ -156,15 +181,32 @@ impl Into<Value> for LoxFunction {
     }
 }
 
-fn clock(_: &mut Interpreter, _: Vec<Value>) -> Result<Value, RuntimeError> {
-    use std::time::{SystemTime, UNIX_EPOCH};
+/// The seconds since the interpreter was created, for timing scripts.
+fn clock(interpreter: &mut Interpreter, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    let elapsed = interpreter.start_time().elapsed();
+    Ok(Value::Number(elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9))
+}
+
+pub type NativeFn = fn(&mut Interpreter, Vec<Value>) -> Result<Value, RuntimeError>;
+
+/// A global function implemented in Rust.
+#[derive(Clone)]
+pub struct Native {
+    name: &'static str,
+    arity: usize,
+    function: NativeFn,
+}
+
+impl Native {
+    pub fn new(name: &'static str, arity: usize, function: NativeFn) -> Self {
+        Native { name, arity, function }
+    }
+}
 
-    let epoch_time =
-        SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("[FATAL] failed to get system time")
-        .as_secs();
-    Ok(Value::Number(epoch_time as f64))
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        self.name == other.name
+    }
 }
 
 #[derive(Clone)]
@@ -202,11 +244,24 @@ impl LoxClassHandle {
     fn arity(&self) -> usize {
         self.init().map(|m| m.arity()).unwrap_or(0)
     }
+
+    /// Looks up a method to call with an explicit receiver.
+    pub fn unbound_method(&self, name: &str) -> Option<Value> {
+        self.method(name).map(|m| Value::Callable(Callable::Method(self.clone(), m)))
+    }
 }
 
 impl LoxClass {
-    pub fn new(name: &str, method_stmts: Vec<FunctionStmt>, env: Environment, superclass: Option<LoxClassHandle>) -> Self {
+    pub fn new(name: &str, method_stmts: Vec<FunctionStmt>, env: Environment, superclass: Option<LoxClassHandle>, mixins: Vec<LoxClassHandle>) -> Self {
         let mut methods = HashMap::new();
+        // Mixed in methods belong to the class, so they take precedence over
+        // the superclass's, but the class's own methods override them.
+        for mixin in mixins {
+            for mname in mixin.method_names() {
+                let method = mixin.method(&mname).expect("mixin to have the method");
+                methods.insert(mname, method);
+            }
+        }
         for stmt in method_stmts {
             let mname = stmt.var.name();
             let fun_decl = stmt.declaration.clone();
@@ -224,8 +279,9 @@ impl LoxClass {
         &self.name
     }
 
+    /// The initializer, which may be inherited.
     fn init(&self) -> Option<LoxFunction> {
-        self.methods.get("init").map(Clone::clone)
+        self.method("init")
     }
 
     pub fn method(&self, name: &str) -> Option<LoxFunction> {
@@ -234,6 +290,21 @@ impl LoxClass {
             .map(Clone::clone)
             .or_else(|| self.superclass.as_ref().and_then(|sc| sc.method(name)))
     }
+
+    /// The names of every method on the class, including inherited ones, in
+    /// alphabetical order.
+    pub fn method_names(&self) -> Vec<String> {
+        let mut names = self.superclass.as_ref().map_or_else(BTreeSet::new, |sc| {
+            sc.method_names().into_iter().collect()
+        });
+        names.extend(self.methods.keys().cloned());
+        names.into_iter().collect()
+    }
+
+    pub fn is_subclass_of(&self, other: &LoxClass) -> bool {
+        self as *const _ == other as *const _ ||
+            self.superclass.as_ref().map_or(false, |sc| sc.is_subclass_of(other))
+    }
 }
 
 impl PartialEq for LoxClassHandle {
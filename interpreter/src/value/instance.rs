@@ -28,7 +28,8 @@ impl LoxInstance {
             .or_else(|| self.get_method(field))
     }
 
-    fn get_method(&self, method: &str) -> Option<Value> {
+    /// A method of the instance's class, bound to the instance.
+    pub fn get_method(&self, method: &str) -> Option<Value> {
         self.class.method(method)
             .map(|m| {
                 let this = Value::Instance(self.clone());
@@ -37,10 +38,25 @@ impl LoxInstance {
             })
     }
 
+    pub fn is_instance_of(&self, class: &LoxClass) -> bool {
+        self.class.is_subclass_of(class)
+    }
+
     pub fn set(&self, field: &str, value: Value) {
         let mut fields = self.fields.borrow_mut();
         fields.insert(field.into(), value);
     }
+
+    /// The names of the instance's fields, in alphabetical order.
+    pub fn field_names(&self) -> Vec<String> {
+        let mut names = self.fields.borrow().keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn remove(&self, field: &str) -> Option<Value> {
+        self.fields.borrow_mut().remove(field)
+    }
 }
 
 impl PartialEq for LoxInstance {
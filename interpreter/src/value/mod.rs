@@ -5,8 +5,8 @@ use std::cell::RefCell;
 use parser::ast::{FunctionDecl, FunctionStmt};
 use environment::Environment;
 
-use self::callable::Callable;
-use self::instance::LoxInstance;
+use self::callable::{Callable, Native, NativeFn};
+pub use self::instance::LoxInstance;
 
 mod callable;
 mod instance;
@@ -32,20 +32,27 @@ impl Value {
         Value::Callable(Callable::new_lambda(declaration, env))
     }
 
-    pub fn new_class(name: &str, methods: Vec<FunctionStmt>, env: Environment, superclass: Option<Value>) -> Self {
+    pub fn new_class(name: &str, methods: Vec<FunctionStmt>, env: Environment, superclass: Option<Value>, mixins: Vec<Value>) -> Self {
         let superclass = superclass.map(|value| {
             if let Value::Callable(Callable::Class(class)) = value {
                 return class;
             }
             panic!("superclass should have been validated in interpreter")
         });
-        Value::Callable(Callable::new_class(name, methods, env, superclass))
+        let mixins = mixins.into_iter().map(|value| {
+            value.into_class().expect("mixins should have been validated in interpreter")
+        }).collect();
+        Value::Callable(Callable::new_class(name, methods, env, superclass, mixins))
     }
 
     pub fn builtin_clock() -> Self {
         Value::Callable(Callable::Clock)
     }
 
+    pub fn native(name: &'static str, arity: usize, function: NativeFn) -> Self {
+        Value::Callable(Callable::Native(Native::new(name, arity, function)))
+    }
+
     pub fn truthy(&self) -> bool {
         match *self {
             Value::Nil => false,
@@ -54,6 +61,19 @@ impl Value {
         }
     }
 
+    /// The name of the value's type, as returned by the `type` native.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::True | Value::False => "bool",
+            Value::Nil | Value::Void => "nil",
+            Value::Callable(Callable::Class(_)) => "class",
+            Value::Callable(_) => "function",
+            Value::Instance(_) => "instance",
+        }
+    }
+
     pub fn into_class(self) -> Option<callable::LoxClassHandle> {
         if let Value::Callable(Callable::Class(cls)) = self {
             return Some(cls);
@@ -0,0 +1,12 @@
+#!/bin/bash
+
+# Wrapper around cargo test to avoid testing features that are not
+# implemented in the interpreter.
+
+# The limits are those of the bytecode VM.
+cargo test --\
+    --skip limit::loop_too_large\
+    --skip limit::too_many_constants\
+    --skip limit::too_many_locals\
+    --skip limit::too_many_upvalues\
+    $@
@@ -133,8 +133,9 @@ pub enum Stmt {
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
     // Does not need line
     While(Expr, Box<Stmt>),
-    // Needs line
-    Break,
+    // Does not need line
+    ForBody(ForBody),
+    Break(Position),
     // Needs line, internally.
     Function(FunctionStmt),
     // Needs line
@@ -142,29 +143,81 @@ pub enum Stmt {
     Class(Class),
 }
 
+/// The body and increment of a `for` loop that declares a variable. The
+/// body gets its own copy of the variable on each iteration, so closures
+/// created by one iteration don't see the changes made by the next.
+#[derive(PartialEq, Debug)]
+pub struct ForBody {
+    /// The loop's variable, which the copy is made from and written back to
+    /// before the increment.
+    pub var: Variable,
+    pub body: Box<Stmt>,
+    pub increment: Option<Expr>,
+    /// Whether a closure captures the body's copy, set by the resolver. If
+    /// none does, the copy can't be told apart from the variable itself.
+    pub captured: bool,
+}
+
+impl ForBody {
+    pub(super) fn new(name: &str, body: Stmt, increment: Option<Expr>) -> Self {
+        ForBody {
+            var: Variable::new_global(name),
+            body: Box::new(body),
+            increment,
+            captured: false,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub struct Class {
     pub var: Variable,
+    /// Where the class's name is, or its keyword for class expressions.
+    pub pos: Position,
     pub methods: Vec<FunctionStmt>,
     pub class_methods: Vec<FunctionStmt>,
     pub superclass: Option<Variable>,
+    /// Classes whose methods are copied into this one when it is created.
+    pub mixins: Vec<Variable>,
+}
+
+/// The name given to classes created by a class expression.
+pub const ANONYMOUS_CLASS: &str = "<anonymous>";
+
+impl Class {
+    pub(super) fn new(name: &str, pos: Position, methods: Vec<FunctionStmt>, class_methods: Vec<FunctionStmt>, superclass: Option<Variable>, mixins: Vec<Variable>) -> Class {
+        Class {
+            var: Variable::new_global(name),
+            pos,
+            methods,
+            class_methods,
+            superclass,
+            mixins,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct FunctionStmt {
     pub var: Variable,
+    /// Where the function's name is.
+    pub pos: Position,
     pub declaration: Rc<RefCell<FunctionDecl>>,
 }
 
 impl FunctionStmt {
-    pub fn new(name: &str, declaration: FunctionDecl) -> Self {
+    pub fn new(name: &str, pos: Position, declaration: FunctionDecl) -> Self {
         FunctionStmt {
             var: Variable::new_global(name),
+            pos,
             declaration: Rc::new(RefCell::new(declaration)),
         }
     }
 }
 
+/// The name given to functions created by a function expression.
+pub const ANONYMOUS_FUNCTION: &str = "<anonymous>";
+
 #[derive(PartialEq, Debug)]
 pub struct FunctionDecl {
     // FIXME: this field should probably be an enum variant instead.
@@ -199,16 +252,6 @@ impl Stmt {
         Stmt::If(cond, Box::new(then_clause), Some(Box::new(else_clause)))
     }
 
-    pub(super) fn class(name: &str, methods: Vec<FunctionStmt>, class_methods: Vec<FunctionStmt>, superclass: Option<Variable>) -> Stmt {
-        let var = Variable::new_global(name);
-        Stmt::Class(Class {
-            var,
-            methods,
-            class_methods,
-            superclass,
-        })
-    }
-
     pub fn position(&self) -> Option<&Position> {
         match *self {
             Stmt::Expr(ref expr) => Some(&expr.pos),
@@ -222,9 +265,10 @@ impl Stmt {
             // for those below.
             Stmt::Var(_, ref expr) => Some(&expr.pos),
             Stmt::Block(_) => None,
-            Stmt::Break => None,
-            Stmt::Class(_) => None,
-            Stmt::Function(_) => None,
+            Stmt::ForBody(ref for_body) => for_body.body.position(),
+            Stmt::Break(ref pos) => Some(pos),
+            Stmt::Class(ref class) => Some(&class.pos),
+            Stmt::Function(ref function) => Some(&function.pos),
         }
     }
 }
@@ -241,6 +285,7 @@ impl Expr {
             start: 0,
             end: 0,
             line: 0,
+            column: 0,
         };
         Expr {
             pos,
@@ -268,6 +313,10 @@ pub enum ExprKind {
     This(Variable, Position),
     Super(Variable, Position, String),
     Function(Rc<RefCell<FunctionDecl>>),
+    Class(Box<Class>),
+    /// `value is Class`, which is whether the value is an instance of the
+    /// class or of one that inherits from it.
+    Is(Box<Expr>, Box<Expr>),
 }
 
 impl ExprKind {
@@ -314,6 +363,10 @@ impl ExprKind {
     pub(super) fn function(declaration: FunctionDecl) -> Self {
         ExprKind::Function(Rc::new(RefCell::new(declaration)))
     }
+
+    pub(super) fn class(class: Class) -> Self {
+        ExprKind::Class(Box::new(class))
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -1,5 +1,32 @@
-#[derive(Debug, Fail, Clone)]
-pub enum SyntaxError {
+use std::fmt;
+
+use failure::Fail;
+
+use scanner::Position;
+
+/// An error in the syntax of a script, along with where it was found.
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    pub kind: SyntaxErrorKind,
+    pub position: Position,
+}
+
+impl SyntaxError {
+    pub fn new(kind: SyntaxErrorKind, position: Position) -> Self {
+        SyntaxError { kind, position }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [line {}:{}]", self.kind, self.position.line, self.position.column)
+    }
+}
+
+impl Fail for SyntaxError {}
+
+#[derive(Debug, Fail, Clone, PartialEq)]
+pub enum SyntaxErrorKind {
     #[fail(display = "unexpected end of input")]
     UnexpectedEOF,
     #[fail(display = "unexpected character '{}'", _0)]
@@ -18,17 +45,41 @@ pub enum SyntaxError {
     TooManyArguments,
     #[fail(display = "Cannot have more than 8 parameters.")]
     TooManyParameters,
+    #[fail(display = "Code is nested too deeply.")]
+    TooDeeplyNested,
+    #[fail(display = "Source is not valid UTF-8.")]
+    InvalidUtf8,
 }
 
+/// An error found while resolving variables, such as a misplaced `this`,
+/// along with where it was found.
+#[derive(Debug, PartialEq)]
+pub struct ResolveError {
+    pub kind: ResolveErrorKind,
+    pub position: Position,
+}
+
+impl ResolveError {
+    pub fn new(kind: ResolveErrorKind, position: Position) -> Self {
+        ResolveError { kind, position }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [line {}:{}]", self.kind, self.position.line, self.position.column)
+    }
+}
+
+impl Fail for ResolveError {}
+
 #[derive(Debug, Fail, PartialEq)]
-pub enum ResolveError {
-    #[fail(display =  "Cannot return from top-level code.")]
-    ReturnOutsideFunction,
+pub enum ResolveErrorKind {
     #[fail(display =  "Cannot break outside of a loop.")]
     BreakOutsideLoop,
     #[fail(display = "Cannot read local variable in its own initializer.")]
-    InitializerSelfReference,
-    #[fail(display = "Variable with this name already declared in this scope.")]
+    InitializerSelfReference(String),
+    #[fail(display = "Already a variable with this name in this scope.")]
     AlreadyDeclared,
     #[fail(display = "Cannot use 'this' outside of a class.")]
     ThisOutsideClass,
@@ -38,12 +89,29 @@ pub enum ResolveError {
     SuperOutsideClass,
     #[fail(display = "Cannot use 'super' in a class with no superclass.")]
     SuperInBaseClass,
-    #[fail(display = "Multiple errors found in block.")]
-    BlockErrors(Vec<ResolveError>),
 }
 
-impl From<Vec<ResolveError>> for ResolveError {
-    fn from(errs: Vec<ResolveError>) -> Self {
-        ResolveError::BlockErrors(errs)
+/// Code that is allowed but probably a mistake. Warnings never stop a script
+/// from running.
+#[derive(Debug, Fail, PartialEq, Clone)]
+pub enum Warning {
+    #[fail(display = "[line {}] Local variable '{}' is never used.", line, name)]
+    UnusedVariable { name: String, line: usize },
+    #[fail(display = "[line {}] Parameter '{}' is never used.", line, name)]
+    UnusedParameter { name: String, line: usize },
+    #[fail(display = "[line {}] Unreachable code.", line)]
+    UnreachableCode { line: usize },
+    #[fail(display = "[line {}] Condition is always false.", line)]
+    AlwaysFalse { line: usize },
+}
+
+impl Warning {
+    pub fn line(&self) -> usize {
+        match *self {
+            Warning::UnusedVariable { line, .. }
+            | Warning::UnusedParameter { line, .. }
+            | Warning::UnreachableCode { line }
+            | Warning::AlwaysFalse { line } => line,
+        }
     }
 }
@@ -0,0 +1,239 @@
+//! An opinionated formatter for Lox source.
+//!
+//! Formatting only changes the whitespace between tokens, so comments stay
+//! where they were and the program means the same thing afterwards:
+//!
+//! * Blocks are indented by four spaces, with the opening brace at the end of
+//!   the line that starts them and the closing brace on a line of its own.
+//! * Each statement goes on its own line, keeping at most one of the blank
+//!   lines between them.
+//! * Operators are surrounded by single spaces. There are none inside
+//!   parentheses, before commas and semicolons, around dots or after unary
+//!   operators.
+
+use errors::SyntaxError;
+use scanner::{Keyword, Scanner, Token, TokenType};
+
+const INDENT: &str = "    ";
+
+/// Formats `source`, which has to parse.
+pub fn format(source: &str) -> Result<String, Vec<SyntaxError>> {
+    ::parse(source)?;
+    let tokens = Scanner::with_comments(source)
+        .filter(|token| token.as_ref().map_or(true, |token| token.ty != TokenType::EOF))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| vec![err])?;
+    let mut formatter = Formatter::default();
+    for (i, token) in tokens.iter().enumerate() {
+        formatter.token(token, tokens.get(i + 1).map(|next| next.ty));
+    }
+    let mut out = formatter.out;
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Formatter<'a> {
+    out: String,
+    indent: usize,
+    /// How many parentheses are open in the innermost block. Semicolons
+    /// inside them, as in `for` loops, don't end a line.
+    parens: usize,
+    /// The open parentheses of each of the blocks around the current one.
+    outer_parens: Vec<usize>,
+    /// Whether the next token starts a new line.
+    newline: bool,
+    /// The last token that was written, which may be a comment.
+    prev: Option<Token<'a>>,
+    /// The line that `prev` ended on in the source.
+    prev_line: usize,
+    /// Whether the last token that was not a comment is a unary operator.
+    unary: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn token(&mut self, token: &Token<'a>, next: Option<TokenType>) {
+        match token.ty {
+            TokenType::Comment => {
+                if self.prev.is_some() && token.position.line == self.prev_line {
+                    self.out.push(' ');
+                } else {
+                    self.start_line(token);
+                }
+                self.out.push_str(token.value);
+                // The comment runs to the end of the line.
+                self.newline = true;
+            },
+            TokenType::RightBrace => {
+                self.indent = self.indent.saturating_sub(1);
+                self.parens = self.outer_parens.pop().unwrap_or(0);
+                let empty = self.prev.map_or(false, |prev| prev.ty == TokenType::LeftBrace);
+                if !empty {
+                    self.newline = true;
+                    self.start_line(token);
+                }
+                self.out.push('}');
+                self.newline = match next {
+                    Some(TokenType::Keyword(Keyword::Else)) | Some(TokenType::Semicolon)
+                    | Some(TokenType::RightParen) | Some(TokenType::Comma) | Some(TokenType::Dot) => false,
+                    _ => true,
+                };
+            },
+            _ => {
+                if self.newline {
+                    self.start_line(token);
+                } else if self.spaced(token) {
+                    self.out.push(' ');
+                }
+                self.out.push_str(token.value);
+                match token.ty {
+                    TokenType::LeftParen => self.parens += 1,
+                    TokenType::RightParen => self.parens = self.parens.saturating_sub(1),
+                    TokenType::LeftBrace => {
+                        self.outer_parens.push(self.parens);
+                        self.parens = 0;
+                        self.indent += 1;
+                        self.newline = next != Some(TokenType::RightBrace);
+                    },
+                    TokenType::Semicolon => self.newline = self.parens == 0,
+                    _ => {},
+                }
+                self.unary = self.is_unary(token);
+            },
+        }
+        self.prev = Some(*token);
+        self.prev_line = token.position.line + token.value.matches('\n').count();
+    }
+
+    /// Ends the current line, keeping one blank line if there were any
+    /// before `token` in the source.
+    fn start_line(&mut self, token: &Token) {
+        self.newline = false;
+        let prev = match self.prev {
+            Some(prev) => prev,
+            // Nothing comes before the first token.
+            None => return,
+        };
+        self.out.push('\n');
+        let blank = token.position.line > self.prev_line + 1;
+        if blank && prev.ty != TokenType::LeftBrace && token.ty != TokenType::RightBrace {
+            self.out.push('\n');
+        }
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// Whether there is a space between the last token and `token`, which
+    /// are on the same line.
+    fn spaced(&self, token: &Token) -> bool {
+        let prev = match self.prev {
+            Some(prev) => prev.ty,
+            None => return false,
+        };
+        match (prev, token.ty) {
+            (_, TokenType::Semicolon) | (_, TokenType::Comma) | (_, TokenType::Dot) | (_, TokenType::RightParen) => false,
+            (TokenType::LeftParen, _) | (TokenType::Dot, _) => false,
+            // A call, as opposed to a grouping or the parameters of a
+            // function expression.
+            (TokenType::Identifier, TokenType::LeftParen) | (TokenType::RightParen, TokenType::LeftParen) => false,
+            _ => !self.unary,
+        }
+    }
+
+    fn is_unary(&self, token: &Token) -> bool {
+        match token.ty {
+            TokenType::Bang => true,
+            // A minus is only binary after something that ends an operand.
+            TokenType::Minus => match self.prev.map(|prev| prev.ty) {
+                Some(TokenType::Identifier) | Some(TokenType::Number(_)) | Some(TokenType::String(_))
+                | Some(TokenType::RightParen) | Some(TokenType::Keyword(Keyword::True))
+                | Some(TokenType::Keyword(Keyword::False)) | Some(TokenType::Keyword(Keyword::Nil))
+                | Some(TokenType::Keyword(Keyword::This)) => false,
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<&str> {
+        Scanner::with_comments(source).map(|token| token.unwrap().value).collect()
+    }
+
+    const MESSY: &str = "
+// A counter.
+class Counter < Base{
+  init(start){this.count=start;}   // Starts anywhere.
+  class zero( ) { return Counter( 0 ) ; }
+
+
+  up(){
+this.count=this.count+1;return -this.count;}
+}
+fun apply(f,x){return f(x);}
+for(var i=0;i<3;i=i+1)print !(i==1)and-i<=2;
+if (true) { print \"yes\"; } else {}
+var id = fun (x) { return x; };
+print apply(fun (n) {return n*2;},-1)-1;
+";
+
+    const FORMATTED: &str = "\
+// A counter.
+class Counter < Base {
+    init(start) {
+        this.count = start;
+    } // Starts anywhere.
+    class zero() {
+        return Counter(0);
+    }
+
+    up() {
+        this.count = this.count + 1;
+        return -this.count;
+    }
+}
+fun apply(f, x) {
+    return f(x);
+}
+for (var i = 0; i < 3; i = i + 1) print !(i == 1) and -i <= 2;
+if (true) {
+    print \"yes\";
+} else {}
+var id = fun (x) {
+    return x;
+};
+print apply(fun (n) {
+    return n * 2;
+}, -1) - 1;
+";
+
+    #[test]
+    fn formats_source() {
+        assert_eq!(format(MESSY).unwrap(), FORMATTED);
+    }
+
+    #[test]
+    fn only_changes_whitespace() {
+        assert_eq!(tokens(&format(MESSY).unwrap()), tokens(MESSY));
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        assert_eq!(format(FORMATTED).unwrap(), FORMATTED);
+        assert_eq!(format("").unwrap(), "");
+        assert_eq!(format("\n\n// Only a comment.\n\n").unwrap(), "// Only a comment.\n");
+    }
+
+    #[test]
+    fn rejects_invalid_source() {
+        assert!(format("print (1;").is_err());
+        assert!(format("print \"unterminated;").is_err());
+    }
+}
@@ -30,16 +30,19 @@ use std::iter::Peekable;
 
 use self::errors::*;
 use self::scanner::Scanner;
-use self::ast::{Expr, ExprKind, Stmt, Literal, FunctionDecl, FunctionStmt, Variable};
+use self::ast::{Expr, ExprKind, Stmt, Literal, FunctionDecl, FunctionStmt, Variable, Class, ForBody, ANONYMOUS_CLASS};
 use self::scanner::Token;
 use self::scanner::TokenType;
 
 pub use self::scanner::Keyword;
 pub use self::scanner::Position;
+pub use self::format::format;
 
 pub mod ast;
 pub mod errors;
+pub mod visit;
 mod ext;
+mod format;
 mod scanner;
 mod resolver;
 mod peek;
@@ -51,14 +54,71 @@ pub fn parse(input: &str) -> ::std::result::Result<Vec<Stmt>, Vec<SyntaxError>>
     parser.parse()
 }
 
+/// Parses `input` like `parse`, allowing its syntax tree to be at most
+/// `max_depth` levels deep rather than `MAX_DEPTH`.
+pub fn parse_with_max_depth(input: &str, max_depth: usize) -> ::std::result::Result<Vec<Stmt>, Vec<SyntaxError>> {
+    let mut parser = Parser::new(&input);
+    parser.max_depth = max_depth;
+    parser.parse()
+}
+
+/// Parses a script given as bytes, such as input from a fuzzer. Bytes that
+/// aren't valid UTF-8 are a syntax error, where the first of them is.
+pub fn parse_bytes(input: &[u8]) -> ::std::result::Result<Vec<Stmt>, Vec<SyntaxError>> {
+    let valid = match ::std::str::from_utf8(input) {
+        Ok(source) => return parse(source),
+        Err(err) => err.valid_up_to(),
+    };
+    let source = ::std::str::from_utf8(&input[..valid]).expect("prefix to be valid UTF-8");
+    let last_line = source.rsplit('\n').next().unwrap_or("");
+    let position = Position {
+        start: valid,
+        end: valid + 1,
+        line: source.matches('\n').count() + 1,
+        column: last_line.chars().count() + 1,
+    };
+    Err(vec![SyntaxError::new(SyntaxErrorKind::InvalidUtf8, position)])
+}
+
 pub fn parse_stmt(input: &str) -> ::std::result::Result<Stmt, SyntaxError> {
     let mut parser = Parser::new(&input);
     parser.parse_statement()
 }
 
+/// Parses a single expression, which must be the whole of `input`.
 pub fn parse_expr(input: &str) -> ::std::result::Result<Expr, SyntaxError> {
     let mut parser = Parser::new(&input);
-    parser.expression()
+    let expr = parser.expression()?;
+    parser.expect(TokenType::EOF).alias_as("end of input").after("expression")?;
+    Ok(expr)
+}
+
+/// A single token as produced by the scanner, exposed for debugging tools.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lexeme<'a> {
+    pub kind: &'static str,
+    pub text: &'a str,
+    pub position: Position,
+}
+
+pub fn tokenize<'a>(input: &'a str) -> ::std::result::Result<Vec<Lexeme<'a>>, Vec<SyntaxError>> {
+    let mut lexemes = Vec::new();
+    let mut errors = Vec::new();
+    for token in Scanner::new(input) {
+        match token {
+            Ok(token) => lexemes.push(Lexeme {
+                kind: token.ty.kind(),
+                text: token.value,
+                position: token.position,
+            }),
+            Err(err) => errors.push(err),
+        }
+    }
+    if errors.is_empty() {
+        Ok(lexemes)
+    } else {
+        Err(errors)
+    }
 }
 
 pub fn resolve(stmts: &mut [Stmt]) -> ::std::result::Result<(), Vec<ResolveError>> {
@@ -66,10 +126,32 @@ pub fn resolve(stmts: &mut [Stmt]) -> ::std::result::Result<(), Vec<ResolveError
     resolver.resolve(stmts)
 }
 
+/// Resolves `stmts` like `resolve`, and also returns warnings about code that
+/// is allowed but probably a mistake, such as unused locals or code after a
+/// `return`. The warnings are returned even if there were errors.
+pub fn resolve_with_warnings(stmts: &mut [Stmt]) -> (::std::result::Result<(), Vec<ResolveError>>, Vec<Warning>) {
+    let resolver = resolver::Resolver::new();
+    resolver.resolve_with_warnings(stmts)
+}
+
 const MAX_NUM_PARAMETERS: usize = 8;
 
+/// How deeply statements and expressions can be nested by default.
+///
+/// The resolver, the compilers and the interpreter all recurse through the
+/// syntax tree, so limiting its depth here keeps every one of them from
+/// overflowing the stack on something like a thousand nested parentheses.
+/// It is low enough for an unoptimized build, where each parenthesis takes
+/// tens of kilobytes of the parser's own stack.
+pub const MAX_DEPTH: usize = 128;
+
 struct Parser<'t> {
     scanner: Peekable<Scanner<'t>>,
+    /// Where the last token that was consumed is, for errors past the end.
+    previous: Position,
+    /// How deep in the syntax tree the node being parsed is.
+    depth: usize,
+    max_depth: usize,
 }
 
 // Encapsulates rules with the following form:
@@ -77,12 +159,15 @@ struct Parser<'t> {
 macro_rules! __binary_rule (
     ($name:ident, $inner:ident, $convert:ident, $cons:expr, $($pattern:pat)|*) => (
         fn $name(&mut self) -> Result<Expr> {
+            let depth = self.depth;
             let mut expr = self.$inner()?;
             while let Ok(ty) = self.peek_type() {
                 let tok = match ty {
                     $($pattern)|* => { self.advance()? },
                     _ => break,
                 };
+                // Each operator nests everything before it one level deeper.
+                self.descend()?;
                 let rhs = self.$inner()?;
                 let operator = tok.ty.$convert().expect("attempted invalid conversion into operator type");
                 expr = Expr {
@@ -90,6 +175,7 @@ macro_rules! __binary_rule (
                     node: $cons(operator, expr, rhs),
                 };
             }
+            self.depth = depth;
             Ok(expr)
         }
     );
@@ -124,6 +210,9 @@ impl<'t> Parser<'t> {
         let scanner = Scanner::new(program);
         Parser {
             scanner: scanner.peekable(),
+            previous: Position { start: 0, end: 0, line: 1, column: 1 },
+            depth: 0,
+            max_depth: MAX_DEPTH,
         }
     }
 
@@ -139,6 +228,8 @@ impl<'t> Parser<'t> {
 
     // program → declaration* eof ;
     pub fn parse_statement(&mut self) -> Result<Stmt> {
+        // An error leaves the depth wherever it was raised.
+        self.depth = 0;
         match self.declaration() {
             Ok(stmt) => Ok(stmt),
             Err(err) => {
@@ -151,6 +242,13 @@ impl<'t> Parser<'t> {
     // declaration → varDecl
     //             | statement ;
     fn declaration(&mut self) -> Result<Stmt> {
+        let depth = self.descend()?;
+        let stmt = self.declaration_kind()?;
+        self.depth = depth;
+        Ok(stmt)
+    }
+
+    fn declaration_kind(&mut self) -> Result<Stmt> {
         match self.peek_type()? {
             TokenType::Keyword(Keyword::Var) => {
                 self.advance()?;
@@ -165,7 +263,7 @@ impl<'t> Parser<'t> {
                 if let TokenType::Identifier = self.peek_type()? {
                     let ident = self.advance()?;
                     let decl = self.function_declaration(false)?;
-                    Ok(Stmt::Function(FunctionStmt::new(ident.value, decl)))
+                    Ok(Stmt::Function(FunctionStmt::new(ident.value, ident.position, decl)))
                 } else {
                     // TODO: Unify the parsing. If we could scan two tokens
                     // ahead we could fallback to a expression statement
@@ -177,45 +275,66 @@ impl<'t> Parser<'t> {
                     Ok(Stmt::Expr(Expr{ pos, node }))
                 }
             },
-            _ => self.statement(),
+            _ => self.statement_kind(),
         }
     }
 
     fn class_decl(&mut self) -> Result<Stmt> {
         let ident =
             self.expect(TokenType::Identifier).after("keyword 'class'")?;
+        let class = self.class_body(ident.value, ident.position)?;
+        Ok(Stmt::Class(class))
+    }
+
+    // Everything in a class after its name, which class expressions don't
+    // have.
+    fn class_body(&mut self, name: &str, pos: Position) -> Result<Class> {
         let superclass = if let TokenType::LessThan = self.peek_type()? {
             self.advance()?;
             let superclass_ident = self.expect(TokenType::Identifier)
                 .alias_as("superclass name")
                 .check()?;
-            Some(Variable::new_local(superclass_ident.value))
+            Some(Variable::new_global(superclass_ident.value))
         } else {
             None
         };
+        let mut mixins = Vec::new();
+        if self.peek_contextual("with") {
+            self.advance()?;
+            loop {
+                let mixin_ident = self.expect(TokenType::Identifier)
+                    .alias_as("mixin name")
+                    .check()?;
+                mixins.push(Variable::new_global(mixin_ident.value));
+                if let TokenType::Comma = self.peek_type()? {
+                    self.advance()?;
+                } else {
+                    break;
+                }
+            }
+        }
         self.expect(TokenType::LeftBrace).after("class name")?;
         let mut methods = Vec::new();
         let mut class_methods = Vec::new();
         loop {
-            if let TokenType::RightBrace = self.peek_type()? {
-                break;
-            }
-            if let TokenType::Keyword(Keyword::Class) = self.peek_type()? {
-                self.advance()?;
-                class_methods.push(self.function_statement(true)?);
-            } else {
-                methods.push(self.function_statement(true)?);
+            match self.peek_type()? {
+                TokenType::RightBrace | TokenType::EOF => break,
+                TokenType::Keyword(Keyword::Class) => {
+                    self.advance()?;
+                    class_methods.push(self.function_statement(true)?);
+                },
+                _ => methods.push(self.function_statement(true)?),
             }
         }
         self.expect(TokenType::RightBrace).after("method declarations")?;
-        Ok(Stmt::class(ident.value, methods, class_methods, superclass))
+        Ok(Class::new(name, pos, methods, class_methods, superclass, mixins))
     }
 
     fn function_statement(&mut self, method: bool) -> Result<FunctionStmt> {
         let ident =
             self.expect(TokenType::Identifier).after("function name")?;
         let decl = self.function_declaration(method)?;
-        Ok(FunctionStmt::new(ident.value, decl))
+        Ok(FunctionStmt::new(ident.value, ident.position, decl))
     }
 
     fn function_declaration(&mut self, method: bool) -> Result<FunctionDecl> {
@@ -226,11 +345,13 @@ impl<'t> Parser<'t> {
             _ => {
                 loop {
                     let param =
-                        self.expect(TokenType::Identifier).after("parameters")?;
+                        self.expect(TokenType::Identifier)
+                            .alias_as("parameter name")
+                            .check()?;
                     parameters.push(Variable::new_global(param.value.into()));
                     if parameters.len() > MAX_NUM_PARAMETERS {
                         // FIXME: This shouldn't stop parsing the function
-                        return Err(SyntaxError::TooManyParameters);
+                        return Err(SyntaxError::new(SyntaxErrorKind::TooManyParameters, param.position));
                     }
                     if let TokenType::Comma = self.peek_type()? {
                         self.advance()?;
@@ -267,6 +388,13 @@ impl<'t> Parser<'t> {
     //            | printStmt
     //            | block ;
     fn statement(&mut self) -> Result<Stmt> {
+        let depth = self.descend()?;
+        let stmt = self.statement_kind()?;
+        self.depth = depth;
+        Ok(stmt)
+    }
+
+    fn statement_kind(&mut self) -> Result<Stmt> {
         match self.peek_type()? {
             TokenType::Keyword(Keyword::While) => {
                 self.advance()?;
@@ -289,9 +417,9 @@ impl<'t> Parser<'t> {
                 self.for_statement()
             },
             TokenType::Keyword(Keyword::Break) => {
-                self.advance()?;
+                let keyword = self.advance()?;
                 self.expect(TokenType::Semicolon).after("break")?;
-                Ok(Stmt::Break)
+                Ok(Stmt::Break(keyword.position))
             },
             TokenType::LeftBrace => {
                 self.advance()?;
@@ -321,22 +449,38 @@ impl<'t> Parser<'t> {
         self.expect(TokenType::LeftParen).after("if")?;
         let cond = self.expression()?;
         self.expect(TokenType::RightParen).after("if condition")?;
-        let then_clause = self.declaration()?;
+        let then_clause = self.branch()?;
         if let TokenType::Keyword(Keyword::Else) = self.peek_type()? {
             self.advance()?;
-            let else_clause = self.declaration()?;
+            let else_clause = self.branch()?;
             Ok(Stmt::if_else_stmt(cond, then_clause, else_clause))
         } else {
             Ok(Stmt::if_stmt(cond, then_clause))
         }
     }
 
+    /// A branch of an `if`, which like the body of a loop can't be a
+    /// declaration. Class and function expressions are still allowed.
+    fn branch(&mut self) -> Result<Stmt> {
+        let declaration = match self.peek_type()? {
+            TokenType::Keyword(Keyword::Var)
+            | TokenType::Keyword(Keyword::Class)
+            | TokenType::Keyword(Keyword::Fun) => true,
+            _ => false,
+        };
+        self.statement().map_err(|err| if declaration {
+            SyntaxError::new(SyntaxErrorKind::Expect("expression"), err.position)
+        } else {
+            err
+        })
+    }
+
     fn while_statement(&mut self) -> Result<Stmt> {
         self.expect(TokenType::LeftParen).after("while")?;
         let cond = self.expression()?;
         self.expect(TokenType::RightParen).after("while condition")?;
         let body = self.statement()
-            .map_err(|_| SyntaxError::Expect("expression"))?;
+            .map_err(|err| SyntaxError::new(SyntaxErrorKind::Expect("expression"), err.position))?;
         Ok(Stmt::While(cond, Box::new(body)))
     }
 
@@ -385,11 +529,16 @@ impl<'t> Parser<'t> {
         //     }
         // }
         //
-        body = match increment {
-            Some(increment) => {
+        // If the initializer declares a variable, the body and increment
+        // are kept apart so that each iteration can have its own copy of it.
+        body = match (&init, increment) {
+            (&Some(Stmt::Var(ref var, _)), increment) => {
+                Stmt::ForBody(ForBody::new(var.name(), body, increment))
+            },
+            (_, Some(increment)) => {
                 Stmt::Block(vec![body, Stmt::Expr(increment)])
             },
-            None => body,
+            (_, None) => body,
         };
         let condition_expr = condition.unwrap_or_else(|| {
             Expr {
@@ -409,13 +558,13 @@ impl<'t> Parser<'t> {
     fn block(&mut self) -> Result<Vec<Stmt>> {
         let mut block = Vec::new();
         loop {
-            if let TokenType::RightBrace = self.peek_type()? {
-                self.advance()?;
-                return Ok(block);
+            match self.peek_type()? {
+                TokenType::RightBrace | TokenType::EOF => break,
+                _ => block.push(self.declaration()?),
             }
-            let stmt = self.declaration()?;
-            block.push(stmt);
         }
+        self.expect(TokenType::RightBrace).after("block")?;
+        Ok(block)
     }
 
     fn expect<'a>(&'a mut self, expected: TokenType<'t>) -> Expect<'a, 't> {
@@ -434,10 +583,12 @@ impl<'t> Parser<'t> {
     }
 
     fn assignment(&mut self) -> Result<Expr> {
+        let depth = self.descend()?;
         let expr = self.logical_or()?;
         if let TokenType::Equal = self.peek_type()? {
-            self.advance()?;
+            let equals = self.advance()?;
             let value = self.assignment()?;
+            self.depth = depth;
             if let ExprKind::Var(var) = expr.node {
                 let node = ExprKind::Assign(var, Box::new(value));
                 return Ok(Expr { node, pos: expr.pos });
@@ -446,16 +597,35 @@ impl<'t> Parser<'t> {
                 let node = ExprKind::set(expr, name, value);
                 return Ok(Expr { node, pos });
             }
-            return Err(SyntaxError::InvalidAssignment);
+            return Err(SyntaxError::new(SyntaxErrorKind::InvalidAssignment, equals.position));
         }
+        self.depth = depth;
         Ok(expr)
     }
 
     logical_impl!(logical_or, logical_and, TokenType::Keyword(Keyword::Or));
     logical_impl!(logical_and, equality, TokenType::Keyword(Keyword::And));
 
-    // equality   → comparison ( ( "!=" | "==" ) comparison )*
-    binary_impl!(equality, comparison, TokenType::BangEq | TokenType::EqualEq);
+    // equality   → is ( ( "!=" | "==" ) is )*
+    binary_impl!(equality, is, TokenType::BangEq | TokenType::EqualEq);
+
+    // is         → comparison ( "is" comparison )*
+    fn is(&mut self) -> Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.comparison()?;
+        while let Ok(TokenType::Keyword(Keyword::Is)) = self.peek_type() {
+            let tok = self.advance()?;
+            self.descend()?;
+            let class = self.comparison()?;
+            expr = Expr {
+                pos: tok.position,
+                node: ExprKind::Is(Box::new(expr), Box::new(class)),
+            };
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+
     // comparison → term ( ( ">" | ">=" | "<" | "<=" ) term )*
     binary_impl!(comparison, term,
         TokenType::GreaterThan
@@ -473,8 +643,10 @@ impl<'t> Parser<'t> {
         match self.peek_type()? {
             TokenType::Bang | TokenType::Minus => {
                 let tok = self.advance()?;
-                let operator = tok.ty.into_unary().unwrap();
+                let operator = tok.ty.into_unary().expect("attempted invalid conversion into operator type");
+                let depth = self.descend()?;
                 let unary = self.unary()?;
+                self.depth = depth;
                 let node = ExprKind::unary(operator, unary);
                 Ok(Expr { node, pos: tok.position })
             }
@@ -483,15 +655,18 @@ impl<'t> Parser<'t> {
     }
 
     fn call(&mut self) -> Result<Expr> {
+        let depth = self.depth;
         let mut expr = self.primary()?;
         loop {
             match self.peek_type()? {
                 TokenType::LeftParen => {
                     self.advance()?;
+                    self.descend()?;
                     expr = self.finish_call(expr)?;
                 },
                 TokenType::Dot => {
                     let pos = self.advance()?.position;
+                    self.descend()?;
                     let name = self.expect(TokenType::Identifier)
                         .alias_as("property name")
                         .after("'.'")?;
@@ -501,6 +676,7 @@ impl<'t> Parser<'t> {
                 _ => break,
             }
         }
+        self.depth = depth;
         Ok(expr)
     }
 
@@ -511,10 +687,11 @@ impl<'t> Parser<'t> {
             _ => {
                 loop {
                     // FIXME: This shouldn't stop parsing the call
-                    arguments.push(self.expression()?);
-                    if arguments.len() > MAX_NUM_PARAMETERS {
-                        return Err(SyntaxError::TooManyArguments);
+                    let argument = self.expression()?;
+                    if arguments.len() == MAX_NUM_PARAMETERS {
+                        return Err(SyntaxError::new(SyntaxErrorKind::TooManyArguments, argument.pos));
                     }
+                    arguments.push(argument);
                     match self.peek_type()? {
                         TokenType::Comma => {
                             self.advance()?;
@@ -535,6 +712,8 @@ impl<'t> Parser<'t> {
     // primary    → NUMBER | STRING | "false" | "true" | "nil"
     //            | "(" expression ")"
     //            | IDENTIFIER
+    //            | "class" ( "<" IDENTIFIER )? ( "with" IDENTIFIER ( "," IDENTIFIER )* )?
+    //              "{" function* "}"
     fn primary(&mut self) -> Result<Expr> {
         let peek_type = self.peek_type()?;
         match peek_type {
@@ -606,7 +785,21 @@ impl<'t> Parser<'t> {
                     pos: token.position
                 })
             },
-            _ => Err(SyntaxError::Expect("expression"))
+            TokenType::Keyword(Keyword::Class) => {
+                let token = self.advance()?;
+                // Named classes are only declarations, and can't appear
+                // where a statement is expected such as the body of an if.
+                let named = self.peek_type()? == TokenType::Identifier;
+                if named && !self.peek_contextual("with") {
+                    return Err(self.error(SyntaxErrorKind::Expect("expression")));
+                }
+                let class = self.class_body(ANONYMOUS_CLASS, token.position)?;
+                Ok(Expr {
+                    node: ExprKind::class(class),
+                    pos: token.position,
+                })
+            },
+            _ => Err(self.error(SyntaxErrorKind::Expect("expression")))
         }
     }
 
@@ -614,35 +807,55 @@ impl<'t> Parser<'t> {
     fn synchronize(&mut self) {
         loop {
             match self.peek_type() {
-                Ok(ty) => {
-                    match ty {
-                        TokenType::Semicolon => {
-                            self.advance().unwrap();
-                            return;
-                        },
-                        TokenType::Keyword(Keyword::Class)
-                        | TokenType::Keyword(Keyword::Fun)
-                        | TokenType::Keyword(Keyword::Var)
-                        | TokenType::Keyword(Keyword::For)
-                        | TokenType::Keyword(Keyword::If)
-                        | TokenType::Keyword(Keyword::While)
-                        | TokenType::Keyword(Keyword::Return)
-                        | TokenType::Keyword(Keyword::Print)
-                        | TokenType::EOF => return,
-                        _ => { self.advance().unwrap(); }
-                    }
+                Ok(TokenType::Semicolon) => {
+                    let _ = self.advance();
+                    return;
                 },
-                // We want to skip scanning errors as well.
-                //
-                // This will never loop with Err(UnexpectedEOF) because the happy
-                // Ok(EOF) case will be picked up first.
-                Err(_) => { self.advance().unwrap_err(); }
+                Ok(TokenType::Keyword(Keyword::Class))
+                | Ok(TokenType::Keyword(Keyword::Fun))
+                | Ok(TokenType::Keyword(Keyword::Var))
+                | Ok(TokenType::Keyword(Keyword::For))
+                | Ok(TokenType::Keyword(Keyword::If))
+                | Ok(TokenType::Keyword(Keyword::While))
+                | Ok(TokenType::Keyword(Keyword::Return))
+                | Ok(TokenType::Keyword(Keyword::Print))
+                | Ok(TokenType::EOF) => return,
+                // Scanning errors are skipped as well. Once the scanner is
+                // exhausted `peek_type` gives EOF, so this always ends.
+                _ => { let _ = self.advance(); },
             }
         }
     }
 
     fn advance(&mut self) -> Result<Token<'t>> {
-        self.scanner.next().unwrap_or_else(|| Err(SyntaxError::UnexpectedEOF))
+        match self.scanner.next() {
+            Some(Ok(token)) => {
+                self.previous = token.position;
+                Ok(token)
+            },
+            Some(Err(err)) => Err(err),
+            None => Err(SyntaxError::new(SyntaxErrorKind::UnexpectedEOF, self.previous)),
+        }
+    }
+
+    /// Goes one level deeper into the syntax tree, returning the depth to
+    /// go back to afterwards.
+    fn descend(&mut self) -> Result<usize> {
+        if self.depth >= self.max_depth {
+            return Err(self.error(SyntaxErrorKind::TooDeeplyNested));
+        }
+        self.depth += 1;
+        Ok(self.depth - 1)
+    }
+
+    /// An error at the next token, which is where parsing stopped.
+    fn error(&mut self, kind: SyntaxErrorKind) -> SyntaxError {
+        let position = match self.scanner.peek() {
+            Some(Ok(tok)) => tok.position,
+            Some(Err(err)) => err.position,
+            None => self.previous,
+        };
+        SyntaxError::new(kind, position)
     }
 
     fn has_next(&mut self) -> bool {
@@ -652,6 +865,15 @@ impl<'t> Parser<'t> {
         }
     }
 
+    /// Whether the next token is an identifier that acts as a keyword in
+    /// this position only, so that it can still be used as a name elsewhere.
+    fn peek_contextual(&mut self, word: &str) -> bool {
+        match self.scanner.peek() {
+            Some(&Ok(tok)) => tok.ty == TokenType::Identifier && tok.value == word,
+            _ => false,
+        }
+    }
+
     fn peek_type(&mut self) -> Result<TokenType<'t>> {
         match self.scanner.peek() {
             Some(&Ok(tok)) => Ok(tok.ty),
@@ -698,17 +920,17 @@ impl<'a, 't> Expect<'a, 't> {
 
     fn after(self, message: &'static str) -> Result<Token<'t>> {
         let expected = impl_expected!(self);
-        Err(SyntaxError::ExpectAfter(expected, message))
+        Err(self.parser.error(SyntaxErrorKind::ExpectAfter(expected, message)))
     }
 
     fn before(self, message: &'static str) -> Result<Token<'t>> {
         let expected = impl_expected!(self);
-        Err(SyntaxError::ExpectBefore(expected, message))
+        Err(self.parser.error(SyntaxErrorKind::ExpectBefore(expected, message)))
     }
 
     fn check(self) -> Result<Token<'t>> {
         let expected = impl_expected!(self);
-        Err(SyntaxError::Expect(expected))
+        Err(self.parser.error(SyntaxErrorKind::Expect(expected)))
     }
 }
 
@@ -986,4 +1208,74 @@ mod tests {
     //     let mut parser = Parser::new(prog);
     //     parser.parse().unwrap();
     // }
+
+    use super::*;
+
+    fn first_error(prog: &str) -> SyntaxError {
+        parse(prog).unwrap_err().remove(0)
+    }
+
+    #[test]
+    fn unexpected_end_of_input() {
+        let cases = [
+            ("{", "Expect '}' after block."),
+            ("class A {", "Expect '}' after method declarations."),
+            ("fun f(", "Expect parameter name."),
+            ("print (1", "Expect ')' after expression."),
+            ("a.", "Expect property name after '.'."),
+            ("var x =", "Expect expression."),
+        ];
+        for &(prog, message) in &cases {
+            assert_eq!(first_error(prog).kind.to_string(), message, "parsing {:?}", prog);
+        }
+    }
+
+    #[test]
+    fn every_prefix_fails_without_panicking() {
+        let prog = "
+            class A < B {
+              init(a, b) { this.x = a; }
+              get() { return super.get() + this.x; }
+            }
+            fun f(n) {
+              for (var i = 0; i < n; i = i + 1) {
+                if (!(i == 2)) print -i; else break;
+              }
+              while (n > 0) n = n - 1;
+            }
+            var g = fun () { return \"s\" and nil or 1.5; };
+        ";
+        for (i, _) in prog.char_indices() {
+            let _ = parse(&prog[..i]);
+        }
+        parse(prog).unwrap();
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let too_deep = |prog: &str| {
+            match parse_with_max_depth(prog, 8) {
+                Err(errs) => errs[0].kind == SyntaxErrorKind::TooDeeplyNested,
+                Ok(_) => false,
+            }
+        };
+        assert!(!too_deep("print ((((1))));"));
+        assert!(too_deep("print ((((((((1))))))));"));
+        assert!(too_deep("print --------1;"));
+        assert!(too_deep("print 1 + 2 + 3 + 4 + 5 + 6 + 7 + 8;"));
+        assert!(too_deep("f()()()()()()()();"));
+        assert!(too_deep("{{{{{{{{{}}}}}}}}}"));
+        assert!(too_deep("if (a) if (a) if (a) if (a) if (a) if (a) if (a) if (a) print a;"));
+        // The depth starts again with each statement.
+        assert!(!too_deep("print ((((1)))); print ((((1))));"));
+    }
+
+    #[test]
+    fn parse_invalid_utf8() {
+        assert!(parse_bytes(b"print 1;").is_ok());
+        let errs = parse_bytes(b"print 1;\nprint \"\xc3\xa9\xff\";").unwrap_err();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].kind, SyntaxErrorKind::InvalidUtf8);
+        assert_eq!((errs[0].position.line, errs[0].position.column, errs[0].position.start), (2, 9, 18));
+    }
 }
@@ -1,13 +1,15 @@
 use std::collections::HashMap;
 
 use ast::*;
-use errors::ResolveError;
+use errors::{ResolveError, ResolveErrorKind, Warning};
+use scanner::Position;
 
 pub struct Resolver {
     scopes: Scopes,
     class: Option<ClassType>,
     loop_depth: usize,
     errors: Vec<ResolveError>,
+    warnings: Vec<Warning>,
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -23,13 +25,23 @@ enum ClassType {
     Subclass,
 }
 
-type Result = ::std::result::Result<(), ResolveError>;
+type Result = ::std::result::Result<(), ResolveErrorKind>;
 
 struct Scopes {
-    scopes: Vec<HashMap<String, bool>>,
+    scopes: Vec<HashMap<String, Local>>,
     functions: Vec<(FunctionType, usize)>,
 }
 
+struct Local {
+    defined: bool,
+    used: bool,
+    /// Whether a function nested in the one declaring the variable refers
+    /// to it.
+    captured: bool,
+    /// What to warn about if the variable goes out of scope unused.
+    unused: Option<Warning>,
+}
+
 impl Scopes {
     pub fn new() -> Self {
         Scopes {
@@ -39,17 +51,29 @@ impl Scopes {
     }
 
     fn resolve_local(&mut self, var: &mut Variable) {
+        self.resolve(var, true);
+    }
+
+    /// Resolves a variable where it is declared, which doesn't count as a
+    /// use of it.
+    fn resolve_declaration(&mut self, var: &mut Variable) {
+        self.resolve(var, false);
+    }
+
+    fn resolve(&mut self, var: &mut Variable, is_use: bool) {
         let scope_len = self.scopes.len();
-        // We skip the first scope to treat it as global.
-        let scopes_iter = self.scopes.iter().skip(1).rev();
         // The depth of this variable relative to the top-level scope of the
         // enclosing function.
         //
         // This is used to track if a var is being closed over.
         let function_depth = self.scopes.len() - self.function_start() - 1;
+        // We skip the first scope to treat it as global.
+        let scopes_iter = self.scopes.iter_mut().skip(1).rev();
 
         for (depth, scope) in scopes_iter.enumerate() {
-            if scope.contains_key(var.name()) {
+            if let Some(local) = scope.get_mut(var.name()) {
+                local.used |= is_use;
+                local.captured |= depth > function_depth;
                 var.resolve_local(depth, function_depth);
                 debug!("[scope={}] var '{}' resolved to depth={} function_depth={}", scope_len, var.name(), depth + 1, function_depth);
                 return;
@@ -59,12 +83,14 @@ impl Scopes {
     }
 
     fn init(&mut self, var: &str) -> Result {
-        self.declare(var)?;
+        self.declare(var, None)?;
         self.define(var);
         Ok(())
     }
 
-    fn declare(&mut self, var: &str) -> Result {
+    /// Declares `var` in the innermost scope. If it's a local, `unused` is
+    /// what to warn about if nothing refers to it.
+    fn declare(&mut self, var: &str, unused: Option<Warning>) -> Result {
         use std::collections::hash_map::Entry;
 
         let scope_len = self.scopes.len();
@@ -75,12 +101,17 @@ impl Scopes {
 
         match scope.entry(var.into()) {
             Entry::Occupied(_) if is_local => {
-                Err(ResolveError::AlreadyDeclared)
+                Err(ResolveErrorKind::AlreadyDeclared)
             }
             // Global Scope, okay to redeclare.
             Entry::Occupied(_) => Ok(()),
             Entry::Vacant(entry) => {
-                entry.insert(false);
+                entry.insert(Local {
+                    defined: false,
+                    used: false,
+                    captured: false,
+                    unused: if is_local && !var.starts_with('_') { unused } else { None },
+                });
                 Ok(())
             }
         }
@@ -90,15 +121,24 @@ impl Scopes {
         let scope_len = self.scopes.len();
         debug!("[scope={}] defining variable {}", scope_len, var);
         let scope = self.scopes.last_mut().expect("scope stack to be nonempty");
-        if let Some(val) = scope.get_mut(var) {
-            *val = true;
+        if let Some(local) = scope.get_mut(var) {
+            local.defined = true;
         }
     }
 
     fn check_var(&self, var: &str) -> Option<bool> {
         self.scopes.last()
             .and_then(|scope| scope.get(var))
-            .map(|b| *b)
+            .map(|local| local.defined)
+    }
+
+    /// Whether the local `var` of the innermost scope is used, and whether
+    /// it is captured.
+    fn usage(&self, var: &str) -> (bool, bool) {
+        self.scopes.last()
+            .and_then(|scope| scope.get(var))
+            .map(|local| (local.used, local.captured))
+            .unwrap_or((false, false))
     }
 
     fn begin(&mut self) {
@@ -106,9 +146,15 @@ impl Scopes {
         self.scopes.push(HashMap::new());
     }
 
-    fn end(&mut self) {
+    /// Leaves the innermost scope, returning a warning for each of its
+    /// locals that was never used.
+    fn end(&mut self) -> Vec<Warning> {
         debug!("exiting scope {}", self.scopes.len());
-        self.scopes.pop().expect("scopes stack to be nonempty");
+        let scope = self.scopes.pop().expect("scopes stack to be nonempty");
+        scope.into_iter()
+            .filter(|(_, local)| !local.used)
+            .filter_map(|(_, local)| local.unused)
+            .collect()
     }
 
     pub fn function(&self) -> Option<FunctionType> {
@@ -126,10 +172,11 @@ impl Scopes {
         self.functions.push((function, scope));
     }
 
-    fn end_function(&mut self) {
-        self.end();
+    fn end_function(&mut self) -> Vec<Warning> {
+        let unused = self.end();
         self.functions.pop().expect("end_function called at top-level");
         debug!("[scope={}] exiting function", self.scopes.len());
+        unused
     }
 }
 
@@ -140,23 +187,72 @@ impl Resolver {
             class: None,
             loop_depth: 0,
             errors: Vec::new(),
+            warnings: Vec::new(),
         }
     }
 
-    pub fn resolve(mut self, stmts: &mut [Stmt]) -> ::std::result::Result<(), Vec<ResolveError>> {
+    pub fn resolve(self, stmts: &mut [Stmt]) -> ::std::result::Result<(), Vec<ResolveError>> {
+        self.resolve_with_warnings(stmts).0
+    }
+
+    /// Resolves `stmts`, also returning the warnings about them in the order
+    /// they appear, whether or not there were errors.
+    pub fn resolve_with_warnings(mut self, stmts: &mut [Stmt]) -> (::std::result::Result<(), Vec<ResolveError>>, Vec<Warning>) {
         self.resolve_inner(stmts);
+        let mut warnings = self.warnings;
+        warnings.sort_by_key(|a| (a.line(), a.to_string()));
         if self.errors.len() > 0 {
-            return Err(self.errors)
+            return (Err(self.errors), warnings)
         }
-        Ok(())
+        (Ok(()), warnings)
     }
 
     fn resolve_inner(&mut self, stmts: &mut [Stmt]) {
+        let mut returned = false;
         for stmt in stmts {
+            if returned {
+                // Only the first unreachable statement is worth pointing out.
+                if let Some(pos) = stmt.position() {
+                    self.warnings.push(Warning::UnreachableCode { line: pos.line });
+                    returned = false;
+                }
+            }
+            match *stmt {
+                Stmt::Return(_) | Stmt::Break(_) => returned = true,
+                _ => {},
+            }
             self.resolve_stmt(stmt);
         }
     }
 
+    fn error(&mut self, kind: ResolveErrorKind, position: Position) {
+        self.errors.push(ResolveError::new(kind, position));
+    }
+
+    fn check(&mut self, res: Result, position: Position) {
+        if let Err(kind) = res {
+            self.error(kind, position);
+        }
+    }
+
+    fn end_scope(&mut self) {
+        let unused = self.scopes.end();
+        self.warnings.extend(unused);
+    }
+
+    fn check_condition(&mut self, cond: &Expr) {
+        let mut cond = cond;
+        while let ExprKind::Grouping(ref inner) = cond.node {
+            cond = inner;
+        }
+        match cond.node {
+            ExprKind::Literal(Literal::False) | ExprKind::Literal(Literal::Nil) => {
+                self.warnings.push(Warning::AlwaysFalse { line: cond.pos.line });
+            },
+            _ => {},
+        }
+    }
+
     pub fn resolve_stmt(&mut self, stmt: &mut Stmt) {
         match *stmt {
             Stmt::Expr(ref mut expr) => {
@@ -166,28 +262,31 @@ impl Resolver {
                 self.resolve_expr(expr);
             },
             Stmt::Var(ref mut var, ref mut expr) => {
-                if let Err(e) = self.scopes.declare(var.name()) {
-                    self.errors.push(e);
+                let unused = Warning::UnusedVariable {
+                    name: var.name().to_owned(),
+                    line: expr.pos.line,
                 };
+                let declared = self.scopes.declare(var.name(), Some(unused));
+                self.check(declared, expr.pos);
                 self.resolve_expr(expr);
                 self.scopes.define(var.name());
-                self.scopes.resolve_local(var);
+                self.scopes.resolve_declaration(var);
             },
             Stmt::Function(ref mut function) => {
                 // Define the function itself
-                if let Err(e) = self.scopes.init(function.var.name()) {
-                    self.errors.push(e);
-                };
+                let declared = self.scopes.init(function.var.name());
+                self.check(declared, function.pos);
                 self.scopes.resolve_local(&mut function.var);
                 let mut declaration = function.declaration.borrow_mut();
-                self.resolve_function(&mut *declaration, FunctionType::Function);
+                self.resolve_function(&mut declaration, FunctionType::Function, function.pos);
             },
             Stmt::Block(ref mut stmts) => {
                 self.scopes.begin();
                 self.resolve_inner(stmts);
-                self.scopes.end();
+                self.end_scope();
             },
             Stmt::If(ref mut cond, ref mut then_clause, ref mut else_clause) => {
+                self.check_condition(cond);
                 self.resolve_expr(cond);
                 self.resolve_stmt(then_clause);
                 if let Some(ref mut else_clause) = *else_clause {
@@ -195,66 +294,91 @@ impl Resolver {
                 }
             },
 			Stmt::While(ref mut cond, ref mut body) => {
+                self.check_condition(cond);
                 self.resolve_expr(cond);
                 self.loop_depth += 1;
                 self.resolve_stmt(body);
                 self.loop_depth -= 1;
             },
-            Stmt::Break => {
+            Stmt::ForBody(ref mut for_body) => {
+                self.scopes.begin();
+                self.scopes.declare(for_body.var.name(), None)
+                    .expect("a new scope to have room for the loop variable");
+                self.scopes.define(for_body.var.name());
+                self.resolve_stmt(&mut for_body.body);
+                let (used, captured) = self.scopes.usage(for_body.var.name());
+                for_body.captured = captured;
+                self.end_scope();
+                // The copy is made from the loop's variable, which only
+                // counts as a use of it if the body uses the copy.
+                if used {
+                    self.scopes.resolve_local(&mut for_body.var);
+                } else {
+                    self.scopes.resolve_declaration(&mut for_body.var);
+                }
+                if let Some(ref mut increment) = for_body.increment {
+                    self.resolve_expr(increment);
+                }
+            },
+            Stmt::Break(pos) => {
                 if self.loop_depth == 0 {
-                    self.errors.push(ResolveError::BreakOutsideLoop);
+                    self.error(ResolveErrorKind::BreakOutsideLoop, pos);
                 }
             },
             Stmt::Return(ref mut expr) => {
                 match (self.scopes.function(), expr) {
-                    (Some(FunctionType::Initializer), &mut Some(_)) =>
-                        self.errors.push(ResolveError::ReturnFromInitializer),
-                    (Some(_), &mut Some(ref mut expr)) => {
+                    (Some(FunctionType::Initializer), &mut Some(ref expr)) =>
+                        self.error(ResolveErrorKind::ReturnFromInitializer, expr.pos),
+                    // A return at the top level ends the script.
+                    (_, &mut Some(ref mut expr)) => {
                         self.resolve_expr(expr);
                     },
-                    (Some(_), _) => {},
-                    (None, _) => self.errors.push(ResolveError::ReturnOutsideFunction),
+                    (_, _) => {},
                 }
             },
             Stmt::Class(ref mut class_decl) => {
-                if let Err(e) = self.scopes.init(class_decl.var.name()) {
-                    self.errors.push(e);
-                };
+                let declared = self.scopes.init(class_decl.var.name());
+                self.check(declared, class_decl.pos);
                 self.scopes.resolve_local(&mut class_decl.var);
-                let enclosing_class = self.class.take();
-                if let Some(ref mut superclass) = class_decl.superclass {
-                    self.class = Some(ClassType::Subclass);
-                    self.scopes.resolve_local(superclass);
-                    self.scopes.begin(); // begin 'super' scope
-                    if let Err(e) = self.scopes.init("super") {
-                        self.errors.push(e);
-                    };
-                } else {
-                    self.class = Some(ClassType::Class);
-                }
-                self.scopes.begin(); // begin 'this' scope
-                if let Err(e) = self.scopes.init("this") {
-                    self.errors.push(e);
-                };
-                for method in &class_decl.methods {
-                    let name = method.var.name();
-                    let mut declaration = method.declaration.borrow_mut();
-
-                    if name == "init" {
-                        self.resolve_function(&mut *declaration, FunctionType::Initializer);
-                    } else {
-                        self.resolve_function(&mut *declaration, FunctionType::Method);
-                    }
-                }
-                self.scopes.end(); // end 'this' scope
-                if class_decl.superclass.is_some() {
-                    self.scopes.end(); // end 'super' scope
-                }
-                self.class = enclosing_class;
+                self.resolve_class(class_decl);
             },
         }
     }
 
+    fn resolve_class(&mut self, class_decl: &mut Class) {
+        let enclosing_class = self.class.take();
+        for mixin in &mut class_decl.mixins {
+            self.scopes.resolve_local(mixin);
+        }
+        if let Some(ref mut superclass) = class_decl.superclass {
+            self.class = Some(ClassType::Subclass);
+            self.scopes.resolve_local(superclass);
+            self.scopes.begin(); // begin 'super' scope
+            let declared = self.scopes.init("super");
+            self.check(declared, class_decl.pos);
+        } else {
+            self.class = Some(ClassType::Class);
+        }
+        self.scopes.begin(); // begin 'this' scope
+        let declared = self.scopes.init("this");
+        self.check(declared, class_decl.pos);
+        for method in &class_decl.methods {
+            let name = method.var.name();
+            let mut declaration = method.declaration.borrow_mut();
+
+            if name == "init" {
+                self.resolve_function(&mut declaration, FunctionType::Initializer, method.pos);
+            } else {
+                self.resolve_function(&mut declaration, FunctionType::Method, method.pos);
+            }
+        }
+        self.end_scope(); // end 'this' scope
+        if class_decl.superclass.is_some() {
+            self.end_scope(); // end 'super' scope
+        }
+        self.class = enclosing_class;
+    }
+
     pub fn resolve_expr(&mut self, expr: &mut Expr) {
         match expr.node {
             ExprKind::Grouping(ref mut inner) => {
@@ -268,13 +392,20 @@ impl Resolver {
                 self.resolve_expr(&mut inner.lhs);
                 self.resolve_expr(&mut inner.rhs);
             },
+            ExprKind::Is(ref mut value, ref mut class) => {
+                self.resolve_expr(value);
+                self.resolve_expr(class);
+            },
             ExprKind::Unary(ref mut inner) => {
                 self.resolve_expr(&mut inner.unary);
             },
             ExprKind::Literal(_) => {},
             ExprKind::Var(ref mut var) => {
+                // A local is declared before its initializer is resolved but
+                // only defined after, so a read in between is of itself.
                 if let Some(false) = self.scopes.check_var(var.name()) {
-                    self.errors.push(ResolveError::InitializerSelfReference);
+                    let kind = ResolveErrorKind::InitializerSelfReference(var.name().to_owned());
+                    self.error(kind, expr.pos);
                 } else {
                     self.scopes.resolve_local(var);
                 }
@@ -298,7 +429,7 @@ impl Resolver {
             },
             ExprKind::This(ref mut var, _) => {
                 if self.class.is_none() {
-                    self.errors.push(ResolveError::ThisOutsideClass);
+                    self.error(ResolveErrorKind::ThisOutsideClass, expr.pos);
                 }
                 // FIXME: Resolving 'this' should always resolve to a local
                 // when in a method call, rather than an upvalue.
@@ -306,28 +437,41 @@ impl Resolver {
             },
             ExprKind::Super(ref mut var, _, _) => {
                 match self.class {
-                    None => self.errors.push(ResolveError::SuperOutsideClass),
-                    Some(ClassType::Class) => self.errors.push(ResolveError::SuperInBaseClass),
+                    None => self.error(ResolveErrorKind::SuperOutsideClass, expr.pos),
+                    Some(ClassType::Class) => self.error(ResolveErrorKind::SuperInBaseClass, expr.pos),
                     _ => (),
                 };
                 self.scopes.resolve_local(var);
             },
             ExprKind::Function(ref mut function) => {
                 let mut declaration = function.borrow_mut();
-                self.resolve_function(&mut *declaration, FunctionType::Function);
+                self.resolve_function(&mut declaration, FunctionType::Function, expr.pos);
+            },
+            ExprKind::Class(ref mut class_decl) => {
+                self.resolve_class(class_decl);
             },
         }
     }
 
-    fn resolve_function(&mut self, declaration: &mut FunctionDecl, function_type: FunctionType) {
+    /// Resolves a function declared at `pos`, which is where problems with
+    /// its parameters are reported.
+    fn resolve_function(&mut self, declaration: &mut FunctionDecl, function_type: FunctionType, pos: Position) {
+        // A loop around the function can't be broken out of from inside it.
+        let loop_depth = ::std::mem::replace(&mut self.loop_depth, 0);
         self.scopes.begin_function(function_type);
         for param in &declaration.parameters {
-            if let Err(e) = self.scopes.init(param.name()) {
-                self.errors.push(e);
+            let unused = Warning::UnusedParameter {
+                name: param.name().to_owned(),
+                line: pos.line,
             };
+            let declared = self.scopes.declare(param.name(), Some(unused));
+            self.check(declared, pos);
+            self.scopes.define(param.name());
         }
         self.resolve_inner(&mut declaration.body);
-        self.scopes.end_function();
+        let unused = self.scopes.end_function();
+        self.warnings.extend(unused);
+        self.loop_depth = loop_depth;
     }
 }
 
@@ -348,18 +492,49 @@ mod tests {
     fn break_outside_loop() {
         let prog = "break;";
         let err  = parse_and_resolve(prog).unwrap_err();
-        assert_contains!(err, ResolveError::BreakOutsideLoop);
+        assert_contains!(err, ResolveErrorKind::BreakOutsideLoop);
+    }
+
+    #[test]
+    fn break_inside_function_in_loop() {
+        let prog = "while (true) { fun f() { break; } }";
+        let err  = parse_and_resolve(prog).unwrap_err();
+        assert_contains!(err, ResolveErrorKind::BreakOutsideLoop);
+    }
+
+    #[test]
+    fn local_in_own_initializer() {
+        let prog = "var a = 1;\n{\n  var a = a;\n}";
+        let err  = parse_and_resolve(prog).unwrap_err();
+        assert_contains!(err, ResolveErrorKind::InitializerSelfReference("a".into()));
+
+        let prog = "var a = 1;\nvar a = a;";
+        parse_and_resolve(prog).expect("globals may refer to themselves");
+    }
+
+    #[test]
+    fn duplicate_local() {
+        let prog = "{ var a = 1; var a = 2; }";
+        let err  = parse_and_resolve(prog).unwrap_err();
+        assert_contains!(err, ResolveErrorKind::AlreadyDeclared);
+
+        let prog = "fun f(a) { var a; }";
+        let err  = parse_and_resolve(prog).unwrap_err();
+        assert_contains!(err, ResolveErrorKind::AlreadyDeclared);
+
+        let prog = "var a = 1; var a = 2; { var a = 3; { var a = 4; } }";
+        parse_and_resolve(prog).expect("globals and shadowing locals may share names");
     }
 
     #[test]
     fn this_outside_class() {
         let prog = "this;";
         let err  = parse_and_resolve(prog).unwrap_err();
-        assert_contains!(err, ResolveError::ThisOutsideClass);
+        assert_contains!(err, ResolveErrorKind::ThisOutsideClass);
 
         let prog = "fun foo() { this; }";
         let err  = parse_and_resolve(prog).unwrap_err();
-        assert_contains!(err, ResolveError::ThisOutsideClass);
+        assert_contains!(err, ResolveErrorKind::ThisOutsideClass);
     }
 
     #[test]
@@ -372,25 +547,109 @@ mod tests {
     fn return_value_from_init() {
         let prog = "class Foo { init() { return 1; } }";
         let err  = parse_and_resolve(prog).unwrap_err();
-        assert_contains!(err, ResolveError::ReturnFromInitializer);
+        assert_contains!(err, ResolveErrorKind::ReturnFromInitializer);
     }
 
     #[test]
     fn super_outside_class() {
         let prog = "super.init();";
         let err  = parse_and_resolve(prog).unwrap_err();
-        assert_contains!(err, ResolveError::SuperOutsideClass);
+        assert_contains!(err, ResolveErrorKind::SuperOutsideClass);
     }
 
     #[test]
     fn super_from_base() {
         let prog = "class Foo { init() { super.init(); } }";
         let err  = parse_and_resolve(prog).unwrap_err();
-        assert_contains!(err, ResolveError::SuperInBaseClass);
+        assert_contains!(err, ResolveErrorKind::SuperInBaseClass);
+    }
+
+    #[test]
+    fn functions_nested_in_methods() {
+        // Only the initializer itself can't return a value, and functions
+        // inside a method still see its class.
+        let prog = "class Foo < Bar { init() { fun f() { return this; } var g = fun () { return super.x; }; } }";
+        parse_and_resolve(prog).expect("no error in nested functions");
+    }
+
+    #[test]
+    fn warns_about_likely_mistakes() {
+        let prog = "
+            fun f(a, b, _c) {
+                var unused;
+                var used = a;
+                return used;
+                print \"after\";
+            }
+            var global;
+            while (false) {}
+            if ((nil)) {}
+            class Foo { method(x) { return this; } }";
+        assert_eq!(warnings(prog), vec![
+            Warning::UnusedParameter { name: "b".into(), line: 2 },
+            Warning::UnusedVariable { name: "unused".into(), line: 3 },
+            Warning::UnreachableCode { line: 6 },
+            Warning::AlwaysFalse { line: 9 },
+            Warning::AlwaysFalse { line: 10 },
+            Warning::UnusedParameter { name: "x".into(), line: 11 },
+        ]);
+    }
+
+    #[test]
+    fn no_warnings_for_used_locals() {
+        let prog = "
+            fun counter(start) {
+                var count = start;
+                fun increment() { count = count + 1; return count; }
+                return increment;
+            }
+            for (var i = 0; i < 3; i = i + 1) print i;";
+        assert_eq!(warnings(prog), vec![]);
+    }
+
+    #[test]
+    fn marks_captured_loop_variables() {
+        fn captured(prog: &str) -> bool {
+            let mut stmts = parse(prog).unwrap();
+            Resolver::new().resolve(&mut stmts).expect("no errors");
+            match stmts[0] {
+                Stmt::Block(ref stmts) => match stmts[1] {
+                    Stmt::While(_, ref body) => match **body {
+                        Stmt::ForBody(ref for_body) => for_body.captured,
+                        ref body => panic!("expected a for loop body, got {:?}", body),
+                    },
+                    ref stmt => panic!("expected a while loop, got {:?}", stmt),
+                },
+                ref stmt => panic!("expected a block, got {:?}", stmt),
+            }
+        }
+        assert!(captured("for (var i = 0; i < 3; i = i + 1) { fun f() { return i; } }"));
+        assert!(!captured("for (var i = 0; i < 3; i = i + 1) { var j = i; fun f() { return j; } }"));
+        assert!(!captured("for (var i = 0; i < 3; i = i + 1) print i;"));
+    }
+
+    fn warnings(prog: &str) -> Vec<Warning> {
+        let mut stmts = parse(prog).unwrap();
+        let (res, warnings) = Resolver::new().resolve_with_warnings(&mut stmts);
+        res.expect("no errors");
+        warnings
+    }
+
+    #[test]
+    fn error_positions() {
+        let prog = "{\n  var a = 1;\n  var a = 2;\n}\nbreak;";
+        let mut stmts = parse(prog).unwrap();
+        let errors = Resolver::new().resolve(&mut stmts).unwrap_err();
+        let positions = errors.iter()
+            .map(|e| (e.position.line, e.position.column))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![(3, 11), (5, 1)]);
+        assert_eq!(errors[1].to_string(), "Cannot break outside of a loop. [line 5:1]");
     }
 
-    fn parse_and_resolve(prog: &str) -> ::std::result::Result<(), Vec<ResolveError>> {
+    fn parse_and_resolve(prog: &str) -> ::std::result::Result<(), Vec<ResolveErrorKind>> {
         let mut stmts = parse(prog).unwrap();
         Resolver::new().resolve(&mut stmts)
+            .map_err(|errors| errors.into_iter().map(|e| e.kind).collect())
     }
 }
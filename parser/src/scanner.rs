@@ -19,6 +19,8 @@ pub struct Position {
     pub start: usize,
     pub end: usize,
     pub line: usize,
+    /// 1-based column of `start`, counted in characters.
+    pub column: usize,
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -105,6 +107,37 @@ impl<'s> TokenType<'s> {
         }
     }
 
+    /// The name of the variant, without any associated data.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            TokenType::LeftParen => "LeftParen",
+            TokenType::RightParen => "RightParen",
+            TokenType::LeftBrace => "LeftBrace",
+            TokenType::RightBrace => "RightBrace",
+            TokenType::Comma => "Comma",
+            TokenType::Dot => "Dot",
+            TokenType::Minus => "Minus",
+            TokenType::Plus => "Plus",
+            TokenType::Semicolon => "Semicolon",
+            TokenType::Star => "Star",
+            TokenType::Bang => "Bang",
+            TokenType::BangEq => "BangEq",
+            TokenType::Equal => "Equal",
+            TokenType::EqualEq => "EqualEq",
+            TokenType::LessThan => "LessThan",
+            TokenType::LessThanEq => "LessThanEq",
+            TokenType::GreaterThan => "GreaterThan",
+            TokenType::GreaterThanEq => "GreaterThanEq",
+            TokenType::Slash => "Slash",
+            TokenType::Comment => "Comment",
+            TokenType::String(_) => "String",
+            TokenType::Number(_) => "Number",
+            TokenType::Identifier => "Identifier",
+            TokenType::Keyword(_) => "Keyword",
+            TokenType::EOF => "EOF",
+        }
+    }
+
     pub fn name(&self) -> &'static str {
         match *self {
             TokenType::LeftParen => "'('",
@@ -155,6 +188,7 @@ pub enum Keyword {
     True,
     While,
     Break,
+    Is,
 }
 
 impl Keyword {
@@ -177,6 +211,7 @@ impl Keyword {
             Keyword::True   => "true",
             Keyword::While  => "while",
             Keyword::Break  => "break",
+            Keyword::Is     => "is",
         }
     }
 }
@@ -209,6 +244,7 @@ impl str::FromStr for Keyword {
             "true"   => Ok(Keyword::True),
             "while"  => Ok(Keyword::While),
             "break"  => Ok(Keyword::Break),
+            "is"     => Ok(Keyword::Is),
             _ => Err(()),
         }
     }
@@ -220,6 +256,7 @@ pub(super) struct Scanner<'a> {
     current: usize,
     line: usize,
     at_eof: bool,
+    comments: bool,
 }
 
 impl<'a> Scanner<'a> {
@@ -230,6 +267,16 @@ impl<'a> Scanner<'a> {
             current: 0,
             line: 1,
             at_eof: false,
+            comments: false,
+        }
+    }
+
+    /// A scanner that yields comments as tokens too, rather than skipping
+    /// them.
+    pub fn with_comments(source: &'a str) -> Self {
+        Scanner {
+            comments: true,
+            ..Scanner::new(source)
         }
     }
 
@@ -269,15 +316,14 @@ impl<'a> Scanner<'a> {
     fn scan_token(&mut self) -> Option<Result<Token<'a>>> {
         self.eatwhitespace();
 
-        let c = self.advance();
-        if c.is_none() {
-            if !self.at_eof {
+        let (start, c) = match self.advance() {
+            Some(c) => c,
+            None if !self.at_eof => {
                 self.at_eof = true;
                 return Some(Ok(self.eof()));
-            }
-            return None
-        }
-        let (start, c) = c.unwrap();
+            },
+            None => return None,
+        };
 
         let ty = match c {
             '(' => TokenType::LeftParen,
@@ -335,19 +381,19 @@ impl<'a> Scanner<'a> {
             '"' => {
                 match self.string(start) {
                     Ok(ty) => ty,
-                    Err(err) => return Some(Err(err)),
+                    Err(kind) => return Some(Err(self.error(kind, start))),
                 }
             },
             _ if c.is_digit(10) => {
                 match self.number(start) {
                     Ok(ty) => ty,
-                    Err(err) => return Some(Err(err)),
+                    Err(kind) => return Some(Err(self.error(kind, start))),
                 }
             },
             'a'..='z' | 'A'..='Z' | '_' => {
                 self.identifier(start)
             },
-            c => return Some(Err(SyntaxError::UnexpectedChar(c))),
+            c => return Some(Err(self.error(SyntaxErrorKind::UnexpectedChar(c), start))),
         };
 
         let token = self.yield_token(start, ty);
@@ -355,17 +401,30 @@ impl<'a> Scanner<'a> {
     }
 
     fn yield_token(&mut self, start: usize, ty: TokenType<'a>) -> Token<'a> {
-        let token_contents = self.token_contents(start);
-        let token_len = token_contents.len();
-        let position = Position {
-            start: start,
-            end: start + token_len,
-            line: self.line,
-        };
         Token {
             ty: ty,
-            value: token_contents,
-            position: position,
+            value: self.token_contents(start),
+            position: self.position(start),
+        }
+    }
+
+    fn error(&mut self, kind: SyntaxErrorKind, start: usize) -> SyntaxError {
+        SyntaxError::new(kind, self.position(start))
+    }
+
+    /// The position of the token that started at `start` and has just been
+    /// scanned.
+    fn position(&mut self, start: usize) -> Position {
+        let token_len = self.token_contents(start).len();
+        // Report where the token starts, even if it spans several lines.
+        let end = self.iter.peek().map(|&(i, _)| i).unwrap_or(self.source.len());
+        let line = self.line - self.source[start..end].matches('\n').count();
+        let line_start = self.source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        Position {
+            start: start,
+            end: start + token_len,
+            line: line,
+            column: self.source[line_start..start].chars().count() + 1,
         }
     }
 
@@ -395,7 +454,7 @@ impl<'a> Scanner<'a> {
             .unwrap_or(TokenType::Identifier)
     }
 
-    fn number(&mut self, start: usize) -> Result<TokenType<'a>> {
+    fn number(&mut self, start: usize) -> ::std::result::Result<TokenType<'a>, SyntaxErrorKind> {
         self.advance_while(|c| c.is_digit(10));
         if let Some('.') = self.peek() {
             let cont = self.peek_next().map(|c| c.is_digit(10)).unwrap_or(false);
@@ -403,11 +462,11 @@ impl<'a> Scanner<'a> {
                 self.advance();
                 let count = self.advance_while(|&c| c.is_digit(10));
                 if count == 0 {
-                    return Err(SyntaxError::UnexpectedChar('.'));
+                    return Err(SyntaxErrorKind::UnexpectedChar('.'));
                 }
             }
         };
-        let num = self.token_contents(start).parse::<f64>().unwrap();
+        let num = self.token_contents(start).parse::<f64>().expect("digits to parse as a number");
         Ok(TokenType::Number(num))
     }
 
@@ -415,10 +474,10 @@ impl<'a> Scanner<'a> {
         self.peek().is_none()
     }
 
-    fn string(&mut self, start: usize) -> Result<TokenType<'a>> {
+    fn string(&mut self, start: usize) -> ::std::result::Result<TokenType<'a>, SyntaxErrorKind> {
         self.advance_while(|&c| c != '"');
         if self.is_at_end() {
-            return Err(SyntaxError::UnterminatedString);
+            return Err(SyntaxErrorKind::UnterminatedString);
         }
         // consume the "
         self.advance();
@@ -433,7 +492,7 @@ impl<'a> Iterator for Scanner<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(item) = self.scan_token() {
             match item {
-                Ok(t) if t.ty != TokenType::Comment => return Some(Ok(t)),
+                Ok(t) if t.ty != TokenType::Comment || self.comments => return Some(Ok(t)),
                 Err(e) => return Some(Err(e)),
                 _ => {},
             }
@@ -534,12 +593,23 @@ mod tests {
         assert_eq!(token.ty, TokenType::String("Hello, World"));
     }
 
+    #[test]
+    fn test_positions() {
+        let prog = "var a;\n  print a;";
+        let tokens = Scanner::new(prog).collect::<Result<Vec<_>>>().unwrap();
+        let positions = tokens.iter()
+            .map(|t| (t.position.line, t.position.column))
+            .collect::<Vec<_>>();
+        assert_eq!(&[(1, 1), (1, 5), (1, 6), (2, 3), (2, 9), (2, 10), (2, 11)], &positions[..]);
+    }
+
     #[test]
     fn unclosed_string() {
-        let err = Scanner::new("\"Hello, World!").next().unwrap().unwrap_err();
-        match err {
-            SyntaxError::UnterminatedString => (),
-            _ => panic!("Expected SyntaxError::UnterminatedString"),
+        let err = Scanner::new("\n  \"Hello, World!").next().unwrap().unwrap_err();
+        match err.kind {
+            SyntaxErrorKind::UnterminatedString => (),
+            _ => panic!("Expected SyntaxErrorKind::UnterminatedString"),
         }
+        assert_eq!((err.position.line, err.position.column), (2, 3));
     }
 }
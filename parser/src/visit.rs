@@ -0,0 +1,330 @@
+//! Walking the syntax tree without matching on every kind of node.
+//!
+//! A pass implements `Visitor`, or `VisitorMut` if it changes the tree, and
+//! overrides only the methods for the nodes it cares about. The defaults
+//! visit every child of a node, using the `walk_*` functions, which an
+//! override can also call to carry on into the children:
+//!
+//! ```ignore
+//! struct Prints(usize);
+//!
+//! impl Visitor for Prints {
+//!     fn visit_stmt(&mut self, stmt: &Stmt) {
+//!         if let Stmt::Print(_) = *stmt {
+//!             self.0 += 1;
+//!         }
+//!         walk_stmt(self, stmt);
+//!     }
+//! }
+//! ```
+
+use ast::*;
+
+pub trait Visitor: Sized {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+
+    /// A function declaration, expression or method.
+    fn visit_function(&mut self, function: &FunctionDecl) {
+        walk_function(self, function);
+    }
+
+    /// A class declaration or expression.
+    fn visit_class(&mut self, class: &Class) {
+        walk_class(self, class);
+    }
+
+    /// Every variable that is declared, assigned or read, including `this`
+    /// and `super`.
+    fn visit_variable(&mut self, _variable: &Variable) {}
+
+    fn visit_literal(&mut self, _literal: &Literal) {}
+}
+
+pub fn visit_stmts<V: Visitor>(visitor: &mut V, stmts: &[Stmt]) {
+    for stmt in stmts {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<V: Visitor>(visitor: &mut V, stmt: &Stmt) {
+    match *stmt {
+        Stmt::Expr(ref expr) | Stmt::Print(ref expr) => visitor.visit_expr(expr),
+        Stmt::Var(ref var, ref initializer) => {
+            visitor.visit_variable(var);
+            visitor.visit_expr(initializer);
+        },
+        Stmt::Block(ref stmts) => visit_stmts(visitor, stmts),
+        Stmt::If(ref cond, ref then_clause, ref else_clause) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(then_clause);
+            if let Some(ref else_clause) = *else_clause {
+                visitor.visit_stmt(else_clause);
+            }
+        },
+        Stmt::While(ref cond, ref body) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(body);
+        },
+        Stmt::ForBody(ref for_body) => {
+            visitor.visit_variable(&for_body.var);
+            visitor.visit_stmt(&for_body.body);
+            if let Some(ref increment) = for_body.increment {
+                visitor.visit_expr(increment);
+            }
+        },
+        Stmt::Break(_) => {},
+        Stmt::Function(ref function) => {
+            visitor.visit_variable(&function.var);
+            visitor.visit_function(&function.declaration.borrow());
+        },
+        Stmt::Return(ref retval) => {
+            if let Some(ref retval) = *retval {
+                visitor.visit_expr(retval);
+            }
+        },
+        Stmt::Class(ref class) => visitor.visit_class(class),
+    }
+}
+
+pub fn walk_expr<V: Visitor>(visitor: &mut V, expr: &Expr) {
+    match expr.node {
+        ExprKind::Logical(ref logical) => {
+            visitor.visit_expr(&logical.lhs);
+            visitor.visit_expr(&logical.rhs);
+        },
+        ExprKind::Binary(ref binary) => {
+            visitor.visit_expr(&binary.lhs);
+            visitor.visit_expr(&binary.rhs);
+        },
+        ExprKind::Is(ref value, ref class) => {
+            visitor.visit_expr(value);
+            visitor.visit_expr(class);
+        },
+        ExprKind::Call(ref call) => {
+            visitor.visit_expr(&call.callee);
+            for argument in &call.arguments {
+                visitor.visit_expr(argument);
+            }
+        },
+        ExprKind::Grouping(ref group) => visitor.visit_expr(group),
+        ExprKind::Literal(ref literal) => visitor.visit_literal(literal),
+        ExprKind::Unary(ref unary) => visitor.visit_expr(&unary.unary),
+        ExprKind::Var(ref var) => visitor.visit_variable(var),
+        ExprKind::Assign(ref var, ref value) => {
+            visitor.visit_variable(var);
+            visitor.visit_expr(value);
+        },
+        ExprKind::Get(ref object, _) => visitor.visit_expr(object),
+        ExprKind::Set(ref object, _, ref value) => {
+            visitor.visit_expr(object);
+            visitor.visit_expr(value);
+        },
+        ExprKind::This(ref var, _) | ExprKind::Super(ref var, _, _) => visitor.visit_variable(var),
+        ExprKind::Function(ref function) => visitor.visit_function(&function.borrow()),
+        ExprKind::Class(ref class) => visitor.visit_class(class),
+    }
+}
+
+pub fn walk_function<V: Visitor>(visitor: &mut V, function: &FunctionDecl) {
+    for parameter in &function.parameters {
+        visitor.visit_variable(parameter);
+    }
+    visit_stmts(visitor, &function.body);
+}
+
+pub fn walk_class<V: Visitor>(visitor: &mut V, class: &Class) {
+    visitor.visit_variable(&class.var);
+    if let Some(ref superclass) = class.superclass {
+        visitor.visit_variable(superclass);
+    }
+    for mixin in &class.mixins {
+        visitor.visit_variable(mixin);
+    }
+    for method in class.methods.iter().chain(&class.class_methods) {
+        visitor.visit_function(&method.declaration.borrow());
+    }
+}
+
+/// Like `Visitor`, for passes that change the nodes they visit.
+pub trait VisitorMut: Sized {
+    fn visit_stmt(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+
+    fn visit_function(&mut self, function: &mut FunctionDecl) {
+        walk_function_mut(self, function);
+    }
+
+    fn visit_class(&mut self, class: &mut Class) {
+        walk_class_mut(self, class);
+    }
+
+    fn visit_variable(&mut self, _variable: &mut Variable) {}
+
+    fn visit_literal(&mut self, _literal: &mut Literal) {}
+}
+
+pub fn visit_stmts_mut<V: VisitorMut>(visitor: &mut V, stmts: &mut [Stmt]) {
+    for stmt in stmts {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt_mut<V: VisitorMut>(visitor: &mut V, stmt: &mut Stmt) {
+    match *stmt {
+        Stmt::Expr(ref mut expr) | Stmt::Print(ref mut expr) => visitor.visit_expr(expr),
+        Stmt::Var(ref mut var, ref mut initializer) => {
+            visitor.visit_variable(var);
+            visitor.visit_expr(initializer);
+        },
+        Stmt::Block(ref mut stmts) => visit_stmts_mut(visitor, stmts),
+        Stmt::If(ref mut cond, ref mut then_clause, ref mut else_clause) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(then_clause);
+            if let Some(ref mut else_clause) = *else_clause {
+                visitor.visit_stmt(else_clause);
+            }
+        },
+        Stmt::While(ref mut cond, ref mut body) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(body);
+        },
+        Stmt::ForBody(ref mut for_body) => {
+            visitor.visit_variable(&mut for_body.var);
+            visitor.visit_stmt(&mut for_body.body);
+            if let Some(ref mut increment) = for_body.increment {
+                visitor.visit_expr(increment);
+            }
+        },
+        Stmt::Break(_) => {},
+        Stmt::Function(ref mut function) => {
+            visitor.visit_variable(&mut function.var);
+            visitor.visit_function(&mut function.declaration.borrow_mut());
+        },
+        Stmt::Return(ref mut retval) => {
+            if let Some(ref mut retval) = *retval {
+                visitor.visit_expr(retval);
+            }
+        },
+        Stmt::Class(ref mut class) => visitor.visit_class(class),
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut>(visitor: &mut V, expr: &mut Expr) {
+    match expr.node {
+        ExprKind::Logical(ref mut logical) => {
+            visitor.visit_expr(&mut logical.lhs);
+            visitor.visit_expr(&mut logical.rhs);
+        },
+        ExprKind::Binary(ref mut binary) => {
+            visitor.visit_expr(&mut binary.lhs);
+            visitor.visit_expr(&mut binary.rhs);
+        },
+        ExprKind::Is(ref mut value, ref mut class) => {
+            visitor.visit_expr(value);
+            visitor.visit_expr(class);
+        },
+        ExprKind::Call(ref mut call) => {
+            visitor.visit_expr(&mut call.callee);
+            for argument in &mut call.arguments {
+                visitor.visit_expr(argument);
+            }
+        },
+        ExprKind::Grouping(ref mut group) => visitor.visit_expr(group),
+        ExprKind::Literal(ref mut literal) => visitor.visit_literal(literal),
+        ExprKind::Unary(ref mut unary) => visitor.visit_expr(&mut unary.unary),
+        ExprKind::Var(ref mut var) => visitor.visit_variable(var),
+        ExprKind::Assign(ref mut var, ref mut value) => {
+            visitor.visit_variable(var);
+            visitor.visit_expr(value);
+        },
+        ExprKind::Get(ref mut object, _) => visitor.visit_expr(object),
+        ExprKind::Set(ref mut object, _, ref mut value) => {
+            visitor.visit_expr(object);
+            visitor.visit_expr(value);
+        },
+        ExprKind::This(ref mut var, _) | ExprKind::Super(ref mut var, _, _) => visitor.visit_variable(var),
+        ExprKind::Function(ref function) => visitor.visit_function(&mut function.borrow_mut()),
+        ExprKind::Class(ref mut class) => visitor.visit_class(class),
+    }
+}
+
+pub fn walk_function_mut<V: VisitorMut>(visitor: &mut V, function: &mut FunctionDecl) {
+    for parameter in &mut function.parameters {
+        visitor.visit_variable(parameter);
+    }
+    visit_stmts_mut(visitor, &mut function.body);
+}
+
+pub fn walk_class_mut<V: VisitorMut>(visitor: &mut V, class: &mut Class) {
+    visitor.visit_variable(&mut class.var);
+    if let Some(ref mut superclass) = class.superclass {
+        visitor.visit_variable(superclass);
+    }
+    for mixin in &mut class.mixins {
+        visitor.visit_variable(mixin);
+    }
+    for method in class.methods.iter_mut().chain(&mut class.class_methods) {
+        visitor.visit_function(&mut method.declaration.borrow_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse;
+
+    #[derive(Default)]
+    struct Names(Vec<String>);
+
+    impl Visitor for Names {
+        fn visit_variable(&mut self, variable: &Variable) {
+            self.0.push(variable.name().to_owned());
+        }
+    }
+
+    struct Double;
+
+    impl VisitorMut for Double {
+        fn visit_literal(&mut self, literal: &mut Literal) {
+            if let Literal::Number(ref mut n) = *literal {
+                *n *= 2.0;
+            }
+        }
+    }
+
+    const SOURCE: &str = "
+        class A < B {
+            get(x) { return this.x + x; }
+            class make() { return A(); }
+        }
+        fun f(a) { var b = a; { b = fun () { return 2; }; } }
+        while (f(1)) print super.g;
+    ";
+
+    #[test]
+    fn visits_every_variable() {
+        let stmts = parse(SOURCE).unwrap();
+        let mut names = Names::default();
+        visit_stmts(&mut names, &stmts);
+        assert_eq!(names.0, ["A", "B", "x", "this", "x", "A", "f", "a", "b", "a", "b", "f", "super"]);
+    }
+
+    #[test]
+    fn changes_nested_nodes() {
+        let mut stmts = parse(SOURCE).unwrap();
+        visit_stmts_mut(&mut Double, &mut stmts);
+        let expected = parse(&SOURCE.replace("1", "2").replace("2;", "4;")).unwrap();
+        assert_eq!(stmts, expected);
+    }
+}
@@ -0,0 +1,78 @@
+//! A host application that lets a script react to its events.
+//!
+//! The host decides everything the script can touch: it gets no clock or
+//! command line arguments, only the `log` native and the events below.
+//!
+//! Run with `cargo run --example host_app`.
+
+extern crate vm;
+
+use std::process;
+
+use vm::{Capabilities, HookRegistry, LoxBuilder, RuntimeError, Value, VM};
+
+const SCRIPT: &str = r#"
+var score = 0;
+
+fun start(player) {
+  log("Welcome, " + player + "!");
+}
+
+fun tick(frame, bonus) {
+  if (bonus) score = score + 10;
+  else score = score + 1;
+  return score;
+}
+
+fun quit() {
+  log("Thanks for playing!");
+  return score;
+}
+
+on("start", start);
+on("tick", tick);
+on("quit", quit);
+"#;
+
+/// Writes a message from the script to stderr, prefixed so it stands apart
+/// from the host's own output.
+fn log(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let message = vm.object(args[1])
+        .and_then(|o| o.as_string())
+        .cloned()
+        .ok_or(RuntimeError::BadArgument("Can only log strings"))?;
+    eprintln!("[script] {}", message);
+    Ok(Value::nil())
+}
+
+fn run() -> Result<(), vm::LoxError> {
+    let hooks = HookRegistry::new()
+        .event("start")
+        .event("tick")
+        .event("quit");
+    let mut lox = LoxBuilder::new()
+        .capabilities(Capabilities::none())
+        .native("log", 1, log)
+        .hooks(hooks)
+        .build();
+
+    lox.run(SCRIPT)?;
+    lox.fire("start", ("Ada",))?;
+    for frame in 0..5 {
+        let bonus = frame % 2 == 0;
+        for score in lox.fire("tick", (frame as f64, bonus))? {
+            println!("frame {}: score {}", frame, score.with_heap(lox.vm().heap()));
+        }
+    }
+    for score in lox.fire("quit", ())? {
+        println!("final score {}", score.with_heap(lox.vm().heap()));
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("[error]: {}", e);
+        process::exit(70);
+    }
+}
@@ -0,0 +1,132 @@
+//! The `bench` command, which times a script over several runs so that the
+//! speed of different versions of rlox can be compared.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use failure;
+
+use vm::{Config, Program};
+use vm::register;
+
+/// Runs `program` `warmup` times without timing it, and then `runs` times,
+/// printing a summary of how long the runs took. Each run has a fresh VM and
+/// its output is discarded.
+pub fn bench(program: &Program, runs: usize, warmup: usize, config: &Config) -> Result<(), failure::Error> {
+    measure(|| execute(program, config), runs, warmup)
+}
+
+/// Like `bench`, but runs a program compiled for the register machine.
+pub fn bench_register(program: &register::Program, runs: usize, warmup: usize, config: &Config)
+    -> Result<(), failure::Error>
+{
+    measure(|| execute_register(program, config), runs, warmup)
+}
+
+fn measure<F>(mut execute: F, runs: usize, warmup: usize) -> Result<(), failure::Error>
+    where F: FnMut() -> Result<(Duration, usize), failure::Error>
+{
+    for _ in 0..warmup {
+        execute()?;
+    }
+    let mut times = Vec::with_capacity(runs);
+    let mut instructions = Vec::with_capacity(runs);
+    for _ in 0..runs {
+        let (time, count) = execute()?;
+        times.push(time);
+        instructions.push(count);
+    }
+
+    let summary = Summary::new(&times);
+    println!("{} runs, after {} to warm up", runs, warmup);
+    println!("    min          {:.3} ms", summary.min);
+    println!("    median       {:.3} ms", summary.median);
+    println!("    stddev       {:.3} ms", summary.stddev);
+    let (least, most) = (instructions.iter().min(), instructions.iter().max());
+    match (least, most) {
+        // Scripts that use `clock` or `random` may not do the same each run.
+        (Some(least), Some(most)) if least != most => println!("    instructions {} to {}", least, most),
+        (Some(count), _) => println!("    instructions {}", count),
+        _ => {},
+    }
+    Ok(())
+}
+
+/// Runs `program` once, returning how long it took and how many
+/// instructions it executed.
+fn execute(program: &Program, config: &Config) -> Result<(Duration, usize), failure::Error> {
+    let mut vm = config.vm();
+    vm.set_stdout(io::sink());
+    let start = Instant::now();
+    vm.run_program(program).map_err(|traceback| format_err!("{}", traceback))?;
+    Ok((start.elapsed(), vm.instruction_count()))
+}
+
+fn execute_register(program: &register::Program, config: &Config) -> Result<(Duration, usize), failure::Error> {
+    let mut machine = register::Machine::new(&config.limits);
+    machine.set_stdout(io::sink());
+    let start = Instant::now();
+    machine.run(program).map_err(|traceback| format_err!("{}", traceback))?;
+    Ok((start.elapsed(), machine.instruction_count()))
+}
+
+/// Statistics on a set of times, in milliseconds.
+#[derive(Debug, PartialEq)]
+struct Summary {
+    min: f64,
+    median: f64,
+    stddev: f64,
+}
+
+impl Summary {
+    fn new(times: &[Duration]) -> Summary {
+        let mut millis = times.iter()
+            .map(|time| time.as_secs() as f64 * 1e3 + f64::from(time.subsec_nanos()) / 1e6)
+            .collect::<Vec<_>>();
+        millis.sort_by(|a, b| a.partial_cmp(b).expect("times to be comparable"));
+        let n = millis.len();
+        let median = if n % 2 == 0 { (millis[n / 2 - 1] + millis[n / 2]) / 2.0 } else { millis[n / 2] };
+        let mean = millis.iter().sum::<f64>() / n as f64;
+        let variance = millis.iter().map(|t| (t - mean) * (t - mean)).sum::<f64>() / n as f64;
+        Summary {
+            min: millis[0],
+            median,
+            stddev: variance.sqrt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_times() {
+        let millis = |ms: &[u64]| ms.iter().map(|&ms| Duration::from_millis(ms)).collect::<Vec<_>>();
+        assert_eq!(Summary::new(&millis(&[4, 2, 6])), Summary { min: 2.0, median: 4.0, stddev: (8.0f64 / 3.0).sqrt() });
+        assert_eq!(Summary::new(&millis(&[5, 1, 3, 7])), Summary { min: 1.0, median: 4.0, stddev: 5.0f64.sqrt() });
+        assert_eq!(Summary::new(&millis(&[3])), Summary { min: 3.0, median: 3.0, stddev: 0.0 });
+    }
+
+    #[test]
+    fn counts_instructions() {
+        let program = Program::compile("var a = 0; for (var i = 0; i < 10; i = i + 1) a = a + i;").unwrap();
+        let config = Config::default();
+        let (_, once) = execute(&program, &config).unwrap();
+        let (_, again) = execute(&program, &config).unwrap();
+        assert!(once > 10);
+        assert_eq!(once, again);
+        assert!(execute(&Program::compile("nil();").unwrap(), &config).is_err());
+    }
+
+    #[test]
+    fn counts_register_instructions() {
+        let program = register::Program::compile("var a = 0; for (var i = 0; i < 10; i = i + 1) a = a + i;").unwrap();
+        let config = Config::default();
+        let (_, once) = execute_register(&program, &config).unwrap();
+        let (_, again) = execute_register(&program, &config).unwrap();
+        assert!(once > 10);
+        assert_eq!(once, again);
+        assert!(execute_register(&register::Program::compile("nil();").unwrap(), &config).is_err());
+    }
+}
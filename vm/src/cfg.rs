@@ -0,0 +1,187 @@
+//! Splits decoded bytecode into basic blocks and draws the control flow
+//! between them as a Graphviz graph, which makes badly patched jumps easy to
+//! spot.
+
+use std::fmt::Write;
+
+use debug::{Instruction, Listing, Operand};
+
+/// A run of instructions that is only ever entered at the top and left at
+/// the bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub instructions: Vec<Instruction>,
+    /// The offsets of the blocks that control can go to next, with the
+    /// target of a jump first.
+    pub successors: Vec<usize>,
+}
+
+impl BasicBlock {
+    /// The offset of the block's first instruction.
+    pub fn start(&self) -> usize {
+        self.instructions[0].offset
+    }
+}
+
+/// Where control can go after an instruction.
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Stop,
+}
+
+fn flow(instruction: &Instruction) -> Flow {
+    let target = instruction.operands.iter().filter_map(|operand| match *operand {
+        Operand::Jump(target) => Some(target),
+        _ => None,
+    }).next();
+    match (instruction.op, target) {
+        ("OP_RETURN", _) | ("OP_HALT", _) => Flow::Stop,
+        ("OP_JUMP", Some(target)) | ("OP_LOOP", Some(target)) => Flow::Jump(target),
+        (_, Some(target)) => Flow::Branch(target),
+        _ => Flow::Next,
+    }
+}
+
+/// Splits the instructions of a chunk into basic blocks, in order of offset.
+/// A block starts at the start of the chunk, at the target of any jump and
+/// after any instruction that jumps or returns.
+pub fn basic_blocks(instructions: &[Instruction]) -> Vec<BasicBlock> {
+    let mut leaders = vec![false; instructions.len()];
+    let index_of = |offset: usize| instructions.iter().position(|i| i.offset == offset);
+    for (i, instruction) in instructions.iter().enumerate() {
+        let target = match flow(instruction) {
+            Flow::Next => continue,
+            Flow::Jump(target) | Flow::Branch(target) => index_of(target),
+            Flow::Stop => None,
+        };
+        if let Some(target) = target {
+            leaders[target] = true;
+        }
+        if i + 1 < instructions.len() {
+            leaders[i + 1] = true;
+        }
+    }
+    if !leaders.is_empty() {
+        leaders[0] = true;
+    }
+
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    for (instruction, &leader) in instructions.iter().zip(&leaders) {
+        if leader {
+            blocks.push(BasicBlock { instructions: Vec::new(), successors: Vec::new() });
+        }
+        blocks.last_mut().expect("the first instruction to lead a block").instructions.push(instruction.clone());
+    }
+    let starts = blocks.iter().skip(1).map(BasicBlock::start).map(Some).chain(Some(None)).collect::<Vec<_>>();
+    for (block, next) in blocks.iter_mut().zip(starts) {
+        let last = block.instructions.last().expect("blocks to be nonempty");
+        block.successors = match flow(last) {
+            Flow::Next => next.into_iter().collect(),
+            Flow::Jump(target) => vec![target],
+            Flow::Branch(target) => Some(target).into_iter().chain(next).collect(),
+            Flow::Stop => vec![],
+        };
+    }
+    blocks
+}
+
+/// Draws the basic blocks of every function as a Graphviz `dot` graph, with
+/// each function in its own cluster.
+pub fn dot(listings: &[Listing]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "digraph cfg {{");
+    let _ = writeln!(out, "    node [shape=box, fontname=monospace];");
+    for (f, listing) in listings.iter().enumerate() {
+        let blocks = basic_blocks(&listing.instructions);
+        let _ = writeln!(out, "    subgraph cluster_{} {{", f);
+        let _ = writeln!(out, "        label=\"{}\";", escape(&listing.name));
+        for block in &blocks {
+            let mut label = String::new();
+            for instruction in &block.instructions {
+                let _ = write!(label, "{:04} {}\\l", instruction.offset, escape(&instruction.to_string()));
+            }
+            let _ = writeln!(out, "        f{}_{} [label=\"{}\"];", f, block.start(), label);
+        }
+        for block in &blocks {
+            let branches = block.successors.len() > 1;
+            for (i, successor) in block.successors.iter().enumerate() {
+                let label = match (branches, i) {
+                    (false, _) => "",
+                    (true, 0) => " [label=\"jump\"]",
+                    (true, _) => " [label=\"next\"]",
+                };
+                let _ = writeln!(out, "        f{}_{} -> f{}_{}{};", f, block.start(), f, successor, label);
+            }
+        }
+        let _ = writeln!(out, "    }}");
+    }
+    let _ = writeln!(out, "}}");
+    out
+}
+
+/// Escapes text for a quoted `dot` string, turning tabs into spaces.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::Program;
+
+    fn blocks(source: &str) -> Vec<BasicBlock> {
+        basic_blocks(&Program::compile(source).unwrap().listings()[0].instructions)
+    }
+
+    #[test]
+    fn straight_line_code_is_one_block() {
+        let blocks = blocks("var a = 1; print a + 2;");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].successors, vec![]);
+    }
+
+    #[test]
+    fn branches_and_loops() {
+        let blocks = blocks("var a = 0; if (a) print 1; else print 2; while (a < 3) a = a + 1;");
+        let starts = blocks.iter().map(BasicBlock::start).collect::<Vec<_>>();
+        for block in &blocks {
+            for successor in &block.successors {
+                assert!(starts.contains(successor), "{} is not the start of a block", successor);
+            }
+            for instruction in &block.instructions[..block.instructions.len() - 1] {
+                match flow(instruction) {
+                    Flow::Next => {},
+                    _ => panic!("{} in the middle of a block", instruction),
+                }
+            }
+        }
+        // The condition of the `if` branches two ways, and the `while` loops
+        // back up to its condition.
+        assert_eq!(blocks[0].successors.len(), 2);
+        let looping = blocks.iter().find(|b| b.instructions.last().unwrap().op.starts_with("OP_LOOP")).unwrap();
+        assert!(looping.successors[0] < looping.start());
+    }
+
+    #[test]
+    fn draws_a_graph() {
+        let program = Program::compile("fun f(s) { if (s) return \"a\"; return nil; } f(true);").unwrap();
+        let graph = dot(&program.listings());
+        assert!(graph.starts_with("digraph cfg {\n"));
+        assert!(graph.contains("label=\"f\";"));
+        assert!(graph.contains("[label=\"jump\"]"));
+        assert!(graph.ends_with("}\n"));
+        assert_eq!(escape("say \"hi\"\t\\"), "say \\\"hi\\\" \\\\");
+    }
+}
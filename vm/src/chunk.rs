@@ -1,37 +1,145 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
 use broom::Heap;
+use broom::prelude::Handle;
 use broom::prelude::Trace;
 use broom::prelude::Tracer;
 
 use gc::value::Value;
 use gc::object::Object;
 
+/// The most constants a chunk can hold, since `Op::ConstantLong` has a 24 bit
+/// index.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    code: Vec<u8>,
+    /// The ops that the VM runs, which it may rewrite into equivalent ones
+    /// that are specialized for the operands it has seen.
+    code: Vec<Cell<u8>>,
     name: String,
     constants: Vec<Value>,
     lines: Vec<LineInfo>,
+    locals: Vec<LocalInfo>,
+    /// The global names of the program that this chunk was loaded from,
+    /// which its global variable ops index into.
+    globals: Rc<GlobalPool>,
+    /// An inline cache for each method lookup site, holding the method that
+    /// was found for the class that was last looked up there.
+    caches: Vec<Cell<Option<(Handle<Object>, Handle<Object>)>>>,
+}
+
+/// The most method lookup sites a chunk can have, since their ops have a 16
+/// bit cache index.
+pub const MAX_CACHES: usize = 1 << 16;
+
+/// The global variables of a program. Ops refer to them by their index in the
+/// program's own pool, and `slots` maps each one to where the VM that loaded
+/// the program keeps its value, so running the op doesn't need to look the
+/// name up.
+#[derive(Debug, Default)]
+pub struct GlobalPool {
+    pub names: Vec<String>,
+    pub slots: Vec<usize>,
 }
 
 impl Trace<Object> for Chunk {
     fn trace(&self, tracer: &mut Tracer<Object>) {
         self.constants.trace(tracer);
+        // Cached classes are kept alive so that a new class can't be
+        // allocated in the same place and hit the cache.
+        for cache in &self.caches {
+            if let Some((class, method)) = cache.get() {
+                class.trace(tracer);
+                method.trace(tracer);
+            }
+        }
     }
 }
 
 #[derive(Debug, Copy, Clone)]
-struct LineInfo {
+pub struct LineInfo {
     pub start: usize,
     pub line: usize,
 }
 
-impl Chunk {
+/// Records that the local variable `name` lives in `slot` while the ip is
+/// within `start..end`, so that debugging tools can show it by name.
+#[derive(Debug, Clone)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: u8,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The writable form of a `Chunk`, used while compiling a function.
+///
+/// Once compilation is done the builder is frozen into a `Chunk`, which the
+/// VM only ever reads from.
+#[derive(Debug, Clone)]
+pub struct ChunkBuilder {
+    code: Vec<u8>,
+    name: String,
+    constants: Vec<Value>,
+    lines: Vec<LineInfo>,
+    locals: Vec<LocalInfo>,
+    caches: usize,
+}
+
+impl ChunkBuilder {
     pub fn new(name: String) -> Self {
-        Chunk {
+        ChunkBuilder {
             code: Vec::new(),
             name,
             constants: Vec::new(),
             lines: Vec::new(),
+            locals: Vec::new(),
+            caches: 0,
+        }
+    }
+
+    pub fn build(mut self) -> Chunk {
+        // Locals that are still in scope live until the end of the chunk.
+        let len = self.code.len();
+        for local in &mut self.locals {
+            local.end = local.end.min(len);
+        }
+        // The names are only known once the whole program has been compiled,
+        // and are attached when it is loaded.
+        let globals = Rc::new(GlobalPool::default());
+        Chunk::from_parts(self.name, self.code, self.constants, self.lines, self.locals, self.caches, globals)
+    }
+
+    /// Reserves an inline cache for a method lookup site and returns its
+    /// index, or `None` if the chunk already has as many as it can.
+    pub fn add_cache(&mut self) -> Option<u16> {
+        if self.caches == MAX_CACHES {
+            return None;
+        }
+        self.caches += 1;
+        Some((self.caches - 1) as u16)
+    }
+
+    /// Marks the start of the scope of the local `name` in `slot`.
+    pub fn begin_local(&mut self, name: &str, slot: u8) {
+        self.locals.push(LocalInfo {
+            name: name.to_owned(),
+            slot,
+            start: self.code.len(),
+            end: ::std::usize::MAX,
+        });
+    }
+
+    /// Marks the end of the scope of whichever local currently lives in `slot`.
+    pub fn end_local(&mut self, slot: u8) {
+        let len = self.code.len();
+        let open = self.locals.iter_mut()
+            .rev()
+            .find(|l| l.slot == slot && l.end == ::std::usize::MAX);
+        if let Some(local) = open {
+            local.end = len;
         }
     }
 
@@ -48,6 +156,7 @@ impl Chunk {
         self.code[idx] = byte;
     }
 
+    #[cfg(feature = "op-immediate")]
     pub fn write_u64(&mut self, val: u64) {
         let b1 = (val & 0xff) as u8;
         let b2 = ((val >> 8) & 0xff) as u8;
@@ -67,61 +176,55 @@ impl Chunk {
         self.write_byte(b8);
     }
 
+    /// Records that the code from here on is on `line`. Lines are run-length
+    /// encoded, so this only adds an entry when the line changes.
     fn add_line(&mut self, line: usize) {
-        match self.lines.last().cloned() {
-            Some(last) if last.line >= line => return,
-            _ => (),
+        let start = self.code.len();
+        if let Some(last) = self.lines.last().cloned() {
+            if last.line == line {
+                return;
+            }
+            // No code was written for the last line, so it's replaced.
+            if last.start == start {
+                self.lines.pop();
+            }
+        }
+        if self.lines.last().map_or(false, |last| last.line == line) {
+            return;
         }
         self.lines.push(LineInfo {
-            start: self.code.len(),
-            line: line,
+            start,
+            line,
         });
     }
 
-    pub fn line(&self, offset: usize) -> usize {
-        let idx =
-            self.lines
-                .binary_search_by_key(&offset, |line_info| line_info.start)
-                .map_err(|idx| idx - 1) // on failure we want the earlier line
-                .unwrap_or_else(|idx| idx);
-        self.lines[idx].line
-    }
-
-    pub fn name(&self) -> &str {
-        &self.name
-    }
-
-    pub fn add_constant(&mut self, constant: Value) -> u8 {
+    /// Adds a constant, or finds an equal one that was already added, and
+    /// returns its index. Only the first 256 can be loaded by the ops that
+    /// take a single byte index, the rest need `Op::ConstantLong`. This is
+    /// `None` once the chunk has as many constants as it can.
+    pub fn add_constant(&mut self, constant: Value) -> Option<usize> {
         for (i, c) in self.constants.iter().enumerate() {
             if *c == constant {
-                return i as u8;
+                return Some(i);
             }
         }
-        if self.constants.len() == 256 {
-            panic!("A chunk cannot have more than 256 constants");
+        if self.constants.len() == MAX_CONSTANTS {
+            return None;
         }
         self.constants.push(constant);
-        self.constants.len() as u8 - 1
+        Some(self.constants.len() - 1)
     }
 
-    pub fn get(&self, ip: usize) -> u8 {
-        self.code[ip]
-    }
-
-    pub fn get_constant(&self, idx: u8) -> Option<&Value> {
-        self.constants.get(idx as usize)
-    }
-
-    pub fn string_constant(&mut self, heap: &mut Heap<Object>, string: &str) -> u8 {
+    pub fn string_constant(&mut self, heap: &mut Heap<Object>, string: &str) -> Option<usize> {
         // Scan constants for one that already exists
-        for (i, c) in self.constants().enumerate() {
+        for (i, c) in self.constants.iter().enumerate() {
             let obj = c
                 .as_object()
                 .and_then(|o| heap.get(o))
                 .and_then(|o| o.as_string());
             if let Some(s) = obj {
                 if s == string {
-                    return i as u8
+                    return Some(i)
                 }
             }
         }
@@ -133,13 +236,137 @@ impl Chunk {
     pub fn len(&self) -> usize {
         self.code.len()
     }
+}
+
+impl Chunk {
+    pub fn from_parts(
+        name: String,
+        code: Vec<u8>,
+        constants: Vec<Value>,
+        lines: Vec<LineInfo>,
+        locals: Vec<LocalInfo>,
+        caches: usize,
+        globals: Rc<GlobalPool>,
+    ) -> Self {
+        Chunk {
+            code: code.into_iter().map(Cell::new).collect(),
+            name,
+            constants,
+            lines,
+            locals,
+            globals,
+            caches: vec![Cell::new(None); caches],
+        }
+    }
+
+    pub fn cache_count(&self) -> usize {
+        self.caches.len()
+    }
+
+    /// The method cached at `cache` if it was found for `class`.
+    pub fn cached_method(&self, cache: usize, class: Handle<Object>) -> Option<Handle<Object>> {
+        match self.caches[cache].get() {
+            Some((cached, method)) if cached == class => Some(method),
+            _ => None,
+        }
+    }
+
+    pub fn cache_method(&self, cache: usize, class: Handle<Object>, method: Handle<Object>) {
+        self.caches[cache].set(Some((class, method)));
+    }
+
+    pub fn global_names(&self) -> &[String] {
+        &self.globals.names
+    }
+
+    pub fn global_name(&self, idx: usize) -> &str {
+        &self.globals.names[idx]
+    }
+
+    /// Where the VM keeps the global at `idx` in the program's pool.
+    pub fn global_slot(&self, idx: usize) -> usize {
+        self.globals.slots[idx]
+    }
+
+    pub fn line(&self, offset: usize) -> usize {
+        let idx =
+            self.lines
+                .binary_search_by_key(&offset, |line_info| line_info.start)
+                .map_err(|idx| idx - 1) // on failure we want the earlier line
+                .unwrap_or_else(|idx| idx);
+        self.lines[idx].line
+    }
+
+    pub fn lines(&self) -> &[LineInfo] {
+        &self.lines
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn locals(&self) -> &[LocalInfo] {
+        &self.locals
+    }
+
+    /// Returns the locals that are in scope at `offset`, in slot order.
+    pub fn locals_at(&self, offset: usize) -> Vec<&LocalInfo> {
+        let mut locals = self.locals.iter()
+            .filter(|l| l.start <= offset && offset < l.end)
+            .collect::<Vec<_>>();
+        locals.sort_by_key(|l| l.slot);
+        locals
+    }
+
+    /// Returns the name of the local in `slot` at `offset`, if it has one.
+    pub fn local_name(&self, slot: u8, offset: usize) -> Option<&str> {
+        self.locals.iter()
+            .rev()
+            .find(|l| l.slot == slot && l.start <= offset && offset < l.end)
+            .map(|l| &l.name[..])
+    }
+
+    pub fn get(&self, ip: usize) -> u8 {
+        self.code[ip].get()
+    }
+
+    /// The chunk's code, including any op that the VM has quickened.
+    pub fn code(&self) -> Vec<u8> {
+        self.code.iter().map(Cell::get).collect()
+    }
+
+    /// Replaces the op at `offset` with `op`, which must do the same thing
+    /// for any operands and have the same length.
+    pub fn quicken(&self, offset: usize, op: Op) {
+        let mut buf = Vec::with_capacity(1);
+        op.write(&mut buf);
+        debug_assert_eq!(buf.len(), 1, "{:?} is quickened in place", op);
+        self.code[offset].set(buf[0]);
+    }
+
+    pub fn get_constant(&self, idx: usize) -> Option<&Value> {
+        self.constants.get(idx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Every chunk the compiler produces ends with a `Return` or a `Halt`, so
+    /// the VM never needs to compare the ip against the length of the chunk.
+    pub fn is_terminated(&self) -> bool {
+        match self.code.last().map(Cell::get) {
+            Some(0x00) | Some(0x32) => true,
+            _ => false,
+        }
+    }
 
     pub fn constants(&self) -> Constants {
         Constants::new(self.constants.iter())
     }
 
     pub fn read_byte(&self, idx: usize) -> u8 {
-        self.code[idx]
+        self.code[idx].get()
     }
 
     pub fn read_u16(&self, idx: usize) -> u16 {
@@ -147,7 +374,7 @@ impl Chunk {
         let size = ::std::mem::size_of::<u16>();
         unsafe {
             ::std::ptr::copy_nonoverlapping(
-                &self.code[idx],
+                self.code[idx..idx + size].as_ptr() as *const u8,
                 &mut t as *mut u16 as *mut u8,
                 size);
         }
@@ -159,7 +386,7 @@ impl Chunk {
         let size = ::std::mem::size_of::<u64>();
         unsafe {
             ::std::ptr::copy_nonoverlapping(
-                &self.code[idx],
+                self.code[idx..idx + size].as_ptr() as *const u8,
                 &mut t as *mut u64 as *mut u8,
                 size);
         }
@@ -185,21 +412,20 @@ impl<'c> Iterator for Constants<'c> {
     }
 }
 
-impl AsRef<[u8]> for Chunk {
-    fn as_ref(&self) -> &[u8] {
-        &self.code[..]
-    }
-}
-
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Op {
     Return,
     Constant(u8),
+    /// Loads a constant whose index doesn't fit in a byte, stored in the
+    /// next three bytes.
+    ConstantLong(u32),
     Nil,
     True,
     False,
     Pop,
+    /// Pops the given number of values, such as the locals of a block.
+    PopN(u8),
     GetLocal,
     SetLocal,
     GetGlobal,
@@ -209,7 +435,7 @@ pub enum Op {
     SetUpValue,
     GetProperty,
     SetProperty,
-    // GetSuper,
+    GetSuper(u8),
     Equal,
     GreaterThan,
     LessThan,
@@ -217,14 +443,47 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    /// The arithmetic and comparisons for when both operands are numbers,
+    /// either because the compiler can tell or because the VM has seen that
+    /// they were before. If they turn out not to be, they act like the
+    /// generic op and the VM puts that back.
+    AddNumber,
+    SubtractNumber,
+    MultiplyNumber,
+    DivideNumber,
+    LessNumber,
+    GreaterNumber,
+    /// Pops a class and a value, and pushes whether the value is an instance
+    /// of the class or of one of its subclasses.
+    Is,
     Not,
     Negate,
     Print,
     Jump,
     JumpIfFalse,
+    /// Pops two numbers and jumps if the first is less than the second. These
+    /// fused comparisons replace a comparison that feeds straight into a
+    /// `JumpIfFalse`, and leave nothing on the stack to pop afterwards.
+    JumpIfLess,
+    JumpIfNotLess,
+    JumpIfGreater,
+    JumpIfNotGreater,
     Loop,
+    /// Pops the condition at the bottom of a `while` loop and jumps back to
+    /// the top of the body if it is truthy, so that each iteration only runs
+    /// one jump. The fused comparisons below do the same for numbers.
+    LoopIfTrue,
+    LoopIfLess,
+    LoopIfNotLess,
+    LoopIfGreater,
+    LoopIfNotGreater,
+    #[cfg_attr(not(feature = "op-immediate"), allow(dead_code))]
     Immediate,
     Call(u8),
+    /// A call in tail position, which reuses the caller's frame when calling
+    /// a closure. Anything else is called normally, so it is followed by a
+    /// `Return`.
+    TailCall(u8),
     Invoke(u8),
     // Invoke_0,
     // Invoke_1,
@@ -248,8 +507,10 @@ pub enum Op {
     CloseUpValue,
     // Return,
     Class(u8),
-    // SubClass,
+    Inherit,
+    Mixin(u8),
     // Method,
+    Halt,
 }
 
 impl Op {
@@ -290,15 +551,136 @@ impl Op {
             Op::GetProperty => buf.push(0x27),
             Op::SetProperty => buf.push(0x28),
             Op::Invoke(a) => buf.push(0x29 + a),
+            Op::Halt => buf.push(0x32),
+            Op::Inherit => buf.push(0x33),
+            Op::GetSuper(idx) => { buf.push(0x34); buf.push(idx); },
+            Op::Mixin(count) => { buf.push(0x35); buf.push(count); },
+            Op::ConstantLong(idx) => {
+                buf.push(0x36);
+                buf.push((idx & 0xff) as u8);
+                buf.push(((idx >> 8) & 0xff) as u8);
+                buf.push(((idx >> 16) & 0xff) as u8);
+            },
+            Op::PopN(count) => { buf.push(0x37); buf.push(count); },
+            Op::JumpIfLess => buf.push(0x38),
+            Op::JumpIfNotLess => buf.push(0x39),
+            Op::JumpIfGreater => buf.push(0x3a),
+            Op::JumpIfNotGreater => buf.push(0x3b),
+            // 0x3c -> 0x44
+            Op::TailCall(a) => buf.push(0x3c + a),
+            Op::LoopIfTrue => buf.push(0x45),
+            Op::LoopIfLess => buf.push(0x46),
+            Op::LoopIfNotLess => buf.push(0x47),
+            Op::LoopIfGreater => buf.push(0x48),
+            Op::LoopIfNotGreater => buf.push(0x49),
+            Op::AddNumber => buf.push(0x4a),
+            Op::SubtractNumber => buf.push(0x4b),
+            Op::MultiplyNumber => buf.push(0x4c),
+            Op::DivideNumber => buf.push(0x4d),
+            Op::LessNumber => buf.push(0x4e),
+            Op::GreaterNumber => buf.push(0x4f),
+            Op::Is => buf.push(0x50),
         }
     }
 }
 
+/// The name of the instruction that starts with `byte`.
+pub fn op_name(byte: u8) -> String {
+    let name = match byte {
+        0x00 => "OP_RETURN",
+        0x01 => "OP_CONSTANT",
+        0x02 => "OP_PRINT",
+        0x03 => "OP_ADD",
+        0x04 => "OP_SUB",
+        0x05 => "OP_MUL",
+        0x06 => "OP_DIV",
+        0x07 => "OP_NOT",
+        0x08 => "OP_NEG",
+        0x09 => "OP_EQ",
+        0x0a => "OP_GT",
+        0x0b => "OP_LT",
+        0x0c => "OP_JUMP",
+        0x0d => "OP_JUMP_IF_FALSE",
+        0x0e => "OP_POP",
+        0x0f => "OP_GET_GLOBAL",
+        0x10 => "OP_SET_GLOBAL",
+        0x11 => "OP_GET_LOCAL",
+        0x12 => "OP_SET_LOCAL",
+        0x13 => "OP_FLOAT",
+        0x14 => "OP_NIL",
+        0x15 => "OP_TRUE",
+        0x16 => "OP_FALSE",
+        0x17..=0x1f => return format!("OP_CALL_{}", byte - 0x17),
+        0x20 => "OP_LOOP",
+        0x21 => "OP_CLOSE_UPVALUE",
+        0x22 => "OP_GET_UPVALUE",
+        0x23 => "OP_SET_UPVALUE",
+        0x24 => "OP_CLOSURE",
+        0x25 => "OP_DEFINE_GLOBAL",
+        0x26 => "OP_CLASS",
+        0x27 => "OP_GET_PROPERTY",
+        0x28 => "OP_SET_PROPERTY",
+        0x29..=0x31 => return format!("OP_INVOKE_{}", byte - 0x29),
+        0x32 => "OP_HALT",
+        0x33 => "OP_INHERIT",
+        0x34 => "OP_GET_SUPER",
+        0x35 => "OP_MIXIN",
+        0x36 => "OP_CONSTANT_LONG",
+        0x37 => "OP_POPN",
+        0x38 => "OP_JUMP_IF_LESS",
+        0x39 => "OP_JUMP_IF_NOT_LESS",
+        0x3a => "OP_JUMP_IF_GREATER",
+        0x3b => "OP_JUMP_IF_NOT_GREATER",
+        0x3c..=0x44 => return format!("OP_TAIL_CALL_{}", byte - 0x3c),
+        0x45 => "OP_LOOP_IF_TRUE",
+        0x46 => "OP_LOOP_IF_LESS",
+        0x47 => "OP_LOOP_IF_NOT_LESS",
+        0x48 => "OP_LOOP_IF_GREATER",
+        0x49 => "OP_LOOP_IF_NOT_GREATER",
+        0x4a => "OP_ADD_NUMBER",
+        0x4b => "OP_SUB_NUMBER",
+        0x4c => "OP_MUL_NUMBER",
+        0x4d => "OP_DIV_NUMBER",
+        0x4e => "OP_LESS_NUMBER",
+        0x4f => "OP_GREATER_NUMBER",
+        0x50 => "OP_IS",
+        _ => return format!("OP_UNKNOWN_{:02x}", byte),
+    };
+    name.to_owned()
+}
+
+/// One past the last opcode that `decode_op!` knows.
+pub const OP_COUNT: usize = 0x51;
+
+/// Builds an array of `OP_COUNT` handlers for `$ty`, indexed by opcode, that
+/// each run `decode_op!` for their own opcode. The match on a constant folds
+/// down to the one arm, so running an instruction through the array is a
+/// single indirect call.
+macro_rules! op_table {
+    ($ty:ty, $ret:ty) => {
+        op_table!(@ $ty, $ret,
+            0x00 0x01 0x02 0x03 0x04 0x05 0x06 0x07 0x08 0x09 0x0a 0x0b 0x0c 0x0d 0x0e 0x0f
+            0x10 0x11 0x12 0x13 0x14 0x15 0x16 0x17 0x18 0x19 0x1a 0x1b 0x1c 0x1d 0x1e 0x1f
+            0x20 0x21 0x22 0x23 0x24 0x25 0x26 0x27 0x28 0x29 0x2a 0x2b 0x2c 0x2d 0x2e 0x2f
+            0x30 0x31 0x32 0x33 0x34 0x35 0x36 0x37 0x38 0x39 0x3a 0x3b 0x3c 0x3d 0x3e 0x3f
+            0x40 0x41 0x42 0x43 0x44 0x45 0x46 0x47 0x48 0x49 0x4a 0x4b 0x4c 0x4d 0x4e 0x4f
+            0x50
+        )
+    };
+    (@ $ty:ty, $ret:ty, $($op:tt)*) => {
+        [$(|this: &mut $ty| -> $ret { decode_op!($op, this) }),*]
+    };
+}
+
+/// Dispatches a single instruction to the matching method on `$this`.
+///
+/// Every arm evaluates to the method's return value, so all of the methods on
+/// one implementor must share a return type.
 macro_rules! decode_op {
     ($op:expr, $this:ident) => {
         match $op {
             0x00 => $this.ret(),
-            0x01 => { let idx = $this.read_byte(); $this.constant(idx); }
+            0x01 => { let idx = $this.read_byte(); $this.constant(idx) }
             0x02 => $this.print(),
             0x03 => $this.add(),
             0x04 => $this.sub(),
@@ -311,7 +693,7 @@ macro_rules! decode_op {
             0x0b => $this.lt(),
             0x0c => $this.jmp(),
             0x0d => $this.jze(),
-            0x0e => { $this.pop(); },
+            0x0e => $this.op_pop(),
             0x0f => $this.get_global(),
             0x10 => $this.set_global(),
             0x11 => $this.get_local(),
@@ -329,10 +711,38 @@ macro_rules! decode_op {
             0x23 => $this.set_upvalue(),
             0x24 => $this.closure(),
             0x25 => $this.define_global(),
-            0x26 => { let idx = $this.read_byte(); $this.class(idx); }
+            0x26 => { let idx = $this.read_byte(); $this.class(idx) }
             0x27 => $this.get_property(),
             0x28 => $this.set_property(),
             a @ 0x29..=0x31 => $this.invoke(a - 0x29),
+            0x32 => $this.halt(),
+            0x33 => $this.inherit(),
+            0x34 => { let idx = $this.read_byte(); $this.get_super(idx) }
+            0x35 => { let count = $this.read_byte(); $this.mixin(count) }
+            0x36 => {
+                let lo = $this.read_byte() as usize;
+                let mid = $this.read_byte() as usize;
+                let hi = $this.read_byte() as usize;
+                $this.constant_long(lo | (mid << 8) | (hi << 16))
+            }
+            0x37 => { let count = $this.read_byte(); $this.pop_n(count) }
+            0x38 => $this.jump_if_less(),
+            0x39 => $this.jump_if_not_less(),
+            0x3a => $this.jump_if_greater(),
+            0x3b => $this.jump_if_not_greater(),
+            a @ 0x3c..=0x44 => $this.tail_call(a - 0x3c),
+            0x45 => $this.loop_if_true(),
+            0x46 => $this.loop_if_less(),
+            0x47 => $this.loop_if_not_less(),
+            0x48 => $this.loop_if_greater(),
+            0x49 => $this.loop_if_not_greater(),
+            0x4a => $this.add_number(),
+            0x4b => $this.sub_number(),
+            0x4c => $this.mul_number(),
+            0x4d => $this.div_number(),
+            0x4e => $this.lt_number(),
+            0x4f => $this.gt_number(),
+            0x50 => $this.is(),
             _ => {
                 panic!("Unknown op {}", $op);
             }
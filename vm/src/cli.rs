@@ -0,0 +1,338 @@
+//! Command line parsing for the `vm` binary.
+//!
+//! Global flags come first, followed by a subcommand and its arguments. A
+//! bare filename in place of a subcommand is shorthand for `run`.
+
+use vm::config::Config;
+use vm::config::ConfigError;
+
+pub const USAGE: &str = "\
+Usage: vm [flags] [command] [args...]
+
+Commands:
+    <script> [args...]          Execute a script, or read one from stdin if given '-'.
+    run <file> [args...]        Execute a script or a compiled .loxc file.
+    -e <source> [args...]       Execute the given source code.
+    repl                        Start an interactive session. This is the default.
+    debug [-i | --cfg] <script> Show the compiled bytecode for a script, without executing.
+                                With -i or --interactive, step through its instructions instead.
+                                With --cfg, print its control flow graph in Graphviz dot format.
+    compile <script> [-o out] [--strip]
+                                Compile a script to a .loxc bytecode file. With --strip,
+                                leave out the names of local variables.
+    fmt [--check] <script>      Format a script in place, or print it formatted if given '-'.
+                                With --check, fail if the script is not formatted instead.
+    check <script>              Report errors and warnings in a script, without executing.
+    bench [-n runs] [-w warmup] <script>
+                                Time a script over several runs, after running it a few
+                                times to warm up, and report how long it took and how many
+                                instructions it executed. The defaults are 10 and 2.
+    test [paths...]             Run the .lox scripts in files and directories, checking their
+                                output against '// expect: ...' comments. The default is '.'.
+    dap                         Debug scripts from an editor, speaking the Debug Adapter
+                                Protocol over stdin and stdout.
+    doctor                      Check that this build runs scripts correctly.
+    help                        Show help like this.
+
+Flags:
+    --backend <name>     The backend to run scripts with: 'vm', or the experimental 'register', which
+                         runs source scripts without classes or closures. 'debug' lists its code too.
+    --trace              Print every executed instruction and the stack to stderr.
+    --gc-stress          Collect garbage on every allocation.
+    --gc-stats           Print garbage collector statistics to stderr on exit.
+    --stats              Print the number of instructions executed and objects allocated to stderr on exit.
+                         Builds with the 'stats' feature also count each opcode and pair of opcodes.
+    --coverage <file>    Write an lcov report of which lines of the script ran to a file on exit.
+    -O, --optimize       Fold constant expressions when compiling. 'debug' always shows unoptimized bytecode.
+    --warn               Print warnings about unused variables, unreachable code and conditions that are always false.
+    --deny-warnings      Like --warn, but don't run or compile a script that has warnings.
+    --error-format <f>   Print errors and warnings as 'human' readable text or as 'json', one object per line.
+    --gc-heap <bytes>    How large the heap may grow before the first collection.
+    --gc-growth <n>      How many times larger the heap may grow after each collection.
+    --max-heap <bytes>   The largest the heap may grow. Allocating past it is an error.
+    --stack-limit <n>    The maximum number of values on the stack.
+    --frame-limit <n>    The maximum depth of nested calls.
+    --max-instructions <n>
+                         The most instructions a script may execute.
+    --timeout <ms>       How long a script may run for.
+
+Each flag can also be set with an environment variable, such as RLOX_GC_HEAP=4096
+for --gc-heap. Flags take precedence over the environment.";
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum UsageError {
+    #[fail(display = "Unknown flag '{}'.", _0)]
+    UnknownFlag(String),
+    #[fail(display = "{}", _0)]
+    Config(#[cause] ConfigError),
+    #[fail(display = "Missing argument to {}.", _0)]
+    MissingArgument(String),
+    #[fail(display = "Unexpected argument '{}'.", _0)]
+    UnexpectedArgument(String),
+    #[fail(display = "Compiling from stdin requires -o.")]
+    StdinWithoutOutput,
+}
+
+impl From<ConfigError> for UsageError {
+    fn from(err: ConfigError) -> Self {
+        UsageError::Config(err)
+    }
+}
+
+type Result<T> = ::std::result::Result<T, UsageError>;
+
+/// Where the source of a script comes from.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// A file, which may be either source or compiled bytecode. "-" is stdin.
+    File(String),
+    Source(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Run { input: Input, args: Vec<String> },
+    Repl,
+    Debug { script: String, interactive: bool, cfg: bool },
+    Compile { script: String, output: Option<String>, strip: bool },
+    Fmt { script: String, check: bool },
+    Check { script: String },
+    Test { paths: Vec<String> },
+    Bench { script: String, runs: usize, warmup: usize },
+    Dap,
+    Doctor,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub config: Config,
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parses the arguments to the binary, not including its own name.
+    /// `config` provides the defaults that flags are applied on top of.
+    pub fn parse<I>(config: Config, args: I) -> Result<Cli>
+        where I: IntoIterator<Item = String>
+    {
+        let mut parser = Parser {
+            args: args.into_iter().collect::<Vec<_>>().into_iter(),
+        };
+        let config = parser.flags(config)?;
+        let command = parser.command()?;
+        Ok(Cli { config, command })
+    }
+}
+
+struct Parser {
+    args: ::std::vec::IntoIter<String>,
+}
+
+impl Parser {
+    fn flags(&mut self, mut config: Config) -> Result<Config> {
+        let is_flag = |a: &String| (a.starts_with("--") && a != "--help") || a == "-O";
+        while self.args.as_slice().first().map_or(false, is_flag) {
+            let flag = self.args.next().expect("flag to be present");
+            let flag = if flag == "-O" { "--optimize".to_owned() } else { flag };
+            let (flag, value) = match flag.find('=') {
+                Some(idx) => (flag[..idx].to_owned(), Some(flag[idx + 1..].to_owned())),
+                None => (flag, None),
+            };
+            let name = &flag[2..];
+            let value = match (Config::setting(name), value) {
+                (None, _) => return Err(UsageError::UnknownFlag(flag)),
+                (Some(_), Some(value)) => value,
+                (Some(true), None) => "1".to_owned(),
+                (Some(false), None) => self.required(&flag)?,
+            };
+            config.set(name, &value).map_err(|_| ConfigError::InvalidValue {
+                name: flag.clone(),
+                value,
+            })?;
+        }
+        Ok(config)
+    }
+
+    fn command(&mut self) -> Result<Command> {
+        let command = match self.args.next() {
+            Some(command) => command,
+            None => return Ok(Command::Repl),
+        };
+        let command = match &command[..] {
+            "help" | "-h" | "--help" => Command::Help,
+            "repl" => Command::Repl,
+            "run" => Command::Run {
+                input: Input::File(self.required("run")?),
+                args: self.rest(),
+            },
+            "-e" => Command::Run {
+                input: Input::Source(self.required("-e")?),
+                args: self.rest(),
+            },
+            "debug" => {
+                let (mut interactive, mut cfg) = (false, false);
+                let script = loop {
+                    let arg = self.required("debug")?;
+                    match &arg[..] {
+                        "-i" | "--interactive" => interactive = true,
+                        "--cfg" => cfg = true,
+                        _ => break arg,
+                    }
+                };
+                if interactive && cfg {
+                    return Err(UsageError::UnexpectedArgument("--cfg".into()));
+                }
+                Command::Debug { script, interactive, cfg }
+            },
+            "compile" => {
+                let script = self.required("compile")?;
+                let mut output = None;
+                let mut strip = false;
+                while let Some(arg) = self.args.next() {
+                    match &arg[..] {
+                        "-o" => output = Some(self.required("-o")?),
+                        "--strip" => strip = true,
+                        _ => return Err(UsageError::UnexpectedArgument(arg)),
+                    }
+                }
+                if script == "-" && output.is_none() {
+                    return Err(UsageError::StdinWithoutOutput);
+                }
+                Command::Compile { script, output, strip }
+            },
+            "fmt" => {
+                let script = self.required("fmt")?;
+                match &script[..] {
+                    "--check" => Command::Fmt { script: self.required("fmt")?, check: true },
+                    _ => Command::Fmt { script, check: false },
+                }
+            },
+            "check" => Command::Check { script: self.required("check")? },
+            "test" => return Ok(Command::Test { paths: self.rest() }),
+            "bench" => {
+                let (mut runs, mut warmup) = (10, 2);
+                loop {
+                    let arg = self.required("bench")?;
+                    match &arg[..] {
+                        "-n" => runs = self.count("-n", 1)?,
+                        "-w" => warmup = self.count("-w", 0)?,
+                        _ => break Command::Bench { script: arg, runs, warmup },
+                    }
+                }
+            },
+            "dap" => Command::Dap,
+            "doctor" => Command::Doctor,
+            _ => return Ok(Command::Run {
+                input: Input::File(command),
+                args: self.rest(),
+            }),
+        };
+        match self.args.next() {
+            Some(arg) => Err(UsageError::UnexpectedArgument(arg)),
+            None => Ok(command),
+        }
+    }
+
+    fn required(&mut self, what: &str) -> Result<String> {
+        self.args.next().ok_or_else(|| UsageError::MissingArgument(what.to_owned()))
+    }
+
+    /// Reads the value of `flag`, which must be a number of at least `min`.
+    fn count(&mut self, flag: &str, min: usize) -> Result<usize> {
+        let value = self.required(flag)?;
+        match value.parse() {
+            Ok(count) if count >= min => Ok(count),
+            _ => Err(UsageError::Config(ConfigError::InvalidValue { name: flag.to_owned(), value })),
+        }
+    }
+
+    fn rest(&mut self) -> Vec<String> {
+        self.args.by_ref().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::config::Backend;
+
+    fn parse(args: &[&str]) -> Result<Cli> {
+        Cli::parse(Config::default(), args.iter().map(|&a| a.to_owned()))
+    }
+
+    #[test]
+    fn script_arguments_are_not_flags() {
+        let cli = parse(&["--trace", "script.lox", "--gc-stats", "x"]).unwrap();
+        assert!(cli.config.trace);
+        assert!(!cli.config.gc_stats);
+        assert_eq!(cli.command, Command::Run {
+            input: Input::File("script.lox".into()),
+            args: vec!["--gc-stats".into(), "x".into()],
+        });
+    }
+
+    #[test]
+    fn flags_with_values() {
+        assert_eq!(parse(&["--backend", "vm"]).unwrap().config.backend, Backend::Vm);
+        assert_eq!(parse(&["--backend=vm", "repl"]).unwrap().command, Command::Repl);
+        assert_eq!(parse(&["--backend=register"]).unwrap().config.backend, Backend::Register);
+        assert_eq!(parse(&["--stack-limit", "16"]).unwrap().config.limits.max_stack, 16);
+        assert_eq!(parse(&["--max-heap=1024"]).unwrap().config.gc.max_heap, Some(1024));
+        assert!(parse(&["-O", "script.lox"]).unwrap().config.optimize);
+        assert!(parse(&["--deny-warnings", "check", "x.lox"]).unwrap().config.deny_warnings);
+        assert_eq!(parse(&["--backend=tree"]).unwrap_err(), UsageError::Config(ConfigError::InvalidValue {
+            name: "--backend".into(),
+            value: "tree".into(),
+        }));
+    }
+
+    #[test]
+    fn interactive_debug() {
+        assert_eq!(parse(&["debug", "a.lox"]).unwrap().command, Command::Debug { script: "a.lox".into(), interactive: false, cfg: false });
+        assert_eq!(parse(&["debug", "-i", "a.lox"]).unwrap().command, Command::Debug { script: "a.lox".into(), interactive: true, cfg: false });
+        assert_eq!(parse(&["debug", "--interactive", "a.lox"]).unwrap().command, Command::Debug { script: "a.lox".into(), interactive: true, cfg: false });
+        assert_eq!(parse(&["debug", "-i"]).unwrap_err(), UsageError::MissingArgument("debug".into()));
+        assert_eq!(parse(&["debug", "--cfg", "a.lox"]).unwrap().command, Command::Debug { script: "a.lox".into(), interactive: false, cfg: true });
+        assert_eq!(parse(&["debug", "-i", "--cfg", "a.lox"]).unwrap_err(), UsageError::UnexpectedArgument("--cfg".into()));
+        assert_eq!(parse(&["compile", "a.lox"]).unwrap().command, Command::Compile { script: "a.lox".into(), output: None, strip: false });
+        assert_eq!(parse(&["compile", "-", "-o", "a.loxc", "--strip"]).unwrap().command, Command::Compile {
+            script: "-".into(),
+            output: Some("a.loxc".into()),
+            strip: true,
+        });
+        assert_eq!(parse(&["compile", "-", "--strip"]).unwrap_err(), UsageError::StdinWithoutOutput);
+    }
+
+    #[test]
+    fn fmt_check() {
+        assert_eq!(parse(&["fmt", "a.lox"]).unwrap().command, Command::Fmt { script: "a.lox".into(), check: false });
+        assert_eq!(parse(&["fmt", "--check", "a.lox"]).unwrap().command, Command::Fmt { script: "a.lox".into(), check: true });
+    }
+
+    #[test]
+    fn bench_options() {
+        assert_eq!(parse(&["bench", "a.lox"]).unwrap().command, Command::Bench { script: "a.lox".into(), runs: 10, warmup: 2 });
+        assert_eq!(parse(&["bench", "-w", "0", "-n", "3", "a.lox"]).unwrap().command, Command::Bench {
+            script: "a.lox".into(),
+            runs: 3,
+            warmup: 0,
+        });
+        assert_eq!(parse(&["bench", "-n", "0", "a.lox"]).unwrap_err(), UsageError::Config(ConfigError::InvalidValue {
+            name: "-n".into(),
+            value: "0".into(),
+        }));
+        assert_eq!(parse(&["bench", "-n", "3"]).unwrap_err(), UsageError::MissingArgument("bench".into()));
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(parse(&["--nope"]).unwrap_err(), UsageError::UnknownFlag("--nope".into()));
+        assert_eq!(parse(&["run"]).unwrap_err(), UsageError::MissingArgument("run".into()));
+        assert_eq!(parse(&["check", "a", "b"]).unwrap_err(), UsageError::UnexpectedArgument("b".into()));
+        assert_eq!(parse(&["doctor", "x"]).unwrap_err(), UsageError::UnexpectedArgument("x".into()));
+        assert_eq!(parse(&["dap", "x"]).unwrap_err(), UsageError::UnexpectedArgument("x".into()));
+        assert_eq!(parse(&["compile", "-"]).unwrap_err(), UsageError::StdinWithoutOutput);
+        assert_eq!(parse(&["fmt", "--check"]).unwrap_err(), UsageError::MissingArgument("fmt".into()));
+    }
+}
@@ -1,15 +1,45 @@
-use chunk::{Chunk, Op};
+use chunk::{ChunkBuilder, Op};
+
+use std::collections::HashMap;
 
 use broom::Heap;
+use fnv::FnvBuildHasher;
 
 use gc::value::Value;
 use gc::object::{Object, LoxFunction, LoxFunctionBuilder};
+use fold;
 
 use parser::ast::*;
 
+/// Errors in programs that parse and resolve, but go past the limits of the
+/// bytecode.
+#[derive(Debug, Fail, PartialEq)]
+pub enum CompileError {
+    #[fail(display = "Too much code to jump over.")]
+    JumpTooLarge,
+    #[fail(display = "Loop body too large.")]
+    LoopTooLarge,
+    #[fail(display = "Too many local variables in function.")]
+    TooManyLocals,
+    #[fail(display = "Too many closure variables in function.")]
+    TooManyUpValues,
+    #[fail(display = "Too many constants in one chunk.")]
+    TooManyConstants,
+    #[fail(display = "Too many global variables.")]
+    TooManyGlobals,
+    #[fail(display = "Too many method lookups in one chunk.")]
+    TooManyCaches,
+}
+
 pub struct Compiler<'g> {
     heap: &'g mut Heap<Object>,
     states: Vec<CompileState>,
+    errors: Vec<CompileError>,
+    optimize: bool,
+    // Names of global variables, shared by every function in the program so
+    // that each name is only stored once.
+    names: Vec<String>,
+    name_slots: HashMap<String, u16, FnvBuildHasher>,
 }
 
 #[derive(Debug)]
@@ -26,6 +56,15 @@ struct UpValue {
     pub is_local: bool,
 }
 
+/// A loop being compiled.
+#[derive(Debug)]
+struct Loop {
+    /// The scope depth outside the loop, which `break` drops the locals
+    /// past.
+    depth: usize,
+    breaks: Vec<usize>,
+}
+
 #[derive(Debug)]
 struct CompileState {
     line: usize,
@@ -33,7 +72,7 @@ struct CompileState {
     upvalues: Vec<UpValue>,
     function: LoxFunctionBuilder,
     scope_depth: usize,
-    breaks: Vec<usize>,
+    loops: Vec<Loop>,
     method: bool,
 }
 
@@ -41,13 +80,17 @@ impl CompileState {
     fn new(method: bool, reserved: &str, function: LoxFunctionBuilder, scope_depth: usize) -> Self {
         // Reserve the first local
         let locals = vec![Local { name: reserved.into(), depth: 1, captured: false, reserved: true}];
+        let mut function = function;
+        if !reserved.is_empty() {
+            function.chunk_mut().begin_local(reserved, 0);
+        }
         CompileState {
             line: 1,
             locals,
             upvalues: Vec::new(),
             function,
             scope_depth,
-            breaks: Vec::new(),
+            loops: Vec::new(),
             method,
         }
     }
@@ -62,10 +105,10 @@ impl CompileState {
         None
     }
 
-    fn add_local(&mut self, var: &str, depth: usize) -> u8 {
+    fn add_local(&mut self, var: &str, depth: usize) -> Result<u8, CompileError> {
         let depth = self.scope_depth - depth;
         if self.locals.len() == ::std::u8::MAX as usize {
-            panic!("TOO MANY LOCAL VARIABLES");
+            return Err(CompileError::TooManyLocals);
         }
         self.locals.push(Local {
             name: var.into(),
@@ -75,36 +118,45 @@ impl CompileState {
         });
 
         let i = (self.locals.len() - 1) as u8;
+        self.function.chunk_mut().begin_local(var, i);
         debug!("resolved to new local #{}", i);
-        i
+        Ok(i)
     }
 
-    fn resolve_local(&mut self, var: &str) -> u8 {
+    /// Drops the most recent local without popping it, leaving its value as
+    /// a temporary on the stack.
+    fn forget_local(&mut self) {
+        self.locals.pop().expect("locals to be nonempty");
+        let slot = self.locals.len() as u8;
+        self.function.chunk_mut().end_local(slot);
+    }
+
+    fn resolve_local(&mut self, var: &str) -> Option<u8> {
         debug!("scope_depth: {}, resolve_local {}", self.scope_depth, var);
         debug!("scope_depth: {}, current locals {:?}", self.scope_depth, self.locals);
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name == var {
                 debug!("resolved {} to local #{}", var, i);
-                return i as u8;
+                return Some(i as u8);
             }
         }
-        panic!("unresolved local");
+        None
     }
 
-    fn add_upvalue(&mut self, index: u8, is_local: bool) -> u8 {
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, CompileError> {
         for (i, upval) in self.upvalues.iter().enumerate() {
             if upval.index == index && upval.is_local == is_local {
-                return i as u8;
+                return Ok(i as u8);
             }
         }
         if self.upvalues.len() == ::std::u8::MAX as usize {
-            panic!("TOO MANY UPVALUES");
+            return Err(CompileError::TooManyUpValues);
         }
         self.upvalues.push(UpValue {
             index,
             is_local,
         });
-        (self.upvalues.len() - 1) as u8
+        Ok((self.upvalues.len() - 1) as u8)
     }
 
     fn begin_scope(&mut self) {
@@ -112,22 +164,45 @@ impl CompileState {
     }
 
     fn end_scope(&mut self) {
-        let last = self.scope_depth;
         self.scope_depth -= 1;
-        let mut ops = Vec::new();
+        let depth = self.scope_depth;
+        self.emit_discard(depth);
+        let ended = self.locals.iter()
+            .enumerate()
+            .filter(|&(_, local)| local.depth > depth && !local.reserved)
+            .map(|(i, _)| i as u8)
+            .collect::<Vec<_>>();
         self.locals.retain(|local| {
-            if local.depth < last || local.reserved {
+            if local.depth <= depth || local.reserved {
                 return true;
             }
-            if local.captured {
-                ops.push(Op::CloseUpValue);
-            } else {
-                ops.push(Op::Pop);
-                debug!("end_scope: remove local {:?}", local);
-            }
+            debug!("end_scope: remove local {:?}", local);
             false
         });
-        ops.into_iter().rev().for_each(|op| self.emit(op));
+        for slot in ended {
+            self.function.chunk_mut().end_local(slot);
+        }
+    }
+
+    /// Emits the ops that drop the locals past `depth` off the stack,
+    /// closing the captured ones, but leaves them in scope.
+    fn emit_discard(&mut self, depth: usize) {
+        let captured = self.locals.iter()
+            .filter(|local| local.depth > depth && !local.reserved)
+            .map(|local| local.captured)
+            .collect::<Vec<_>>();
+        // Runs of locals that weren't captured are popped all at once.
+        let mut pops = 0;
+        for captured in captured.into_iter().rev() {
+            if !captured {
+                pops += 1;
+                continue;
+            }
+            self.emit_pops(pops);
+            pops = 0;
+            self.emit(Op::CloseUpValue);
+        }
+        self.emit_pops(pops);
     }
 
     // TODO: Unify this with Compiler
@@ -135,15 +210,33 @@ impl CompileState {
         self.function.chunk_mut().write(op, self.line);
     }
 
-    fn add_break(&mut self, jmp: usize) {
-        self.breaks.push(jmp);
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => (),
+            1 => self.emit(Op::Pop),
+            n => self.emit(Op::PopN(n)),
+        }
     }
 
-    // This shouldn't need to return the whole Vec.
-    fn breaks(&mut self) -> Vec<usize> {
-        let bs = self.breaks.clone();
-        self.breaks.clear();
-        bs
+    fn begin_loop(&mut self) {
+        let depth = self.scope_depth;
+        self.loops.push(Loop { depth, breaks: Vec::new() });
+    }
+
+    /// Leaves the innermost loop, returning the jumps of its breaks.
+    fn end_loop(&mut self) -> Vec<usize> {
+        self.loops.pop().expect("loops to be nonempty").breaks
+    }
+
+    /// Emits the ops that drop the locals declared inside the innermost
+    /// loop, which a `break` out of it needs before its jump.
+    fn emit_loop_discard(&mut self) {
+        let depth = self.loops.last().expect("break to be inside a loop").depth;
+        self.emit_discard(depth);
+    }
+
+    fn add_break(&mut self, jmp: usize) {
+        self.loops.last_mut().expect("break to be inside a loop").breaks.push(jmp);
     }
 }
 
@@ -152,21 +245,36 @@ impl<'g> Compiler<'g> {
         Compiler {
             heap,
             states: Vec::new(),
+            errors: Vec::new(),
+            optimize: false,
+            names: Vec::new(),
+            name_slots: HashMap::with_hasher(FnvBuildHasher::default()),
         }
     }
 
-    pub fn compile(mut self, stmts: &[Stmt]) -> LoxFunction {
+    /// Whether to fold constant expressions. This is off by default so that
+    /// the bytecode mirrors the source.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Compiles a script, returning its top-level function along with the
+    /// pool of global names that the global variable ops index into.
+    pub fn compile(mut self, stmts: &[Stmt]) -> Result<(LoxFunction, Vec<String>), Vec<CompileError>> {
         self.start_function(false, "<top>", 0, 0);
         for stmt in stmts {
             self.compile_stmt(stmt);
         }
-        self.end_function()
+        let function = self.end_function();
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        Ok((function, self.names))
     }
 
     fn compile_stmt(&mut self, stmt: &Stmt) {
-        let line = stmt.position().map(|pos| pos.line);
-        if let Some(line) = line {
-            self.state_mut().line = line;
+        if let Some(pos) = stmt.position() {
+            self.set_line(pos.line);
         }
         match *stmt {
             Stmt::Print(ref expr) => {
@@ -185,91 +293,161 @@ impl<'g> Compiler<'g> {
                 self.state_mut().end_scope();
             },
             Stmt::If(ref cond, ref then_clause, ref else_clause) => {
-                self.compile_expr(cond);
                 // Jump to the else clause if false
-                let else_jmp = self.emit_jze();
-                self.emit(Op::Pop); // condition
+                let (else_jmp, on_stack) = self.emit_condition(cond);
+                if on_stack {
+                    self.emit(Op::Pop); // condition
+                }
                 self.compile_stmt(&*then_clause);
                 let end_jmp = self.emit_jmp();
                 // Jump to just past the else clause from the then clause
                 self.patch_jmp(else_jmp);
-                self.emit(Op::Pop); // condition
+                if on_stack {
+                    self.emit(Op::Pop); // condition
+                }
                 if let &Some(ref else_clause) = else_clause {
                     self.compile_stmt(&*else_clause);
                 }
                 self.patch_jmp(end_jmp);
             },
             Stmt::While(ref cond, ref body) => {
+                // The condition is tested at the bottom of the loop, so that
+                // an iteration only jumps back to the top of the body rather
+                // than out to the condition and back in again.
+                let entry_jmp = self.emit_jmp();
                 let ip = self.ip(); // remember loop start
-                self.compile_expr(cond);
-                let end_jmp = self.emit_jze();
-                self.emit(Op::Pop); // condition
+                self.state_mut().begin_loop();
                 self.compile_stmt(body);
-                self.emit_loop(ip);
-                self.patch_jmp(end_jmp);
-                self.emit(Op::Pop); // condition
+                // The jump back covers the body too, so a body that is too
+                // large is reported by that jump rather than this one.
+                let errors = self.errors.len();
+                self.patch_jmp(entry_jmp);
+                self.errors.truncate(errors);
+                self.emit_loop_condition(cond, ip);
 
                 // Patch all breaks to end at `end_jmp`
-                for b in self.state_mut().breaks() {
+                for b in self.state_mut().end_loop() {
                     self.patch_jmp(b);
                 }
             },
+            Stmt::ForBody(ref for_body) => {
+                if for_body.captured {
+                    // The copy shadows the loop's variable for the body, and
+                    // is closed at the end of each iteration.
+                    let name = for_body.var.name();
+                    let slot = self.resolve_local(name);
+                    self.state_mut().begin_scope();
+                    self.emit(Op::GetLocal);
+                    self.emit_byte(slot);
+                    let copy = self.add_local(name, 0);
+                    self.compile_stmt(&for_body.body);
+                    self.emit(Op::GetLocal);
+                    self.emit_byte(copy);
+                    self.emit(Op::SetLocal);
+                    self.emit_byte(slot);
+                    self.emit(Op::Pop);
+                    self.state_mut().end_scope();
+                } else {
+                    self.compile_stmt(&for_body.body);
+                }
+                if let Some(ref increment) = for_body.increment {
+                    self.compile_expr(increment);
+                    self.emit(Op::Pop);
+                }
+            },
             Stmt::Function(ref f) => {
-                self.function_decl(f);
-                self.var_define(&f.var, None);
+                // A local is defined first so that the function can refer to
+                // itself. Its closure then ends up in the local's slot.
+                if let Scope::Local(_) = f.var.scope() {
+                    self.var_define(&f.var);
+                    self.function_decl(f);
+                } else {
+                    self.function_decl(f);
+                    self.var_define(&f.var);
+                }
             }
             Stmt::Return(ref expr) => {
                 self.emit_return(expr.as_ref());
             }
             Stmt::Class(ref class) => {
-                // Populate the stack with a closure per-method.
-                for method in &class.methods {
-                    // FIXME: This should somehow be aware of the enclosing class
-                    // so that we can prepend the classname in debugging.
-                    self.function_decl(method);
+                if let Some(ref superclass) = class.superclass {
+                    let slot = if let Scope::Local(_) = class.var.scope() {
+                        self.emit(Op::Nil);
+                        self.var_define(&class.var);
+                        Some(self.resolve_local(class.var.name()))
+                    } else {
+                        None
+                    };
+                    self.subclass(class, superclass, slot);
+                } else if let Scope::Local(_) = class.var.scope() {
+                    // As with functions, this lets the methods refer to the class.
+                    self.var_define(&class.var);
+                    self.class(class);
+                } else {
+                    self.class(class);
+                    // Attach the class to a variable.
+                    self.var_define(&class.var);
                 }
-                // Place the op_class to construct the class.
-                let idx = self.string_constant(class.var.name());
-                self.emit(Op::Class(idx));
-
-                let method_count = class.methods.len() as u8;
-                self.emit_byte(method_count);
-
-                // Attach the class to a variable.
-                self.var_define(&class.var, Some(idx));
             },
-            Stmt::Break => {
+            Stmt::Break(_) => {
+                self.state_mut().emit_loop_discard();
                 let jmp = self.emit_jmp();
                 self.state_mut().add_break(jmp);
             }
             Stmt::Var(ref var, ref init) => {
                 self.compile_expr(init);
-                self.var_define(var, None);
+                self.var_define(var);
             }
             // ref s => unimplemented!("{:?}", s),
         }
     }
 
     fn compile_expr(&mut self, expr: &Expr) {
+        // Operators are emitted after their operands, which may be on later
+        // lines, so the line is put back once each operand is compiled.
+        let line = self.set_line(expr.line());
+        self.compile_expr_kind(expr);
+        self.set_line(line);
+    }
+
+    fn compile_expr_kind(&mut self, expr: &Expr) {
+        if self.optimize {
+            if let ExprKind::Binary(_) | ExprKind::Unary(_) | ExprKind::Logical(_) | ExprKind::Grouping(_) = expr.node {
+                if let Some(lit) = fold::fold(expr) {
+                    self.emit_constant(&lit);
+                    return;
+                }
+            }
+        }
         match expr.node {
             ExprKind::Binary(ref binary) => {
                 self.compile_expr(&*binary.lhs);
                 self.compile_expr(&*binary.rhs);
 
+                let numbers = is_number(&binary.lhs) && is_number(&binary.rhs);
+                let (less, greater) = if numbers {
+                    (Op::LessNumber, Op::GreaterNumber)
+                } else {
+                    (Op::LessThan, Op::GreaterThan)
+                };
                 match binary.operator {
+                    BinaryOperator::Plus if numbers => self.emit(Op::AddNumber),
+                    BinaryOperator::Minus if numbers => self.emit(Op::SubtractNumber),
+                    BinaryOperator::Star if numbers => self.emit(Op::MultiplyNumber),
+                    BinaryOperator::Slash if numbers => self.emit(Op::DivideNumber),
                     BinaryOperator::Plus => self.emit(Op::Add),
                     BinaryOperator::Minus => self.emit(Op::Subtract),
                     BinaryOperator::Star => self.emit(Op::Multiply),
                     BinaryOperator::Slash => self.emit(Op::Divide),
                     BinaryOperator::Equal => self.emit(Op::Equal),
-                    BinaryOperator::GreaterThan => self.emit(Op::GreaterThan),
-                    BinaryOperator::LessThan => self.emit(Op::LessThan),
+                    BinaryOperator::GreaterThan => self.emit(greater),
+                    BinaryOperator::LessThan => self.emit(less),
                     BinaryOperator::GreaterThanEq => {
-                        self.emit(Op::LessThan);
+                        self.emit(less);
                         self.emit(Op::Not);
                     },
                     BinaryOperator::LessThanEq => {
-                        self.emit(Op::GreaterThan);
+                        self.emit(greater);
                         self.emit(Op::Not);
                     },
                     BinaryOperator::BangEq => {
@@ -278,6 +456,11 @@ impl<'g> Compiler<'g> {
                     }
                 }
             }
+            ExprKind::Is(ref value, ref class) => {
+                self.compile_expr(value);
+                self.compile_expr(class);
+                self.emit(Op::Is);
+            },
             ExprKind::Grouping(ref group) => self.compile_expr(group),
             ExprKind::Literal(ref lit) => self.emit_constant(lit),
             ExprKind::Unary(ref unary) => {
@@ -302,43 +485,19 @@ impl<'g> Compiler<'g> {
                 match var.scope() {
                     Scope::Global => self.set_global(var.name()),
                     Scope::Local(_) => {
-                        let idx = self.state_mut().resolve_local(var.name());
+                        let idx = self.resolve_local(var.name());
                         self.emit(Op::SetLocal);
                         self.emit_byte(idx);
                     },
                 }
             }
-            ExprKind::Call(ref call) => {
-                let arity = call.arguments.len();
-                if arity > 8 {
-                    panic!("Too many arguments.");
-                }
-
-                let invoke = if let ExprKind::Get(ref lhs, ref method) = call.callee.node {
-                    self.compile_expr(lhs);
-                    Some(method)
-                } else {
-                    self.compile_expr(&call.callee);
-                    None
-                };
-
-                for arg in call.arguments.iter() {
-                    self.compile_expr(arg);
-                }
-
-                if let Some(method) = invoke {
-                    self.emit(Op::Invoke(arity as u8));
-                    let idx = self.string_constant(method);
-                    self.emit_byte(idx);
-                } else {
-                    self.emit(Op::Call(arity as u8));
-                }
-            },
+            ExprKind::Call(ref call) => self.call(call, false),
             ExprKind::Get(ref lhs, ref prop) => {
                 self.compile_expr(lhs);
                 self.emit(Op::GetProperty);
                 let idx = self.string_constant(prop);
                 self.emit_byte(idx);
+                self.emit_cache();
             },
             ExprKind::Set(ref lhs, ref prop, ref rhs) => {
                 self.compile_expr(lhs);
@@ -361,10 +520,80 @@ impl<'g> Compiler<'g> {
                     self.var_get(var);
                 }
             },
-            ref e => unimplemented!("{:?}", e),
+            ExprKind::Class(ref class) => {
+                if let Some(ref superclass) = class.superclass {
+                    // The class needs a slot below `super` to end up in, but
+                    // once it is there it is just the value of the expression.
+                    self.emit(Op::Nil);
+                    let slot = self.add_local(ANONYMOUS_CLASS, 0);
+                    self.subclass(class, superclass, Some(slot));
+                    self.state_mut().forget_local();
+                } else {
+                    self.class(class);
+                }
+            },
+            ExprKind::Super(ref var, _, ref method) => {
+                if self.state_mut().method {
+                    self.emit(Op::GetLocal);
+                    self.emit_byte(0);
+                } else {
+                    let idx = self.resolve_upvalue("this");
+                    self.emit(Op::GetUpValue);
+                    self.emit_byte(idx);
+                }
+                self.var_get(var);
+                let idx = self.string_constant(method);
+                self.emit(Op::GetSuper(idx));
+            },
+            ExprKind::Function(ref decl) => self.function(ANONYMOUS_FUNCTION, expr.line(), &decl.borrow()),
         }
     }
 
+    /// Leaves a new class with all of its methods on the stack.
+    fn class(&mut self, class: &Class) {
+        // Populate the stack with a closure per-method.
+        for method in &class.methods {
+            // FIXME: This should somehow be aware of the enclosing class
+            // so that we can prepend the classname in debugging.
+            self.function_decl(method);
+        }
+        // Place the op_class to construct the class.
+        let idx = self.string_constant(class.var.name());
+        self.emit(Op::Class(idx));
+
+        let method_count = class.methods.len() as u8;
+        self.emit_byte(method_count);
+
+        if !class.mixins.is_empty() {
+            for mixin in &class.mixins {
+                self.var_get(mixin);
+            }
+            self.emit(Op::Mixin(class.mixins.len() as u8));
+        }
+    }
+
+    /// The superclass is held in a local named `super` while the methods are
+    /// created so that they can capture it. Since the class has to outlive
+    /// that scope, it is stored to `slot` once it has been created, or
+    /// defined as a global if there is no slot.
+    fn subclass(&mut self, class: &Class, superclass: &Variable, slot: Option<u8>) {
+        self.var_get(superclass);
+        self.state_mut().begin_scope();
+        self.add_local("super", 0);
+
+        self.class(class);
+        self.emit(Op::Inherit);
+        match slot {
+            Some(slot) => {
+                self.emit(Op::SetLocal);
+                self.emit_byte(slot);
+                self.emit(Op::Pop);
+            },
+            None => self.var_define(&class.var),
+        }
+        self.state_mut().end_scope();
+    }
+
     fn var_get(&mut self, var: &Variable) {
         if var.is_upvalue() {
             let idx = self.resolve_upvalue(var.name());
@@ -376,31 +605,29 @@ impl<'g> Compiler<'g> {
         match var.scope() {
             Scope::Global => {
                 self.emit(Op::GetGlobal);
-                let idx = self.string_constant(var.name());
-                self.emit_byte(idx);
+                let slot = self.global_name(var.name());
+                self.emit_u16(slot);
             },
             Scope::Local(_) => {
-                let idx = self.state_mut().resolve_local(var.name());
+                let idx = self.resolve_local(var.name());
                 self.emit(Op::GetLocal);
                 self.emit_byte(idx);
             },
         }
     }
 
-    fn var_define(&mut self, var: &Variable, constant: Option<u8>) {
+    fn var_define(&mut self, var: &Variable) {
         debug!("var_define {:?}", var);
         match var.scope() {
             Scope::Global => {
                 self.emit(Op::DefineGlobal);
-                let idx = constant.unwrap_or_else(|| {
-                    self.string_constant(var.name())
-                });
-                self.emit_byte(idx);
+                let slot = self.global_name(var.name());
+                self.emit_u16(slot);
             },
             Scope::Local(d) => {
                 // Declarations do not need to call have a SET_LOCAL instruction.
-                self.state_mut().add_local(var.name(), d);
-                self.state_mut().resolve_local(var.name());
+                self.add_local(var.name(), d);
+                self.resolve_local(var.name());
             },
         }
     }
@@ -425,20 +652,31 @@ impl<'g> Compiler<'g> {
 
     fn set_global(&mut self, name: &str) {
         self.emit(Op::SetGlobal);
-        let idx = {
-            let chunk = self.states.last_mut()
-                .unwrap()
-                .function
-                .chunk_mut();
-            chunk.string_constant(self.heap, name)
-        };
-        self.emit_byte(idx);
+        let slot = self.global_name(name);
+        self.emit_u16(slot);
+    }
+
+    fn global_name(&mut self, name: &str) -> u16 {
+        if let Some(&slot) = self.name_slots.get(name) {
+            return slot;
+        }
+        if self.names.len() > u16::max_value() as usize {
+            self.errors.push(CompileError::TooManyGlobals);
+            return 0;
+        }
+        let slot = self.names.len() as u16;
+        self.names.push(name.to_owned());
+        self.name_slots.insert(name.to_owned(), slot);
+        slot
     }
 
     fn function_decl(&mut self, f: &FunctionStmt) {
-        let name = f.var.name();
-        let decl = f.declaration.borrow();
+        self.function(f.var.name(), f.pos.line, &f.declaration.borrow());
+    }
 
+    /// Leaves a closure for a function declared on `line` on the stack.
+    fn function(&mut self, name: &str, line: usize, decl: &FunctionDecl) {
+        let line = self.set_line(line);
         let parameters = &decl.parameters;
         let body = &decl.body;
         let arity = parameters.len() as u8;
@@ -448,8 +686,8 @@ impl<'g> Compiler<'g> {
         // Now that we've pushed to states we are in a new scope.
 
         for p in parameters {
-            self.state_mut().add_local(p.name(), 0);
-            self.state_mut().resolve_local(p.name());
+            self.add_local(p.name(), 0);
+            self.resolve_local(p.name());
         }
         for stmt in body {
             self.compile_stmt(stmt);
@@ -464,7 +702,9 @@ impl<'g> Compiler<'g> {
         let handle = self.heap.insert(Object::LoxFunction(function)).into_handle();
         let value = Value::object(handle);
         let idx = self.chunk_mut().add_constant(value);
+        let idx = self.constant_index(idx);
         self.emit(Op::Closure);
+        let idx = self.short_index(idx);
         self.emit_byte(idx);
         for upvalue in upvalues {
             self.emit_byte(if upvalue.is_local {
@@ -474,6 +714,7 @@ impl<'g> Compiler<'g> {
             });
             self.emit_byte(upvalue.index);
         }
+        self.set_line(line);
     }
 
     /// Walk outwards through enclosing scopes to find and mark locals as captured.
@@ -483,27 +724,29 @@ impl<'g> Compiler<'g> {
     fn resolve_upvalue(&mut self, name: &str) -> u8 {
         debug!("resolve upvalue {:?}", name);
         let end = self.states.len() - 1;
-        let (scope, mut index) =
+        let found =
             self.states[..end].iter_mut()
                 .enumerate()
                 .rev()
                 .filter_map(|(i, enclosing)| {
                     enclosing.capture_local(name).map(|local| (i, local))
                 })
-                .next()
-                .expect("upvalue marked during resolution but could not be found");
+                .next();
+        let (scope, index) = match found {
+            Some(found) => found,
+            None => return self.unresolved(name),
+        };
 
         // Add the local as an upvalue to the inner scope and update the index
-        index = self.states[scope + 1].add_upvalue(index, true);
-        if scope >= self.states.len() - 2 {
-            // If we are only one scope up from the current function, we are done.
-            return index;
-        }
+        let mut res = self.states[scope + 1].add_upvalue(index, true);
         // Walk forwards and propagate the upvalue up to our current function
         for enclosing in &mut self.states[scope + 2..] {
-            index = enclosing.add_upvalue(index, false);
+            res = res.and_then(|index| enclosing.add_upvalue(index, false));
         }
-        index
+        res.unwrap_or_else(|err| {
+            self.errors.push(err);
+            0
+        })
     }
 
     // FIXME: what is this "scope" var? it's either 1 or 0
@@ -512,20 +755,54 @@ impl<'g> Compiler<'g> {
     fn start_function(&mut self, method: bool, name: &str, arity: u8, scope: usize) {
         let next_function = LoxFunctionBuilder::new(name, arity);
         let reserved_var = if method { "this" } else { "" };
-        let state = CompileState::new(method, reserved_var, next_function, scope);
+        let mut state = CompileState::new(method, reserved_var, next_function, scope);
+        state.line = self.states.last().map_or(1, |s| s.line);
         self.states.push(state);
     }
 
     fn end_function(&mut self) -> LoxFunction {
         self.emit_return(None);
         let mut state = self.states.pop().expect("states to be nonempty");
+        state.function.set_upvalue_count(state.upvalues.len());
+        // TODO: This should be removed instead of copied so that it cannot be used again.
+        let function = state.function.build();
         #[cfg(feature="dis")]
         {
-            self.dissassemble(state.function.chunk_mut());
+            self.dissassemble(function.chunk());
+        }
+        function
+    }
+
+    /// Compiles a call, as a tail call if `tail` is set and it isn't a method
+    /// invocation.
+    fn call(&mut self, call: &Call, tail: bool) {
+        let arity = call.arguments.len();
+        if arity > 8 {
+            panic!("Too many arguments.");
+        }
+
+        let invoke = if let ExprKind::Get(ref lhs, ref method) = call.callee.node {
+            self.compile_expr(lhs);
+            Some(method)
+        } else {
+            self.compile_expr(&call.callee);
+            None
+        };
+
+        for arg in call.arguments.iter() {
+            self.compile_expr(arg);
+        }
+
+        if let Some(method) = invoke {
+            self.emit(Op::Invoke(arity as u8));
+            let idx = self.string_constant(method);
+            self.emit_byte(idx);
+            self.emit_cache();
+        } else if tail {
+            self.emit(Op::TailCall(arity as u8));
+        } else {
+            self.emit(Op::Call(arity as u8));
         }
-        state.function.set_upvalue_count(state.upvalues.len());
-        // TODO: This should be removed instead of copied so that it cannot be used again.
-        state.function.build()
     }
 
     fn emit_return(&mut self, retval: Option<&Expr>) {
@@ -535,18 +812,32 @@ impl<'g> Compiler<'g> {
             self.emit(Op::GetLocal);
             self.emit_byte(0);
         } else if let Some(ref expr) = retval {
-            self.compile_expr(expr);
+            match expr.node {
+                // The script's frame can't be reused, since it halts rather
+                // than returning.
+                ExprKind::Call(ref call) if self.states.len() > 1 => {
+                    let line = self.set_line(expr.line());
+                    self.call(call, true);
+                    self.set_line(line);
+                },
+                _ => self.compile_expr(expr),
+            }
         } else {
             self.emit(Op::Nil);
         }
-        self.emit(Op::Return);
+        if self.states.len() == 1 {
+            // The script has no caller to return to, so it stops the VM instead.
+            self.emit(Op::Halt);
+        } else {
+            self.emit(Op::Return);
+        }
     }
 
     #[cfg(feature="dis")]
-    fn dissassemble(&self, chunk: &Chunk) {
+    fn dissassemble(&self, chunk: &::chunk::Chunk) {
         use debug::Disassembler;
 
-        let dis = Disassembler::new(chunk, &self.heap);
+        let dis = Disassembler::new(chunk, &self.heap, &self.names);
         dis.disassemble();
     }
 
@@ -554,7 +845,7 @@ impl<'g> Compiler<'g> {
         self.states.last_mut().expect("states to be nonempty")
     }
 
-    fn chunk_mut(&mut self) -> &mut Chunk {
+    fn chunk_mut(&mut self) -> &mut ChunkBuilder {
         self.states.last_mut()
             .expect("states to be nonempty")
             .function
@@ -567,6 +858,16 @@ impl<'g> Compiler<'g> {
             .line
     }
 
+    /// Attributes the code emitted from now on to `line`, returning the
+    /// previous line. Synthesized nodes have no line and leave it alone.
+    fn set_line(&mut self, line: usize) -> usize {
+        let previous = self.line();
+        if line != 0 {
+            self.state_mut().line = line;
+        }
+        previous
+    }
+
     fn emit_constant(&mut self, lit: &Literal) {
         match *lit {
             Literal::Nil => self.emit(Op::Nil),
@@ -578,14 +879,31 @@ impl<'g> Compiler<'g> {
                     let chunk = self.states.last_mut().unwrap().function.chunk_mut();
                     chunk.string_constant(self.heap, s)
                 };
-                self.emit(Op::Constant(idx));
+                let idx = self.constant_index(idx);
+                self.emit_load_constant(idx);
             }
         }
     }
 
+    /// Loads the constant at `idx`, using the long form of the op if its
+    /// index doesn't fit in a byte.
+    fn emit_load_constant(&mut self, idx: usize) {
+        if idx <= u8::max_value() as usize {
+            self.emit(Op::Constant(idx as u8));
+        } else {
+            self.emit(Op::ConstantLong(idx as u32));
+        }
+    }
+
+    /// A string constant for the operand of an op, such as a property name,
+    /// which only has room for a single byte index.
     fn string_constant(&mut self, s: &str) -> u8 {
-        let chunk = self.states.last_mut().unwrap().function.chunk_mut();
-        chunk.string_constant(self.heap, s)
+        let idx = {
+            let chunk = self.states.last_mut().unwrap().function.chunk_mut();
+            chunk.string_constant(self.heap, s)
+        };
+        let idx = self.constant_index(idx);
+        self.short_index(idx)
     }
 
     #[cfg(feature = "op-immediate")]
@@ -602,7 +920,8 @@ impl<'g> Compiler<'g> {
             let chunk = self.states.last_mut().unwrap().function.chunk_mut();
             chunk.add_constant(Value::float(n))
         };
-        self.emit(Op::Constant(idx));
+        let idx = self.constant_index(idx);
+        self.emit_load_constant(idx);
     }
 
     // FIXME: The high-level global ops should have this in their repr, or
@@ -611,32 +930,119 @@ impl<'g> Compiler<'g> {
         self.chunk_mut().write_byte(byte);
     }
 
+    fn emit_u16(&mut self, val: u16) {
+        self.emit_byte((val & 0xff) as u8);
+        self.emit_byte((val >> 8) as u8);
+    }
+
+    /// Compiles `cond` and a jump that is taken if it is false, returning
+    /// the jump to patch and whether the condition is left on the stack.
+    ///
+    /// A comparison of two operands is fused with the jump, which pops them
+    /// both and so leaves nothing to pop on either branch.
+    fn emit_condition(&mut self, cond: &Expr) -> (usize, bool) {
+        let folds = self.optimize && fold::fold(cond).is_some();
+        if let ExprKind::Binary(ref binary) = cond.node {
+            let op = match binary.operator {
+                BinaryOperator::LessThan => Some(Op::JumpIfNotLess),
+                BinaryOperator::GreaterThan => Some(Op::JumpIfNotGreater),
+                // These are the negations of the opposite comparisons.
+                BinaryOperator::GreaterThanEq => Some(Op::JumpIfLess),
+                BinaryOperator::LessThanEq => Some(Op::JumpIfGreater),
+                _ => None,
+            };
+            if let (Some(op), false) = (op, folds) {
+                let line = self.set_line(cond.line());
+                self.compile_expr(&*binary.lhs);
+                self.compile_expr(&*binary.rhs);
+                let jmp = self.emit_jump(op);
+                self.set_line(line);
+                return (jmp, false);
+            }
+        }
+        self.compile_expr(cond);
+        (self.emit_jze(), true)
+    }
+
+    /// Emits the test at the bottom of a loop, which jumps back to `ip` while
+    /// `cond` holds. As in `emit_condition`, comparisons are fused into the
+    /// jump.
+    fn emit_loop_condition(&mut self, cond: &Expr, ip: usize) {
+        if self.optimize {
+            if let Some(lit) = fold::fold(cond) {
+                // A constant condition doesn't need testing at all.
+                if fold::truthy(&lit) {
+                    self.emit_loop(Op::Loop, ip);
+                }
+                return;
+            }
+        }
+        if let ExprKind::Binary(ref binary) = cond.node {
+            let op = match binary.operator {
+                BinaryOperator::LessThan => Some(Op::LoopIfLess),
+                BinaryOperator::GreaterThan => Some(Op::LoopIfGreater),
+                BinaryOperator::GreaterThanEq => Some(Op::LoopIfNotLess),
+                BinaryOperator::LessThanEq => Some(Op::LoopIfNotGreater),
+                _ => None,
+            };
+            if let Some(op) = op {
+                let line = self.set_line(cond.line());
+                self.compile_expr(&*binary.lhs);
+                self.compile_expr(&*binary.rhs);
+                self.emit_loop(op, ip);
+                self.set_line(line);
+                return;
+            }
+        }
+        self.compile_expr(cond);
+        self.emit_loop(Op::LoopIfTrue, ip);
+    }
+
     fn emit_jze(&mut self) -> usize {
-        let line = self.line();
-        let chunk = self.chunk_mut();
-        chunk.write(Op::JumpIfFalse, line);
-        chunk.write_byte(0xff);
-        chunk.write_byte(0xff);
-        chunk.len() - 2
+        self.emit_jump(Op::JumpIfFalse)
     }
 
     fn emit_jmp(&mut self) -> usize {
+        self.emit_jump(Op::Jump)
+    }
+
+    /// Emits the index of a new inline cache for the method lookup op that
+    /// was just emitted.
+    fn emit_cache(&mut self) {
+        let cache = match self.chunk_mut().add_cache() {
+            Some(cache) => cache,
+            None => {
+                self.errors.push(CompileError::TooManyCaches);
+                0
+            },
+        };
+        self.emit_byte((cache & 0xff) as u8);
+        self.emit_byte((cache >> 8) as u8);
+    }
+
+    /// Emits a jump with a placeholder offset, returning where the offset is
+    /// so that it can be patched.
+    fn emit_jump(&mut self, op: Op) -> usize {
         let line = self.line();
         let chunk = self.chunk_mut();
-        chunk.write(Op::Jump, line);
+        chunk.write(op, line);
         chunk.write_byte(0xff);
         chunk.write_byte(0xff);
         chunk.len() - 2
     }
 
-    fn emit_loop(&mut self, ip: usize) {
+    /// Emits `op` to jump back to `ip`, by an offset from the end of the
+    /// instruction.
+    fn emit_loop(&mut self, op: Op, ip: usize) {
+        let sub = self.ip() - ip + 3; // 3 bytes for the instruction itself
+        if sub > u16::max_value() as usize {
+            self.errors.push(CompileError::LoopTooLarge);
+        }
         let line = self.line();
         let chunk = self.chunk_mut();
-        let sub = chunk.len() - ip + 3; // 3 bytes for the instruction itself
-
         let lo = (sub & 0xff) as u8;
         let hi = ((sub >> 8) & 0xff) as u8;
-        chunk.write(Op::Loop, line);
+        chunk.write(op, line);
         chunk.write_byte(lo);
         chunk.write_byte(hi);
     }
@@ -646,8 +1052,14 @@ impl<'g> Compiler<'g> {
         self.chunk_mut().len()
     }
 
+    /// Points the jump whose operand is at `idx` to the current ip. Jumps
+    /// are relative to the end of their instruction, so that a chunk's code
+    /// doesn't depend on where it starts.
     fn patch_jmp(&mut self, idx: usize) {
-        let jmp = self.ip();
+        let jmp = self.ip() - (idx + 2);
+        if jmp > u16::max_value() as usize {
+            self.errors.push(CompileError::JumpTooLarge);
+        }
         let lo = (jmp & 0xff) as u8;
         let hi = ((jmp >> 8) & 0xff) as u8;
         self.chunk_mut().write_byte_at(idx, lo);
@@ -658,4 +1070,66 @@ impl<'g> Compiler<'g> {
         let line = self.line();
         self.chunk_mut().write(op, line);
     }
+
+    /// Adds a local to the current function. Past the limit, this reports an
+    /// error and returns a slot that is never used, since the bytecode won't
+    /// be either.
+    fn add_local(&mut self, var: &str, depth: usize) -> u8 {
+        match self.state_mut().add_local(var, depth) {
+            Ok(slot) => slot,
+            Err(err) => {
+                self.errors.push(err);
+                0
+            },
+        }
+    }
+
+    fn resolve_local(&mut self, var: &str) -> u8 {
+        match self.state_mut().resolve_local(var) {
+            Some(slot) => slot,
+            None => self.unresolved(var),
+        }
+    }
+
+    /// Handles a variable that the resolver found but the compiler didn't,
+    /// which only happens once there are too many locals to add another.
+    fn unresolved(&self, var: &str) -> u8 {
+        assert!(!self.errors.is_empty(), "could not find the variable '{}'", var);
+        0
+    }
+
+    /// The index of a constant that was added to the chunk, or a placeholder
+    /// once the chunk is full, which is reported as an error.
+    fn constant_index(&mut self, idx: Option<usize>) -> usize {
+        idx.unwrap_or_else(|| {
+            self.errors.push(CompileError::TooManyConstants);
+            0
+        })
+    }
+
+    /// Narrows a constant index for an op that only has a single byte for it.
+    fn short_index(&mut self, idx: usize) -> u8 {
+        if idx > u8::max_value() as usize {
+            self.errors.push(CompileError::TooManyConstants);
+        }
+        idx as u8
+    }
+}
+
+
+/// Whether `expr` can only evaluate to a number, if it doesn't fail. The
+/// arithmetic ops other than `+` fail on anything else, and `+` fails when
+/// only one of its operands is a number.
+fn is_number(expr: &Expr) -> bool {
+    match expr.node {
+        ExprKind::Literal(Literal::Number(_)) => true,
+        ExprKind::Grouping(ref group) => is_number(group),
+        ExprKind::Unary(ref unary) => unary.operator == UnaryOperator::Minus,
+        ExprKind::Binary(ref binary) => match binary.operator {
+            BinaryOperator::Minus | BinaryOperator::Star | BinaryOperator::Slash => true,
+            BinaryOperator::Plus => is_number(&binary.lhs) || is_number(&binary.rhs),
+            _ => false,
+        },
+        _ => false,
+    }
 }
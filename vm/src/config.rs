@@ -0,0 +1,250 @@
+//! Settings for running scripts, resolved from the environment and then from
+//! command line flags.
+//!
+//! Every setting has a name like `gc-heap`, which is set by the `--gc-heap`
+//! flag or the `RLOX_GC_HEAP` environment variable. Flags take precedence.
+
+use std::env;
+use std::time::Duration;
+
+use vm::{GcConfig, Limits, VM};
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum ConfigError {
+    #[fail(display = "Invalid value '{}' for {}.", value, name)]
+    InvalidValue {
+        name: String,
+        value: String,
+    },
+}
+
+type Result<T> = ::std::result::Result<T, ConfigError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Vm,
+    /// The experimental register machine, which only runs part of the
+    /// language.
+    Register,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Vm
+    }
+}
+
+/// How errors and warnings are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    Human,
+    /// One JSON object per line, for tools that run scripts.
+    Json,
+}
+
+impl Default for ErrorFormat {
+    fn default() -> Self {
+        ErrorFormat::Human
+    }
+}
+
+/// The names of every setting, and whether it is a boolean flag.
+const SETTINGS: &[(&str, bool)] = &[
+    ("backend", false),
+    ("trace", true),
+    ("gc-stress", true),
+    ("gc-stats", true),
+    ("stats", true),
+    ("coverage", false),
+    ("optimize", true),
+    ("warn", true),
+    ("deny-warnings", true),
+    ("error-format", false),
+    ("gc-heap", false),
+    ("gc-growth", false),
+    ("max-heap", false),
+    ("stack-limit", false),
+    ("frame-limit", false),
+    ("max-instructions", false),
+    ("timeout", false),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub backend: Backend,
+    /// Print every executed instruction along with the stack.
+    pub trace: bool,
+    /// Collect garbage on every allocation.
+    pub gc_stress: bool,
+    /// Print garbage collector statistics on exit.
+    pub gc_stats: bool,
+    /// Print how many instructions were executed and objects allocated on
+    /// exit.
+    pub stats: bool,
+    /// Write an lcov report of the lines that ran to this file on exit.
+    pub coverage: Option<String>,
+    /// Fold constant expressions when compiling scripts.
+    pub optimize: bool,
+    /// Print warnings about scripts when compiling them.
+    pub warn: bool,
+    /// Refuse to run scripts that have warnings.
+    pub deny_warnings: bool,
+    pub error_format: ErrorFormat,
+    pub gc: GcConfig,
+    pub limits: Limits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: Backend::default(),
+            trace: false,
+            gc_stress: false,
+            gc_stats: false,
+            stats: false,
+            coverage: None,
+            optimize: false,
+            warn: false,
+            deny_warnings: false,
+            error_format: ErrorFormat::default(),
+            gc: GcConfig::default(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Config {
+    /// The default settings, overridden by any `RLOX_*` environment variables.
+    pub fn from_env() -> Result<Config> {
+        Config::default().with_vars(|name| env::var(name).ok())
+    }
+
+    /// Overrides settings using the `RLOX_*` variables that `var` looks up.
+    pub fn with_vars<F>(mut self, var: F) -> Result<Config>
+        where F: Fn(&str) -> Option<String>
+    {
+        for &(name, _) in SETTINGS {
+            let var_name = format!("RLOX_{}", name.replace('-', "_").to_uppercase());
+            if let Some(value) = var(&var_name) {
+                self.set(name, &value).map_err(|_| ConfigError::InvalidValue {
+                    name: var_name,
+                    value,
+                })?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Returns true if `name` is a setting, and if so whether it is a flag
+    /// that doesn't need a value.
+    pub fn setting(name: &str) -> Option<bool> {
+        SETTINGS.iter().find(|s| s.0 == name).map(|s| s.1)
+    }
+
+    /// Parses `value` into the setting `name`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let invalid = || ConfigError::InvalidValue {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        };
+        let flag = || match value {
+            "1" | "true" => Ok(true),
+            "0" | "false" | "" => Ok(false),
+            _ => Err(invalid()),
+        };
+        match name {
+            "backend" => self.backend = match value {
+                "vm" => Backend::Vm,
+                "register" => Backend::Register,
+                _ => return Err(invalid()),
+            },
+            "trace" => self.trace = flag()?,
+            "gc-stress" => self.gc_stress = flag()?,
+            "gc-stats" => self.gc_stats = flag()?,
+            "stats" => self.stats = flag()?,
+            "coverage" => self.coverage = Some(value.to_owned()),
+            "optimize" => self.optimize = flag()?,
+            "warn" => self.warn = flag()?,
+            "deny-warnings" => self.deny_warnings = flag()?,
+            "error-format" => self.error_format = match value {
+                "human" => ErrorFormat::Human,
+                "json" => ErrorFormat::Json,
+                _ => return Err(invalid()),
+            },
+            "gc-heap" => self.gc.initial_heap = value.parse().map_err(|_| invalid())?,
+            "gc-growth" => match value.parse() {
+                Ok(n) if n > 1 => self.gc.growth_factor = n,
+                _ => return Err(invalid()),
+            },
+            "max-heap" => self.gc.max_heap = Some(value.parse().map_err(|_| invalid())?),
+            "stack-limit" => match value.parse() {
+                Ok(n) if n > 0 => self.limits.max_stack = n,
+                _ => return Err(invalid()),
+            },
+            "frame-limit" => match value.parse() {
+                Ok(n) if n > 0 => self.limits.max_frames = n,
+                _ => return Err(invalid()),
+            },
+            "max-instructions" => self.limits.max_instructions = Some(value.parse().map_err(|_| invalid())?),
+            "timeout" => {
+                let millis = value.parse().map_err(|_| invalid())?;
+                self.limits.timeout = Some(Duration::from_millis(millis));
+            },
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    /// Creates a VM that uses these settings.
+    pub fn vm(&self) -> VM {
+        let mut vm = VM::with_limits(self.limits);
+        vm.set_trace(self.trace);
+        vm.set_gc_stress(self.gc_stress);
+        vm.set_gc_config(self.gc);
+        vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<Config> {
+        Config::default().with_vars(|name| {
+            vars.iter().find(|v| v.0 == name).map(|v| v.1.to_owned())
+        })
+    }
+
+    #[test]
+    fn env_vars() {
+        let config = from_vars(&[
+            ("RLOX_TRACE", "1"),
+            ("RLOX_GC_HEAP", "4096"),
+            ("RLOX_MAX_HEAP", "65536"),
+            ("RLOX_STACK_LIMIT", "64"),
+            ("RLOX_BACKEND", "vm"),
+            ("RLOX_ERROR_FORMAT", "json"),
+            ("RLOX_MAX_INSTRUCTIONS", "1000"),
+            ("RLOX_TIMEOUT", "250"),
+            ("RLOX_COVERAGE", "lcov.info"),
+        ]).unwrap();
+        assert!(config.trace);
+        assert!(!config.gc_stress);
+        assert_eq!(config.gc.initial_heap, 4096);
+        assert_eq!(config.gc.max_heap, Some(65536));
+        assert_eq!(config.limits.max_stack, 64);
+        assert_eq!(config.error_format, ErrorFormat::Json);
+        assert_eq!(config.limits.max_instructions, Some(1000));
+        assert_eq!(config.limits.timeout, Some(Duration::from_millis(250)));
+        assert_eq!(config.coverage, Some("lcov.info".to_owned()));
+    }
+
+    #[test]
+    fn invalid_env_var() {
+        let err = from_vars(&[("RLOX_STACK_LIMIT", "0")]).unwrap_err();
+        assert_eq!(err, ConfigError::InvalidValue {
+            name: "RLOX_STACK_LIMIT".into(),
+            value: "0".into(),
+        });
+    }
+}
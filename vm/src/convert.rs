@@ -0,0 +1,177 @@
+//! Converting values between Rust and Lox.
+//!
+//! Numbers, booleans and nil convert into a `Value` directly. Anything that
+//! has to be allocated, such as a string, goes in as an `Arg` instead, which
+//! the VM only allocates once it can keep the string alive. Going the other
+//! way, `FromLox` reads a Rust value out of a `Value` using the VM that owns
+//! it, and `FromArgs` does the same for all the arguments to a native:
+//!
+//! ```ignore
+//! let sum = f64::try_from(lox.eval("1 + 2")?)?;
+//! let name = String::from_lox(lox.eval("\"Ada\"")?, lox.vm())?;
+//!
+//! fn greet(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+//!     let (greeting, times): (String, f64) = FromArgs::from_args(&args[1..], vm)?;
+//!     ...
+//! }
+//! ```
+
+use std::convert::TryFrom;
+
+use gc::object::Object;
+use gc::value::{Value, Variant};
+use native::type_name;
+use vm::{RuntimeError, VM};
+
+#[derive(Debug, Clone, Fail, PartialEq)]
+pub enum ConversionError {
+    #[fail(display = "Expected {} but got {}", expected, found)]
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[fail(display = "Expected {} arguments but got {}", expected, found)]
+    WrongCount {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl From<ConversionError> for RuntimeError {
+    fn from(err: ConversionError) -> Self {
+        match err {
+            ConversionError::WrongCount { expected, found } => RuntimeError::ArityMismatch(expected as u8, found as u8),
+            err => RuntimeError::BadConversion(err),
+        }
+    }
+}
+
+type Result<T> = ::std::result::Result<T, ConversionError>;
+
+fn wrong_type(expected: &'static str, found: &'static str) -> ConversionError {
+    ConversionError::WrongType { expected, found }
+}
+
+/// The type of a value that can be told without looking at the heap.
+fn unboxed_type(value: Value) -> &'static str {
+    match value.decode() {
+        Variant::Float(_) => "number",
+        Variant::True | Variant::False => "bool",
+        Variant::Nil => "nil",
+        Variant::Obj(_) => "object",
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<f64> {
+        match value.decode() {
+            Variant::Float(n) => Ok(n),
+            _ => Err(wrong_type("number", unboxed_type(value))),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<bool> {
+        match value.decode() {
+            Variant::True => Ok(true),
+            Variant::False => Ok(false),
+            _ => Err(wrong_type("bool", unboxed_type(value))),
+        }
+    }
+}
+
+/// A Rust type that can be read out of a Lox value.
+pub trait FromLox: Sized {
+    fn from_lox(value: Value, vm: &VM) -> Result<Self>;
+}
+
+impl FromLox for Value {
+    fn from_lox(value: Value, _: &VM) -> Result<Value> {
+        Ok(value)
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value, vm: &VM) -> Result<f64> {
+        f64::try_from(value).map_err(|_| wrong_type("number", type_name(vm, value)))
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(value: Value, vm: &VM) -> Result<bool> {
+        bool::try_from(value).map_err(|_| wrong_type("bool", type_name(vm, value)))
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value, vm: &VM) -> Result<String> {
+        match vm.object(value) {
+            Some(&Object::String(ref s)) => Ok(s.clone()),
+            _ => Err(wrong_type("string", type_name(vm, value))),
+        }
+    }
+}
+
+impl FromLox for () {
+    fn from_lox(value: Value, vm: &VM) -> Result<()> {
+        match value.decode() {
+            Variant::Nil => Ok(()),
+            _ => Err(wrong_type("nil", type_name(vm, value))),
+        }
+    }
+}
+
+/// Nil is `None`, and anything else has to convert to `T`.
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value, vm: &VM) -> Result<Option<T>> {
+        match value.decode() {
+            Variant::Nil => Ok(None),
+            _ => T::from_lox(value, vm).map(Some),
+        }
+    }
+}
+
+/// The arguments to a native as Rust values, either as a tuple with one
+/// element per argument or as a `Vec` of any number of them.
+pub trait FromArgs: Sized {
+    fn from_args(args: &[Value], vm: &VM) -> Result<Self>;
+}
+
+impl<T: FromLox> FromArgs for Vec<T> {
+    fn from_args(args: &[Value], vm: &VM) -> Result<Vec<T>> {
+        args.iter().map(|&arg| T::from_lox(arg, vm)).collect()
+    }
+}
+
+impl FromArgs for () {
+    fn from_args(args: &[Value], _: &VM) -> Result<()> {
+        match args.len() {
+            0 => Ok(()),
+            found => Err(ConversionError::WrongCount { expected: 0, found }),
+        }
+    }
+}
+
+macro_rules! impl_from_args {
+    ($count:expr, $($name:ident),+) => {
+        impl<$($name: FromLox),+> FromArgs for ($($name,)+) {
+            fn from_args(args: &[Value], vm: &VM) -> Result<Self> {
+                if args.len() != $count {
+                    return Err(ConversionError::WrongCount { expected: $count, found: args.len() });
+                }
+                let mut args = args.iter();
+                Ok(($($name::from_lox(*args.next().expect("argument to be present"), vm)?,)+))
+            }
+        }
+    }
+}
+
+impl_from_args!(1, A);
+impl_from_args!(2, A, B);
+impl_from_args!(3, A, B, C);
+impl_from_args!(4, A, B, C, D);
@@ -0,0 +1,80 @@
+//! Line coverage, which records which lines of a script ran so that they can
+//! be reported in the lcov format that coverage tools read.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use program::Program;
+use vm::{Debugger, Result, VM};
+
+/// Counts how many times each line of a script starts running, which is
+/// whenever a call moves onto it from another line. Clones share the counts,
+/// so one can be given to a VM with `set_debugger` and another kept to
+/// report on afterwards.
+#[derive(Clone, Default)]
+pub struct Coverage {
+    hits: Rc<RefCell<BTreeMap<usize, u64>>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// How many times `line` started running.
+    pub fn hits(&self, line: usize) -> u64 {
+        self.hits.borrow().get(&line).cloned().unwrap_or(0)
+    }
+
+    /// Writes an lcov report on `program`, which was read from `path`. Every
+    /// line that has code is listed, including the ones that never ran.
+    pub fn write_lcov(&self, program: &Program, path: &str, out: &mut dyn Write) -> io::Result<()> {
+        let lines = program.lines();
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", path)?;
+        for &line in &lines {
+            writeln!(out, "DA:{},{}", line, self.hits(line))?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(out, "LH:{}", lines.iter().filter(|&&line| self.hits(line) > 0).count())?;
+        writeln!(out, "end_of_record")
+    }
+}
+
+impl Debugger for Coverage {
+    fn line(&mut self, _vm: &VM, line: usize) -> Result<()> {
+        *self.hits.borrow_mut().entry(line).or_insert(0) += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_lines_that_ran() {
+        let source = "var a = 1;\nif (a > 2) {\n  print \"big\";\n} else {\n  print \"small\";\n}\n\nfun f() {\n  return 1;\n}\nf();\nf();\n";
+        let program = Program::compile(source).unwrap();
+        let coverage = Coverage::new();
+        let mut vm = VM::new();
+        vm.set_stdout(io::sink());
+        vm.set_debugger(coverage.clone());
+        vm.run_program(&program).unwrap();
+
+        assert_eq!(coverage.hits(3), 0);
+        assert_eq!(coverage.hits(5), 1);
+        assert_eq!(coverage.hits(9), 2);
+        let mut report = Vec::new();
+        coverage.write_lcov(&program, "a.lox", &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(&lines[..2], ["TN:", "SF:a.lox"]);
+        assert!(lines.contains(&"DA:3,0"), "{}", report);
+        assert!(lines.contains(&"DA:9,2"), "{}", report);
+        assert!(!lines.iter().any(|line| line.starts_with("DA:7,")), "{}", report);
+        assert_eq!(&lines[lines.len() - 1..], ["end_of_record"]);
+    }
+}
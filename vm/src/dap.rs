@@ -0,0 +1,536 @@
+//! A debug adapter, which lets editors that speak the Debug Adapter Protocol
+//! set breakpoints in a script, step through it and look at its variables.
+//!
+//! The editor launches the adapter, sends it requests over one stream and
+//! reads responses and events from another, normally stdin and stdout. Once
+//! the editor has set the breakpoints and finished configuring the script,
+//! the adapter runs it and sends what it prints as `output` events.
+//!
+//! Everything happens on one thread, so the adapter only answers requests
+//! while the script is stopped or once it has finished. There is therefore
+//! no way to pause a script that is running.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+
+use config::Config;
+use gc::object::Object;
+use gc::value::{Value, Variant};
+use json::Json;
+use program::Program;
+use vm::{Debugger, RuntimeError, VM};
+
+/// Scripts only have the one thread.
+const THREAD_ID: usize = 1;
+
+/// The variables reference for the globals. The locals of each frame come
+/// after it.
+const GLOBALS: usize = 1;
+
+/// Debugs the scripts the editor asks for until it disconnects.
+pub fn serve<R, W>(input: R, output: W, config: &Config) -> io::Result<()>
+    where R: BufRead + 'static,
+          W: Write + 'static,
+{
+    let conn = Rc::new(RefCell::new(Connection {
+        output: Box::new(output),
+        seq: 1,
+    }));
+    let adapter = Rc::new(RefCell::new(Adapter {
+        input: Box::new(input),
+        conn: conn.clone(),
+        optimize: config.optimize,
+        program: None,
+        breakpoints: HashSet::new(),
+        step: Step::Continue,
+        disconnected: false,
+    }));
+
+    loop {
+        let request = match adapter.borrow_mut().read()? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        match adapter.borrow_mut().handle(&request, None)? {
+            Action::Run => break,
+            Action::Disconnect => return Ok(()),
+            _ => {},
+        }
+    }
+
+    let program = adapter.borrow_mut().program.take().expect("a program to be launched");
+    let mut vm = config.vm();
+    vm.set_stdout(Output {
+        conn: conn.clone(),
+        line: Vec::new(),
+    });
+    vm.set_debugger(Stopper(adapter.clone()));
+    let res = vm.run_program(&program);
+    vm.flush()?;
+
+    let mut adapter = adapter.borrow_mut();
+    if adapter.disconnected {
+        return Ok(());
+    }
+    let exit_code = match res {
+        Ok(value) => match value.decode() {
+            Variant::Float(n) => n as i32,
+            _ => 0,
+        },
+        Err(traceback) => {
+            conn.borrow_mut().output("stderr", format!("[error]: {}\n", traceback))?;
+            70
+        },
+    };
+    conn.borrow_mut().event("exited", Json::object(vec![("exitCode", (exit_code as f64).into())]))?;
+    conn.borrow_mut().event("terminated", Json::object(vec![]))?;
+    // The editor can still ask about threads and the like until it hangs up.
+    while let Some(request) = adapter.read()? {
+        if let Action::Disconnect = adapter.handle(&request, None)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Writes messages to the editor, numbering each one.
+struct Connection {
+    output: Box<dyn Write>,
+    seq: usize,
+}
+
+impl Connection {
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        fields.insert(0, ("seq", self.seq.into()));
+        self.seq += 1;
+        let body = Json::object(fields).to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ])
+    }
+
+    fn output(&mut self, category: &str, output: String) -> io::Result<()> {
+        self.event("output", Json::object(vec![
+            ("category", category.into()),
+            ("output", output.into()),
+        ]))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", true.into()),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("body", body),
+        ])
+    }
+
+    fn fail(&mut self, request: &Json, message: String) -> io::Result<()> {
+        self.send(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", false.into()),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("message", message.into()),
+        ])
+    }
+}
+
+/// Sends what the script prints to the editor, a line at a time.
+struct Output {
+    conn: Rc<RefCell<Connection>>,
+    line: Vec<u8>,
+}
+
+impl Output {
+    fn send(&mut self, len: usize) -> io::Result<()> {
+        let text = String::from_utf8_lossy(&self.line[..len]).into_owned();
+        self.line.drain(..len);
+        self.conn.borrow_mut().output("stdout", text)
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if let Some(end) = self.line.iter().rposition(|&b| b == b'\n') {
+            self.send(end + 1)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            let len = self.line.len();
+            self.send(len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where to stop next, other than at a breakpoint.
+#[derive(Clone, Copy)]
+enum Step {
+    Continue,
+    /// At the next line to run, in any frame.
+    In,
+    /// At the next line in a frame at most this deep.
+    Over(usize),
+    /// At the next line in a frame shallower than this.
+    Out(usize),
+}
+
+/// What to do after handling a request.
+enum Action {
+    Wait,
+    /// Run the script, or carry on running it.
+    Run,
+    Disconnect,
+}
+
+struct Adapter {
+    input: Box<dyn BufRead>,
+    conn: Rc<RefCell<Connection>>,
+    optimize: bool,
+    /// The script to debug, along with the path it was read from.
+    program: Option<Program>,
+    breakpoints: HashSet<usize>,
+    step: Step,
+    disconnected: bool,
+}
+
+impl Adapter {
+    /// Reads the next request, or returns `None` once the editor has closed
+    /// the stream.
+    fn read(&mut self) -> io::Result<Option<Json>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                if length.is_some() {
+                    break;
+                }
+                continue;
+            }
+            let mut parts = header.splitn(2, ':');
+            if let (Some("Content-Length"), Some(value)) = (parts.next(), parts.next()) {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let mut body = vec![0; length.expect("length to be read")];
+        self.input.read_exact(&mut body)?;
+        let body = String::from_utf8(body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Json::parse(&body)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+
+    /// Answers `request`. `vm` is the VM running the script, if the script
+    /// is stopped.
+    fn handle(&mut self, request: &Json, vm: Option<&VM>) -> io::Result<Action> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let args = request.get("arguments").cloned().unwrap_or_else(|| Json::object(vec![]));
+        let mut conn = self.conn.borrow_mut();
+        match (command, vm) {
+            ("initialize", _) => {
+                conn.respond(request, Json::object(vec![("supportsConfigurationDoneRequest", true.into())]))?;
+                conn.event("initialized", Json::object(vec![]))?;
+            },
+            ("launch", _) => {
+                let path = args.get("program").and_then(Json::as_str).unwrap_or("");
+                match load(path, self.optimize) {
+                    Ok(program) => {
+                        self.program = Some(program);
+                        let stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+                        self.step = if stop_on_entry { Step::In } else { Step::Continue };
+                        conn.respond(request, Json::object(vec![]))?;
+                    },
+                    Err(message) => conn.fail(request, message)?,
+                }
+            },
+            ("setBreakpoints", _) => {
+                let lines = args.get("breakpoints").and_then(Json::as_array).unwrap_or(&[])
+                    .iter()
+                    .flat_map(|breakpoint| breakpoint.get("line").and_then(Json::as_f64))
+                    .map(|line| line as usize)
+                    .collect::<Vec<_>>();
+                self.breakpoints = lines.iter().cloned().collect();
+                let breakpoints = lines.into_iter()
+                    .map(|line| Json::object(vec![("verified", true.into()), ("line", line.into())]))
+                    .collect::<Vec<_>>();
+                conn.respond(request, Json::object(vec![("breakpoints", breakpoints.into())]))?;
+            },
+            ("configurationDone", _) if self.program.is_some() => {
+                conn.respond(request, Json::object(vec![]))?;
+                return Ok(Action::Run);
+            },
+            ("threads", _) => {
+                let thread = Json::object(vec![("id", THREAD_ID.into()), ("name", "main".into())]);
+                conn.respond(request, Json::object(vec![("threads", vec![thread].into())]))?;
+            },
+            ("stackTrace", Some(vm)) => {
+                let frames = vm.backtrace().into_iter().enumerate()
+                    .map(|(id, (line, name))| Json::object(vec![
+                        ("id", id.into()),
+                        ("name", name.into()),
+                        ("line", line.into()),
+                        ("column", 1usize.into()),
+                    ]))
+                    .collect::<Vec<_>>();
+                let total = frames.len();
+                conn.respond(request, Json::object(vec![
+                    ("stackFrames", frames.into()),
+                    ("totalFrames", total.into()),
+                ]))?;
+            },
+            ("scopes", Some(_)) => {
+                let frame = args.get("frameId").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+                let scope = |name: &str, reference: usize| Json::object(vec![
+                    ("name", name.into()),
+                    ("variablesReference", reference.into()),
+                    ("expensive", false.into()),
+                ]);
+                let scopes = vec![scope("Locals", GLOBALS + 1 + frame), scope("Globals", GLOBALS)];
+                conn.respond(request, Json::object(vec![("scopes", scopes.into())]))?;
+            },
+            ("variables", Some(vm)) => {
+                let variables = match args.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as usize {
+                    0 => Vec::new(),
+                    GLOBALS => vm.defined_globals().into_iter()
+                        .filter(|&(_, value)| vm.object(value).map_or(true, |o| o.as_native().is_none()))
+                        .collect(),
+                    reference if reference - GLOBALS - 1 < vm.depth() => vm.frame_locals(reference - GLOBALS - 1),
+                    _ => Vec::new(),
+                };
+                let variables = variables.into_iter()
+                    // The callee's own slot has no name.
+                    .filter(|&(ref name, _)| !name.is_empty())
+                    .map(|(name, value)| Json::object(vec![
+                        ("name", name.into()),
+                        ("value", describe(vm, value).into()),
+                        ("variablesReference", 0usize.into()),
+                    ]))
+                    .collect::<Vec<_>>();
+                conn.respond(request, Json::object(vec![("variables", variables.into())]))?;
+            },
+            ("continue", Some(vm)) | ("next", Some(vm)) | ("stepIn", Some(vm)) | ("stepOut", Some(vm)) => {
+                self.step = match command {
+                    "next" => Step::Over(vm.depth()),
+                    "stepIn" => Step::In,
+                    "stepOut" => Step::Out(vm.depth()),
+                    _ => Step::Continue,
+                };
+                conn.respond(request, Json::object(vec![("allThreadsContinued", true.into())]))?;
+                return Ok(Action::Run);
+            },
+            ("disconnect", _) | ("terminate", _) => {
+                self.disconnected = true;
+                conn.respond(request, Json::object(vec![]))?;
+                return Ok(Action::Disconnect);
+            },
+            _ if vm.is_none() && command != "configurationDone" => {
+                conn.fail(request, format!("'{}' needs a stopped script", command))?;
+            },
+            _ => conn.fail(request, format!("Unsupported request '{}'", command))?,
+        }
+        Ok(Action::Wait)
+    }
+
+    /// Whether to stop at `line`, and why.
+    fn should_stop(&self, vm: &VM, line: usize) -> Option<&'static str> {
+        if self.breakpoints.contains(&line) {
+            return Some("breakpoint");
+        }
+        let depth = vm.depth();
+        match self.step {
+            Step::In => Some("step"),
+            Step::Over(max) if depth <= max => Some("step"),
+            Step::Out(max) if depth < max => Some("step"),
+            _ => None,
+        }
+    }
+
+    /// Tells the editor the script has stopped, then answers its requests
+    /// until it resumes the script. Returns false if the editor disconnected.
+    fn stop(&mut self, vm: &VM, reason: &str) -> io::Result<bool> {
+        self.step = Step::Continue;
+        self.conn.borrow_mut().event("stopped", Json::object(vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]))?;
+        while let Some(request) = self.read()? {
+            match self.handle(&request, Some(vm))? {
+                Action::Run => return Ok(true),
+                Action::Disconnect => return Ok(false),
+                Action::Wait => {},
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Stops the script for the editor.
+struct Stopper(Rc<RefCell<Adapter>>);
+
+impl Debugger for Stopper {
+    fn line(&mut self, vm: &VM, line: usize) -> Result<(), RuntimeError> {
+        let mut adapter = self.0.borrow_mut();
+        let reason = match adapter.should_stop(vm, line) {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+        match adapter.stop(vm, reason) {
+            Ok(true) => Ok(()),
+            // There is nobody left to debug the script for.
+            Ok(false) | Err(_) => {
+                adapter.disconnected = true;
+                Err(RuntimeError::Cancelled)
+            },
+        }
+    }
+}
+
+fn load(path: &str, optimize: bool) -> Result<Program, String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| format!("Could not read '{}': {}", path, err))?;
+    Program::compile_with(&source, optimize).map_err(|errors| {
+        errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n")
+    })
+}
+
+/// Shows strings quoted, so that they can be told apart from other values.
+fn describe(vm: &VM, value: Value) -> String {
+    match vm.object(value) {
+        Some(&Object::String(ref s)) => format!("{:?}", s),
+        _ => vm.stringify(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn requests(requests: &[(&str, Json)]) -> Cursor<Vec<u8>> {
+        let mut input = Vec::new();
+        for (seq, &(command, ref arguments)) in requests.iter().enumerate() {
+            let request = Json::object(vec![
+                ("seq", (seq + 1).into()),
+                ("type", "request".into()),
+                ("command", command.into()),
+                ("arguments", arguments.clone()),
+            ]).to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", request.len(), request).unwrap();
+        }
+        Cursor::new(input)
+    }
+
+    /// Splits the adapter's output into its messages.
+    fn messages(output: &[u8]) -> Vec<Json> {
+        let mut output = ::std::str::from_utf8(output).unwrap();
+        let mut messages = Vec::new();
+        while !output.is_empty() {
+            let header_end = output.find("\r\n\r\n").unwrap();
+            let length = output[..header_end]["Content-Length: ".len()..].parse::<usize>().unwrap();
+            let body_start = header_end + 4;
+            messages.push(Json::parse(&output[body_start..body_start + length]).unwrap());
+            output = &output[body_start + length..];
+        }
+        messages
+    }
+
+    fn field<'a>(message: &'a Json, path: &[&str]) -> &'a Json {
+        path.iter().fold(message, |json, name| json.get(name).unwrap_or_else(|| panic!("no {} in {}", name, message)))
+    }
+
+    #[test]
+    fn debugs_a_script() {
+        let path = env::temp_dir().join(format!("rlox_dap_{}.lox", ::std::process::id()));
+        fs::write(&path, "var a = \"one\";\nfun f(x) {\n    var y = x + 1;\n    return y;\n}\nprint f(2);\nprint a;\n").unwrap();
+        let path = path.to_str().unwrap().to_owned();
+        let input = requests(&[
+            ("initialize", Json::object(vec![("adapterID", "rlox".into())])),
+            ("launch", Json::object(vec![("program", path.as_str().into())])),
+            ("setBreakpoints", Json::object(vec![
+                ("source", Json::object(vec![("path", path.as_str().into())])),
+                ("breakpoints", vec![Json::object(vec![("line", 4usize.into())])].into()),
+            ])),
+            ("configurationDone", Json::object(vec![])),
+            ("stackTrace", Json::object(vec![("threadId", 1usize.into())])),
+            ("variables", Json::object(vec![("variablesReference", 2usize.into())])),
+            ("variables", Json::object(vec![("variablesReference", 1usize.into())])),
+            ("next", Json::object(vec![("threadId", 1usize.into())])),
+            ("stackTrace", Json::object(vec![("threadId", 1usize.into())])),
+            ("continue", Json::object(vec![("threadId", 1usize.into())])),
+            ("disconnect", Json::object(vec![])),
+        ]);
+        let output = Sink::default();
+        serve(input, output.clone(), &Config::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let messages = messages(&output.0.borrow());
+        let summary = messages.iter().map(|message| match field(message, &["type"]).as_str() {
+            Some("event") => format!("event {}", field(message, &["event"]).as_str().unwrap()),
+            _ => format!("{} {}", field(message, &["command"]).as_str().unwrap(), field(message, &["success"])),
+        }).collect::<Vec<_>>();
+        assert_eq!(summary, [
+            "initialize true", "event initialized", "launch true", "setBreakpoints true", "configurationDone true",
+            "event stopped", "stackTrace true", "variables true", "variables true", "next true",
+            "event output", "event stopped", "stackTrace true", "continue true", "event output",
+            "event exited", "event terminated", "disconnect true",
+        ]);
+
+        assert_eq!(field(&messages[5], &["body", "reason"]).as_str(), Some("breakpoint"));
+        let frames = field(&messages[6], &["body", "stackFrames"]).as_array().unwrap();
+        let frames = frames.iter()
+            .map(|frame| (field(frame, &["name"]).as_str().unwrap(), field(frame, &["line"]).as_f64().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(frames, [("f", 4.0), ("<top>", 6.0)]);
+
+        let variables = |message: &Json| field(message, &["body", "variables"]).as_array().unwrap().iter()
+            .map(|v| format!("{}={}", field(v, &["name"]).as_str().unwrap(), field(v, &["value"]).as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(variables(&messages[7]), ["x=2", "y=3"]);
+        assert_eq!(variables(&messages[8]), ["a=\"one\"", "f=<fn f>"]);
+
+        assert_eq!(field(&messages[10], &["body", "output"]).as_str(), Some("3\n"));
+        assert_eq!(field(&messages[11], &["body", "reason"]).as_str(), Some("step"));
+        let line = field(&messages[12], &["body", "stackFrames"]).as_array().unwrap()[0].get("line").cloned();
+        assert_eq!(line, Some(Json::Number(7.0)));
+        assert_eq!(field(&messages[14], &["body", "output"]).as_str(), Some("one\n"));
+        assert_eq!(field(&messages[15], &["body", "exitCode"]).as_f64(), Some(0.0));
+    }
+}
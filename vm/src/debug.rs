@@ -1,108 +1,259 @@
+//! Disassembles bytecode, either into `Instruction`s for tools that render
+//! it themselves or into the text that `--disassemble` and `--trace` print.
+
 use chunk::Chunk;
-use gc::object::Object;
+use gc::object::{LoxFunction, Object};
 use gc::value::Value;
 
 use broom::Heap;
 
+use std::fmt;
+use std::io::{self, Write};
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    /// Where the instruction starts in its chunk.
+    pub offset: usize,
+    /// The name of the opcode, like `OP_ADD`.
+    pub op: &'static str,
+    pub operands: Vec<Operand>,
+    /// The source line the instruction was compiled from.
+    pub line: usize,
+}
+
+/// An operand of an instruction, along with what it refers to where that is
+/// known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// An index into the chunk's constants, and the constant.
+    Constant(usize, String),
+    /// A global's slot and name.
+    Global(usize, String),
+    /// A local's slot and name, or `?` if the chunk has no name for it.
+    Local(u8, String),
+    /// An index into the enclosing closure's upvalues.
+    UpValue(u8),
+    /// A variable captured by a new closure, either a local of the enclosing
+    /// function or one of its upvalues.
+    Capture { local: bool, index: u8 },
+    /// The offset a jump or loop goes to.
+    Jump(usize),
+    /// The number of arguments, methods or values an instruction works on.
+    Count(u8),
+    /// A value embedded in the instruction itself.
+    Immediate(String),
+    /// The slot of an inline cache.
+    Cache(u16),
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.op)?;
+        for operand in &self.operands {
+            write!(f, "\t{}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Constant(index, ref value) => write!(f, "{}\t{}", index, value),
+            Operand::Global(slot, ref name) => write!(f, "{}\t{}", slot, name),
+            Operand::Local(slot, ref name) => write!(f, "{}\t{}", slot, name),
+            Operand::UpValue(index) => write!(f, "{}", index),
+            Operand::Capture { local: true, index } => write!(f, "L{}", index),
+            Operand::Capture { local: false, index } => write!(f, "U{}", index),
+            Operand::Jump(target) => write!(f, "-> {}", target),
+            Operand::Count(count) => write!(f, "{}", count),
+            Operand::Immediate(ref value) => write!(f, "{}", value),
+            Operand::Cache(slot) => write!(f, "cache {}", slot),
+        }
+    }
+}
+
+/// The instructions of one function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub name: String,
+    pub instructions: Vec<Instruction>,
+}
+
+/// Disassembles `function` followed by every function nested within it,
+/// writing them to stderr.
+pub fn disassemble_program(function: &LoxFunction, heap: &Heap<Object>, names: &[String]) {
+    for function in functions(function, heap) {
+        Disassembler::new(function.chunk(), heap, names).disassemble();
+    }
+}
+
+/// Decodes `function` followed by every function nested within it.
+pub fn listings(function: &LoxFunction, heap: &Heap<Object>, names: &[String]) -> Vec<Listing> {
+    functions(function, heap).into_iter()
+        .map(|function| Listing {
+            name: function.chunk().name().to_owned(),
+            instructions: Disassembler::new(function.chunk(), heap, names).instructions().collect(),
+        })
+        .collect()
+}
+
+fn functions<'h>(function: &'h LoxFunction, heap: &'h Heap<Object>) -> Vec<&'h LoxFunction> {
+    let mut found = vec![function];
+    for constant in function.chunk().constants() {
+        let nested = constant.as_object()
+            .and_then(|o| heap.get(o))
+            .and_then(|o| o.as_function());
+        if let Some(nested) = nested {
+            found.extend(functions(nested, heap));
+        }
+    }
+    found
+}
+
+/// Decodes the instructions of a chunk, or writes them out as text.
 pub struct Disassembler<'c> {
     offset: usize,
-    line: usize,
     chunk: &'c Chunk,
     heap: &'c Heap<Object>,
+    names: &'c [String],
+    out: Box<dyn Write + 'c>,
 }
 
+type Decoded = (&'static str, Vec<Operand>);
+
 impl<'c> Disassembler<'c> {
-    pub fn new(chunk: &'c Chunk, heap: &'c Heap<Object>) -> Self {
+    pub fn new(chunk: &'c Chunk, heap: &'c Heap<Object>, names: &'c [String]) -> Self {
         Disassembler {
             offset: 0,
-            line: 0,
             chunk,
             heap,
+            names,
+            out: Box::new(io::stderr()),
         }
     }
 
+    /// Writes the disassembly to `out` instead of stderr.
+    pub fn output(mut self, out: &'c mut dyn Write) -> Self {
+        self.out = Box::new(out);
+        self
+    }
+
+    /// Decodes the instructions of the chunk, in order.
+    pub fn instructions(self) -> Instructions<'c> {
+        Instructions(self)
+    }
+
+    /// Writes every instruction in the chunk. A disassembly that can't be
+    /// written isn't worth stopping the program for, so errors are ignored.
     pub fn disassemble(mut self) {
-        let bytes = self.chunk.as_ref();
-        eprintln!("== {} ==", self.chunk.name());
-        while self.offset < bytes.len() {
-            self.disassemble_instruction();
+        let _ = writeln!(self.out, "== {} ==", self.chunk.name());
+        let mut line = 0;
+        while let Some(instruction) = self.decode() {
+            self.write(&instruction, &mut line);
         }
     }
 
-    fn disassemble_instruction(&mut self) {
-        print!("{:04} ", self.offset);
-        let line = self.chunk.line(self.offset);
-        if self.line == line {
-            print!("   | ");
-        } else {
-            self.line = line;
-            print!("{:4} ", line);
+    /// Writes only the instruction starting at `offset`.
+    pub fn disassemble_at(mut self, offset: usize) {
+        self.offset = offset;
+        if let Some(instruction) = self.decode() {
+            self.write(&instruction, &mut 0);
         }
-        let inst = self.read_byte();
-        decode_op!(inst, self);
     }
 
-    fn constant(&mut self, idx: u8) {
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprintln!("OP_CONSTANT\t{}\t{:?}", idx, val);
-    }
-
-    fn ret(&self) { eprintln!("OP_RETURN"); }
-    fn print(&self) { eprintln!("OP_PRINT"); }
-    fn add(&self) { eprintln!("OP_ADD"); }
-    fn sub(&self) { eprintln!("OP_SUB"); }
-    fn mul(&self) { eprintln!("OP_MUL"); }
-    fn div(&self) { eprintln!("OP_DIV"); }
-    fn neg(&self) { eprintln!("OP_NEG"); }
-    fn not(&self) { eprintln!("OP_NOT"); }
-    fn eq(&self) { eprintln!("OP_EQ"); }
-    fn gt(&self) { eprintln!("OP_GT"); }
-    fn lt(&self) { eprintln!("OP_LT"); }
-    fn pop(&self) { eprintln!("OP_POP"); }
-
-    fn jmp(&mut self) {
-        let offset = self.offset - 1;
-        let ip = self.read_u16();
-        eprintln!("OP_JUMP\t{} -> {}", offset, ip);
+    fn decode(&mut self) -> Option<Instruction> {
+        if self.offset >= self.chunk.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let inst = self.read_byte();
+        let (op, operands) = decode_op!(inst, self);
+        Some(Instruction {
+            offset,
+            op,
+            operands,
+            line: self.chunk.line(offset),
+        })
     }
 
-    fn jze(&mut self) {
-        let offset = self.offset - 1;
-        let ip = self.read_u16();
-        eprintln!("OP_JUMP_IF_FALSE\t{} -> {}", offset, ip);
+    /// Writes an instruction, with its line unless it's the same as the
+    /// `last_line` written.
+    fn write(&mut self, instruction: &Instruction, last_line: &mut usize) {
+        let _ = if *last_line == instruction.line {
+            writeln!(self.out, "{:04}    | {}", instruction.offset, instruction)
+        } else {
+            *last_line = instruction.line;
+            writeln!(self.out, "{:04} {:4} {}", instruction.offset, instruction.line, instruction)
+        };
     }
 
-    fn op_loop(&mut self) {
-        let sub = self.read_u16() as usize;
-        eprintln!("OP_LOOP\t{} -> {}", self.offset, self.offset - sub);
+    fn constant(&mut self, idx: u8) -> Decoded {
+        ("OP_CONSTANT", vec![self.constant_operand(idx as usize)])
     }
 
-    fn get_global(&mut self) {
-        let val = self.read_constant();
-        eprintln!("OP_GET_GLOBAL\t{}", val.with_heap(self.heap));
+    fn constant_long(&mut self, idx: usize) -> Decoded {
+        ("OP_CONSTANT_LONG", vec![self.constant_operand(idx)])
     }
 
-    fn set_global(&mut self) {
-        let val = self.read_constant();
-        eprintln!("OP_SET_GLOBAL\t{}", val.with_heap(self.heap));
-    }
+    fn ret(&self) -> Decoded { ("OP_RETURN", vec![]) }
+    fn print(&self) -> Decoded { ("OP_PRINT", vec![]) }
+    fn add(&self) -> Decoded { ("OP_ADD", vec![]) }
+    fn sub(&self) -> Decoded { ("OP_SUB", vec![]) }
+    fn mul(&self) -> Decoded { ("OP_MUL", vec![]) }
+    fn div(&self) -> Decoded { ("OP_DIV", vec![]) }
+    fn neg(&self) -> Decoded { ("OP_NEG", vec![]) }
+    fn not(&self) -> Decoded { ("OP_NOT", vec![]) }
+    fn eq(&self) -> Decoded { ("OP_EQ", vec![]) }
+    fn gt(&self) -> Decoded { ("OP_GT", vec![]) }
+    fn lt(&self) -> Decoded { ("OP_LT", vec![]) }
+    fn add_number(&self) -> Decoded { ("OP_ADD_NUMBER", vec![]) }
+    fn sub_number(&self) -> Decoded { ("OP_SUB_NUMBER", vec![]) }
+    fn mul_number(&self) -> Decoded { ("OP_MUL_NUMBER", vec![]) }
+    fn div_number(&self) -> Decoded { ("OP_DIV_NUMBER", vec![]) }
+    fn lt_number(&self) -> Decoded { ("OP_LESS_NUMBER", vec![]) }
+    fn gt_number(&self) -> Decoded { ("OP_GREATER_NUMBER", vec![]) }
+    fn is(&self) -> Decoded { ("OP_IS", vec![]) }
+    fn op_pop(&self) -> Decoded { ("OP_POP", vec![]) }
+    fn halt(&self) -> Decoded { ("OP_HALT", vec![]) }
+    fn inherit(&self) -> Decoded { ("OP_INHERIT", vec![]) }
+    fn imm_nil(&self) -> Decoded { ("OP_NIL", vec![]) }
+    fn imm_true(&self) -> Decoded { ("OP_TRUE", vec![]) }
+    fn imm_false(&self) -> Decoded { ("OP_FALSE", vec![]) }
+    fn close_upvalue(&self) -> Decoded { ("OP_CLOSE_UPVALUE", vec![]) }
 
-    fn define_global(&mut self) {
-        let val = self.read_constant();
-        eprintln!("OP_DEFINE_GLOBAL\t{}", val.with_heap(self.heap));
-    }
+    fn jmp(&mut self) -> Decoded { self.jump("OP_JUMP") }
+    fn jze(&mut self) -> Decoded { self.jump("OP_JUMP_IF_FALSE") }
+    fn jump_if_less(&mut self) -> Decoded { self.jump("OP_JUMP_IF_LESS") }
+    fn jump_if_not_less(&mut self) -> Decoded { self.jump("OP_JUMP_IF_NOT_LESS") }
+    fn jump_if_greater(&mut self) -> Decoded { self.jump("OP_JUMP_IF_GREATER") }
+    fn jump_if_not_greater(&mut self) -> Decoded { self.jump("OP_JUMP_IF_NOT_GREATER") }
 
-    fn get_local(&mut self) {
-        let val = self.read_byte();
-        eprintln!("OP_GET_LOCAL\t{}", val);
+    fn jump(&mut self, op: &'static str) -> Decoded {
+        let ip = self.read_u16() as usize + self.offset;
+        (op, vec![Operand::Jump(ip)])
     }
 
-    fn set_local(&mut self) {
-        let val = self.read_byte();
-        eprintln!("OP_SET_LOCAL\t{}", val);
+    fn op_loop(&mut self) -> Decoded { self.jump_back("OP_LOOP") }
+    fn loop_if_true(&mut self) -> Decoded { self.jump_back("OP_LOOP_IF_TRUE") }
+    fn loop_if_less(&mut self) -> Decoded { self.jump_back("OP_LOOP_IF_LESS") }
+    fn loop_if_not_less(&mut self) -> Decoded { self.jump_back("OP_LOOP_IF_NOT_LESS") }
+    fn loop_if_greater(&mut self) -> Decoded { self.jump_back("OP_LOOP_IF_GREATER") }
+    fn loop_if_not_greater(&mut self) -> Decoded { self.jump_back("OP_LOOP_IF_NOT_GREATER") }
+
+    fn jump_back(&mut self, op: &'static str) -> Decoded {
+        let sub = self.read_u16() as usize;
+        (op, vec![Operand::Jump(self.offset - sub)])
     }
 
-    fn immediate(&mut self) {
+    fn get_global(&mut self) -> Decoded { ("OP_GET_GLOBAL", vec![self.global_operand()]) }
+    fn set_global(&mut self) -> Decoded { ("OP_SET_GLOBAL", vec![self.global_operand()]) }
+    fn define_global(&mut self) -> Decoded { ("OP_DEFINE_GLOBAL", vec![self.global_operand()]) }
+    fn get_local(&mut self) -> Decoded { ("OP_GET_LOCAL", vec![self.local_operand()]) }
+    fn set_local(&mut self) -> Decoded { ("OP_SET_LOCAL", vec![self.local_operand()]) }
+
+    fn immediate(&mut self) -> Decoded {
         self.offset += 8;
         let b1 = self.chunk.get(self.offset - 8) as u64;
         let b2 = self.chunk.get(self.offset - 7) as u64;
@@ -121,87 +272,100 @@ impl<'c> Disassembler<'c> {
             (b7 << 48) +
             (b8 << 56);
         let val = unsafe { Value::from_raw(raw) };
-        eprintln!("OP_FLOAT\t{}", val.with_heap(self.heap));
-    }
-
-    fn imm_nil(&self) {
-        eprintln!("OP_NIL");
-    }
-
-    fn imm_true(&self) {
-        eprintln!("OP_TRUE");
+        ("OP_FLOAT", vec![Operand::Immediate(val.with_heap(self.heap).to_string())])
     }
 
-    fn imm_false(&self) {
-        eprintln!("OP_FALSE");
+    fn call(&self, arity: u8) -> Decoded {
+        ("OP_CALL", vec![Operand::Count(arity)])
     }
 
-    fn call(&self, arity: u8) {
-        eprintln!("OP_CALL_{}", arity);
+    fn tail_call(&self, arity: u8) -> Decoded {
+        ("OP_TAIL_CALL", vec![Operand::Count(arity)])
     }
 
-    fn invoke(&mut self, arity: u8) {
+    fn invoke(&mut self, arity: u8) -> Decoded {
         let idx = self.read_byte();
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprintln!("OP_INVOKE_{} {}", arity, val.with_heap(&self.heap));
+        let name = self.constant_operand(idx as usize);
+        let cache = self.read_u16();
+        ("OP_INVOKE", vec![name, Operand::Count(arity), Operand::Cache(cache)])
     }
 
-    fn close_upvalue(&self) {
-        eprintln!("OP_CLOSE_UPVALUE");
+    fn get_upvalue(&mut self) -> Decoded {
+        ("OP_GET_UPVALUE", vec![Operand::UpValue(self.read_byte())])
     }
 
-    fn get_upvalue(&mut self) {
-        let index = self.read_byte();
-        eprintln!("OP_GET_UPVALUE\t{}", index);
+    fn set_upvalue(&mut self) -> Decoded {
+        ("OP_SET_UPVALUE", vec![Operand::UpValue(self.read_byte())])
     }
 
-    fn set_upvalue(&mut self) {
-        let index = self.read_byte();
-        eprintln!("OP_SET_UPVALE\t{}", index);
-    }
-
-    fn closure(&mut self) {
-        let val = self.read_constant();
-        let count = val
-            .as_object()
+    fn closure(&mut self) -> Decoded {
+        let idx = self.read_byte();
+        let function = self.constant_operand(idx as usize);
+        let count = self.chunk.get_constant(idx as usize)
+            .and_then(|val| val.as_object())
             .and_then(|o| self.heap.get(o))
             .and_then(|o| o.as_function())
             .expect("closure argument to be a function")
             .upvalue_count();
-        print!("OP_CLOSURE\t{} ", val.with_heap(self.heap));
+        let mut operands = vec![function];
         for _ in 0..count {
-            let is_local = self.read_byte() > 0;
+            let local = self.read_byte() > 0;
             let index = self.read_byte();
-            if is_local {
-                print!("L{}", index);
-            } else {
-                print!("U{}", index);
-            }
+            operands.push(Operand::Capture { local, index });
         }
-        eprintln!();
+        ("OP_CLOSURE", operands)
     }
 
-    fn class(&mut self, idx: u8) {
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
+    fn class(&mut self, idx: u8) -> Decoded {
+        let name = self.constant_operand(idx as usize);
         let methods = self.read_byte();
-        eprintln!("OP_CLASS\t{}\t{}\t({} method(s))", idx, val.with_heap(&self.heap), methods);
+        ("OP_CLASS", vec![name, Operand::Count(methods)])
     }
 
-    fn get_property(&mut self) {
+    fn get_super(&mut self, idx: u8) -> Decoded {
+        ("OP_GET_SUPER", vec![self.constant_operand(idx as usize)])
+    }
+
+    fn mixin(&mut self, count: u8) -> Decoded {
+        ("OP_MIXIN", vec![Operand::Count(count)])
+    }
+
+    fn pop_n(&mut self, count: u8) -> Decoded {
+        ("OP_POPN", vec![Operand::Count(count)])
+    }
+
+    fn get_property(&mut self) -> Decoded {
         let idx = self.read_byte();
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprintln!("GET_PROPERTY\t{}\t{}", idx, val.with_heap(&self.heap));
+        let name = self.constant_operand(idx as usize);
+        let cache = self.read_u16();
+        ("OP_GET_PROPERTY", vec![name, Operand::Cache(cache)])
     }
 
-    fn set_property(&mut self) {
+    fn set_property(&mut self) -> Decoded {
         let idx = self.read_byte();
+        ("OP_SET_PROPERTY", vec![self.constant_operand(idx as usize)])
+    }
+
+    fn constant_operand(&self, idx: usize) -> Operand {
         let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprintln!("SET_PROPERTY\t{}\t{}", idx, val.with_heap(&self.heap));
+        Operand::Constant(idx, val.with_heap(self.heap).to_string())
+    }
+
+    fn global_operand(&mut self) -> Operand {
+        let slot = self.read_u16() as usize;
+        Operand::Global(slot, self.names[slot].clone())
+    }
+
+    fn local_operand(&mut self) -> Operand {
+        let offset = self.offset - 1;
+        let slot = self.read_byte();
+        let name = self.chunk.local_name(slot, offset).unwrap_or("?");
+        Operand::Local(slot, name.to_owned())
     }
 
     fn read_byte(&mut self) -> u8 {
         self.offset += 1;
-        self.chunk.as_ref()[self.offset - 1]
+        self.chunk.get(self.offset - 1)
     }
 
     fn read_u16(&mut self) -> u16 {
@@ -210,9 +374,15 @@ impl<'c> Disassembler<'c> {
         let hi = self.chunk.get(self.offset - 1) as u16;
         lo + (hi << 8)
     }
+}
 
-    fn read_constant(&mut self) -> Value {
-        let idx = self.read_byte();
-        *self.chunk.get_constant(idx).expect("invalid constant segment index")
+/// The instructions of a chunk, from `Disassembler::instructions`.
+pub struct Instructions<'c>(Disassembler<'c>);
+
+impl<'c> Iterator for Instructions<'c> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        self.0.decode()
     }
 }
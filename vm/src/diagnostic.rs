@@ -0,0 +1,178 @@
+//! Errors and warnings in a form that tools wrapping the `vm` binary can
+//! read, which is what `--error-format=json` prints.
+//!
+//! Each diagnostic is printed as a JSON object on a line of its own. Fields
+//! that aren't known for a diagnostic, such as the column of a runtime error,
+//! are `null`.
+
+use failure;
+
+use compile::CompileError;
+use parser::errors::{ResolveError, SyntaxError, Warning};
+use parser::Position;
+use serialize::LoadError;
+use vm::Traceback;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Which stage found the problem, such as "syntax" or "runtime".
+    pub kind: &'static str,
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// The byte offsets of the start and end of the code at fault.
+    pub span: Option<(usize, usize)>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, kind: &'static str, message: String) -> Self {
+        Diagnostic {
+            severity,
+            kind,
+            message,
+            line: None,
+            column: None,
+            span: None,
+        }
+    }
+
+    /// An error about a script that isn't any of the usual kinds.
+    pub fn error(kind: &'static str, message: String) -> Self {
+        Diagnostic::new(Severity::Error, kind, message)
+    }
+
+    fn at(mut self, position: &Position) -> Self {
+        self.line = Some(position.line);
+        self.column = Some(position.column);
+        self.span = Some((position.start, position.end));
+        self
+    }
+
+    /// Describes an error from compiling or loading a program.
+    pub fn from_error(err: &failure::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<SyntaxError>() {
+            Diagnostic::new(Severity::Error, "syntax", err.kind.to_string()).at(&err.position)
+        } else if let Some(err) = err.downcast_ref::<ResolveError>() {
+            Diagnostic::new(Severity::Error, "resolve", err.kind.to_string()).at(&err.position)
+        } else if let Some(err) = err.downcast_ref::<CompileError>() {
+            Diagnostic::new(Severity::Error, "compile", err.to_string())
+        } else if let Some(err) = err.downcast_ref::<LoadError>() {
+            Diagnostic::new(Severity::Error, "load", err.to_string())
+        } else {
+            Diagnostic::new(Severity::Error, "error", err.to_string())
+        }
+    }
+
+    pub fn from_warning(warning: &Warning) -> Self {
+        // The message of a warning starts with its line, which has a field
+        // of its own here.
+        let text = warning.to_string();
+        let message = match text.find("] ") {
+            Some(idx) if text.starts_with("[line ") => text[idx + 2..].to_owned(),
+            _ => text,
+        };
+        let mut diagnostic = Diagnostic::new(Severity::Warning, "warning", message);
+        diagnostic.line = Some(warning.line());
+        diagnostic
+    }
+
+    pub fn from_traceback(traceback: &Traceback) -> Self {
+        let mut diagnostic = Diagnostic::new(Severity::Error, "runtime", format!("{}.", traceback.error()));
+        diagnostic.line = traceback.line();
+        diagnostic
+    }
+
+    /// The diagnostic as a single line of JSON. `file` is the script it is
+    /// about, if the script came from a file.
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let mut out = String::from("{\"severity\":");
+        push_str(&mut out, match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        });
+        out.push_str(",\"kind\":");
+        push_str(&mut out, self.kind);
+        out.push_str(",\"message\":");
+        push_str(&mut out, &self.message);
+        out.push_str(",\"file\":");
+        match file {
+            Some(file) => push_str(&mut out, file),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"line\":");
+        push_number(&mut out, self.line);
+        out.push_str(",\"column\":");
+        push_number(&mut out, self.column);
+        out.push_str(",\"span\":");
+        match self.span {
+            Some((start, end)) => out.push_str(&format!("[{},{}]", start, end)),
+            None => out.push_str("null"),
+        }
+        out.push('}');
+        out
+    }
+}
+
+fn push_number(out: &mut String, n: Option<usize>) {
+    match n {
+        Some(n) => out.push_str(&n.to_string()),
+        None => out.push_str("null"),
+    }
+}
+
+fn push_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::Program;
+
+    fn compile_errors(source: &str) -> Vec<String> {
+        Program::compile(source).unwrap_err().iter()
+            .map(|err| Diagnostic::from_error(err).to_json(Some("a \"b\".lox")))
+            .collect()
+    }
+
+    #[test]
+    fn errors_as_json() {
+        assert_eq!(compile_errors("print 1 +;"), vec![
+            "{\"severity\":\"error\",\"kind\":\"syntax\",\"message\":\"Expect expression.\",\
+             \"file\":\"a \\\"b\\\".lox\",\"line\":1,\"column\":10,\"span\":[9,10]}",
+        ]);
+        assert_eq!(compile_errors("{\n  break;\n}"), vec![
+            "{\"severity\":\"error\",\"kind\":\"resolve\",\"message\":\"Cannot break outside of a loop.\",\
+             \"file\":\"a \\\"b\\\".lox\",\"line\":2,\"column\":3,\"span\":[4,9]}",
+        ]);
+    }
+
+    #[test]
+    fn warnings_as_json() {
+        let (_, warnings) = Program::compile_with_warnings("{\n  var x;\n}", false);
+        let json: Vec<_> = warnings.iter().map(|w| Diagnostic::from_warning(w).to_json(None)).collect();
+        assert_eq!(json, vec![
+            "{\"severity\":\"warning\",\"kind\":\"warning\",\"message\":\"Local variable 'x' is never used.\",\
+             \"file\":null,\"line\":2,\"column\":null,\"span\":null}",
+        ]);
+    }
+}
@@ -0,0 +1,195 @@
+//! The `doctor` command, which runs a few small programs in every way this
+//! build can run them and reports which ones misbehave.
+//!
+//! This is meant for checking a local build or a port to a new platform, so
+//! every program is embedded in the binary rather than read from the tests.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+use std::rc::Rc;
+
+use vm::{Config, Program};
+
+/// A name, the source of a program and what it should print.
+const PROGRAMS: &[(&str, &str, &str)] = &[
+    ("arithmetic", "
+        print 1 + 2 * 3 - 4 / 2;
+        print -(3 - 5) == 2;
+        print !nil and 1 < 2;
+    ", "5\ntrue\ntrue\n"),
+    ("strings", "
+        var s = \"\";
+        for (var i = 0; i < 3; i = i + 1) s = s + \"ab\";
+        print s;
+        print s == \"ababab\";
+    ", "ababab\ntrue\n"),
+    ("recursion", "
+        fun fib(n) {
+          if (n < 2) return n;
+          return fib(n - 2) + fib(n - 1);
+        }
+        print fib(20);
+    ", "6765\n"),
+    ("closures", "
+        fun counter() {
+          var count = 0;
+          fun increment() {
+            count = count + 1;
+            return count;
+          }
+          return increment;
+        }
+        var a = counter();
+        var b = counter();
+        a();
+        print a();
+        print b();
+    ", "2\n1\n"),
+    ("classes", "
+        class Shape {
+          init(name) { this.name = name; }
+          describe() { return this.name + \" with \" + this.sides(); }
+        }
+        class Square < Shape {
+          init() { super.init(\"square\"); }
+          sides() { return \"4 sides\"; }
+        }
+        print Square().describe();
+    ", "square with 4 sides\n"),
+    ("garbage", "
+        class Node {}
+        var list = nil;
+        for (var i = 0; i < 2000; i = i + 1) {
+          var node = Node();
+          node.next = list;
+          node.label = \"node\" + \"!\";
+          if (i < 1000) list = node;
+        }
+        var length = 0;
+        while (list != nil) {
+          length = length + 1;
+          list = list.next;
+        }
+        print length;
+    ", "1000\n"),
+];
+
+const COLUMNS: &[&str] = &["vm", "gc stress", "bytecode", "interpreter"];
+
+/// What running a program one way printed, or why it couldn't be run.
+type Outcome = Result<String, String>;
+
+/// Runs every program with every backend and prints a table of the results.
+/// Returns whether they all passed.
+pub fn doctor(config: &Config) -> bool {
+    let interpreter = env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name(if cfg!(windows) { "rlox.exe" } else { "rlox" }))
+        .filter(|path| path.exists());
+
+    let width = PROGRAMS.iter().map(|p| p.0.len()).max().unwrap_or(0);
+    let mut header = format!("{:width$}", "", width = width);
+    for column in COLUMNS {
+        header.push_str(&format!("  {:11}", column));
+    }
+    println!("{}", header.trim_end());
+
+    let mut failures = Vec::new();
+    for &(name, source, expected) in PROGRAMS {
+        let outcomes = vec![
+            Some(run_vm(config, source, false)),
+            Some(run_vm(config, source, true)),
+            Some(run_bytecode(config, source)),
+            interpreter.as_ref().map(|path| run_interpreter(path, name, source)),
+        ];
+        let mut row = format!("{:width$}", name, width = width);
+        for (column, outcome) in COLUMNS.iter().zip(outcomes) {
+            let result = match outcome {
+                None => "skipped",
+                Some(Ok(ref output)) if output == expected => "pass",
+                Some(Ok(output)) => {
+                    failures.push(format!("{} ({}): expected {:?} but got {:?}", name, column, expected, output));
+                    "FAIL"
+                },
+                Some(Err(err)) => {
+                    failures.push(format!("{} ({}): {}", name, column, err));
+                    "FAIL"
+                },
+            };
+            row.push_str(&format!("  {:11}", result));
+        }
+        println!("{}", row.trim_end());
+    }
+
+    if interpreter.is_none() {
+        println!();
+        println!("The interpreter was skipped because there is no rlox binary next to this one.");
+    }
+    if !failures.is_empty() {
+        println!();
+        for failure in &failures {
+            println!("{}", failure);
+        }
+    }
+    failures.is_empty()
+}
+
+/// Collects the output of a VM.
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn compile(source: &str) -> Result<Program, String> {
+    Program::compile(source).map_err(|errors| {
+        errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    })
+}
+
+fn run_vm(config: &Config, source: &str, gc_stress: bool) -> Outcome {
+    execute(config, &compile(source)?, gc_stress)
+}
+
+/// Runs a program after writing it out as bytecode and loading it back, as
+/// with a `.loxc` file.
+fn run_bytecode(config: &Config, source: &str) -> Outcome {
+    let program = compile(source)?;
+    let loaded = Program::from_bytes(program.as_bytes().to_vec()).map_err(|e| e.to_string())?;
+    execute(config, &loaded, false)
+}
+
+fn execute(config: &Config, program: &Program, gc_stress: bool) -> Outcome {
+    let output = Capture::default();
+    let mut vm = config.vm();
+    vm.set_gc_stress(gc_stress || config.gc_stress);
+    vm.set_stdout(output.clone());
+    vm.run_program(program).map_err(|traceback| traceback.to_string())?;
+    let bytes = output.0.borrow().clone();
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+/// Runs a program with the tree-walking interpreter, which only reads
+/// scripts from files.
+fn run_interpreter(binary: &Path, name: &str, source: &str) -> Outcome {
+    let script = env::temp_dir().join(format!("rlox-doctor-{}-{}.lox", ::std::process::id(), name));
+    fs::write(&script, source).map_err(|e| e.to_string())?;
+    let output = Command::new(binary).arg(&script).output();
+    let _ = fs::remove_file(&script);
+    let output = output.map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned());
+    }
+    String::from_utf8(output.stdout).map_err(|e| e.to_string())
+}
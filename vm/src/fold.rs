@@ -0,0 +1,125 @@
+//! Constant folding, which evaluates expressions whose operands are all
+//! literals at compile time.
+//!
+//! Folding gives the same result as running the expression would. Anything
+//! that would be a runtime error, such as adding a number to a string or
+//! dividing by zero, is left alone so that the error is still raised.
+
+use parser::ast::*;
+
+/// The literal that `expr` always evaluates to, if it only depends on other
+/// literals.
+pub fn fold(expr: &Expr) -> Option<Literal> {
+    match expr.node {
+        ExprKind::Literal(ref lit) => Some(copy(lit)),
+        ExprKind::Grouping(ref group) => fold(group),
+        ExprKind::Unary(ref unary) => {
+            let operand = fold(&unary.unary)?;
+            match unary.operator {
+                UnaryOperator::Minus => match operand {
+                    Literal::Number(n) => Some(Literal::Number(-n)),
+                    _ => None,
+                },
+                UnaryOperator::Bang => Some(boolean(!truthy(&operand))),
+            }
+        },
+        ExprKind::Binary(ref binary) => {
+            let lhs = fold(&binary.lhs)?;
+            let rhs = fold(&binary.rhs)?;
+            binary_op(binary.operator, lhs, rhs)
+        },
+        ExprKind::Logical(ref logical) => {
+            let lhs = fold(&logical.lhs)?;
+            let rhs = fold(&logical.rhs)?;
+            let short_circuits = match logical.operator {
+                LogicalOperator::And => !truthy(&lhs),
+                LogicalOperator::Or => truthy(&lhs),
+            };
+            Some(if short_circuits { lhs } else { rhs })
+        },
+        _ => None,
+    }
+}
+
+fn binary_op(operator: BinaryOperator, lhs: Literal, rhs: Literal) -> Option<Literal> {
+    use self::BinaryOperator::*;
+    let folded = match (operator, lhs, rhs) {
+        // Whether NaN equals itself depends on how the VM represents it.
+        (Equal, Literal::Number(n), _) | (Equal, _, Literal::Number(n))
+        | (BangEq, Literal::Number(n), _) | (BangEq, _, Literal::Number(n)) if n.is_nan() => return None,
+        (Equal, a, b) => boolean(a == b),
+        (BangEq, a, b) => boolean(a != b),
+        (Plus, Literal::String(a), Literal::String(b)) => Literal::String(a + &b),
+        (_, Literal::Number(a), Literal::Number(b)) => match operator {
+            Plus => Literal::Number(a + b),
+            Minus => Literal::Number(a - b),
+            Star => Literal::Number(a * b),
+            Slash if b == 0.0 => return None,
+            Slash => Literal::Number(a / b),
+            GreaterThan => boolean(a > b),
+            LessThan => boolean(a < b),
+            // These are compiled as the negation of the opposite
+            // comparison, which differs for NaN.
+            GreaterThanEq => boolean(!(a < b)),
+            LessThanEq => boolean(!(a > b)),
+            Equal | BangEq => unreachable!(),
+        },
+        _ => return None,
+    };
+    Some(folded)
+}
+
+/// Whether `lit` counts as true in a condition.
+pub fn truthy(lit: &Literal) -> bool {
+    match *lit {
+        Literal::Nil | Literal::False => false,
+        _ => true,
+    }
+}
+
+fn boolean(b: bool) -> Literal {
+    if b { Literal::True } else { Literal::False }
+}
+
+fn copy(lit: &Literal) -> Literal {
+    match *lit {
+        Literal::Number(n) => Literal::Number(n),
+        Literal::String(ref s) => Literal::String(s.clone()),
+        Literal::True => Literal::True,
+        Literal::False => Literal::False,
+        Literal::Nil => Literal::Nil,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn fold_source(expr: &str) -> Option<Literal> {
+        let stmts = parser::parse(&format!("print {};", expr)).expect("source to parse");
+        match stmts[0] {
+            Stmt::Print(ref expr) => fold(expr),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(fold_source("1 + 2 * 3"), Some(Literal::Number(7.0)));
+        assert_eq!(fold_source("-(4 - 6) / 2"), Some(Literal::Number(1.0)));
+        assert_eq!(fold_source("\"a\" + \"b\" + \"c\""), Some(Literal::String("abc".into())));
+        assert_eq!(fold_source("!nil and 1 <= 2"), Some(Literal::True));
+        assert_eq!(fold_source("nil or \"x\""), Some(Literal::String("x".into())));
+        assert_eq!(fold_source("\"1\" == 1"), Some(Literal::False));
+    }
+
+    #[test]
+    fn leaves_errors_and_variables() {
+        assert_eq!(fold_source("1 + \"a\""), None);
+        assert_eq!(fold_source("-\"a\""), None);
+        assert_eq!(fold_source("1 / 0"), None);
+        assert_eq!(fold_source("1 + x"), None);
+        assert_eq!(fold_source("false and x"), None);
+    }
+}
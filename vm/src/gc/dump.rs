@@ -0,0 +1,201 @@
+//! Heap dumps, which list every live object along with what refers to it,
+//! for finding what keeps the memory of a script growing.
+//!
+//! A dump starts with the number of objects and bytes of each type, counting
+//! the instances of each class separately. Then come the roots, which are the
+//! values the VM holds itself, and then each object that can be reached from
+//! them, with the objects it refers to:
+//!
+//! ```text
+//! global points -> #3
+//! #3 instance List 120 bytes: head=#4 class=#5
+//! ```
+//!
+//! Sizes are the memory an object owns, such as the contents of a string or
+//! the fields of an instance, and are close rather than exact.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
+
+use broom::{Handle, Heap};
+
+use gc::object::{LoxUpValue, Object};
+use gc::value::Value;
+
+/// How much of a string is shown.
+const STRING_PREVIEW: usize = 40;
+
+/// Writes a dump of the objects that can be reached from `roots`, each of
+/// which has a label saying where the VM holds it.
+pub fn write(heap: &Heap<Object>, roots: &[(String, Value)], out: &mut dyn Write) -> io::Result<()> {
+    let mut graph = Graph::default();
+    for &(_, root) in roots {
+        graph.value(root);
+    }
+    let mut next = 0;
+    let mut objects = Vec::new();
+    while next < graph.objects.len() {
+        let handle = graph.objects[next];
+        next += 1;
+        let object = heap.get(handle).expect("object to be live");
+        let referents = referents(object)
+            .into_iter()
+            .flat_map(|(label, value)| value.as_object().map(|handle| (label, graph.object(handle))))
+            .collect::<Vec<_>>();
+        objects.push((kind(object), size(object), preview(object), referents));
+    }
+
+    let mut totals = HashMap::new();
+    for &(ref kind, size, _, _) in &objects {
+        let total = totals.entry(kind.clone()).or_insert((0, 0));
+        total.0 += 1;
+        total.1 += size;
+    }
+    let mut totals = totals.into_iter().collect::<Vec<_>>();
+    totals.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(&b.0)));
+    let bytes = objects.iter().map(|object| object.1).sum::<usize>();
+    writeln!(out, "{} objects, {} bytes", objects.len(), bytes)?;
+    for (kind, (count, bytes)) in totals {
+        writeln!(out, "{:>8} {:>10} bytes  {}", count, bytes, kind)?;
+    }
+
+    writeln!(out)?;
+    for &(ref label, root) in roots {
+        if let Some(handle) = root.as_object() {
+            writeln!(out, "{} -> #{}", label, graph.id(handle))?;
+        }
+    }
+
+    writeln!(out)?;
+    for (id, (kind, size, preview, referents)) in objects.into_iter().enumerate() {
+        write!(out, "#{} {}", id, kind)?;
+        if let Some(preview) = preview {
+            write!(out, " {}", preview)?;
+        }
+        write!(out, " {} bytes", size)?;
+        for (i, (label, referent)) in referents.into_iter().enumerate() {
+            write!(out, "{}{}=#{}", if i == 0 { ": " } else { " " }, label, referent)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// The objects found so far, numbered in the order that they were found.
+#[derive(Default)]
+struct Graph {
+    objects: Vec<Handle<Object>>,
+    ids: HashMap<u64, usize>,
+}
+
+impl Graph {
+    fn object(&mut self, handle: Handle<Object>) -> usize {
+        let objects = &mut self.objects;
+        *self.ids.entry(Value::from(handle).to_raw()).or_insert_with(|| {
+            objects.push(handle);
+            objects.len() - 1
+        })
+    }
+
+    fn value(&mut self, value: Value) {
+        if let Some(handle) = value.as_object() {
+            self.object(handle);
+        }
+    }
+
+    fn id(&self, handle: Handle<Object>) -> usize {
+        self.ids[&Value::from(handle).to_raw()]
+    }
+}
+
+fn kind(object: &Object) -> String {
+    match *object {
+        Object::String(_) => "string".to_owned(),
+        Object::LoxFunction(_) => "function".to_owned(),
+        Object::LoxClosure(_) => "closure".to_owned(),
+        Object::LoxClass(_) => "class".to_owned(),
+        Object::LoxInstance(ref instance) => format!("instance {}", instance.classname()),
+        Object::NativeFunction(_) => "native".to_owned(),
+        Object::BoundMethod(_) => "bound method".to_owned(),
+        Object::UnboundMethod(_) => "unbound method".to_owned(),
+    }
+}
+
+fn preview(object: &Object) -> Option<String> {
+    match *object {
+        Object::String(ref s) if s.chars().count() > STRING_PREVIEW => {
+            Some(format!("{:?}...", s.chars().take(STRING_PREVIEW).collect::<String>()))
+        },
+        Object::String(ref s) => Some(format!("{:?}", s)),
+        Object::LoxFunction(ref function) => Some(function.name().to_owned()),
+        Object::LoxClosure(ref closure) => Some(closure.name().to_owned()),
+        Object::LoxClass(ref class) => Some(class.name().to_owned()),
+        Object::NativeFunction(ref native) => Some(native.name.clone()),
+        _ => None,
+    }
+}
+
+fn size(object: &Object) -> usize {
+    let owned = match *object {
+        Object::String(ref s) => s.capacity(),
+        Object::LoxClosure(ref closure) => closure.upvalue_count() * mem::size_of::<LoxUpValue>(),
+        Object::LoxClass(ref class) => class.methods()
+            .map(|(name, _)| mem::size_of::<(String, Handle<Object>)>() + name.len())
+            .sum(),
+        Object::LoxInstance(ref instance) => instance.field_names().into_iter()
+            .map(|name| mem::size_of::<(String, Value)>() + name.len())
+            .sum(),
+        Object::NativeFunction(ref native) => native.name.len(),
+        Object::LoxFunction(_) | Object::BoundMethod(_) | Object::UnboundMethod(_) => 0,
+    };
+    mem::size_of::<Object>() + owned
+}
+
+/// The values that `object` refers to, each labelled with how.
+fn referents(object: &Object) -> Vec<(String, Value)> {
+    match *object {
+        Object::String(_) | Object::NativeFunction(_) => Vec::new(),
+        Object::LoxFunction(ref function) => constants(function.chunk().constants()),
+        Object::LoxClosure(ref closure) => {
+            let mut referents = constants(closure.chunk().constants());
+            for i in 0..closure.upvalue_count() {
+                // Open upvalues point into the stack, which is a root anyway.
+                if let Ok(value) = closure.get(i).get() {
+                    referents.push((format!("upvalue{}", i), value));
+                }
+            }
+            referents
+        },
+        Object::LoxClass(ref class) => {
+            let mut methods = class.methods()
+                .map(|(name, &method)| (name.clone(), Value::from(method)))
+                .collect::<Vec<_>>();
+            methods.sort_by(|a, b| a.0.cmp(&b.0));
+            if let Some(superclass) = class.superclass() {
+                methods.push(("superclass".to_owned(), Value::from(superclass)));
+            }
+            methods
+        },
+        Object::LoxInstance(ref instance) => {
+            let mut referents = instance.field_names().into_iter()
+                .map(|name| (name.to_owned(), instance.get_property(name).expect("field to exist")))
+                .collect::<Vec<_>>();
+            referents.sort_by(|a, b| a.0.cmp(&b.0));
+            referents.push(("class".to_owned(), Value::from(instance.class())));
+            referents
+        },
+        Object::BoundMethod(ref bound) => vec![
+            ("receiver".to_owned(), Value::from(bound.receiver)),
+            ("method".to_owned(), Value::from(bound.closure)),
+        ],
+        Object::UnboundMethod(ref unbound) => vec![
+            ("class".to_owned(), Value::from(unbound.class)),
+            ("method".to_owned(), Value::from(unbound.closure)),
+        ],
+    }
+}
+
+fn constants<I: Iterator<Item = Value>>(constants: I) -> Vec<(String, Value)> {
+    constants.enumerate().map(|(i, constant)| (format!("constant{}", i), constant)).collect()
+}
@@ -1,2 +1,4 @@
 pub mod value;
 pub mod object;
+pub mod stats;
+pub mod dump;
@@ -1,13 +1,14 @@
 use super::value::Value;
 use super::value::WithHeap;
 
-use ::chunk::Chunk;
+use ::chunk::{Chunk, ChunkBuilder};
+use ::vm::{RuntimeError, VM};
 
 use broom::prelude::Trace;
 use broom::prelude::Tracer;
-use broom::prelude::Heap;
 use broom::prelude::Handle;
 use fnv::FnvBuildHasher;
+use parser::ast::ANONYMOUS_FUNCTION;
 
 use std::fmt::{Debug, Display};
 use std::rc::Rc;
@@ -22,6 +23,7 @@ pub enum Object {
     LoxInstance(LoxInstance),
     NativeFunction(NativeFunction),
     BoundMethod(BoundMethod),
+    UnboundMethod(UnboundMethod),
 }
 
 /// Quickly implement a method that collapses the enum into an Option for
@@ -39,7 +41,17 @@ macro_rules! impl_as (
 );
 
 impl Object {
-    pub fn native_fn(name: &str, arity: u8, function: fn(&Heap<Object>, &[Value]) -> Value) -> Self {
+    pub fn native_fn(name: &str, arity: u8, function: NativeFn) -> Self {
+        Object::NativeFunction(
+            NativeFunction {
+                name: name.into(),
+                arity,
+                function: Rc::new(function),
+            },
+        )
+    }
+
+    pub fn native_closure(name: &str, arity: u8, function: NativeCallable) -> Self {
         Object::NativeFunction(
             NativeFunction {
                 name: name.into(),
@@ -54,6 +66,23 @@ impl Object {
     impl_as!(as_closure, LoxClosure);
     impl_as!(as_class, LoxClass);
     impl_as!(as_instance, LoxInstance);
+    impl_as!(as_native, NativeFunction);
+
+    pub fn as_class_mut(&mut self) -> Option<&mut LoxClass> {
+        if let Object::LoxClass(ref mut o) = *self {
+            Some(o)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_instance_mut(&mut self) -> Option<&mut LoxInstance> {
+        if let Object::LoxInstance(ref mut o) = *self {
+            Some(o)
+        } else {
+            None
+        }
+    }
 
     pub fn as_closure_mut(&mut self) -> Option<&mut LoxClosure> {
         if let Object::LoxClosure(ref mut o) = *self {
@@ -74,6 +103,7 @@ impl Trace<Self> for Object {
             Object::LoxClosure(c) => c.trace(tracer),
             Object::LoxInstance(c) => c.trace(tracer),
             Object::BoundMethod(c) => c.trace(tracer),
+            Object::UnboundMethod(c) => c.trace(tracer),
         }
     }
 }
@@ -88,6 +118,7 @@ impl Debug for Object {
             Object::LoxInstance(ref inst) => write!(f, "<instance {}>", inst.classname()),
             Object::NativeFunction(ref na) => write!(f, "<native fn {:?}>", na.name),
             Object::BoundMethod(ref b) => write!(f, "<bound method {:?}>", b.closure),
+            Object::UnboundMethod(ref m) => write!(f, "<unbound method {:?}>", m.closure),
         }
     }
 }
@@ -96,15 +127,22 @@ impl<'h, 'a> Display for WithHeap<'h, &'a Object> {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match self.item {
             Object::String(ref s) => write!(f, "{}", s),
+            // Function expressions print like they do in the interpreter.
+            Object::LoxFunction(ref fun) if fun.name == ANONYMOUS_FUNCTION => write!(f, "<fn>"),
             Object::LoxFunction(ref fun) => write!(f, "<fn {}>", fun.name),
             Object::LoxClass(ref class) => write!(f, "{}", class.name),
+            Object::LoxClosure(ref cl) if cl.function.name == ANONYMOUS_FUNCTION => write!(f, "<fn>"),
             Object::LoxClosure(ref cl) => write!(f, "<fn {}>", cl.function.name),
             Object::LoxInstance(ref inst) => write!(f, "{} instance", inst.classname()),
             Object::NativeFunction(ref na) => write!(f, "<native fn {}>", na.name),
-            Object::BoundMethod(ref b) => write!(f, "<fn {}>", {
+            Object::BoundMethod(ref b) => {
                 let obj = self.heap.get(b.closure).expect("live reference");
-                self.with(obj)
-            }),
+                write!(f, "{}", self.with(obj))
+            },
+            Object::UnboundMethod(ref m) => {
+                let obj = self.heap.get(m.closure).expect("live reference");
+                write!(f, "{}", self.with(obj))
+            },
         }
     }
 }
@@ -112,7 +150,7 @@ impl<'h, 'a> Display for WithHeap<'h, &'a Object> {
 #[derive(Debug)]
 pub struct LoxFunctionBuilder {
     name: String,
-    chunk: Chunk,
+    chunk: ChunkBuilder,
     arity: u8,
     upvalue_count: usize,
 }
@@ -120,7 +158,7 @@ pub struct LoxFunctionBuilder {
 impl LoxFunctionBuilder {
     pub fn new(name: &str, arity: u8) -> Self {
         let name: String = name.into();
-        let chunk = Chunk::new(name.clone());
+        let chunk = ChunkBuilder::new(name.clone());
         LoxFunctionBuilder { name, arity, chunk, upvalue_count: 0 }
     }
 
@@ -128,7 +166,7 @@ impl LoxFunctionBuilder {
         &self.name
     }
 
-    pub fn chunk_mut(&mut self) -> &mut Chunk {
+    pub fn chunk_mut(&mut self) -> &mut ChunkBuilder {
         &mut self.chunk
     }
 
@@ -154,15 +192,28 @@ impl LoxFunction {
         LoxFunction {
             name: builder.name,
             arity: builder.arity,
-            chunk: builder.chunk,
+            chunk: builder.chunk.build(),
             upvalue_count: builder.upvalue_count,
         }
     }
 
+    pub fn from_parts(name: String, arity: u8, upvalue_count: usize, chunk: Chunk) -> Self {
+        LoxFunction {
+            name,
+            arity,
+            chunk,
+            upvalue_count,
+        }
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    pub fn arity(&self) -> u8 {
+        self.arity
+    }
+
     pub fn chunk(&self) -> &Chunk {
         &self.chunk
     }
@@ -178,11 +229,18 @@ impl Trace<Object> for LoxFunction {
     }
 }
 
+/// A function implemented in Rust. `args` starts with the native itself,
+/// followed by the arguments it was called with.
+pub type NativeFn = fn(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError>;
+
+/// Any native, whether a `NativeFn` or a closure that the host registered.
+pub type NativeCallable = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>>;
+
 #[derive(Clone)]
 pub struct NativeFunction {
     pub name: String,
     pub arity: u8,
-    pub function: fn(&Heap<Object>, &[Value]) -> Value,
+    pub function: NativeCallable,
 }
 
 #[derive(Debug, Clone)]
@@ -203,6 +261,10 @@ impl LoxClosure {
         self.function.name()
     }
 
+    pub fn function(&self) -> &LoxFunction {
+        &self.function
+    }
+
     pub fn arity(&self) -> u8 {
         self.function.arity
     }
@@ -248,6 +310,12 @@ impl LoxUpValue {
         }
     }
 
+    /// Identifies the variable, which is shared by every closure that
+    /// captured it.
+    pub fn id(&self) -> usize {
+        &*self.inner as *const RefCell<_> as usize
+    }
+
     pub fn as_local(&self) -> Option<usize> {
         self.inner.borrow().err()
     }
@@ -268,21 +336,53 @@ impl LoxUpValue {
 pub struct LoxClass {
     name: String,
     methods: HashMap<String, Handle<Object>, FnvBuildHasher>,
+    superclass: Option<Handle<Object>>,
 }
 
 impl LoxClass {
     pub fn new(name: String, methods: HashMap<String, Handle<Object>, FnvBuildHasher>) -> Self {
-        LoxClass { name, methods }
+        LoxClass { name, methods, superclass: None }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
     pub fn method(&self, name: &str) -> Option<Handle<Object>> {
         self.methods.get(name).cloned()
     }
+
+    pub fn methods(&self) -> impl Iterator<Item = (&String, &Handle<Object>)> {
+        self.methods.iter()
+    }
+
+    /// The class this one inherits from, if any. Its methods have already
+    /// been copied in, so this is only needed to tell what a class is a
+    /// subclass of.
+    pub fn superclass(&self) -> Option<Handle<Object>> {
+        self.superclass
+    }
+
+    pub fn set_superclass(&mut self, superclass: Handle<Object>) {
+        self.superclass = Some(superclass);
+    }
+
+    /// Copies in each of `methods` that this class doesn't override.
+    pub fn inherit<I>(&mut self, methods: I)
+        where I: IntoIterator<Item = (String, Handle<Object>)>
+    {
+        for (name, method) in methods {
+            self.methods.entry(name).or_insert(method);
+        }
+    }
 }
 
 impl Trace<Object> for LoxClass {
     fn trace(&self, tracer: &mut Tracer<Object>) {
         self.methods.values().for_each(|v| v.trace(tracer));
+        if let Some(ref superclass) = self.superclass {
+            superclass.trace(tracer);
+        }
     }
 }
 
@@ -316,6 +416,17 @@ impl LoxInstance {
         self.fields.insert(name.into(), value);
     }
 
+    /// The names of the instance's fields, in alphabetical order.
+    pub fn field_names(&self) -> Vec<&str> {
+        let mut names = self.fields.keys().map(|k| &k[..]).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn remove_property(&mut self, name: &str) -> Option<Value> {
+        self.fields.remove(name)
+    }
+
     pub fn classname(&self) -> &str {
         unsafe {
             let class = self.class
@@ -352,3 +463,24 @@ impl Trace<Object> for BoundMethod {
         self.closure.trace(tracer);
     }
 }
+
+/// A method taken from its class, which is called with the receiver as its
+/// first argument.
+#[derive(Debug, Clone)]
+pub struct UnboundMethod {
+    pub class: Handle<Object>,
+    pub closure: Handle<Object>,
+}
+
+impl UnboundMethod {
+    pub fn new(class: Handle<Object>, closure: Handle<Object>) -> Self {
+        UnboundMethod { class, closure }
+    }
+}
+
+impl Trace<Object> for UnboundMethod {
+    fn trace(&self, tracer: &mut Tracer<Object>) {
+        self.class.trace(tracer);
+        self.closure.trace(tracer);
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Counters describing the work the garbage collector has done so far.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    /// Total number of objects allocated while running, including ones since
+    /// freed. Constants loaded along with the program and the natives defined
+    /// for it are not included.
+    pub objects_allocated: usize,
+    /// Total number of bytes allocated for those objects.
+    pub bytes_allocated: usize,
+    /// Number of objects freed by collections.
+    pub objects_freed: usize,
+    /// Number of objects currently on the heap.
+    pub objects_live: usize,
+    /// Number of collections run.
+    pub collections: usize,
+    /// Time spent in all collections.
+    pub total_pause: Duration,
+    /// The longest time spent in a single collection.
+    pub max_pause: Duration,
+}
+
+impl GcStats {
+    pub fn record_allocation(&mut self, bytes: usize) {
+        self.objects_allocated += 1;
+        self.bytes_allocated += bytes;
+    }
+
+    pub fn record_collection(&mut self, freed: usize, pause: Duration) {
+        self.collections += 1;
+        self.objects_freed += freed;
+        self.total_pause += pause;
+        if pause > self.max_pause {
+            self.max_pause = pause;
+        }
+    }
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[gc] allocated: {} objects ({} bytes)", self.objects_allocated, self.bytes_allocated)?;
+        writeln!(f, "[gc] freed: {} objects, live: {} objects", self.objects_freed, self.objects_live)?;
+        write!(f, "[gc] collections: {}, total pause: {:?}, max pause: {:?}",
+               self.collections, self.total_pause, self.max_pause)
+    }
+}
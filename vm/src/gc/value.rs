@@ -75,6 +75,15 @@ impl Value {
         panic!("value not a float");
     }
 
+    /// Whether the value is a number. Unlike `decode`, this doesn't panic on
+    /// raw bits that aren't a value, such as those read from a file.
+    pub fn is_float(&self) -> bool {
+        match self.handle.clone().decode() {
+            Tag::Float(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn truthy(&self) -> bool {
         match self.decode() {
             Variant::False | Variant::Nil => false,
@@ -139,15 +148,15 @@ impl Debug for Value {
     }
 }
 
-impl Into<Value> for f64 {
-   fn into(self) -> Value {
-       Value::float(self)
-   }
+impl From<f64> for Value {
+    fn from(float: f64) -> Self {
+        Value::float(float)
+    }
 }
 
-impl Into<Value> for bool {
-    fn into(self) -> Value {
-        if self {
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        if b {
             Value::truelit()
         } else {
             Value::falselit()
@@ -155,6 +164,12 @@ impl Into<Value> for bool {
     }
 }
 
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::nil()
+    }
+}
+
 pub struct WithHeap<'h, T> {
     pub heap: &'h Heap<Object>,
     pub item: T,
@@ -0,0 +1,167 @@
+//! Events that a host application raises and scripts can subscribe to.
+//!
+//! The host declares which events exist when building the interpreter.
+//! Scripts then pass a callback for one of them to the `on` native, and the
+//! host calls every callback for an event with `Lox::fire`:
+//!
+//! ```ignore
+//! let mut lox = LoxBuilder::new()
+//!     .hooks(HookRegistry::new().event("tick"))
+//!     .build();
+//! lox.run("fun tick(n) { print n; } on(\"tick\", tick);")?;
+//! lox.fire("tick", (1.0,))?;
+//! ```
+
+use std::collections::HashMap;
+
+use fnv::FnvBuildHasher;
+
+use gc::value::Value;
+
+#[derive(Debug, Clone, Default)]
+pub struct HookRegistry {
+    callbacks: HashMap<String, Vec<Value>, FnvBuildHasher>,
+}
+
+impl HookRegistry {
+    pub fn new() -> Self {
+        HookRegistry::default()
+    }
+
+    /// Declares an event that scripts may subscribe to.
+    pub fn event(mut self, name: &str) -> Self {
+        self.callbacks.entry(name.to_owned()).or_insert_with(Vec::new);
+        self
+    }
+
+    /// Whether no events have been declared, in which case scripts don't get
+    /// the `on` native at all.
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    pub fn has_event(&self, name: &str) -> bool {
+        self.callbacks.contains_key(name)
+    }
+
+    /// Adds a callback for `name`, returning false if no such event was
+    /// declared.
+    pub fn subscribe(&mut self, name: &str, callback: Value) -> bool {
+        match self.callbacks.get_mut(name) {
+            Some(callbacks) => {
+                callbacks.push(callback);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The callbacks for `name`, in the order they were subscribed.
+    pub fn callbacks(&self, name: &str) -> &[Value] {
+        self.callbacks.get(name).map_or(&[], |c| &c[..])
+    }
+
+    /// Each declared event along with its callbacks.
+    pub fn events(&self) -> impl Iterator<Item = (&str, &[Value])> {
+        self.callbacks.iter().map(|(name, callbacks)| (&name[..], &callbacks[..]))
+    }
+
+    /// Unsubscribes every callback, keeping the events.
+    pub fn clear(&mut self) {
+        self.callbacks.values_mut().for_each(Vec::clear);
+    }
+
+    /// Every subscribed callback, which the garbage collector treats as roots.
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.callbacks.values().flat_map(|c| c.iter())
+    }
+}
+
+/// An argument passed from the host to a callback. Strings are only
+/// allocated on the Lox heap once the callback is about to be called, so that
+/// they can't be collected before then.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Value(Value),
+}
+
+impl From<()> for Arg {
+    fn from(_: ()) -> Self {
+        Arg::Nil
+    }
+}
+
+impl From<bool> for Arg {
+    fn from(b: bool) -> Self {
+        Arg::Bool(b)
+    }
+}
+
+impl From<f64> for Arg {
+    fn from(n: f64) -> Self {
+        Arg::Number(n)
+    }
+}
+
+impl<'a> From<&'a str> for Arg {
+    fn from(s: &'a str) -> Self {
+        Arg::String(s.to_owned())
+    }
+}
+
+impl From<String> for Arg {
+    fn from(s: String) -> Self {
+        Arg::String(s)
+    }
+}
+
+impl From<Value> for Arg {
+    fn from(value: Value) -> Self {
+        Arg::Value(value)
+    }
+}
+
+impl<T: Into<Arg>> From<Option<T>> for Arg {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Arg::Nil, Into::into)
+    }
+}
+
+/// The arguments for a callback, as a tuple of anything that converts into
+/// an `Arg`.
+pub trait IntoArgs {
+    fn into_args(self) -> Vec<Arg>;
+}
+
+impl<T: Into<Arg>> IntoArgs for Vec<T> {
+    fn into_args(self) -> Vec<Arg> {
+        self.into_iter().map(Into::into).collect()
+    }
+}
+
+impl IntoArgs for () {
+    fn into_args(self) -> Vec<Arg> {
+        Vec::new()
+    }
+}
+
+macro_rules! impl_into_args {
+    ($($name:ident),+) => {
+        impl<$($name: Into<Arg>),+> IntoArgs for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_args(self) -> Vec<Arg> {
+                let ($($name,)+) = self;
+                vec![$($name.into()),+]
+            }
+        }
+    }
+}
+
+impl_into_args!(A);
+impl_into_args!(A, B);
+impl_into_args!(A, B, C);
+impl_into_args!(A, B, C, D);
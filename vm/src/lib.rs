@@ -0,0 +1,57 @@
+//! A bytecode compiler and virtual machine for Lox.
+//!
+//! Most embedders only need `LoxBuilder`, which configures a `Lox` that can
+//! then run any number of scripts. The lower level pieces are available for
+//! tools that work with bytecode directly.
+
+extern crate parser;
+#[macro_use]
+extern crate failure;
+extern crate broom;
+extern crate fnv;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+pub mod chunk;
+pub mod debug;
+pub mod cfg;
+pub mod diagnostic;
+pub mod compile;
+pub mod fold;
+pub mod vm;
+pub mod register;
+pub mod stack;
+pub mod gc;
+pub mod native;
+pub mod hook;
+#[cfg(feature = "stats")]
+pub mod op_stats;
+pub mod random;
+pub mod serialize;
+pub mod verify;
+pub mod snapshot;
+pub mod program;
+pub mod config;
+pub mod convert;
+pub mod coverage;
+pub mod lox;
+pub mod json;
+pub mod dap;
+
+pub use config::{Backend, Config, ErrorFormat};
+pub use convert::{ConversionError, FromArgs, FromLox};
+pub use coverage::Coverage;
+pub use debug::{Disassembler, Instruction, Instructions, Listing, Operand};
+pub use diagnostic::Diagnostic;
+pub use hook::{Arg, HookRegistry, IntoArgs};
+pub use gc::value::Value;
+pub use lox::{Lox, LoxBuilder, LoxError};
+pub use native::Capabilities;
+pub use parser::errors::Warning;
+pub use program::{compile_and_check, Program};
+pub use snapshot::SnapshotError;
+pub use verify::VerifyError;
+pub use vm::{CancelHandle, Debugger, ExecutionLimit, FrameInfo, GcConfig, Limits, Location, RuntimeError, StepResult, Traceback,
+             VM};
@@ -0,0 +1,284 @@
+//! The entry point for embedding Lox in a Rust program.
+//!
+//! A `LoxBuilder` gathers every setting for running scripts in one place and
+//! produces a `Lox`, which keeps its globals between runs:
+//!
+//! ```ignore
+//! let mut lox = LoxBuilder::new()
+//!     .capabilities(Capabilities::none())
+//!     .native("double", 1, double)
+//!     .stdout(output)
+//!     .build();
+//! lox.run("var x = double(21);")?;
+//! lox.run("print x;")?;
+//! ```
+//!
+//! The host can also read and write globals, evaluate expressions and call
+//! the functions that scripts define:
+//!
+//! ```ignore
+//! lox.set_global("name", "Ada");
+//! lox.run("fun greet(greeting) { return greeting + \", \" + name; }")?;
+//! let greeting = lox.call("greet", ("Hello",))?;
+//! let sum = lox.eval("1 + 2")?;
+//! ```
+//!
+//! A `Lox` is tied to the thread that built it, because the values on its
+//! heap are not thread safe. Everything needed to make one is, though: a
+//! `Program` and a `LoxBuilder` are both `Send`, so a host can compile
+//! scripts on one thread and hand them to a pool of workers that each build
+//! their own interpreter:
+//!
+//! ```ignore
+//! let program = Program::compile(source)?;
+//! let builder = LoxBuilder::new().stdout(output);
+//! thread::spawn(move || builder.build().run_program(&program));
+//! ```
+
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use failure;
+
+use config::Config;
+use gc::object::NativeFn;
+use gc::value::Value;
+use hook::{Arg, HookRegistry, IntoArgs};
+use native::Capabilities;
+use program::Program;
+use snapshot::SnapshotError;
+use vm::{CancelHandle, GcConfig, Limits, RuntimeError, Traceback, VM};
+
+#[derive(Debug)]
+pub enum LoxError {
+    /// The script could not be compiled.
+    Compile(Vec<failure::Error>),
+    /// The script failed while running.
+    Runtime(Traceback),
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoxError::Compile(ref errors) => {
+                let messages = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+                write!(f, "{}", messages.join("\n"))
+            },
+            LoxError::Runtime(ref traceback) => write!(f, "{}", traceback),
+        }
+    }
+}
+
+impl failure::Fail for LoxError {}
+
+/// The settings for a `Lox`. Unlike the interpreter it builds, a builder
+/// can be sent to another thread.
+pub struct LoxBuilder {
+    config: Config,
+    capabilities: Capabilities,
+    natives: Vec<(String, u8, NativeFn)>,
+    events: Vec<String>,
+    stdout: Option<Box<dyn Write + Send>>,
+    stderr: Option<Box<dyn Write + Send>>,
+    stdin: Option<Box<dyn BufRead + Send>>,
+}
+
+impl LoxBuilder {
+    /// Starts from the default settings. Unlike the command line, these do
+    /// not read from the environment; use `from_config` for that.
+    pub fn new() -> Self {
+        LoxBuilder::from_config(Config::default())
+    }
+
+    /// Starts from `config`. The register machine has no natives or hooks
+    /// for a host to add to yet, so a `Backend::Register` in it is ignored
+    /// and the stack VM is built instead.
+    pub fn from_config(config: Config) -> Self {
+        LoxBuilder {
+            config,
+            capabilities: Capabilities::default(),
+            natives: Vec::new(),
+            events: Vec::new(),
+            stdout: None,
+            stderr: None,
+            stdin: None,
+        }
+    }
+
+    /// Where the output of `print` goes, instead of stdout.
+    pub fn stdout<W: Write + Send + 'static>(mut self, stdout: W) -> Self {
+        self.stdout = Some(Box::new(stdout));
+        self
+    }
+
+    /// Where tracing goes, instead of stderr.
+    pub fn stderr<W: Write + Send + 'static>(mut self, stderr: W) -> Self {
+        self.stderr = Some(Box::new(stderr));
+        self
+    }
+
+    /// Where `readLine` and `readNumber` read from, instead of stdin.
+    pub fn stdin<R: BufRead + Send + 'static>(mut self, stdin: R) -> Self {
+        self.stdin = Some(Box::new(stdin));
+        self
+    }
+
+    /// Adds a global function for scripts to call.
+    pub fn native(mut self, name: &str, arity: u8, function: NativeFn) -> Self {
+        self.natives.push((name.to_owned(), arity, function));
+        self
+    }
+
+    /// Which of the built in natives scripts may use. Natives added with
+    /// `native` are always available.
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// The events that scripts can subscribe to, which the host raises with
+    /// `Lox::fire`.
+    pub fn hooks(mut self, hooks: HookRegistry) -> Self {
+        // Only the names are kept, since a registry can hold callbacks, which
+        // can't leave the thread of the VM they belong to.
+        self.events = hooks.events().map(|(name, _)| name.to_owned()).collect();
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// When to collect garbage, and how large the heap may grow.
+    pub fn gc(mut self, gc: GcConfig) -> Self {
+        self.config.gc = gc;
+        self
+    }
+
+    /// Collects garbage on every allocation, for flushing out rooting bugs.
+    pub fn gc_stress(mut self, gc_stress: bool) -> Self {
+        self.config.gc_stress = gc_stress;
+        self
+    }
+
+    /// Prints every executed instruction along with the stack to stderr.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.config.trace = trace;
+        self
+    }
+
+    pub fn build(self) -> Lox {
+        let mut vm = self.config.vm();
+        vm.set_capabilities(self.capabilities);
+        vm.set_hooks(self.events.iter().fold(HookRegistry::new(), |hooks, name| hooks.event(name)));
+        if let Some(stdout) = self.stdout {
+            vm.set_stdout(stdout);
+        }
+        if let Some(stderr) = self.stderr {
+            vm.set_stderr(stderr);
+        }
+        if let Some(stdin) = self.stdin {
+            vm.set_stdin(stdin);
+        }
+        for (name, arity, function) in self.natives {
+            vm.define_native(&name, arity, function);
+        }
+        Lox { vm }
+    }
+}
+
+/// A configured interpreter. Globals defined by one script are visible to
+/// the scripts run after it.
+pub struct Lox {
+    vm: VM,
+}
+
+impl Lox {
+    /// Compiles and runs `source`, returning the value of its top-level
+    /// `return`, or nil.
+    pub fn run(&mut self, source: &str) -> Result<Value, LoxError> {
+        let program = Program::compile(source).map_err(LoxError::Compile)?;
+        self.run_program(&program)
+    }
+
+    pub fn run_program(&mut self, program: &Program) -> Result<Value, LoxError> {
+        self.vm.run_program(program).map_err(LoxError::Runtime)
+    }
+
+    /// Evaluates a single expression, such as `add(1, 2)`, and returns its
+    /// value.
+    pub fn eval(&mut self, expr: &str) -> Result<Value, LoxError> {
+        let program = Program::compile_expr(expr).map_err(LoxError::Compile)?;
+        self.run_program(&program)
+    }
+
+    /// Calls the global function `name`, returning its result.
+    pub fn call<A: IntoArgs>(&mut self, name: &str, args: A) -> Result<Value, LoxError> {
+        self.vm.call_global(name, args.into_args()).map_err(LoxError::Runtime)
+    }
+
+    /// The value of the global variable `name`, if scripts or the host have
+    /// defined it.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.vm.global_value(name)
+    }
+
+    /// Defines a global variable for scripts to use, or assigns to it if it
+    /// already exists.
+    pub fn set_global<V: Into<Arg>>(&mut self, name: &str, value: V) {
+        self.vm.set_global_value(name, value.into());
+    }
+
+    /// Calls every callback that scripts subscribed to `event`, returning
+    /// their results. Events without subscribers do nothing.
+    pub fn fire<A: IntoArgs>(&mut self, event: &str, args: A) -> Result<Vec<Value>, LoxError> {
+        self.vm.fire(event, args.into_args()).map_err(LoxError::Runtime)
+    }
+
+    /// Defines a global function that calls `function` with its arguments.
+    /// See `VM::register_fn`.
+    pub fn register_fn<F, R>(&mut self, name: &str, arity: u8, function: F)
+        where F: Fn(&mut VM, &[Value]) -> Result<R, RuntimeError> + 'static,
+              R: Into<Arg>,
+    {
+        self.vm.register_fn(name, arity, function);
+    }
+
+    /// Saves the globals, callbacks and, when called from a native, the
+    /// running script. See `VM::snapshot`.
+    pub fn snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        self.vm.snapshot()
+    }
+
+    /// Brings back the state saved by `snapshot`.
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        self.vm.restore(bytes)
+    }
+
+    /// Continues a script that a restored snapshot suspended, as though the
+    /// native that took the snapshot had returned `result`.
+    pub fn resume<V: Into<Arg>>(&mut self, result: V) -> Result<Value, LoxError> {
+        self.vm.resume(result.into()).map_err(LoxError::Runtime)
+    }
+
+    /// A handle for stopping scripts from another thread.
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        self.vm.cancel_handle()
+    }
+
+    /// The arguments that scripts can read with `argc()` and `arg(n)`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.vm.set_args(args);
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+}
+
+#[allow(dead_code)]
+fn assert_send() {
+    fn is_send<T: Send>() {}
+    is_send::<LoxBuilder>();
+}
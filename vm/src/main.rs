@@ -1,95 +1,335 @@
 use std::env;
+use std::io;
 use std::io::prelude::*;
 use std::fs::File;
+use std::path::Path;
 
-use parser::ast::Stmt;
+use cli::{Cli, Command, Input, USAGE};
+use vm::{Backend, Config, Coverage, Diagnostic, ErrorFormat, Program, Traceback, Warning};
+use vm::gc::value::Variant;
+use vm::register;
+use vm::serialize;
 
+extern crate vm;
 extern crate parser;
+#[macro_use]
 extern crate failure;
 extern crate env_logger;
-extern crate broom;
-extern crate fnv;
 
-#[macro_use]
-extern crate log;
+mod bench;
+mod cli;
+mod doctor;
+mod stepper;
+mod tester;
 
-#[macro_use]
-mod chunk;
-#[cfg(feature="dis")]
-mod debug;
-mod compile;
-mod vm;
-mod gc;
-mod native;
+// Exit codes from sysexits.h, following the reference implementation.
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
 
 fn main() {
     env_logger::init();
 
-    let mut args = env::args();
-    let _app = args.next();
-
-    if let Some(arg) = args.next() {
-        let res = match &arg[..] {
-            "help" => help(args),
-    //         "debug" => debug(args),
-            sourcefile => execute(sourcefile),
-        };
-        if let Err(err) = res {
+    let cli = Config::from_env()
+        .map_err(Into::into)
+        .and_then(|config| Cli::parse(config, env::args().skip(1)));
+    let cli = match cli {
+        Ok(cli) => cli,
+        Err(err) => {
             eprintln!("[error]: {}", err);
-            ::std::process::exit(2);
-        }
-    } else {
-        println!("Usage: rlox [script]");
-        ::std::process::exit(1);
+            eprintln!("Run 'vm help' for usage.");
+            ::std::process::exit(EX_USAGE);
+        },
+    };
+    let config = cli.config;
+    let res = match cli.command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        Command::Run { input, args } => run(input, args, &config),
+        Command::Repl => repl(&config),
+        Command::Debug { script, interactive: false, cfg } => debug(&script, cfg, &config),
+        Command::Debug { script, interactive: true, .. } => debug_interactive(&script, &config),
+        Command::Compile { script, output, strip } => compile(&script, output, strip, &config),
+        Command::Check { script } => check(&script, &config),
+        Command::Fmt { script, check } => fmt(&script, check, &config),
+        Command::Test { paths } => match tester::test(&paths, &config) {
+            Ok(false) => ::std::process::exit(1),
+            res => res.map(|_| ()).map_err(Into::into),
+        },
+        Command::Bench { script, runs, warmup } => bench(&script, runs, warmup, &config),
+        Command::Dap => vm::dap::serve(io::BufReader::new(io::stdin()), io::stdout(), &config).map_err(Into::into),
+        Command::Doctor => if doctor::doctor(&config) {
+            Ok(())
+        } else {
+            ::std::process::exit(EX_SOFTWARE);
+        },
+    };
+    if let Err(err) = res {
+        eprintln!("[error]: {}", err);
+        ::std::process::exit(2);
     }
 }
 
 macro_rules! report_and_bail (
-    ($expr:expr) => (
+    ($expr:expr, $file:expr, $config:expr) => (
         match $expr {
             Ok(ok) => ok,
-            Err(errors) => show_errors(errors),
+            Err(errors) => show_errors(errors, $file, $config),
         }
     );
 );
 
-fn help(_args: env::Args) -> Result<(), failure::Error> {
-    println!("Usage: rlox [script]");
-    println!("       rlox help  - Show help like this.");
-    println!("       rlox debug - Show the compiled bytecode for a script, without executing.");
+fn run(input: Input, args: Vec<String>, config: &Config) -> Result<(), failure::Error> {
+    let (contents, file) = match input {
+        Input::Source(source) => (source.into_bytes(), None),
+        Input::File(filename) => (read_bytes(&filename)?, Some(filename)),
+    };
+    let file = file.as_ref().map(String::as_str).filter(|&f| f != "-");
+    if config.backend == Backend::Register {
+        return run_register(contents, file, config);
+    }
+    let program = load(contents, file, config.optimize, config)?;
+    let mut vm = config.vm();
+    vm.set_args(args);
+    let coverage = Coverage::new();
+    if config.coverage.is_some() {
+        vm.set_debugger(coverage.clone());
+    }
+    let res = vm.run_program(&program);
+    if let Some(ref report) = config.coverage {
+        let mut out = File::create(report)?;
+        coverage.write_lcov(&program, file.unwrap_or("-"), &mut out)?;
+    }
+    if config.gc_stats {
+        eprintln!("{}", vm.gc_stats());
+    }
+    if config.stats {
+        eprintln!("[stats] instructions: {}", vm.instruction_count());
+        eprintln!("[stats] allocations: {}", vm.gc_stats().objects_allocated);
+        #[cfg(feature = "stats")]
+        eprintln!("{}", vm.op_stats());
+    }
+    match res {
+        // A script that returns a number exits with it as the status code.
+        Ok(value) => if let Variant::Float(n) = value.decode() {
+            ::std::process::exit(n as i32);
+        },
+        Err(traceback) => {
+            show_traceback(&traceback, file, config);
+            ::std::process::exit(EX_SOFTWARE);
+        },
+    }
+    Ok(())
+}
+
+/// Runs a script on the experimental register machine, which has no
+/// natives besides `clock`, and doesn't collect coverage.
+fn run_register(contents: Vec<u8>, file: Option<&str>, config: &Config) -> Result<(), failure::Error> {
+    let program = load_register(contents, file, config)?;
+    let mut machine = register::Machine::new(&config.limits);
+    let res = machine.run(&program);
+    if config.stats {
+        eprintln!("[stats] instructions: {}", machine.instruction_count());
+    }
+    match res {
+        Ok(register::Value::Number(n)) => ::std::process::exit(n as i32),
+        Ok(_) => Ok(()),
+        Err(traceback) => {
+            show_traceback(&traceback, file, config);
+            ::std::process::exit(EX_SOFTWARE);
+        },
+    }
+}
+
+/// Compiles a source script for the register machine, reporting any errors
+/// and exiting if there are any.
+fn load_register(contents: Vec<u8>, file: Option<&str>, config: &Config) -> Result<register::Program, failure::Error> {
+    if serialize::is_bytecode(&contents) {
+        return Err(format_err!("The register backend can only run source scripts."));
+    }
+    let source = String::from_utf8(contents)?;
+    Ok(report_and_bail!(register::Program::compile(&source), file, config))
+}
+
+/// Loads a program from either source or compiled bytecode, reporting any
+/// errors in the source and exiting if there are any.
+fn load(contents: Vec<u8>, file: Option<&str>, optimize: bool, config: &Config) -> Result<Program, failure::Error> {
+    if serialize::is_bytecode(&contents) {
+        Ok(Program::from_bytes(contents)?)
+    } else {
+        let source = String::from_utf8(contents)?;
+        Ok(compile_source(&source, file, optimize, config))
+    }
+}
+
+/// Runs each line entered as its own program, sharing globals between them.
+fn repl(config: &Config) -> Result<(), failure::Error> {
+    let mut vm = config.vm();
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let (program, warnings) = Program::compile_with_warnings(&line, config.optimize);
+        if config.warn || config.deny_warnings {
+            show_warnings(&warnings, None, config);
+        }
+        match program {
+            Ok(program) => if let Err(traceback) = vm.run_program(&program) {
+                show_traceback(&traceback, None, config);
+            },
+            Err(errors) => for err in &errors {
+                show_error(err, None, config);
+            },
+        }
+    }
+}
+
+fn debug(filename: &str, cfg: bool, config: &Config) -> Result<(), failure::Error> {
+    let source = String::from_utf8(read_bytes(filename)?)?;
+    if config.backend == Backend::Register {
+        let program = report_and_bail!(register::Program::compile(&source), Some(filename), config);
+        print!("{}", program);
+        return Ok(());
+    }
+    let program = report_and_bail!(Program::compile(&source), Some(filename), config);
+    if cfg {
+        print!("{}", vm::cfg::dot(&program.listings()));
+    } else {
+        program.disassemble();
+    }
+    Ok(())
+}
+
+/// Steps through a script, taking commands from stdin. Like `debug`, this
+/// works on unoptimized bytecode.
+fn debug_interactive(filename: &str, config: &Config) -> Result<(), failure::Error> {
+    let source = String::from_utf8(read_bytes(filename)?)?;
+    let program = compile_source(&source, Some(filename), false, config);
+    let mut vm = config.vm();
+    let stdin = io::stdin();
+    stepper::debug(&mut vm, &program, stdin.lock(), io::stdout())?;
     Ok(())
 }
-//
-// fn debug(mut args: env::Args) -> Result<(), failure::Error> {
-//     let filename = match args.next() {
-//         Some(filename) => filename,
-//         None => return Err(err_msg("missing file")),
-//     };
-//     let mut gc = gc::Gc::new();
-//     let chunk = compile(&filename, &mut gc)?;
-//     let disassembler = debug::Disassembler::new(&chunk);
-//     disassembler.disassemble();
-//     Ok(())
-// }
-
-fn execute(filename: &str) -> Result<(), failure::Error> {
-    let stmts = parse(&filename)?;
-    vm::VM::new().interpret(&stmts);
+
+fn bench(filename: &str, runs: usize, warmup: usize, config: &Config) -> Result<(), failure::Error> {
+    if config.backend == Backend::Register {
+        let program = load_register(read_bytes(filename)?, Some(filename), config)?;
+        return bench::bench_register(&program, runs, warmup, config);
+    }
+    let program = load(read_bytes(filename)?, Some(filename), config.optimize, config)?;
+    bench::bench(&program, runs, warmup, config)
+}
+
+fn compile(filename: &str, output: Option<String>, strip: bool, config: &Config) -> Result<(), failure::Error> {
+    let output = output.unwrap_or_else(|| {
+        Path::new(filename).with_extension("loxc").to_string_lossy().into_owned()
+    });
+    let source = String::from_utf8(read_bytes(filename)?)?;
+    let mut program = compile_source(&source, Some(filename), config.optimize, config);
+    if strip {
+        program = program.stripped();
+    }
+    let mut file = File::create(output)?;
+    file.write_all(program.as_bytes())?;
+    Ok(())
+}
+
+/// Formats a script in place. With `check`, exits with a failure if the
+/// script isn't formatted rather than changing it.
+fn fmt(filename: &str, check: bool, config: &Config) -> Result<(), failure::Error> {
+    let source = String::from_utf8(read_bytes(filename)?)?;
+    let errors = |errors: Vec<parser::errors::SyntaxError>| errors.into_iter().map(Into::into).collect();
+    let formatted = report_and_bail!(parser::format(&source).map_err(errors), Some(filename), config);
+    if check {
+        if formatted != source {
+            eprintln!("[error]: {} is not formatted.", filename);
+            ::std::process::exit(1);
+        }
+    } else if filename == "-" {
+        io::stdout().write_all(formatted.as_bytes())?;
+    } else if formatted != source {
+        File::create(filename)?.write_all(formatted.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reports the errors and warnings in a script without running it, exiting
+/// with a failure if there are errors or denied warnings.
+fn check(filename: &str, config: &Config) -> Result<(), failure::Error> {
+    let source = String::from_utf8(read_bytes(filename)?)?;
+    // Checking a script is for finding problems in it, so its warnings are
+    // always shown.
+    let config = Config { warn: true, ..config.clone() };
+    compile_source(&source, Some(filename), false, &config);
     Ok(())
 }
 
-fn parse(filename: &str) -> Result<Vec<Stmt>, failure::Error> {
-    let mut file = File::open(filename)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let mut stmts = report_and_bail!(parser::parse(&contents));
-    report_and_bail!(parser::resolve(&mut stmts));
-    Ok(stmts)
+/// Compiles a script, reporting its warnings if they were asked for along
+/// with any errors. Exits if there are errors, or warnings that are denied.
+/// `file` is where the script was read from, if it was read from a file.
+fn compile_source(source: &str, file: Option<&str>, optimize: bool, config: &Config) -> Program {
+    let (program, warnings) = Program::compile_with_warnings(source, optimize);
+    if config.warn || config.deny_warnings {
+        show_warnings(&warnings, file, config);
+    }
+    let program = report_and_bail!(program, file, config);
+    if config.deny_warnings && !warnings.is_empty() {
+        match config.error_format {
+            ErrorFormat::Human => eprintln!("[error]: Warnings are denied."),
+            ErrorFormat::Json => {
+                let denied = Diagnostic::error("denied-warnings", "Warnings are denied.".to_owned());
+                eprintln!("{}", denied.to_json(file));
+            },
+        }
+        ::std::process::exit(EX_DATAERR);
+    }
+    program
+}
+
+/// Reads the named file, or stdin if the name is "-".
+fn read_bytes(filename: &str) -> Result<Vec<u8>, failure::Error> {
+    let mut contents = Vec::new();
+    if filename == "-" {
+        io::stdin().read_to_end(&mut contents)?;
+    } else {
+        File::open(filename)?.read_to_end(&mut contents)?;
+    }
+    Ok(contents)
+}
+
+fn show_warnings(warnings: &[Warning], file: Option<&str>, config: &Config) {
+    for warning in warnings {
+        match config.error_format {
+            ErrorFormat::Human => eprintln!("[warning]: {}", warning),
+            ErrorFormat::Json => eprintln!("{}", Diagnostic::from_warning(warning).to_json(file)),
+        }
+    }
+}
+
+fn show_error(err: &failure::Error, file: Option<&str>, config: &Config) {
+    match config.error_format {
+        ErrorFormat::Human => eprintln!("[error]: Parse: {}", err),
+        ErrorFormat::Json => eprintln!("{}", Diagnostic::from_error(err).to_json(file)),
+    }
+}
+
+fn show_errors(errors: Vec<failure::Error>, file: Option<&str>, config: &Config) -> ! {
+    for err in &errors {
+        show_error(err, file, config);
+    }
+    ::std::process::exit(EX_DATAERR);
 }
 
-fn show_errors<E: failure::Fail>(errors: Vec<E>) -> ! {
-    for err in errors {
-        eprintln!("[error]: Parse: {}", err);
+fn show_traceback(traceback: &Traceback, file: Option<&str>, config: &Config) {
+    match config.error_format {
+        ErrorFormat::Human => eprintln!("[error]: {}", traceback),
+        ErrorFormat::Json => eprintln!("{}", Diagnostic::from_traceback(traceback).to_json(file)),
     }
-    ::std::process::exit(1);
 }
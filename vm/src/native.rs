@@ -1,20 +1,420 @@
-use gc::value::Value;
-use gc::object::Object;
-use broom::Heap;
+use gc::object::{LoxInstance, Object};
+use gc::value::{Value, Variant};
+use vm::{Result, RuntimeError, VM};
 
-pub fn clock(_heap: &Heap<Object>, _args: &[Value]) -> Value {
+/// Which groups of built in natives a script is allowed to use, so that
+/// untrusted scripts can be kept away from the host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    /// `clock()` and `now()`.
+    pub time: bool,
+    /// `argc()` and `arg(n)`, which expose the command line arguments.
+    pub args: bool,
+    /// `readLine()` and `readNumber()`, which read from stdin.
+    pub input: bool,
+    /// `readFile(path)`, `writeFile(path, contents)` and
+    /// `appendFile(path, contents)`. These only exist when the VM is built
+    /// with the `io` feature.
+    pub files: bool,
+    /// `env(name)`, `exit(code)` and `cwd()`. These only exist when the VM is
+    /// built with the `process` feature.
+    pub process: bool,
+}
+
+impl Capabilities {
+    pub fn all() -> Self {
+        Capabilities {
+            time: true,
+            args: true,
+            input: true,
+            files: true,
+            process: true,
+        }
+    }
+
+    pub fn none() -> Self {
+        Capabilities {
+            time: false,
+            args: false,
+            input: false,
+            files: false,
+            process: false,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::all()
+    }
+}
+
+/// The seconds since the VM was created, for timing scripts.
+pub fn clock(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    Ok(Value::float(vm.start_time().elapsed().as_secs_f64()))
+}
+
+/// The milliseconds since the unix epoch.
+pub fn now(_vm: &mut VM, _args: &[Value]) -> Result<Value> {
     use std::time::{SystemTime, UNIX_EPOCH};
 
     let epoch_time =
         SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .expect("[FATAL] failed to get system time")
-        .as_secs_f64();
+        .expect("[FATAL] failed to get system time");
+
+    Ok(Value::float(epoch_time.as_millis() as f64))
+}
+
+pub fn native_print(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    vm.println(args[1]).map_err(|e| RuntimeError::Output(e.to_string()))?;
+    Ok(Value::nil())
+}
+
+pub fn argc(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    Ok(Value::float(vm.args().len() as f64))
+}
+
+pub fn arg(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    match args[1].decode() {
+        Variant::Float(n) if n >= 0.0 && n.fract() == 0.0 => {
+            Ok(vm.args().get(n as usize).cloned().unwrap_or_else(Value::nil))
+        },
+        _ => Err(RuntimeError::BadArgument("Argument index must be a whole number")),
+    }
+}
+
+/// The next line of input without its line ending, or nil at the end of the
+/// input.
+pub fn read_line(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    match vm.read_line().map_err(|e| RuntimeError::Input(e.to_string()))? {
+        Some(line) => vm.new_string(line),
+        None => Ok(Value::nil()),
+    }
+}
+
+/// Reads a line and parses it as a number, returning nil at the end of the
+/// input or if the line isn't a number.
+pub fn read_number(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    let line = vm.read_line().map_err(|e| RuntimeError::Input(e.to_string()))?;
+    Ok(line.and_then(|l| l.trim().parse::<f64>().ok()).map_or_else(Value::nil, Value::float))
+}
+
+/// The contents of a string argument, or `error` if it isn't one.
+fn string(vm: &VM, value: Value, error: &'static str) -> Result<String> {
+    vm.object(value).and_then(Object::as_string).cloned().ok_or(RuntimeError::BadArgument(error))
+}
+
+/// A number argument that can be used as an index or a length.
+fn whole_number(value: Value, error: &'static str) -> Result<usize> {
+    match value.decode() {
+        Variant::Float(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Err(RuntimeError::BadArgument(error)),
+    }
+}
+
+/// The number of characters in a string.
+pub fn len(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let s = string(vm, args[1], "Argument must be a string")?;
+    Ok(Value::float(s.chars().count() as f64))
+}
+
+/// The `len` characters starting at `start`, or fewer if the string ends
+/// first.
+pub fn substr(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let s = string(vm, args[1], "Argument must be a string")?;
+    let start = whole_number(args[2], "Start must be a whole number")?;
+    let len = whole_number(args[3], "Length must be a whole number")?;
+    let sub = s.chars().skip(start).take(len).collect();
+    vm.new_string(sub)
+}
+
+/// The character index where `needle` first appears, or -1 if it doesn't.
+pub fn index_of(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let s = string(vm, args[1], "Argument must be a string")?;
+    let needle = string(vm, args[2], "Argument must be a string")?;
+    let index = s.find(&needle[..]).map_or(-1.0, |i| s[..i].chars().count() as f64);
+    Ok(Value::float(index))
+}
+
+pub fn upper(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let s = string(vm, args[1], "Argument must be a string")?;
+    vm.new_string(s.to_uppercase())
+}
+
+pub fn lower(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let s = string(vm, args[1], "Argument must be a string")?;
+    vm.new_string(s.to_lowercase())
+}
+
+/// The string of the single character with the given code point.
+pub fn chr(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let c = match args[1].decode() {
+        Variant::Float(n) if n >= 0.0 && n.fract() == 0.0 && n <= f64::from(u32::max_value()) => {
+            ::std::char::from_u32(n as u32)
+        },
+        _ => None,
+    };
+    match c {
+        Some(c) => vm.new_string(c.to_string()),
+        None => Err(RuntimeError::BadArgument("Invalid character code")),
+    }
+}
+
+/// The code point of a single character string.
+pub fn ord(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let s = string(vm, args[1], "Argument must be a single character")?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Value::float(c as u32 as f64)),
+        _ => Err(RuntimeError::BadArgument("Argument must be a single character")),
+    }
+}
+
+pub fn type_of(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = type_name(vm, args[1]);
+    vm.new_string(name.to_owned())
+}
+
+/// The name of the type of `value`, as `type` returns it.
+pub fn type_name(vm: &VM, value: Value) -> &'static str {
+    match value.decode() {
+        Variant::Float(_) => "number",
+        Variant::True | Variant::False => "bool",
+        Variant::Nil => "nil",
+        Variant::Obj(_) => match *vm.object(value).expect("object to be on the heap") {
+            Object::String(_) => "string",
+            Object::LoxClass(_) => "class",
+            Object::LoxInstance(_) => "instance",
+            Object::LoxFunction(_) |
+            Object::LoxClosure(_) |
+            Object::NativeFunction(_) |
+            Object::BoundMethod(_) |
+            Object::UnboundMethod(_) => "function",
+        },
+    }
+}
+
+/// The value as `print` would show it.
+pub fn str(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    if vm.object(args[1]).and_then(Object::as_string).is_some() {
+        return Ok(args[1]);
+    }
+    let s = vm.stringify(args[1]);
+    vm.new_string(s)
+}
+
+/// Parses a string as a number, returning nil if it isn't one. Numbers are
+/// returned as they are.
+pub fn num(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    if let Variant::Float(_) = args[1].decode() {
+        return Ok(args[1]);
+    }
+    let n = vm.object(args[1])
+        .and_then(Object::as_string)
+        .and_then(|s| s.trim().parse::<f64>().ok())
+        .filter(|n| n.is_finite());
+    Ok(n.map_or_else(Value::nil, Value::float))
+}
+
+/// A number from 0 up to but not including 1.
+pub fn random(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    Ok(Value::float(vm.rng().next_f64()))
+}
+
+/// A whole number from `lo` up to but not including `hi`.
+pub fn random_range(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    match (args[1].decode(), args[2].decode()) {
+        (Variant::Float(lo), Variant::Float(hi)) if lo.fract() == 0.0 && hi.fract() == 0.0 => {
+            if lo >= hi {
+                return Err(RuntimeError::BadArgument("Range must not be empty"));
+            }
+            Ok(Value::float(lo + (vm.rng().next_f64() * (hi - lo)).floor()))
+        },
+        _ => Err(RuntimeError::BadArgument("Range bounds must be whole numbers")),
+    }
+}
+
+/// Restarts the generator, so that it produces the same numbers every time
+/// it is given the same seed.
+pub fn seed_random(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    match args[1].decode() {
+        Variant::Float(seed) => {
+            vm.rng().seed(seed.to_bits());
+            Ok(Value::nil())
+        },
+        _ => Err(RuntimeError::BadArgument("Seed must be a number")),
+    }
+}
+
+#[cfg(feature = "io")]
+pub fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    use std::fs;
+
+    let path = string(vm, args[1], "Path must be a string")?;
+    let contents = fs::read_to_string(&path).map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    vm.new_string(contents)
+}
+
+/// Replaces the contents of a file, creating it if it doesn't exist.
+#[cfg(feature = "io")]
+pub fn write_file(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    use std::fs;
+
+    let path = string(vm, args[1], "Path must be a string")?;
+    let contents = string(vm, args[2], "Contents must be a string")?;
+    fs::write(&path, contents).map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    Ok(Value::nil())
+}
+
+/// Adds to the end of a file, creating it if it doesn't exist.
+#[cfg(feature = "io")]
+pub fn append_file(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let path = string(vm, args[1], "Path must be a string")?;
+    let contents = string(vm, args[2], "Contents must be a string")?;
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    Ok(Value::nil())
+}
+
+/// Writes a dump of the heap to a file, replacing what was in it.
+#[cfg(feature = "io")]
+pub fn heap_dump(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+
+    let path = string(vm, args[1], "Path must be a string")?;
+    File::create(&path)
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            vm.heap_dump(&mut out)?;
+            out.flush()
+        })
+        .map_err(|e| RuntimeError::File(path, e.to_string()))?;
+    Ok(Value::nil())
+}
+
+/// The value of an environment variable, or nil if it isn't set.
+#[cfg(feature = "process")]
+pub fn env(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = string(vm, args[1], "Variable name must be a string")?;
+    match ::std::env::var(name) {
+        Ok(value) => vm.new_string(value),
+        Err(_) => Ok(Value::nil()),
+    }
+}
+
+/// Exits the process straight away with the given status code.
+#[cfg(feature = "process")]
+pub fn exit(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    match args[1].decode() {
+        Variant::Float(code) if code.fract() == 0.0 => {
+            vm.flush().map_err(|e| RuntimeError::Output(e.to_string()))?;
+            ::std::process::exit(code as i32)
+        },
+        _ => Err(RuntimeError::BadArgument("Exit code must be a whole number")),
+    }
+}
+
+/// The current working directory, or nil if it can't be found.
+#[cfg(feature = "process")]
+pub fn cwd(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    match ::std::env::current_dir() {
+        Ok(dir) => vm.new_string(dir.to_string_lossy().into_owned()),
+        Err(_) => Ok(Value::nil()),
+    }
+}
+
+fn instance(vm: &VM, value: Value) -> Result<&LoxInstance> {
+    vm.object(value).and_then(Object::as_instance).ok_or(RuntimeError::BadSet)
+}
+
+pub fn field_count(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let instance = instance(vm, args[1])?;
+    Ok(Value::float(instance.field_names().len() as f64))
+}
+
+/// The name of the `n`th field of an instance, counting in alphabetical
+/// order, so that scripts can loop over the fields up to `fieldCount`.
+pub fn field_name(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = match args[2].decode() {
+        Variant::Float(n) if n >= 0.0 && n.fract() == 0.0 => {
+            instance(vm, args[1])?.field_names().get(n as usize).map(|name| name.to_string())
+        },
+        _ => return Err(RuntimeError::BadArgument("Field index must be a whole number")),
+    };
+    match name {
+        Some(name) => vm.new_string(name),
+        None => Ok(Value::nil()),
+    }
+}
+
+/// Removes a field from an instance, returning whether it had one.
+pub fn delete_field(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = vm.object(args[2])
+        .and_then(Object::as_string)
+        .cloned()
+        .ok_or(RuntimeError::BadArgument("Field name must be a string"))?;
+    let instance = vm.object_mut(args[1])
+        .and_then(Object::as_instance_mut)
+        .ok_or(RuntimeError::BadSet)?;
+    Ok(instance.remove_property(&name).is_some().into())
+}
+
+/// Reads a property whose name is only known at runtime, like `obj.name`.
+pub fn getattr(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = string(vm, args[2], "Property name must be a string")?;
+    vm.property(args[1], &name)?.ok_or(RuntimeError::UndefinedProperty(name))
+}
+
+/// Sets a field whose name is only known at runtime, returning the value
+/// like an assignment does.
+pub fn setattr(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = string(vm, args[2], "Property name must be a string")?;
+    let instance = vm.object_mut(args[1])
+        .and_then(Object::as_instance_mut)
+        .ok_or(RuntimeError::BadSet)?;
+    instance.set_property(&name, args[3]);
+    Ok(args[3])
+}
 
-    Value::float(epoch_time)
+/// Whether a value has a field or method of the given name. Anything other
+/// than an instance has none.
+pub fn hasattr(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = string(vm, args[2], "Property name must be a string")?;
+    let instance = match vm.object(args[1]).and_then(Object::as_instance) {
+        Some(instance) => instance,
+        None => return Ok(false.into()),
+    };
+    let method = vm.object(instance.class().into())
+        .and_then(Object::as_class)
+        .and_then(|class| class.method(&name));
+    Ok((instance.get_property(&name).is_some() || method.is_some()).into())
 }
 
-pub fn native_print(heap: &Heap<Object>, args: &[Value]) -> Value {
-    println!("{}", args[1].with_heap(heap));
-    Value::nil()
+/// Subscribes a callback to one of the events that the host declared.
+pub fn on(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let event = vm.object(args[1])
+        .and_then(Object::as_string)
+        .cloned()
+        .ok_or(RuntimeError::BadArgument("Event name must be a string"))?;
+    match vm.object(args[2]) {
+        Some(&Object::LoxClosure(_))
+            | Some(&Object::BoundMethod(_))
+            | Some(&Object::UnboundMethod(_))
+            | Some(&Object::LoxClass(_))
+            | Some(&Object::NativeFunction(_)) => {},
+        _ => return Err(RuntimeError::BadArgument("Callback must be a function")),
+    }
+    if !vm.hooks_mut().subscribe(&event, args[2]) {
+        return Err(RuntimeError::UnknownEvent(event));
+    }
+    Ok(Value::nil())
 }
@@ -0,0 +1,111 @@
+//! Counts of the instructions that scripts run, and of which instructions
+//! follow which. These show the sequences that are common enough to be worth
+//! fusing into a single instruction.
+//!
+//! Counting slows down every instruction, so it is only compiled in with the
+//! `stats` feature.
+
+use std::fmt;
+
+use chunk::op_name;
+
+/// How many of the most common pairs are shown.
+const SHOWN_PAIRS: usize = 20;
+
+#[derive(Clone)]
+pub struct OpStats {
+    counts: Vec<u64>,
+    /// Indexed by the first instruction of the pair, then the second.
+    pairs: Vec<u64>,
+    prev: Option<u8>,
+}
+
+impl Default for OpStats {
+    fn default() -> Self {
+        OpStats {
+            counts: vec![0; 256],
+            pairs: vec![0; 256 * 256],
+            prev: None,
+        }
+    }
+}
+
+impl OpStats {
+    #[inline]
+    pub fn record(&mut self, op: u8) {
+        self.counts[op as usize] += 1;
+        if let Some(prev) = self.prev {
+            self.pairs[(prev as usize) << 8 | op as usize] += 1;
+        }
+        self.prev = Some(op);
+    }
+
+    /// How many times `op` ran.
+    pub fn count(&self, op: u8) -> u64 {
+        self.counts[op as usize]
+    }
+
+    /// How many times `second` ran straight after `first`, which may have
+    /// been in another function.
+    pub fn pair_count(&self, first: u8, second: u8) -> u64 {
+        self.pairs[(first as usize) << 8 | second as usize]
+    }
+
+    /// Every instruction that ran, most common first.
+    pub fn ops(&self) -> Vec<(u8, u64)> {
+        let mut ops = (0..=255u8)
+            .map(|op| (op, self.count(op)))
+            .filter(|&(_, count)| count > 0)
+            .collect::<Vec<_>>();
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ops
+    }
+
+    /// Every pair of instructions that ran one after the other, most common
+    /// first.
+    pub fn pairs(&self) -> Vec<((u8, u8), u64)> {
+        let mut pairs = self.pairs.iter().enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(i, &count)| (((i >> 8) as u8, i as u8), count))
+            .collect::<Vec<_>>();
+        pairs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pairs
+    }
+}
+
+impl fmt::Display for OpStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ops = self.ops();
+        let total = ops.iter().map(|op| op.1).sum::<u64>().max(1);
+        writeln!(f, "[stats] instructions by opcode:")?;
+        for (op, count) in ops {
+            writeln!(f, "    {:<24} {:>12} {:>6.2}%", op_name(op), count, count as f64 * 100.0 / total as f64)?;
+        }
+        write!(f, "[stats] most common opcode pairs:")?;
+        for ((first, second), count) in self.pairs().into_iter().take(SHOWN_PAIRS) {
+            let pair = format!("{} {}", op_name(first), op_name(second));
+            write!(f, "\n    {:<48} {:>12}", pair, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_ops_and_pairs() {
+        let mut stats = OpStats::default();
+        for &op in &[0x11, 0x03, 0x11, 0x03, 0x02, 0x00] {
+            stats.record(op);
+        }
+        assert_eq!(stats.ops(), [(0x03, 2), (0x11, 2), (0x00, 1), (0x02, 1)]);
+        assert_eq!(stats.pair_count(0x11, 0x03), 2);
+        assert_eq!(stats.pair_count(0x03, 0x11), 1);
+        assert_eq!(stats.pair_count(0x00, 0x11), 0);
+        assert_eq!(stats.pairs()[0], ((0x11, 0x03), 2));
+        assert_eq!(stats.to_string().lines().nth(1).unwrap().split_whitespace().collect::<Vec<_>>(),
+                   ["OP_ADD", "2", "33.33%"]);
+    }
+}
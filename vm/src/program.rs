@@ -0,0 +1,175 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use broom::Heap;
+use failure;
+use parser;
+use parser::ast::Stmt;
+use parser::errors::Warning;
+
+use compile::{CompileError, Compiler};
+use debug::{self, Listing};
+use gc::object::{Object, LoxFunction};
+use serialize::{self, LoadError};
+use verify;
+
+/// A compiled program that is independent of any particular VM.
+///
+/// Compiled functions normally live on the heap of the VM that compiled them,
+/// so a `Program` keeps them in their serialized form instead. Running it
+/// only has to rebuild the constants on the target VM's heap, which is much
+/// cheaper than scanning, parsing, resolving and compiling the source again.
+///
+/// A `Program` is immutable and `Send + Sync`, and cloning it is cheap, so a
+/// single compiled script can be shared by a pool of VMs on many threads.
+#[derive(Debug, Clone)]
+pub struct Program {
+    bytecode: Arc<[u8]>,
+}
+
+impl Program {
+    pub fn compile(source: &str) -> Result<Program, Vec<failure::Error>> {
+        Program::compile_with(source, false)
+    }
+
+    /// Compiles a program, folding constant expressions if `optimize` is
+    /// set.
+    pub fn compile_with(source: &str, optimize: bool) -> Result<Program, Vec<failure::Error>> {
+        Program::compile_with_warnings(source, optimize).0
+    }
+
+    /// Compiles a program like `compile_with`, also returning the resolver's
+    /// warnings about it. There are none if the source doesn't parse.
+    pub fn compile_with_warnings(source: &str, optimize: bool)
+        -> (Result<Program, Vec<failure::Error>>, Vec<Warning>)
+    {
+        let mut stmts = match parser::parse(source) {
+            Ok(stmts) => stmts,
+            Err(errors) => return (Err(into_errors(errors)), Vec::new()),
+        };
+        let (resolved, warnings) = parser::resolve_with_warnings(&mut stmts);
+        let program = resolved.map_err(into_errors)
+            .and_then(|_| Program::from_ast_with(&stmts, optimize).map_err(into_errors));
+        (program, warnings)
+    }
+
+    /// Compiles a single expression into a program that returns its value.
+    pub fn compile_expr(source: &str) -> Result<Program, Vec<failure::Error>> {
+        let expr = parser::parse_expr(source).map_err(|err| vec![err.into()])?;
+        let mut stmts = vec![Stmt::Return(Some(expr))];
+        parser::resolve(&mut stmts).map_err(into_errors)?;
+        Program::from_ast(&stmts).map_err(into_errors)
+    }
+
+    pub fn from_ast(stmts: &[Stmt]) -> Result<Program, Vec<CompileError>> {
+        Program::from_ast_with(stmts, false)
+    }
+
+    pub fn from_ast_with(stmts: &[Stmt], optimize: bool) -> Result<Program, Vec<CompileError>> {
+        let mut heap = Heap::default();
+        let mut compiler = Compiler::new(&mut heap);
+        compiler.set_optimize(optimize);
+        let (function, names) = compiler.compile(stmts)?;
+        if cfg!(debug_assertions) {
+            if let Err(err) = verify::verify_all(&function, &heap, names.len()) {
+                panic!("The compiler produced invalid bytecode. {}", err);
+            }
+        }
+        Ok(Program {
+            bytecode: serialize::serialize(&function, &names, &heap).into(),
+        })
+    }
+
+    /// Loads a program from the contents of a `.loxc` file.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Program, LoadError> {
+        // Decode once up front so that a corrupt file is reported here rather
+        // than every time the program is run.
+        let mut heap = Heap::default();
+        let (function, names) = serialize::deserialize(&bytes, &mut heap, &mut |_| 0)?;
+        verify::verify_all(&function, &heap, names.len())?;
+        Ok(Program { bytecode: bytes.into() })
+    }
+
+    /// Returns a copy of the program without the names of its local
+    /// variables, for shipping bytecode that doesn't need to be debugged.
+    pub fn stripped(&self) -> Program {
+        let mut heap = Heap::default();
+        let (function, names) = self.load(&mut heap, &mut |_| 0);
+        Program {
+            bytecode: serialize::serialize_with(&function, &names, &heap, true).into(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytecode
+    }
+
+    /// Rebuilds the program's functions on `heap`. `resolve` gives the slot
+    /// that the VM keeps each of the program's globals in.
+    pub fn load(&self, heap: &mut Heap<Object>, resolve: &mut dyn FnMut(&str) -> usize) -> (LoxFunction, Vec<String>) {
+        serialize::deserialize(&self.bytecode, heap, resolve).expect("program to be valid bytecode")
+    }
+
+    /// Every line that has code compiled for it, in any of the program's
+    /// functions.
+    pub fn lines(&self) -> BTreeSet<usize> {
+        fn add_lines(function: &LoxFunction, heap: &Heap<Object>, lines: &mut BTreeSet<usize>) {
+            lines.extend(function.chunk().lines().iter().map(|info| info.line));
+            for constant in function.chunk().constants() {
+                let nested = constant.as_object()
+                    .and_then(|o| heap.get(o))
+                    .and_then(|o| o.as_function());
+                if let Some(nested) = nested {
+                    add_lines(nested, heap, lines);
+                }
+            }
+        }
+
+        let mut heap = Heap::default();
+        let (function, _) = self.load(&mut heap, &mut |_| 0);
+        let mut lines = BTreeSet::new();
+        add_lines(&function, &heap, &mut lines);
+        lines
+    }
+
+    /// Prints the bytecode of every function in the program to stderr.
+    pub fn disassemble(&self) {
+        let mut heap = Heap::default();
+        // The disassembler only shows the names of globals, not their slots.
+        let (function, names) = self.load(&mut heap, &mut |_| 0);
+        debug::disassemble_program(&function, &heap, &names);
+    }
+
+    /// Decodes the bytecode of every function in the program, for tools that
+    /// show it in their own way.
+    pub fn listings(&self) -> Vec<Listing> {
+        let mut heap = Heap::default();
+        let (function, names) = self.load(&mut heap, &mut |_| 0);
+        debug::listings(&function, &heap, &names)
+    }
+}
+
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<Program>();
+}
+
+/// Compiles a script given as bytes, both with and without optimizations,
+/// and loads each of the results back as bytecode, returning the script's
+/// warnings. This is meant for fuzzers: input that isn't a valid script is
+/// an error, so a panic is always a bug.
+pub fn compile_and_check(input: &[u8]) -> Result<Vec<Warning>, Vec<failure::Error>> {
+    let mut stmts = parser::parse_bytes(input).map_err(into_errors)?;
+    let (resolved, warnings) = parser::resolve_with_warnings(&mut stmts);
+    resolved.map_err(into_errors)?;
+    for &optimize in &[false, true] {
+        let program = Program::from_ast_with(&stmts, optimize).map_err(into_errors)?;
+        Program::from_bytes(program.as_bytes().to_vec()).map_err(|err| vec![err.into()])?;
+    }
+    Ok(warnings)
+}
+
+pub(crate) fn into_errors<E: failure::Fail>(errors: Vec<E>) -> Vec<failure::Error> {
+    errors.into_iter().map(Into::into).collect()
+}
@@ -0,0 +1,58 @@
+//! The generator behind the `random` natives. The tree-walking interpreter
+//! uses it too, so a seeded script behaves the same with either backend.
+//!
+//! This is splitmix64, which is tiny and good enough for scripts, but not
+//! for anything that needs to be unpredictable.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// A generator seeded from the current time.
+    pub fn from_time() -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Rng::new(since_epoch.as_secs() ^ (u64::from(since_epoch.subsec_nanos()) << 32))
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to but not including 1.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(7);
+        b.seed(42);
+        for _ in 0..10 {
+            let n = a.next_f64();
+            assert!(n >= 0.0 && n < 1.0);
+            assert_eq!(n, b.next_f64());
+        }
+    }
+}
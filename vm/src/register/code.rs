@@ -0,0 +1,173 @@
+//! The instructions of the register machine, and the functions made of
+//! them.
+
+use std::fmt;
+use std::rc::Rc;
+
+use failure;
+use parser;
+
+use program::into_errors;
+use register::compile;
+use register::value::Value;
+
+/// A register, counted from the start of the running function's frame. A
+/// function's parameters are in its first registers, followed by its
+/// locals and then whatever temporaries its expressions need.
+pub type Reg = u8;
+
+/// The index of an instruction in the same function.
+pub type Target = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    Nil { dst: Reg },
+    True { dst: Reg },
+    False { dst: Reg },
+    Constant { dst: Reg, index: u16 },
+    Move { dst: Reg, src: Reg },
+    GetGlobal { dst: Reg, global: u16 },
+    SetGlobal { global: u16, src: Reg },
+    DefineGlobal { global: u16, src: Reg },
+    Add { dst: Reg, lhs: Reg, rhs: Reg },
+    Subtract { dst: Reg, lhs: Reg, rhs: Reg },
+    Multiply { dst: Reg, lhs: Reg, rhs: Reg },
+    Divide { dst: Reg, lhs: Reg, rhs: Reg },
+    Less { dst: Reg, lhs: Reg, rhs: Reg },
+    Greater { dst: Reg, lhs: Reg, rhs: Reg },
+    Equal { dst: Reg, lhs: Reg, rhs: Reg },
+    Not { dst: Reg, src: Reg },
+    Negate { dst: Reg, src: Reg },
+    Print { src: Reg },
+    Jump { target: Target },
+    JumpIfFalse { cond: Reg, target: Target },
+    JumpIfTrue { cond: Reg, target: Target },
+    /// Compares two numbers and jumps on the result, which saves putting
+    /// the result of the comparison in a register just to test it.
+    JumpIfLess { lhs: Reg, rhs: Reg, target: Target },
+    JumpIfNotLess { lhs: Reg, rhs: Reg, target: Target },
+    JumpIfGreater { lhs: Reg, rhs: Reg, target: Target },
+    JumpIfNotGreater { lhs: Reg, rhs: Reg, target: Target },
+    /// Calls the value in `callee` with the `argc` registers after it as
+    /// its arguments, which become the first registers of the callee's
+    /// frame. The result is left in `callee`.
+    Call { callee: Reg, argc: u8 },
+    Return { src: Reg },
+}
+
+impl Instr {
+    /// Points a jump at `to`.
+    pub fn set_target(&mut self, to: Target) {
+        match *self {
+            Instr::Jump { ref mut target }
+            | Instr::JumpIfFalse { ref mut target, .. }
+            | Instr::JumpIfTrue { ref mut target, .. }
+            | Instr::JumpIfLess { ref mut target, .. }
+            | Instr::JumpIfNotLess { ref mut target, .. }
+            | Instr::JumpIfGreater { ref mut target, .. }
+            | Instr::JumpIfNotGreater { ref mut target, .. } => *target = to,
+            _ => panic!("{} is not a jump", self),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instr::Nil { dst } => write!(f, "NIL\tr{}", dst),
+            Instr::True { dst } => write!(f, "TRUE\tr{}", dst),
+            Instr::False { dst } => write!(f, "FALSE\tr{}", dst),
+            Instr::Constant { dst, index } => write!(f, "CONSTANT\tr{}, k{}", dst, index),
+            Instr::Move { dst, src } => write!(f, "MOVE\tr{}, r{}", dst, src),
+            Instr::GetGlobal { dst, global } => write!(f, "GET_GLOBAL\tr{}, g{}", dst, global),
+            Instr::SetGlobal { global, src } => write!(f, "SET_GLOBAL\tg{}, r{}", global, src),
+            Instr::DefineGlobal { global, src } => write!(f, "DEFINE_GLOBAL\tg{}, r{}", global, src),
+            Instr::Add { dst, lhs, rhs } => write!(f, "ADD\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Subtract { dst, lhs, rhs } => write!(f, "SUBTRACT\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Multiply { dst, lhs, rhs } => write!(f, "MULTIPLY\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Divide { dst, lhs, rhs } => write!(f, "DIVIDE\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Less { dst, lhs, rhs } => write!(f, "LESS\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Greater { dst, lhs, rhs } => write!(f, "GREATER\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Equal { dst, lhs, rhs } => write!(f, "EQUAL\tr{}, r{}, r{}", dst, lhs, rhs),
+            Instr::Not { dst, src } => write!(f, "NOT\tr{}, r{}", dst, src),
+            Instr::Negate { dst, src } => write!(f, "NEGATE\tr{}, r{}", dst, src),
+            Instr::Print { src } => write!(f, "PRINT\tr{}", src),
+            Instr::Jump { target } => write!(f, "JUMP\t-> {}", target),
+            Instr::JumpIfFalse { cond, target } => write!(f, "JUMP_IF_FALSE\tr{} -> {}", cond, target),
+            Instr::JumpIfTrue { cond, target } => write!(f, "JUMP_IF_TRUE\tr{} -> {}", cond, target),
+            Instr::JumpIfLess { lhs, rhs, target } => write!(f, "JUMP_IF_LESS\tr{}, r{} -> {}", lhs, rhs, target),
+            Instr::JumpIfNotLess { lhs, rhs, target } => {
+                write!(f, "JUMP_IF_NOT_LESS\tr{}, r{} -> {}", lhs, rhs, target)
+            },
+            Instr::JumpIfGreater { lhs, rhs, target } => {
+                write!(f, "JUMP_IF_GREATER\tr{}, r{} -> {}", lhs, rhs, target)
+            },
+            Instr::JumpIfNotGreater { lhs, rhs, target } => {
+                write!(f, "JUMP_IF_NOT_GREATER\tr{}, r{} -> {}", lhs, rhs, target)
+            },
+            Instr::Call { callee, argc } => write!(f, "CALL\tr{}, {}", callee, argc),
+            Instr::Return { src } => write!(f, "RETURN\tr{}", src),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub arity: u8,
+    /// How many registers a call to the function uses, counting its
+    /// parameters.
+    pub registers: usize,
+    pub code: Vec<Instr>,
+    /// The line of each instruction.
+    pub lines: Vec<usize>,
+    pub constants: Vec<Value>,
+}
+
+impl Function {
+    /// Writes out the function's instructions, followed by those of the
+    /// functions among its constants.
+    fn disassemble(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "== {} ==", self.name)?;
+        for (i, (instr, &line)) in self.code.iter().zip(&self.lines).enumerate() {
+            if i > 0 && self.lines[i - 1] == line {
+                write!(f, "{:04}    | {}", i, instr)?;
+            } else {
+                write!(f, "{:04} {:4} {}", i, line, instr)?;
+            }
+            if let Instr::Constant { index, .. } = *instr {
+                write!(f, "\t{}", self.constants[index as usize])?;
+            }
+            writeln!(f)?;
+        }
+        for constant in &self.constants {
+            if let Value::Function(ref function) = *constant {
+                function.disassemble(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A compiled script, along with the names of the globals it uses.
+#[derive(Debug)]
+pub struct Program {
+    pub script: Rc<Function>,
+    pub globals: Vec<String>,
+}
+
+impl Program {
+    /// Parses, resolves and compiles a script.
+    pub fn compile(source: &str) -> Result<Program, Vec<failure::Error>> {
+        let mut stmts = parser::parse(source).map_err(into_errors)?;
+        parser::resolve(&mut stmts).map_err(into_errors)?;
+        compile::compile(&stmts).map_err(into_errors)
+    }
+}
+
+/// Lists the instructions of every function in the program.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.script.disassemble(f)
+    }
+}
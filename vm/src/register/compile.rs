@@ -0,0 +1,706 @@
+//! Compiles resolved statements into code for the register machine.
+//!
+//! Each local is given a register for as long as it is in scope, and the
+//! registers above the locals hold temporaries, which are freed again once
+//! the statement that needed them is compiled. Expressions are compiled
+//! straight into the register their value is wanted in where they can be,
+//! and reading a local needs no instruction at all.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use fnv::FnvBuildHasher;
+
+use parser::ast::*;
+
+use register::code::{Function, Instr, Program, Reg, Target};
+use register::value::Value;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum CompileError {
+    #[fail(display = "[line {}] {} are not supported by the register backend.", _0, _1)]
+    Unsupported(usize, &'static str),
+    #[fail(display = "Too many registers in function.")]
+    TooManyRegisters,
+    #[fail(display = "Too many constants in one function.")]
+    TooManyConstants,
+    #[fail(display = "Too many global variables.")]
+    TooManyGlobals,
+}
+
+pub fn compile(stmts: &[Stmt]) -> Result<Program, Vec<CompileError>> {
+    let mut compiler = Compiler {
+        states: Vec::new(),
+        globals: Vec::new(),
+        global_slots: HashMap::default(),
+        errors: Vec::new(),
+    };
+    compiler.states.push(FunctionState::new("<top>", 0));
+    for stmt in stmts {
+        compiler.stmt(stmt);
+    }
+    let script = compiler.end_function();
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    Ok(Program {
+        script: Rc::new(script),
+        globals: compiler.globals,
+    })
+}
+
+struct Compiler {
+    states: Vec<FunctionState>,
+    globals: Vec<String>,
+    global_slots: HashMap<String, u16, FnvBuildHasher>,
+    errors: Vec<CompileError>,
+}
+
+struct Local {
+    name: String,
+    reg: Reg,
+    depth: usize,
+}
+
+struct FunctionState {
+    function: Function,
+    line: usize,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    /// The lowest register that isn't holding a local or a temporary.
+    next: usize,
+    /// The jumps out of each loop that is being compiled, innermost last.
+    breaks: Vec<Vec<usize>>,
+}
+
+impl FunctionState {
+    fn new(name: &str, arity: u8) -> Self {
+        FunctionState {
+            function: Function {
+                name: name.to_owned(),
+                arity,
+                registers: 0,
+                code: Vec::new(),
+                lines: Vec::new(),
+                constants: Vec::new(),
+            },
+            line: 1,
+            locals: Vec::new(),
+            scope_depth: 0,
+            next: 0,
+            breaks: Vec::new(),
+        }
+    }
+}
+
+/// Whether compiling `expr` into a register writes to it only once, after
+/// everything in `expr` has been evaluated. Other expressions can't be
+/// compiled straight into a local that they might read.
+fn writes_once(expr: &Expr) -> bool {
+    match expr.node {
+        ExprKind::Grouping(ref expr) => writes_once(expr),
+        ExprKind::Binary(_) | ExprKind::Unary(_) | ExprKind::Literal(_) | ExprKind::Var(_) => true,
+        _ => false,
+    }
+}
+
+fn is_comparison(operator: BinaryOperator) -> bool {
+    match operator {
+        BinaryOperator::LessThan
+        | BinaryOperator::LessThanEq
+        | BinaryOperator::GreaterThan
+        | BinaryOperator::GreaterThanEq => true,
+        _ => false,
+    }
+}
+
+/// Whether evaluating `expr` might assign to a local.
+fn assigns(expr: &Expr) -> bool {
+    match expr.node {
+        ExprKind::Assign(..) => true,
+        ExprKind::Grouping(ref expr) => assigns(expr),
+        ExprKind::Unary(ref unary) => assigns(&unary.unary),
+        ExprKind::Binary(ref binary) => assigns(&binary.lhs) || assigns(&binary.rhs),
+        ExprKind::Logical(ref logical) => assigns(&logical.lhs) || assigns(&logical.rhs),
+        ExprKind::Call(ref call) => assigns(&call.callee) || call.arguments.iter().any(assigns),
+        _ => false,
+    }
+}
+
+impl Compiler {
+    fn state(&self) -> &FunctionState {
+        self.states.last().expect("states to be nonempty")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("states to be nonempty")
+    }
+
+    /// Finishes the function being compiled, which returns nil if it runs
+    /// off the end.
+    fn end_function(&mut self) -> Function {
+        let src = self.alloc();
+        self.emit(Instr::Nil { dst: src });
+        self.emit(Instr::Return { src });
+        let state = self.states.pop().expect("states to be nonempty");
+        state.function
+    }
+
+    /// Attributes the code emitted from now on to `line`, returning the
+    /// previous line. Synthesized nodes have no line and leave it alone.
+    fn set_line(&mut self, line: usize) -> usize {
+        let previous = self.state().line;
+        if line != 0 {
+            self.state_mut().line = line;
+        }
+        previous
+    }
+
+    fn emit(&mut self, instr: Instr) -> usize {
+        let state = self.state_mut();
+        state.function.code.push(instr);
+        state.function.lines.push(state.line);
+        state.function.code.len() - 1
+    }
+
+    fn ip(&self) -> Target {
+        self.state().function.code.len() as Target
+    }
+
+    /// Points the jump at `jump` to the next instruction.
+    fn patch(&mut self, jump: usize) {
+        let ip = self.ip();
+        self.state_mut().function.code[jump].set_target(ip);
+    }
+
+    fn unsupported(&mut self, what: &'static str) {
+        let line = self.state().line;
+        self.errors.push(CompileError::Unsupported(line, what));
+    }
+
+    /// Reserves the lowest free register.
+    fn alloc(&mut self) -> Reg {
+        let state = self.states.last_mut().expect("states to be nonempty");
+        let reg = state.next;
+        if reg > Reg::max_value() as usize {
+            // Keep going with an arbitrary register, so that the rest of
+            // the function is still checked.
+            self.errors.push(CompileError::TooManyRegisters);
+            return 0;
+        }
+        state.next += 1;
+        state.function.registers = state.function.registers.max(state.next);
+        reg as Reg
+    }
+
+    /// Frees every register from `mark` up.
+    fn free_to(&mut self, mark: usize) {
+        self.state_mut().next = mark;
+    }
+
+    fn mark(&self) -> usize {
+        self.state().next
+    }
+
+    fn constant(&mut self, value: Value) -> u16 {
+        let constants = &self.states.last().expect("states to be nonempty").function.constants;
+        let existing = constants.iter().position(|c| match (c, &value) {
+            (&Value::Number(a), &Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (&Value::String(ref a), &Value::String(ref b)) => a == b,
+            _ => false,
+        });
+        let index = existing.unwrap_or(constants.len());
+        if index > u16::max_value() as usize {
+            self.errors.push(CompileError::TooManyConstants);
+            return 0;
+        }
+        if existing.is_none() {
+            self.state_mut().function.constants.push(value);
+        }
+        index as u16
+    }
+
+    fn global(&mut self, name: &str) -> u16 {
+        if let Some(&slot) = self.global_slots.get(name) {
+            return slot;
+        }
+        if self.globals.len() > u16::max_value() as usize {
+            self.errors.push(CompileError::TooManyGlobals);
+            return 0;
+        }
+        let slot = self.globals.len() as u16;
+        self.globals.push(name.to_owned());
+        self.global_slots.insert(name.to_owned(), slot);
+        slot
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state_mut();
+        state.scope_depth -= 1;
+        while state.locals.last().map_or(false, |l| l.depth > state.scope_depth) {
+            let local = state.locals.pop().expect("locals to be nonempty");
+            state.next = local.reg as usize;
+        }
+    }
+
+    /// Gives the next free register to a new local called `name`.
+    fn add_local(&mut self, name: &str) -> Reg {
+        let reg = self.alloc();
+        let state = self.state_mut();
+        state.locals.push(Local {
+            name: name.to_owned(),
+            reg,
+            depth: state.scope_depth,
+        });
+        reg
+    }
+
+    /// The register of a local of the function being compiled, or `None`
+    /// for globals. Variables captured from enclosing functions aren't
+    /// supported, and are reported.
+    fn local(&mut self, var: &Variable) -> Option<Reg> {
+        if let Scope::Global = var.scope() {
+            return None;
+        }
+        if var.is_upvalue() {
+            self.unsupported("Closures");
+            return Some(0);
+        }
+        let reg = self.state().locals.iter().rev()
+            .find(|l| l.name == var.name())
+            .map(|l| l.reg)
+            .expect("resolved locals to be in scope");
+        Some(reg)
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        if let Some(pos) = stmt.position() {
+            self.set_line(pos.line);
+        }
+        let mark = self.mark();
+        match *stmt {
+            Stmt::Expr(ref expr) => self.effect(expr),
+            Stmt::Print(ref expr) => {
+                let src = self.expr_any(expr);
+                self.emit(Instr::Print { src });
+            },
+            Stmt::Var(ref var, ref init) => {
+                if let Scope::Global = var.scope() {
+                    let src = self.expr_any(init);
+                    let global = self.global(var.name());
+                    self.emit(Instr::DefineGlobal { global, src });
+                } else {
+                    // The local only comes into scope once its initializer
+                    // is compiled, and then keeps its register.
+                    let reg = self.alloc();
+                    self.expr_to(init, reg);
+                    self.free_to(mark);
+                    self.add_local(var.name());
+                    return;
+                }
+            },
+            Stmt::Block(ref stmts) => {
+                self.begin_scope();
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.end_scope();
+            },
+            Stmt::If(ref cond, ref then_clause, ref else_clause) => {
+                let else_jump = self.jump_if(cond, false);
+                self.free_to(mark);
+                self.stmt(then_clause);
+                match *else_clause {
+                    Some(ref else_clause) => {
+                        let end_jump = self.emit(Instr::Jump { target: 0 });
+                        self.patch(else_jump);
+                        self.stmt(else_clause);
+                        self.patch(end_jump);
+                    },
+                    None => self.patch(else_jump),
+                }
+            },
+            Stmt::While(ref cond, ref body) => {
+                // As in the stack VM, the condition is tested at the bottom
+                // of the loop.
+                let entry_jump = self.emit(Instr::Jump { target: 0 });
+                let top = self.ip();
+                self.state_mut().breaks.push(Vec::new());
+                self.stmt(body);
+                self.patch(entry_jump);
+                let back = self.jump_if(cond, true);
+                self.state_mut().function.code[back].set_target(top);
+                for jump in self.state_mut().breaks.pop().expect("breaks to be nonempty") {
+                    self.patch(jump);
+                }
+            },
+            // Functions can't capture the loop's variable here, so there is
+            // no need for a copy of it.
+            Stmt::ForBody(ref for_body) => {
+                self.stmt(&for_body.body);
+                if let Some(ref increment) = for_body.increment {
+                    self.effect(increment);
+                }
+            },
+            Stmt::Break(_) => {
+                let jump = self.emit(Instr::Jump { target: 0 });
+                self.state_mut().breaks.last_mut().expect("break to be inside a loop").push(jump);
+            },
+            Stmt::Function(ref f) => {
+                let decl = f.declaration.borrow();
+                if let Scope::Global = f.var.scope() {
+                    let dst = self.alloc();
+                    self.function(f.var.name(), f.pos.line, &decl, dst);
+                    let global = self.global(f.var.name());
+                    self.emit(Instr::DefineGlobal { global, src: dst });
+                } else {
+                    let dst = self.add_local(f.var.name());
+                    self.function(f.var.name(), f.pos.line, &decl, dst);
+                    return;
+                }
+            },
+            Stmt::Return(ref expr) => {
+                let src = match *expr {
+                    Some(ref expr) => self.expr_any(expr),
+                    None => {
+                        let dst = self.alloc();
+                        self.emit(Instr::Nil { dst });
+                        dst
+                    },
+                };
+                self.emit(Instr::Return { src });
+            },
+            Stmt::Class(ref class) => {
+                self.unsupported("Classes");
+                // A local class still gets a register, so that the rest of
+                // the script can be checked.
+                if let Scope::Local(_) = class.var.scope() {
+                    self.add_local(class.var.name());
+                    return;
+                }
+            },
+        }
+        self.free_to(mark);
+    }
+
+    /// Compiles a function into a constant, and loads it into `dst`.
+    fn function(&mut self, name: &str, line: usize, decl: &FunctionDecl, dst: Reg) {
+        let line = self.set_line(line);
+        let mut state = FunctionState::new(name, decl.parameters.len() as u8);
+        state.line = self.state().line;
+        self.states.push(state);
+        for param in &decl.parameters {
+            self.add_local(param.name());
+        }
+        for stmt in &decl.body {
+            self.stmt(stmt);
+        }
+        let function = self.end_function();
+        let index = self.constant(Value::Function(Rc::new(function)));
+        self.emit(Instr::Constant { dst, index });
+        self.set_line(line);
+    }
+
+    /// Compiles an expression whose value isn't used.
+    fn effect(&mut self, expr: &Expr) {
+        match expr.node {
+            ExprKind::Assign(ref var, ref value) => {
+                self.assign(var, value);
+            },
+            _ => {
+                let dst = self.alloc();
+                self.expr_to(expr, dst);
+            },
+        }
+    }
+
+    /// Compiles an expression into whichever register is handiest, and
+    /// returns that register. The value of a local is already in one.
+    fn expr_any(&mut self, expr: &Expr) -> Reg {
+        match expr.node {
+            ExprKind::Grouping(ref expr) => self.expr_any(expr),
+            ExprKind::Var(ref var) => match self.local(var) {
+                Some(reg) => reg,
+                None => {
+                    let dst = self.alloc();
+                    self.expr_to(expr, dst);
+                    dst
+                },
+            },
+            _ => {
+                let dst = self.alloc();
+                self.expr_to(expr, dst);
+                dst
+            },
+        }
+    }
+
+    /// Compiles an expression into a new temporary, even if its value is
+    /// already in a register.
+    fn expr_temp(&mut self, expr: &Expr) -> Reg {
+        let dst = self.alloc();
+        self.expr_to(expr, dst);
+        dst
+    }
+
+    /// Compiles an expression, leaving its value in `dst`.
+    fn expr_to(&mut self, expr: &Expr, dst: Reg) {
+        let line = self.set_line(expr.line());
+        let mark = self.mark();
+        match expr.node {
+            ExprKind::Literal(ref lit) => self.literal(lit, dst),
+            ExprKind::Grouping(ref expr) => self.expr_to(expr, dst),
+            ExprKind::Var(ref var) => match self.local(var) {
+                Some(src) => self.mov(dst, src),
+                None => {
+                    let global = self.global(var.name());
+                    self.emit(Instr::GetGlobal { dst, global });
+                },
+            },
+            ExprKind::Assign(ref var, ref value) => {
+                let src = self.assign(var, value);
+                self.mov(dst, src);
+            },
+            ExprKind::Binary(ref binary) => self.binary(binary, dst),
+            ExprKind::Unary(ref unary) => {
+                let src = self.expr_any(&unary.unary);
+                match unary.operator {
+                    UnaryOperator::Minus => self.emit(Instr::Negate { dst, src }),
+                    UnaryOperator::Bang => self.emit(Instr::Not { dst, src }),
+                };
+            },
+            ExprKind::Logical(ref logical) => {
+                self.expr_to(&logical.lhs, dst);
+                let jump = match logical.operator {
+                    LogicalOperator::And => self.emit(Instr::JumpIfFalse { cond: dst, target: 0 }),
+                    LogicalOperator::Or => self.emit(Instr::JumpIfTrue { cond: dst, target: 0 }),
+                };
+                self.expr_to(&logical.rhs, dst);
+                self.patch(jump);
+            },
+            ExprKind::Call(ref call) => {
+                // The callee and arguments need consecutive registers. If
+                // `dst` is the last register in use, the call can start
+                // there and leave its result where it is wanted.
+                let callee = if dst as usize + 1 == mark { dst } else { self.alloc() };
+                self.expr_to(&call.callee, callee);
+                for argument in &call.arguments {
+                    let reg = self.alloc();
+                    self.expr_to(argument, reg);
+                }
+                self.set_line(call.position.line);
+                self.emit(Instr::Call { callee, argc: call.arguments.len() as u8 });
+                self.mov(dst, callee);
+            },
+            ExprKind::Function(ref decl) => self.function(ANONYMOUS_FUNCTION, expr.line(), &decl.borrow(), dst),
+            ExprKind::Get(..) | ExprKind::Set(..) => self.unsupported("Properties"),
+            ExprKind::This(..) => self.unsupported("'this' expressions"),
+            ExprKind::Super(..) => self.unsupported("'super' expressions"),
+            ExprKind::Class(_) => self.unsupported("Classes"),
+            ExprKind::Is(..) => self.unsupported("'is' expressions"),
+        }
+        self.free_to(mark);
+        self.set_line(line);
+    }
+
+    fn mov(&mut self, dst: Reg, src: Reg) {
+        if dst != src {
+            self.emit(Instr::Move { dst, src });
+        }
+    }
+
+    /// Compiles an assignment, returning the register that holds the
+    /// assigned value.
+    fn assign(&mut self, var: &Variable, value: &Expr) -> Reg {
+        match self.local(var) {
+            Some(reg) => {
+                if writes_once(value) {
+                    self.expr_to(value, reg);
+                } else {
+                    let src = self.expr_temp(value);
+                    self.mov(reg, src);
+                }
+                reg
+            },
+            None => {
+                let src = self.expr_any(value);
+                let global = self.global(var.name());
+                self.emit(Instr::SetGlobal { global, src });
+                src
+            },
+        }
+    }
+
+    fn literal(&mut self, lit: &Literal, dst: Reg) {
+        match *lit {
+            Literal::Nil => self.emit(Instr::Nil { dst }),
+            Literal::True => self.emit(Instr::True { dst }),
+            Literal::False => self.emit(Instr::False { dst }),
+            Literal::Number(n) => {
+                let index = self.constant(Value::Number(n));
+                self.emit(Instr::Constant { dst, index })
+            },
+            Literal::String(ref s) => {
+                let index = self.constant(Value::String(Rc::new(s.clone())));
+                self.emit(Instr::Constant { dst, index })
+            },
+        };
+    }
+
+    /// Compiles both operands of a binary expression, returning their
+    /// registers.
+    fn operands(&mut self, binary: &Binary) -> (Reg, Reg) {
+        // A local operand is read from its register when the operator runs,
+        // so it mustn't be assigned to before then.
+        let lhs = if assigns(&binary.rhs) {
+            self.expr_temp(&binary.lhs)
+        } else {
+            self.expr_any(&binary.lhs)
+        };
+        let rhs = self.expr_any(&binary.rhs);
+        (lhs, rhs)
+    }
+
+    fn binary(&mut self, binary: &Binary, dst: Reg) {
+        let (lhs, rhs) = self.operands(binary);
+        match binary.operator {
+            BinaryOperator::Plus => self.emit(Instr::Add { dst, lhs, rhs }),
+            BinaryOperator::Minus => self.emit(Instr::Subtract { dst, lhs, rhs }),
+            BinaryOperator::Star => self.emit(Instr::Multiply { dst, lhs, rhs }),
+            BinaryOperator::Slash => self.emit(Instr::Divide { dst, lhs, rhs }),
+            BinaryOperator::Equal => self.emit(Instr::Equal { dst, lhs, rhs }),
+            BinaryOperator::LessThan => self.emit(Instr::Less { dst, lhs, rhs }),
+            BinaryOperator::GreaterThan => self.emit(Instr::Greater { dst, lhs, rhs }),
+            BinaryOperator::BangEq => {
+                self.emit(Instr::Equal { dst, lhs, rhs });
+                self.emit(Instr::Not { dst, src: dst })
+            },
+            BinaryOperator::LessThanEq => {
+                self.emit(Instr::Greater { dst, lhs, rhs });
+                self.emit(Instr::Not { dst, src: dst })
+            },
+            BinaryOperator::GreaterThanEq => {
+                self.emit(Instr::Less { dst, lhs, rhs });
+                self.emit(Instr::Not { dst, src: dst })
+            },
+        };
+    }
+
+    /// Emits a jump that is taken if `cond` is `when`, and returns it for
+    /// patching. Comparisons jump on their result directly.
+    fn jump_if(&mut self, cond: &Expr, when: bool) -> usize {
+        let line = self.set_line(cond.line());
+        let mark = self.mark();
+        let jump = match cond.node {
+            ExprKind::Grouping(ref cond) => self.jump_if(cond, when),
+            ExprKind::Unary(Unary { operator: UnaryOperator::Bang, ref unary }) => self.jump_if(unary, !when),
+            ExprKind::Binary(ref binary) if is_comparison(binary.operator) => {
+                let (lhs, rhs) = self.operands(binary);
+                // `a >= b` is false exactly when `a < b` is true, and so on.
+                let jump = match (binary.operator, when) {
+                    (BinaryOperator::LessThan, true) | (BinaryOperator::GreaterThanEq, false) => {
+                        Instr::JumpIfLess { lhs, rhs, target: 0 }
+                    },
+                    (BinaryOperator::LessThan, false) | (BinaryOperator::GreaterThanEq, true) => {
+                        Instr::JumpIfNotLess { lhs, rhs, target: 0 }
+                    },
+                    (BinaryOperator::GreaterThan, true) | (BinaryOperator::LessThanEq, false) => {
+                        Instr::JumpIfGreater { lhs, rhs, target: 0 }
+                    },
+                    _ => Instr::JumpIfNotGreater { lhs, rhs, target: 0 },
+                };
+                self.emit(jump)
+            },
+            _ => {
+                let cond = self.expr_any(cond);
+                if when {
+                    self.emit(Instr::JumpIfTrue { cond, target: 0 })
+                } else {
+                    self.emit(Instr::JumpIfFalse { cond, target: 0 })
+                }
+            },
+        };
+        self.free_to(mark);
+        self.set_line(line);
+        jump
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use register::code::Program;
+
+    fn function(program: &Program, name: &str) -> Rc<Function> {
+        program.script.constants.iter().filter_map(|c| match *c {
+            Value::Function(ref f) if f.name == name => Some(f.clone()),
+            _ => None,
+        }).next().unwrap()
+    }
+
+    #[test]
+    fn locals_are_used_where_they_are() {
+        let program = Program::compile("fun f(a, b) { var c = a + b; c = c * a; return c; }").unwrap();
+        let f = function(&program, "f");
+        assert_eq!(f.code[..3], [
+            Instr::Add { dst: 2, lhs: 0, rhs: 1 },
+            Instr::Multiply { dst: 2, lhs: 2, rhs: 0 },
+            Instr::Return { src: 2 },
+        ]);
+        assert_eq!(f.registers, 4);
+    }
+
+    #[test]
+    fn calls_leave_their_result_in_place() {
+        let program = Program::compile("fun f(a) { return a; } fun g() { var x = f(1); return x; }").unwrap();
+        let g = function(&program, "g");
+        assert_eq!(g.code[..4], [
+            Instr::GetGlobal { dst: 0, global: 0 },
+            Instr::Constant { dst: 1, index: 0 },
+            Instr::Call { callee: 0, argc: 1 },
+            Instr::Return { src: 0 },
+        ]);
+    }
+
+    #[test]
+    fn conditions_jump_on_comparisons() {
+        let program = Program::compile("fun f(a) { while (a <= 3) a = a + 1; if (!(a > 1)) print a; }").unwrap();
+        let f = function(&program, "f");
+        assert_eq!(f.code[3..5], [
+            Instr::Constant { dst: 1, index: 1 },
+            Instr::JumpIfNotGreater { lhs: 0, rhs: 1, target: 1 },
+        ]);
+        // The `!` is folded into the jump, which skips the `print` when
+        // `a > 1`.
+        assert_eq!(f.code[6], Instr::JumpIfGreater { lhs: 0, rhs: 1, target: 8 });
+        assert!(!f.code.iter().any(|i| match *i {
+            Instr::Less { .. } | Instr::Greater { .. } | Instr::Not { .. } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn reads_happen_before_assignments() {
+        // `a` is read before the assignment in the right operand changes it.
+        let program = Program::compile("fun f(a) { return a + (a = 2); }").unwrap();
+        let f = function(&program, "f");
+        assert_eq!(f.code[0], Instr::Move { dst: 2, src: 0 });
+    }
+
+    #[test]
+    fn unsupported_code_is_rejected() {
+        let message = |source: &str| {
+            let errors = Program::compile(source).unwrap_err();
+            errors[0].to_string()
+        };
+        assert_eq!(message("class A {}"), "[line 1] Classes are not supported by the register backend.");
+        assert_eq!(message("fun f() { var a = 1;\nfun g() { return a; } }"),
+                   "[line 2] Closures are not supported by the register backend.");
+        assert_eq!(message("var a = 1; print a.b;"), "[line 1] Properties are not supported by the register backend.");
+    }
+}
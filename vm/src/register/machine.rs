@@ -0,0 +1,308 @@
+//! Runs programs compiled for the register machine.
+//!
+//! Every frame is a window onto one array of registers, starting just after
+//! the register that held the callee, so the arguments of a call are
+//! already where the callee expects its parameters to be.
+
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use register::code::{Function, Instr, Program};
+use register::value::{Native, Value};
+use vm::{Limits, Result, RuntimeError, Traceback};
+
+struct Frame {
+    function: Rc<Function>,
+    /// The instruction to carry on from once the callee returns.
+    ip: usize,
+    base: usize,
+}
+
+pub struct Machine {
+    registers: Vec<Value>,
+    frames: Vec<Frame>,
+    globals: Vec<Option<Value>>,
+    names: Vec<String>,
+    stdout: Box<dyn Write>,
+    max_frames: usize,
+    /// The stack VM's limit on values is a limit on registers here.
+    max_registers: usize,
+    max_string_len: usize,
+    start_time: Instant,
+    instructions: usize,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new(&Limits::default())
+    }
+}
+
+/// Stops with `$err` at the instruction before `$ip`.
+macro_rules! fail (
+    ($self:ident, $ip:expr, $err:expr) => ({
+        $self.frames.last_mut().expect("frames to be nonempty").ip = $ip;
+        return Err($err);
+    });
+);
+
+macro_rules! number_op (
+    ($self:ident, $ip:expr, $base:expr, $dst:expr, $lhs:expr, $rhs:expr, $op:tt, $wrap:path) => ({
+        let result = match (&$self.registers[$base + $lhs as usize], &$self.registers[$base + $rhs as usize]) {
+            (&Value::Number(a), &Value::Number(b)) => $wrap(a $op b),
+            _ => fail!($self, $ip, RuntimeError::BadArgument("Operands must be numbers")),
+        };
+        $self.registers[$base + $dst as usize] = result;
+    });
+);
+
+macro_rules! compare_and_jump (
+    ($self:ident, $ip:ident, $base:expr, $lhs:expr, $rhs:expr, $op:tt, $when:expr, $target:expr) => ({
+        match (&$self.registers[$base + $lhs as usize], &$self.registers[$base + $rhs as usize]) {
+            (&Value::Number(a), &Value::Number(b)) => if (a $op b) == $when {
+                $ip = $target as usize;
+            },
+            _ => fail!($self, $ip, RuntimeError::BadArgument("Operands must be numbers")),
+        }
+    });
+);
+
+impl Machine {
+    pub fn new(limits: &Limits) -> Self {
+        Machine {
+            registers: Vec::new(),
+            frames: Vec::new(),
+            globals: Vec::new(),
+            names: Vec::new(),
+            stdout: Box::new(io::stdout()),
+            max_frames: limits.max_frames,
+            max_registers: limits.max_stack,
+            max_string_len: limits.max_string_len,
+            start_time: Instant::now(),
+            instructions: 0,
+        }
+    }
+
+    /// Redirects the output of `print` statements, which goes to stdout by
+    /// default.
+    pub fn set_stdout<W: Write + 'static>(&mut self, stdout: W) {
+        self.stdout = Box::new(stdout);
+    }
+
+    /// How many instructions have been executed.
+    pub fn instruction_count(&self) -> usize {
+        self.instructions
+    }
+
+    /// Runs a program, returning what the script returned. Each program
+    /// starts with only the natives defined, since globals are numbered
+    /// separately by each program.
+    pub fn run(&mut self, program: &Program) -> ::std::result::Result<Value, Traceback> {
+        self.names = program.globals.clone();
+        self.globals = program.globals.iter()
+            .map(|name| Native::from_name(name).map(Value::Native))
+            .collect();
+        self.registers.clear();
+        self.registers.resize(program.script.registers, Value::Nil);
+        self.frames.push(Frame { function: program.script.clone(), ip: 0, base: 0 });
+        let res = self.execute();
+        let res = res.map_err(|error| {
+            let frames = self.frames.iter().rev().map(|frame| {
+                // The ip is past the instruction that failed or made the call.
+                let ip = frame.ip.saturating_sub(1);
+                (frame.function.lines.get(ip).cloned().unwrap_or(0), frame.function.name.clone())
+            });
+            Traceback::new(error, frames.collect())
+        });
+        self.frames.clear();
+        self.registers.clear();
+        res
+    }
+
+    fn execute(&mut self) -> Result<Value> {
+        let (mut function, mut base) = {
+            let frame = self.frames.last().expect("frames to be nonempty");
+            (frame.function.clone(), frame.base)
+        };
+        let mut ip = 0;
+        loop {
+            let instr = function.code[ip];
+            ip += 1;
+            self.instructions += 1;
+            match instr {
+                Instr::Nil { dst } => self.registers[base + dst as usize] = Value::Nil,
+                Instr::True { dst } => self.registers[base + dst as usize] = Value::Bool(true),
+                Instr::False { dst } => self.registers[base + dst as usize] = Value::Bool(false),
+                Instr::Constant { dst, index } => {
+                    self.registers[base + dst as usize] = function.constants[index as usize].clone();
+                },
+                Instr::Move { dst, src } => {
+                    self.registers[base + dst as usize] = self.registers[base + src as usize].clone();
+                },
+                Instr::GetGlobal { dst, global } => {
+                    let value = match self.globals[global as usize] {
+                        Some(ref value) => value.clone(),
+                        None => fail!(self, ip, RuntimeError::UndefinedVariable(self.names[global as usize].clone())),
+                    };
+                    self.registers[base + dst as usize] = value;
+                },
+                // As in the stack VM, assigning to a global that hasn't been
+                // defined defines it.
+                Instr::SetGlobal { global, src } | Instr::DefineGlobal { global, src } => {
+                    self.globals[global as usize] = Some(self.registers[base + src as usize].clone());
+                },
+                Instr::Add { dst, lhs, rhs } => {
+                    let result = match (&self.registers[base + lhs as usize], &self.registers[base + rhs as usize]) {
+                        (&Value::Number(a), &Value::Number(b)) => Value::Number(a + b),
+                        (&Value::String(ref a), &Value::String(ref b)) => {
+                            if a.len() + b.len() > self.max_string_len {
+                                fail!(self, ip, RuntimeError::AllocationLimit);
+                            }
+                            let mut s = String::with_capacity(a.len() + b.len());
+                            s.push_str(a);
+                            s.push_str(b);
+                            Value::String(Rc::new(s))
+                        },
+                        _ => fail!(self, ip, RuntimeError::BadArgument("Operands must be two numbers or two strings")),
+                    };
+                    self.registers[base + dst as usize] = result;
+                },
+                Instr::Subtract { dst, lhs, rhs } => number_op!(self, ip, base, dst, lhs, rhs, -, Value::Number),
+                Instr::Multiply { dst, lhs, rhs } => number_op!(self, ip, base, dst, lhs, rhs, *, Value::Number),
+                Instr::Divide { dst, lhs, rhs } => {
+                    let result = match (&self.registers[base + lhs as usize], &self.registers[base + rhs as usize]) {
+                        (&Value::Number(a), &Value::Number(b)) => {
+                            if b == 0.0 {
+                                fail!(self, ip, RuntimeError::DivideByZero);
+                            }
+                            Value::Number(a / b)
+                        },
+                        _ => fail!(self, ip, RuntimeError::BadArgument("Operands must be numbers")),
+                    };
+                    self.registers[base + dst as usize] = result;
+                },
+                Instr::Less { dst, lhs, rhs } => number_op!(self, ip, base, dst, lhs, rhs, <, Value::Bool),
+                Instr::Greater { dst, lhs, rhs } => number_op!(self, ip, base, dst, lhs, rhs, >, Value::Bool),
+                Instr::Equal { dst, lhs, rhs } => {
+                    let equal = self.registers[base + lhs as usize].equals(&self.registers[base + rhs as usize]);
+                    self.registers[base + dst as usize] = Value::Bool(equal);
+                },
+                Instr::Not { dst, src } => {
+                    let truthy = self.registers[base + src as usize].truthy();
+                    self.registers[base + dst as usize] = Value::Bool(!truthy);
+                },
+                Instr::Negate { dst, src } => {
+                    let result = match self.registers[base + src as usize] {
+                        Value::Number(n) => Value::Number(-n),
+                        _ => fail!(self, ip, RuntimeError::BadArgument("Operand must be a number")),
+                    };
+                    self.registers[base + dst as usize] = result;
+                },
+                Instr::Print { src } => {
+                    let res = writeln!(self.stdout, "{}", self.registers[base + src as usize]);
+                    if let Err(err) = res {
+                        fail!(self, ip, RuntimeError::Output(err.to_string()));
+                    }
+                },
+                Instr::Jump { target } => ip = target as usize,
+                Instr::JumpIfFalse { cond, target } => if !self.registers[base + cond as usize].truthy() {
+                    ip = target as usize;
+                },
+                Instr::JumpIfTrue { cond, target } => if self.registers[base + cond as usize].truthy() {
+                    ip = target as usize;
+                },
+                Instr::JumpIfLess { lhs, rhs, target } => compare_and_jump!(self, ip, base, lhs, rhs, <, true, target),
+                Instr::JumpIfNotLess { lhs, rhs, target } => {
+                    compare_and_jump!(self, ip, base, lhs, rhs, <, false, target)
+                },
+                Instr::JumpIfGreater { lhs, rhs, target } => {
+                    compare_and_jump!(self, ip, base, lhs, rhs, >, true, target)
+                },
+                Instr::JumpIfNotGreater { lhs, rhs, target } => {
+                    compare_and_jump!(self, ip, base, lhs, rhs, >, false, target)
+                },
+                Instr::Call { callee, argc } => {
+                    let callee = base + callee as usize;
+                    let target = match self.registers[callee] {
+                        Value::Function(ref target) => target.clone(),
+                        Value::Native(native) => {
+                            if native.arity() != argc {
+                                fail!(self, ip, RuntimeError::ArityMismatch(native.arity(), argc));
+                            }
+                            self.registers[callee] = self.call_native(native);
+                            continue;
+                        },
+                        _ => fail!(self, ip, RuntimeError::BadCall),
+                    };
+                    if target.arity != argc {
+                        fail!(self, ip, RuntimeError::ArityMismatch(target.arity, argc));
+                    }
+                    if self.frames.len() == self.max_frames || callee + 1 + target.registers > self.max_registers {
+                        fail!(self, ip, RuntimeError::StackOverflow);
+                    }
+                    self.frames.last_mut().expect("frames to be nonempty").ip = ip;
+                    base = callee + 1;
+                    if self.registers.len() < base + target.registers {
+                        self.registers.resize(base + target.registers, Value::Nil);
+                    }
+                    self.frames.push(Frame { function: target.clone(), ip: 0, base });
+                    function = target;
+                    ip = 0;
+                },
+                Instr::Return { src } => {
+                    let value = self.registers[base + src as usize].clone();
+                    self.frames.pop();
+                    match self.frames.last() {
+                        Some(frame) => {
+                            // The result replaces the callee, just below the
+                            // frame that is returning.
+                            self.registers[base - 1] = value;
+                            function = frame.function.clone();
+                            base = frame.base;
+                            ip = frame.ip;
+                        },
+                        None => return Ok(value),
+                    }
+                },
+            }
+        }
+    }
+
+    fn call_native(&self, native: Native) -> Value {
+        match native {
+            Native::Clock => Value::Number(self.start_time.elapsed().as_secs_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_what_the_script_returns() {
+        let program = Program::compile("fun f(a, b) { return a * b; } return f(6, 7);").unwrap();
+        match Machine::default().run(&program) {
+            Ok(Value::Number(n)) => assert_eq!(n, 42.0),
+            res => panic!("{:?}", res.map(|v| v.to_string()).map_err(|tb| tb.to_string())),
+        }
+    }
+
+    #[test]
+    fn tracebacks_name_each_call() {
+        let program = Program::compile("fun f(a) {\n  return a + nil;\n}\nf(1);").unwrap();
+        let traceback = Machine::default().run(&program).unwrap_err();
+        assert_eq!(traceback.error().to_string(), "Operands must be two numbers or two strings");
+        assert_eq!(traceback.frames(), &[(2, "f".to_owned()), (4, "<top>".to_owned())]);
+    }
+
+    #[test]
+    fn deep_recursion_overflows() {
+        let program = Program::compile("fun f(n) { return f(n + 1); } f(0);").unwrap();
+        let mut machine = Machine::new(&Limits { max_frames: 64, ..Limits::default() });
+        assert_eq!(machine.run(&program).unwrap_err().error().to_string(), "Stack overflow");
+        // The machine can run another program afterwards.
+        assert!(machine.run(&Program::compile("return 1;").unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,23 @@
+//! An experimental backend that compiles scripts for a register machine
+//! rather than for the stack VM.
+//!
+//! The stack VM spends much of its time moving values around: loading
+//! locals onto the stack to use them, and popping whatever statements leave
+//! behind. Here each instruction names the registers it reads and writes
+//! instead, and every local lives in a register of its own, so `a = b + c`
+//! between locals is a single instruction.
+//!
+//! Only part of the language is supported so far: globals and locals,
+//! control flow, functions that don't capture variables, and the `clock`
+//! native. Scripts that use anything else are rejected when they are
+//! compiled, rather than failing part way through running.
+
+pub mod code;
+pub mod value;
+pub mod compile;
+pub mod machine;
+
+pub use self::code::{Function, Instr, Program, Reg};
+pub use self::compile::CompileError;
+pub use self::machine::Machine;
+pub use self::value::Value;
@@ -0,0 +1,84 @@
+//! Values of the register machine. These are reference counted rather than
+//! kept on the garbage collected heap, which is enough while functions
+//! can't capture variables or build objects that refer to each other.
+
+use std::fmt;
+use std::rc::Rc;
+
+use parser::ast::ANONYMOUS_FUNCTION;
+
+use register::code::Function;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<String>),
+    Function(Rc<Function>),
+    Native(Native),
+}
+
+/// The natives that the register machine provides.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Native {
+    Clock,
+}
+
+impl Native {
+    pub fn from_name(name: &str) -> Option<Native> {
+        match name {
+            "clock" => Some(Native::Clock),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Native::Clock => "clock",
+        }
+    }
+
+    pub fn arity(self) -> u8 {
+        match self {
+            Native::Clock => 0,
+        }
+    }
+}
+
+impl Value {
+    pub fn truthy(&self) -> bool {
+        match *self {
+            Value::Nil | Value::Bool(false) => false,
+            _ => true,
+        }
+    }
+
+    /// Compares values the way `==` does in the stack VM: strings by their
+    /// contents and functions by identity.
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (&Value::Nil, &Value::Nil) => true,
+            (&Value::Bool(a), &Value::Bool(b)) => a == b,
+            (&Value::Number(a), &Value::Number(b)) => a == b,
+            (&Value::String(ref a), &Value::String(ref b)) => a == b,
+            (&Value::Function(ref a), &Value::Function(ref b)) => Rc::ptr_eq(a, b),
+            (&Value::Native(a), &Value::Native(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(ref s) => write!(f, "{}", s),
+            Value::Function(ref function) if function.name == ANONYMOUS_FUNCTION => write!(f, "<fn>"),
+            Value::Function(ref function) => write!(f, "<fn {}>", function.name),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name()),
+        }
+    }
+}
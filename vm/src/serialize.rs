@@ -0,0 +1,358 @@
+//! Reading and writing compiled programs as `.loxc` files.
+//!
+//! The layout is a magic header and format version followed by the pool of
+//! global names and then the top-level function. Functions are written
+//! depth-first, so nested functions appear inline within their enclosing
+//! function's constant table.
+//!
+//! ```text
+//! file      → "LOXC" version:u16 names:u32 string* function
+//! function  → name:string arity:u8 upvalues:u32 chunk
+//! chunk     → code:bytes lines:u32 (start:u32 line:u32)* constants:u32 constant* locals caches:u32
+//! locals    → count:u32 (name:string slot:u8 start:u32 end:u32)*
+//! constant  → 0x00 f64 | 0x01 string | 0x02 function
+//! string    → len:u32 utf8*
+//! ```
+//!
+//! All integers are little-endian.
+
+use std::rc::Rc;
+
+use broom::Heap;
+
+use chunk::{Chunk, GlobalPool, LineInfo, LocalInfo, MAX_CACHES};
+use gc::object::{Object, LoxFunction};
+use gc::value::{Value, Variant};
+use verify::VerifyError;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 7;
+
+const TAG_FLOAT: u8 = 0x00;
+const TAG_STRING: u8 = 0x01;
+const TAG_FUNCTION: u8 = 0x02;
+
+#[derive(Debug, Fail)]
+pub enum LoadError {
+    #[fail(display = "Not a compiled lox file.")]
+    BadMagic,
+    #[fail(display = "Unsupported bytecode version {}.", _0)]
+    UnsupportedVersion(u16),
+    #[fail(display = "Unexpected end of bytecode.")]
+    UnexpectedEOF,
+    #[fail(display = "Invalid string constant.")]
+    InvalidString,
+    #[fail(display = "Unknown constant tag {:#04x}.", _0)]
+    UnknownConstant(u8),
+    #[fail(display = "Function '{}' does not end in a return.", _0)]
+    Unterminated(String),
+    #[fail(display = "Function '{}' has too many method lookups.", _0)]
+    TooManyCaches(String),
+    #[fail(display = "{}", _0)]
+    Invalid(#[cause] VerifyError),
+}
+
+impl From<VerifyError> for LoadError {
+    fn from(err: VerifyError) -> Self {
+        LoadError::Invalid(err)
+    }
+}
+
+type Result<T> = ::std::result::Result<T, LoadError>;
+
+/// Returns true if `bytes` looks like the output of `serialize`.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(function: &LoxFunction, names: &[String], heap: &Heap<Object>) -> Vec<u8> {
+    serialize_with(function, names, heap, false)
+}
+
+/// Like `serialize`, but with `strip` the names of local variables are left
+/// out, which makes the file smaller at the cost of less helpful debugging.
+pub fn serialize_with(function: &LoxFunction, names: &[String], heap: &Heap<Object>, strip: bool) -> Vec<u8> {
+    let mut writer = Writer::new(heap);
+    writer.set_strip(strip);
+    writer.bytes(MAGIC);
+    writer.u16(VERSION);
+    writer.u32(names.len());
+    for name in names {
+        writer.string(name);
+    }
+    writer.function(function);
+    writer.into_bytes()
+}
+
+/// Loads a program, calling `resolve` with each of its global names to find
+/// the slot that the VM which will run it keeps the global in.
+pub fn deserialize(bytes: &[u8], heap: &mut Heap<Object>, resolve: &mut dyn FnMut(&str) -> usize)
+    -> Result<(LoxFunction, Vec<String>)>
+{
+    let mut reader = Reader::new(bytes, heap);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(LoadError::BadMagic);
+    }
+    match reader.u16()? {
+        VERSION => {},
+        version => return Err(LoadError::UnsupportedVersion(version)),
+    }
+    let name_count = reader.u32()?;
+    let mut names = Vec::with_capacity(name_count.min(reader.remaining()));
+    for _ in 0..name_count {
+        names.push(reader.string()?);
+    }
+    let slots = names.iter().map(|name| resolve(name)).collect();
+    reader.set_globals(Rc::new(GlobalPool { names, slots }));
+    let function = reader.function()?;
+    Ok((function, reader.globals.names.to_vec()))
+}
+
+/// Writes the parts of the format, which snapshots of the VM share.
+pub struct Writer<'h> {
+    buf: Vec<u8>,
+    heap: &'h Heap<Object>,
+    strip: bool,
+}
+
+impl<'h> Writer<'h> {
+    pub fn new(heap: &'h Heap<Object>) -> Self {
+        Writer {
+            buf: Vec::new(),
+            heap,
+            strip: false,
+        }
+    }
+
+    /// Leaves the local variable table out of every chunk written from now on.
+    pub fn set_strip(&mut self, strip: bool) {
+        self.strip = strip;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: usize) {
+        self.buf.extend_from_slice(&(val as u32).to_le_bytes());
+    }
+
+    pub fn f64(&mut self, val: f64) {
+        self.buf.extend_from_slice(&val.to_bits().to_le_bytes());
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.u32(s.len());
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    /// Writes `function` along with every function nested within it, but not
+    /// the global names that it refers to.
+    pub fn function(&mut self, function: &LoxFunction) {
+        self.string(function.name());
+        self.buf.push(function.arity());
+        self.u32(function.upvalue_count());
+        self.chunk(function.chunk());
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        let code = chunk.code();
+        self.u32(code.len());
+        self.buf.extend_from_slice(&code);
+
+        let lines = chunk.lines();
+        self.u32(lines.len());
+        for info in lines {
+            self.u32(info.start);
+            self.u32(info.line);
+        }
+
+        let constants = chunk.constants().collect::<Vec<_>>();
+        self.u32(constants.len());
+        for constant in constants {
+            self.constant(constant);
+        }
+
+        let locals = if self.strip { &[] } else { chunk.locals() };
+        self.u32(locals.len());
+        for local in locals {
+            self.string(&local.name);
+            self.buf.push(local.slot);
+            self.u32(local.start);
+            self.u32(local.end);
+        }
+
+        self.u32(chunk.cache_count());
+    }
+
+    fn constant(&mut self, constant: Value) {
+        match constant.decode() {
+            Variant::Float(f) => {
+                self.buf.push(TAG_FLOAT);
+                self.f64(f);
+            },
+            Variant::Obj(handle) => {
+                match self.heap.get(handle).expect("constant to be live") {
+                    Object::String(ref s) => {
+                        self.buf.push(TAG_STRING);
+                        self.string(s);
+                    },
+                    Object::LoxFunction(ref f) => {
+                        self.buf.push(TAG_FUNCTION);
+                        self.function(f);
+                    },
+                    o => panic!("unexpected object in constant table: {:?}", o),
+                }
+            },
+            v => panic!("unexpected value in constant table: {:?}", v),
+        }
+    }
+}
+
+/// Reads what `Writer` writes.
+pub struct Reader<'b, 'h> {
+    bytes: &'b [u8],
+    offset: usize,
+    heap: &'h mut Heap<Object>,
+    globals: Rc<GlobalPool>,
+}
+
+impl<'b, 'h> Reader<'b, 'h> {
+    pub fn new(bytes: &'b [u8], heap: &'h mut Heap<Object>) -> Self {
+        Reader {
+            bytes,
+            offset: 0,
+            heap,
+            globals: Rc::new(GlobalPool::default()),
+        }
+    }
+
+    pub fn heap(&mut self) -> &mut Heap<Object> {
+        self.heap
+    }
+
+    /// The global names that the functions read next refer to.
+    pub fn set_globals(&mut self, globals: Rc<GlobalPool>) {
+        self.globals = globals;
+    }
+
+    /// How many bytes are left to read, which also bounds how many items a
+    /// count read from the bytes can really have.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'b [u8]> {
+        let end = self.offset + len;
+        if end > self.bytes.len() {
+            return Err(LoadError::UnexpectedEOF);
+        }
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<usize> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn f64(&mut self) -> Result<f64> {
+        // Values hide tags and handles in the payload of a NaN, so a NaN
+        // from a file has to lose its payload before it becomes a value.
+        let float = f64::from_bits(self.u64()?);
+        Ok(if float.is_nan() { f64::NAN } else { float })
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        ::std::str::from_utf8(bytes)
+            .map(ToOwned::to_owned)
+            .map_err(|_| LoadError::InvalidString)
+    }
+
+    pub fn function(&mut self) -> Result<LoxFunction> {
+        let name = self.string()?;
+        let arity = self.u8()?;
+        let upvalue_count = self.u32()?;
+        let chunk = self.chunk(&name)?;
+        Ok(LoxFunction::from_parts(name, arity, upvalue_count, chunk))
+    }
+
+    fn chunk(&mut self, name: &str) -> Result<Chunk> {
+        let len = self.u32()?;
+        let code = self.take(len)?.to_vec();
+
+        let line_count = self.u32()?;
+        let mut lines = Vec::with_capacity(line_count.min(self.remaining()));
+        for _ in 0..line_count {
+            let start = self.u32()?;
+            let line = self.u32()?;
+            lines.push(LineInfo { start, line });
+        }
+
+        let constant_count = self.u32()?;
+        let mut constants = Vec::with_capacity(constant_count.min(self.remaining()));
+        for _ in 0..constant_count {
+            constants.push(self.constant()?);
+        }
+        let local_count = self.u32()?;
+        let mut locals = Vec::with_capacity(local_count.min(self.remaining()));
+        for _ in 0..local_count {
+            let name = self.string()?;
+            let slot = self.u8()?;
+            let start = self.u32()?;
+            let end = self.u32()?;
+            locals.push(LocalInfo { name, slot, start, end });
+        }
+        let caches = self.u32()?;
+        if caches > MAX_CACHES {
+            return Err(LoadError::TooManyCaches(name.to_owned()));
+        }
+
+        let globals = self.globals.clone();
+        let chunk = Chunk::from_parts(name.to_owned(), code, constants, lines, locals, caches, globals);
+        if !chunk.is_terminated() {
+            return Err(LoadError::Unterminated(name.to_owned()));
+        }
+        Ok(chunk)
+    }
+
+    fn constant(&mut self) -> Result<Value> {
+        let object = match self.u8()? {
+            TAG_FLOAT => return Ok(Value::float(self.f64()?)),
+            TAG_STRING => Object::String(self.string()?),
+            TAG_FUNCTION => Object::LoxFunction(self.function()?),
+            tag => return Err(LoadError::UnknownConstant(tag)),
+        };
+        Ok(self.heap.insert(object).into_handle().into())
+    }
+}
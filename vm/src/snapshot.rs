@@ -0,0 +1,604 @@
+//! Saving the state of a VM as bytes and restoring it later.
+//!
+//! A snapshot holds everything that scripts can reach: globals, the
+//! callbacks subscribed to events and, for a snapshot taken by a native, the
+//! stack and call frames of the script that called it. Every object is
+//! written once and referred to by its index, so that shared and cyclic
+//! references survive being restored. Functions are written by the bytecode
+//! serializer along with an index into the pools of global names, since each
+//! program has a pool of its own. Natives are written by name and restored
+//! as the native of the same name in the VM that loads the snapshot.
+//!
+//! ```text
+//! snapshot → "LOXS" version:u16 pools:u32 pool* objects:u32 upvalues:u32 upvalue* object*
+//!            globals:u32 (name:string value)* hooks:u32 (event:string count:u32 value*)*
+//!            stack:u32 value* frames:u32 (closure:u32 ip:u32 start:u32)* open:u32 upvalue:u32*
+//! pool     → count:u32 string*
+//! upvalue  → 0x00 slot:u32 | 0x01 value
+//! super    → 0x00 | 0x01 class:u32                                 no superclass, superclass
+//! object   → 0x00 string
+//!          | 0x01 pool:u32 function
+//!          | 0x02 pool:u32 function count:u32 upvalue:u32*          closure
+//!          | 0x03 name:string super count:u32 (name:string object:u32)*
+//!                                                                   class
+//!          | 0x04 class:u32 count:u32 (name:string value)*          instance
+//!          | 0x05 name:string                                       native
+//!          | 0x06 receiver:u32 closure:u32                          bound method
+//!          | 0x07 class:u32 closure:u32                             unbound method
+//! value    → 0x00 f64 | 0x01 | 0x02 | 0x03 | 0x04 object:u32      number, nil, true, false, object
+//! ```
+//!
+//! Strings and integers are encoded as in `serialize`.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use broom::{Handle, Heap};
+use fnv::FnvBuildHasher;
+
+use chunk::GlobalPool;
+use gc::object::{BoundMethod, LoxClass, LoxClosure, LoxFunction, LoxInstance, LoxUpValue, NativeFunction, Object,
+                 UnboundMethod};
+use gc::value::{Value, Variant};
+use serialize::{LoadError, Reader, Writer};
+use verify;
+
+pub const MAGIC: &[u8; 4] = b"LOXS";
+pub const VERSION: u16 = 2;
+
+const TAG_STRING: u8 = 0x00;
+const TAG_FUNCTION: u8 = 0x01;
+const TAG_CLOSURE: u8 = 0x02;
+const TAG_CLASS: u8 = 0x03;
+const TAG_INSTANCE: u8 = 0x04;
+const TAG_NATIVE: u8 = 0x05;
+const TAG_BOUND_METHOD: u8 = 0x06;
+const TAG_UNBOUND_METHOD: u8 = 0x07;
+
+const VALUE_FLOAT: u8 = 0x00;
+const VALUE_NIL: u8 = 0x01;
+const VALUE_TRUE: u8 = 0x02;
+const VALUE_FALSE: u8 = 0x03;
+const VALUE_OBJECT: u8 = 0x04;
+
+const UPVALUE_OPEN: u8 = 0x00;
+const UPVALUE_CLOSED: u8 = 0x01;
+
+const SUPER_NONE: u8 = 0x00;
+const SUPER_CLASS: u8 = 0x01;
+
+#[derive(Debug, Fail)]
+pub enum SnapshotError {
+    #[fail(display = "Can only take a snapshot from a native that a script called directly.")]
+    Nested,
+    #[fail(display = "Cannot restore a snapshot while a script is running.")]
+    Running,
+    #[fail(display = "The snapshot uses a native '{}' that isn't defined.", _0)]
+    UnknownNative(String),
+    #[fail(display = "The snapshot subscribes to an event '{}' that isn't declared.", _0)]
+    UnknownEvent(String),
+    #[fail(display = "Invalid snapshot.")]
+    Invalid,
+    #[fail(display = "{}", _0)]
+    Load(#[cause] LoadError),
+}
+
+impl From<LoadError> for SnapshotError {
+    fn from(err: LoadError) -> Self {
+        SnapshotError::Load(err)
+    }
+}
+
+type Result<T> = ::std::result::Result<T, SnapshotError>;
+
+/// What a snapshot holds apart from the objects themselves.
+#[derive(Default)]
+pub struct State {
+    pub globals: Vec<(String, Value)>,
+    pub hooks: Vec<(String, Vec<Value>)>,
+    pub stack: Vec<Value>,
+    /// The closure, ip and start of the stack of each call frame.
+    pub frames: Vec<(Handle<Object>, usize, usize)>,
+    pub open_upvalues: Vec<LoxUpValue>,
+}
+
+pub fn write(state: &State, heap: &Heap<Object>) -> Vec<u8> {
+    let mut graph = Graph::default();
+    let roots = state.globals.iter().map(|global| global.1)
+        .chain(state.hooks.iter().flat_map(|hook| hook.1.iter().cloned()))
+        .chain(state.stack.iter().cloned());
+    for value in roots {
+        graph.value(value);
+    }
+    for frame in &state.frames {
+        graph.object(frame.0);
+    }
+    for upvalue in &state.open_upvalues {
+        graph.upvalue(upvalue);
+    }
+    graph.trace(heap);
+
+    let mut writer = Writer::new(heap);
+    writer.bytes(MAGIC);
+    writer.u16(VERSION);
+    writer.u32(graph.pools.len());
+    for pool in &graph.pools {
+        writer.u32(pool.len());
+        for name in pool.iter() {
+            writer.string(name);
+        }
+    }
+    writer.u32(graph.objects.len());
+    writer.u32(graph.upvalues.len());
+    for upvalue in &graph.upvalues {
+        match upvalue.get() {
+            Err(slot) => {
+                writer.u8(UPVALUE_OPEN);
+                writer.u32(slot);
+            },
+            Ok(value) => {
+                writer.u8(UPVALUE_CLOSED);
+                graph.write_value(&mut writer, value);
+            },
+        }
+    }
+    for &handle in &graph.objects {
+        graph.write_object(&mut writer, heap.get(handle).expect("object to be live"));
+    }
+
+    writer.u32(state.globals.len());
+    for &(ref name, value) in &state.globals {
+        writer.string(name);
+        graph.write_value(&mut writer, value);
+    }
+    writer.u32(state.hooks.len());
+    for &(ref event, ref callbacks) in &state.hooks {
+        writer.string(event);
+        writer.u32(callbacks.len());
+        for &callback in callbacks {
+            graph.write_value(&mut writer, callback);
+        }
+    }
+    writer.u32(state.stack.len());
+    for &value in &state.stack {
+        graph.write_value(&mut writer, value);
+    }
+    writer.u32(state.frames.len());
+    for &(closure, ip, start) in &state.frames {
+        writer.u32(graph.id(closure));
+        writer.u32(ip);
+        writer.u32(start);
+    }
+    writer.u32(state.open_upvalues.len());
+    for upvalue in &state.open_upvalues {
+        writer.u32(graph.upvalue_ids[&upvalue.id()]);
+    }
+    writer.into_bytes()
+}
+
+/// Every object, upvalue and pool of global names reachable from a
+/// snapshot's roots, in the order that they are written.
+#[derive(Default)]
+struct Graph<'h> {
+    objects: Vec<Handle<Object>>,
+    ids: HashMap<u64, usize>,
+    upvalues: Vec<LoxUpValue>,
+    upvalue_ids: HashMap<usize, usize>,
+    pools: Vec<&'h [String]>,
+    pool_ids: HashMap<usize, usize>,
+}
+
+impl<'h> Graph<'h> {
+    fn object(&mut self, handle: Handle<Object>) -> usize {
+        let objects = &mut self.objects;
+        *self.ids.entry(Value::from(handle).to_raw()).or_insert_with(|| {
+            objects.push(handle);
+            objects.len() - 1
+        })
+    }
+
+    fn value(&mut self, value: Value) {
+        if let Some(handle) = value.as_object() {
+            self.object(handle);
+        }
+    }
+
+    fn upvalue(&mut self, upvalue: &LoxUpValue) -> usize {
+        if let Some(&id) = self.upvalue_ids.get(&upvalue.id()) {
+            return id;
+        }
+        let id = self.upvalues.len();
+        self.upvalues.push(upvalue.clone());
+        self.upvalue_ids.insert(upvalue.id(), id);
+        if let Ok(value) = upvalue.get() {
+            self.value(value);
+        }
+        id
+    }
+
+    fn pool(&mut self, names: &'h [String]) -> usize {
+        let pools = &mut self.pools;
+        *self.pool_ids.entry(names.as_ptr() as usize).or_insert_with(|| {
+            pools.push(names);
+            pools.len() - 1
+        })
+    }
+
+    /// Finds everything reachable from the objects found so far.
+    fn trace(&mut self, heap: &'h Heap<Object>) {
+        let mut next = 0;
+        while next < self.objects.len() {
+            let handle = self.objects[next];
+            next += 1;
+            match *heap.get(handle).expect("object to be live") {
+                Object::String(_) | Object::NativeFunction(_) => {},
+                Object::LoxFunction(ref function) => {
+                    self.pool(function.chunk().global_names());
+                },
+                Object::LoxClosure(ref closure) => {
+                    self.pool(closure.chunk().global_names());
+                    for i in 0..closure.upvalue_count() {
+                        self.upvalue(&closure.get(i));
+                    }
+                },
+                Object::LoxClass(ref class) => {
+                    if let Some(superclass) = class.superclass() {
+                        self.object(superclass);
+                    }
+                    for (_, &method) in class.methods() {
+                        self.object(method);
+                    }
+                },
+                Object::LoxInstance(ref instance) => {
+                    self.object(instance.class());
+                    for name in instance.field_names() {
+                        self.value(instance.get_property(name).expect("field to exist"));
+                    }
+                },
+                Object::BoundMethod(ref bound) => {
+                    self.object(bound.receiver);
+                    self.object(bound.closure);
+                },
+                Object::UnboundMethod(ref unbound) => {
+                    self.object(unbound.class);
+                    self.object(unbound.closure);
+                },
+            }
+        }
+    }
+
+    fn id(&self, handle: Handle<Object>) -> usize {
+        self.ids[&Value::from(handle).to_raw()]
+    }
+
+    fn write_value(&self, writer: &mut Writer, value: Value) {
+        match value.decode() {
+            Variant::Float(n) => {
+                writer.u8(VALUE_FLOAT);
+                writer.f64(n);
+            },
+            Variant::Nil => writer.u8(VALUE_NIL),
+            Variant::True => writer.u8(VALUE_TRUE),
+            Variant::False => writer.u8(VALUE_FALSE),
+            Variant::Obj(handle) => {
+                writer.u8(VALUE_OBJECT);
+                writer.u32(self.id(handle));
+            },
+        }
+    }
+
+    fn write_function(&self, writer: &mut Writer, function: &LoxFunction) {
+        writer.u32(self.pool_ids[&(function.chunk().global_names().as_ptr() as usize)]);
+        writer.function(function);
+    }
+
+    fn write_object(&self, writer: &mut Writer, object: &Object) {
+        match *object {
+            Object::String(ref s) => {
+                writer.u8(TAG_STRING);
+                writer.string(s);
+            },
+            Object::LoxFunction(ref function) => {
+                writer.u8(TAG_FUNCTION);
+                self.write_function(writer, function);
+            },
+            Object::LoxClosure(ref closure) => {
+                writer.u8(TAG_CLOSURE);
+                self.write_function(writer, closure.function());
+                writer.u32(closure.upvalue_count());
+                for i in 0..closure.upvalue_count() {
+                    writer.u32(self.upvalue_ids[&closure.get(i).id()]);
+                }
+            },
+            Object::LoxClass(ref class) => {
+                writer.u8(TAG_CLASS);
+                writer.string(class.name());
+                match class.superclass() {
+                    Some(superclass) => {
+                        writer.u8(SUPER_CLASS);
+                        writer.u32(self.id(superclass));
+                    },
+                    None => writer.u8(SUPER_NONE),
+                }
+                let mut methods = class.methods().collect::<Vec<_>>();
+                methods.sort_by_key(|method| method.0);
+                writer.u32(methods.len());
+                for (name, &method) in methods {
+                    writer.string(name);
+                    writer.u32(self.id(method));
+                }
+            },
+            Object::LoxInstance(ref instance) => {
+                writer.u8(TAG_INSTANCE);
+                writer.u32(self.id(instance.class()));
+                let names = instance.field_names();
+                writer.u32(names.len());
+                for name in names {
+                    writer.string(name);
+                    self.write_value(writer, instance.get_property(name).expect("field to exist"));
+                }
+            },
+            Object::NativeFunction(ref native) => {
+                writer.u8(TAG_NATIVE);
+                writer.string(&native.name);
+            },
+            Object::BoundMethod(ref bound) => {
+                writer.u8(TAG_BOUND_METHOD);
+                writer.u32(self.id(bound.receiver));
+                writer.u32(self.id(bound.closure));
+            },
+            Object::UnboundMethod(ref unbound) => {
+                writer.u8(TAG_UNBOUND_METHOD);
+                writer.u32(self.id(unbound.class));
+                writer.u32(self.id(unbound.closure));
+            },
+        }
+    }
+}
+
+/// Loads the objects of a snapshot into `heap` and returns the rest of its
+/// state. `resolve` finds the slot of each global name, like it does for
+/// `serialize::deserialize`, and `native` finds the native to use for each
+/// name.
+pub fn read(
+    bytes: &[u8],
+    heap: &mut Heap<Object>,
+    resolve: &mut dyn FnMut(&str) -> usize,
+    native: &dyn Fn(&str) -> Option<NativeFunction>,
+) -> Result<State> {
+    let mut reader = Reader::new(bytes, heap);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(LoadError::BadMagic.into());
+    }
+    match reader.u16()? {
+        VERSION => {},
+        version => return Err(LoadError::UnsupportedVersion(version).into()),
+    }
+    let mut pools = Vec::new();
+    for _ in 0..reader.u32()? {
+        let mut names = Vec::new();
+        for _ in 0..reader.u32()? {
+            names.push(reader.string()?);
+        }
+        let slots = names.iter().map(|name| resolve(name)).collect();
+        pools.push(Rc::new(GlobalPool { names, slots }));
+    }
+
+    // Objects can refer to each other in any order, so they are all
+    // allocated before any of them are read. Nothing is collected while a
+    // snapshot loads, since only the VM collects garbage.
+    let object_count = reader.u32()?;
+    if object_count > reader.remaining() {
+        return Err(SnapshotError::Invalid);
+    }
+    let handles = (0..object_count)
+        .map(|_| reader.heap().insert(Object::String(String::new())).into_handle())
+        .collect();
+    let mut loader = Loader {
+        reader,
+        pools,
+        handles,
+        upvalues: Vec::new(),
+    };
+    for _ in 0..loader.reader.u32()? {
+        let upvalue = match loader.reader.u8()? {
+            UPVALUE_OPEN => LoxUpValue::new(loader.reader.u32()?),
+            UPVALUE_CLOSED => {
+                let value = loader.value()?;
+                let mut upvalue = LoxUpValue::new(0);
+                upvalue.close(|_| value);
+                upvalue
+            },
+            _ => return Err(SnapshotError::Invalid),
+        };
+        loader.upvalues.push(upvalue);
+    }
+    for i in 0..object_count {
+        let object = loader.object(native)?;
+        let handle = loader.handles[i];
+        *loader.reader.heap().get_mut(handle).expect("object to be live") = object;
+    }
+
+    let mut state = State::default();
+    for _ in 0..loader.reader.u32()? {
+        let name = loader.reader.string()?;
+        state.globals.push((name, loader.value()?));
+    }
+    for _ in 0..loader.reader.u32()? {
+        let event = loader.reader.string()?;
+        let mut callbacks = Vec::new();
+        for _ in 0..loader.reader.u32()? {
+            callbacks.push(loader.value()?);
+        }
+        state.hooks.push((event, callbacks));
+    }
+    for _ in 0..loader.reader.u32()? {
+        state.stack.push(loader.value()?);
+    }
+    for _ in 0..loader.reader.u32()? {
+        let closure = loader.handle()?;
+        let ip = loader.reader.u32()?;
+        let start = loader.reader.u32()?;
+        state.frames.push((closure, ip, start));
+    }
+    for _ in 0..loader.reader.u32()? {
+        state.open_upvalues.push(loader.upvalue()?);
+    }
+    if loader.reader.remaining() > 0 {
+        return Err(SnapshotError::Invalid);
+    }
+    loader.validate(&state)?;
+    Ok(state)
+}
+
+struct Loader<'b, 'h> {
+    reader: Reader<'b, 'h>,
+    pools: Vec<Rc<GlobalPool>>,
+    handles: Vec<Handle<Object>>,
+    upvalues: Vec<LoxUpValue>,
+}
+
+impl<'b, 'h> Loader<'b, 'h> {
+    fn handle(&mut self) -> Result<Handle<Object>> {
+        let id = self.reader.u32()?;
+        self.handles.get(id).cloned().ok_or(SnapshotError::Invalid)
+    }
+
+    fn upvalue(&mut self) -> Result<LoxUpValue> {
+        let id = self.reader.u32()?;
+        self.upvalues.get(id).cloned().ok_or(SnapshotError::Invalid)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        Ok(match self.reader.u8()? {
+            VALUE_FLOAT => Value::float(self.reader.f64()?),
+            VALUE_NIL => Value::nil(),
+            VALUE_TRUE => Value::truelit(),
+            VALUE_FALSE => Value::falselit(),
+            VALUE_OBJECT => self.handle()?.into(),
+            _ => return Err(SnapshotError::Invalid),
+        })
+    }
+
+    fn function(&mut self) -> Result<LoxFunction> {
+        let pool = self.reader.u32()?;
+        let pool = self.pools.get(pool).cloned().ok_or(SnapshotError::Invalid)?;
+        let globals = pool.names.len();
+        self.reader.set_globals(pool);
+        let function = self.reader.function()?;
+        verify::verify_all(&function, self.reader.heap(), globals).map_err(LoadError::from)?;
+        Ok(function)
+    }
+
+    fn object(&mut self, native: &dyn Fn(&str) -> Option<NativeFunction>) -> Result<Object> {
+        Ok(match self.reader.u8()? {
+            TAG_STRING => Object::String(self.reader.string()?),
+            TAG_FUNCTION => Object::LoxFunction(self.function()?),
+            TAG_CLOSURE => {
+                let function = self.function()?;
+                let count = self.reader.u32()?;
+                if count != function.upvalue_count() {
+                    return Err(SnapshotError::Invalid);
+                }
+                let mut upvalues = Vec::with_capacity(count.min(self.reader.remaining()));
+                for _ in 0..count {
+                    upvalues.push(self.upvalue()?);
+                }
+                Object::LoxClosure(LoxClosure::new(function, upvalues))
+            },
+            TAG_CLASS => {
+                let name = self.reader.string()?;
+                let superclass = match self.reader.u8()? {
+                    SUPER_NONE => None,
+                    SUPER_CLASS => Some(self.handle()?),
+                    _ => return Err(SnapshotError::Invalid),
+                };
+                let mut methods = HashMap::with_hasher(FnvBuildHasher::default());
+                for _ in 0..self.reader.u32()? {
+                    let name = self.reader.string()?;
+                    methods.insert(name, self.handle()?);
+                }
+                let mut class = LoxClass::new(name, methods);
+                if let Some(superclass) = superclass {
+                    class.set_superclass(superclass);
+                }
+                Object::LoxClass(class)
+            },
+            TAG_INSTANCE => {
+                let mut instance = LoxInstance::new(self.handle()?);
+                for _ in 0..self.reader.u32()? {
+                    let name = self.reader.string()?;
+                    let value = self.value()?;
+                    instance.set_property(&name, value);
+                }
+                Object::LoxInstance(instance)
+            },
+            TAG_NATIVE => {
+                let name = self.reader.string()?;
+                match native(&name) {
+                    Some(native) => Object::NativeFunction(native),
+                    None => return Err(SnapshotError::UnknownNative(name)),
+                }
+            },
+            TAG_BOUND_METHOD => {
+                let receiver = self.handle()?;
+                Object::BoundMethod(BoundMethod::new(receiver, self.handle()?))
+            },
+            TAG_UNBOUND_METHOD => {
+                let class = self.handle()?;
+                Object::UnboundMethod(UnboundMethod::new(class, self.handle()?))
+            },
+            _ => return Err(SnapshotError::Invalid),
+        })
+    }
+
+    /// Checks that every reference is to the kind of object that the VM
+    /// expects to find there, and that the call frames fit the stack.
+    fn validate(&mut self, state: &State) -> Result<()> {
+        let heap: &Heap<Object> = self.reader.heap();
+        let is_closure = |handle| heap.get(handle).and_then(Object::as_closure).is_some();
+        let is_class = |handle| heap.get(handle).and_then(Object::as_class).is_some();
+        for &handle in &self.handles {
+            let valid = match *heap.get(handle).expect("object to be live") {
+                Object::LoxClass(ref class) => {
+                    class.superclass().map_or(true, is_class) && class.methods().all(|(_, &method)| is_closure(method))
+                },
+                Object::LoxInstance(ref instance) => is_class(instance.class()),
+                Object::BoundMethod(ref bound) => is_closure(bound.closure),
+                Object::UnboundMethod(ref unbound) => is_class(unbound.class) && is_closure(unbound.closure),
+                _ => true,
+            };
+            if !valid {
+                return Err(SnapshotError::Invalid);
+            }
+        }
+        // Every frame is waiting for a call to return, whether to the frame
+        // above it or to the native that took the snapshot, and when it does
+        // the callee and its arguments are replaced by one result.
+        let mut stack_start = 0;
+        for (i, &(closure, ip, start)) in state.frames.iter().enumerate() {
+            let function = match heap.get(closure).and_then(Object::as_closure) {
+                Some(closure) => closure.function(),
+                None => return Err(SnapshotError::Invalid),
+            };
+            let callee = state.frames.get(i + 1).map_or(state.stack.len(), |frame| frame.2);
+            if start < stack_start || start >= state.stack.len() || callee < start {
+                return Err(SnapshotError::Invalid);
+            }
+            let layout = verify::layout(function, heap, function.chunk().global_names().len())
+                .map_err(LoadError::from)?;
+            if layout.depth_after_call(ip) != Some(callee - start + 1) {
+                return Err(SnapshotError::Invalid);
+            }
+            stack_start = start;
+        }
+        let open_in_stack = self.upvalues.iter().all(|upvalue| match upvalue.get() {
+            Err(slot) => slot < state.stack.len(),
+            Ok(_) => true,
+        });
+        if !open_in_stack {
+            return Err(SnapshotError::Invalid);
+        }
+        Ok(())
+    }
+}
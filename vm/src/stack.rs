@@ -0,0 +1,159 @@
+//! The VM's value stack, which holds every local and temporary.
+//!
+//! Each call frame owns the slots from where its callee was pushed up to the
+//! top of the stack, so locals are addressed relative to that start. Slots
+//! are checked against the stack's length in debug builds, which catches a
+//! compiler bug that would otherwise read or write another frame's value.
+
+use std::ops::Range;
+
+use gc::value::Value;
+use vm::RuntimeError;
+
+pub struct Stack {
+    values: Vec<Value>,
+    limit: usize,
+}
+
+impl Stack {
+    /// An empty stack that holds at most `limit` values.
+    pub fn with_limit(limit: usize) -> Self {
+        Stack {
+            values: Vec::with_capacity(limit.min(256)),
+            limit,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.values.len() == self.limit {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.values.push(value);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Value {
+        self.values.pop().expect("stack to be nonempty")
+    }
+
+    /// The value `distance` slots below the top, so `peek(0)` is the top.
+    pub fn peek(&self, distance: usize) -> Value {
+        self.check(distance < self.values.len(), distance);
+        self.values[self.values.len() - 1 - distance]
+    }
+
+    /// The absolute index of the slot `distance` below the top.
+    pub fn below(&self, distance: usize) -> usize {
+        self.check(distance < self.values.len(), distance);
+        self.values.len() - 1 - distance
+    }
+
+    /// The local in slot `idx` of the frame whose values start at `frame`.
+    pub fn slot(&self, frame: usize, idx: usize) -> Value {
+        self.get(frame + idx)
+    }
+
+    pub fn set_slot(&mut self, frame: usize, idx: usize, value: Value) {
+        self.set(frame + idx, value)
+    }
+
+    /// The value at an absolute index, which is how open upvalues and
+    /// callees refer to the stack.
+    pub fn get(&self, idx: usize) -> Value {
+        self.check(idx < self.values.len(), idx);
+        self.values[idx]
+    }
+
+    pub fn set(&mut self, idx: usize, value: Value) {
+        self.check(idx < self.values.len(), idx);
+        self.values[idx] = value;
+    }
+
+    /// The values from `start` to the top.
+    pub fn above(&self, start: usize) -> &[Value] {
+        self.check(start <= self.values.len(), start);
+        &self.values[start..]
+    }
+
+    /// Pops `count` values at once.
+    pub fn pop_n(&mut self, count: usize) {
+        self.check(count <= self.values.len(), count);
+        let len = self.values.len() - count;
+        self.values.truncate(len);
+    }
+
+    /// Pops everything down to where the frame starting at `frame` began.
+    pub fn truncate_to(&mut self, frame: usize) {
+        self.check(frame <= self.values.len(), frame);
+        self.values.truncate(frame);
+    }
+
+    /// Removes the values in `range`, moving everything above it down.
+    pub fn remove(&mut self, range: Range<usize>) {
+        self.check(range.end <= self.values.len(), range.end);
+        self.values.drain(range);
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Every value on the stack from the bottom up, which the garbage
+    /// collector treats as roots.
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter()
+    }
+
+    #[inline]
+    fn check(&self, in_bounds: bool, idx: usize) {
+        debug_assert!(in_bounds, "stack index {} is out of bounds for {} values", idx, self.values.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_relative_to_the_frame() {
+        let mut stack = Stack::with_limit(8);
+        for n in 0..4 {
+            stack.push(Value::float(n as f64)).unwrap();
+        }
+        assert_eq!(stack.slot(1, 2), Value::float(3.0));
+        stack.set_slot(1, 0, Value::nil());
+        assert_eq!(stack.peek(2), Value::nil());
+        stack.remove(1..3);
+        assert_eq!(stack.above(0), &[Value::float(0.0), Value::float(3.0)]);
+        stack.truncate_to(1);
+        assert_eq!(stack.len(), 1);
+    }
+
+    #[test]
+    fn push_fails_at_the_limit() {
+        let mut stack = Stack::with_limit(2);
+        stack.push(Value::nil()).unwrap();
+        stack.push(Value::nil()).unwrap();
+        match stack.push(Value::nil()) {
+            Err(RuntimeError::StackOverflow) => {},
+            _ => panic!("expected a stack overflow"),
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of bounds")]
+    fn slots_past_the_top_panic() {
+        let mut stack = Stack::with_limit(8);
+        stack.push(Value::nil()).unwrap();
+        stack.slot(0, 1);
+    }
+}
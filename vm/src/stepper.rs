@@ -0,0 +1,288 @@
+//! `debug --interactive`, which runs a script an instruction at a time and
+//! takes commands in between to look at the state of the VM.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use vm::gc::object::Object;
+use vm::{Location, Program, StepResult, Value, VM};
+
+const PROMPT: &str = "(lox) ";
+
+const HELP: &str = "\
+Commands:
+    step [n], s [n]             Run the next instruction, or the next n.
+    continue, c                 Run until a breakpoint or the end of the script.
+    break <line>, b <line>      Stop before the first instruction of a line.
+    break <function>:<offset>   Stop before the instruction at an offset in a function.
+                                The script itself is the function <top>.
+    delete <breakpoint>         Remove a breakpoint.
+    breakpoints                 List the breakpoints.
+    stack                       Show the value stack, from the bottom up.
+    frames, bt                  Show the calls that are running, innermost first.
+    locals [frame]              Show the locals of a frame, the innermost by default.
+    globals                     Show the globals that the script has defined.
+    quit, q                     Stop debugging.
+    help                        Show help like this.";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Breakpoint {
+    Line(usize),
+    Offset(String, usize),
+}
+
+impl Breakpoint {
+    fn parse(spec: &str) -> Option<Breakpoint> {
+        match spec.rfind(':') {
+            Some(colon) => {
+                let offset = spec[colon + 1..].parse().ok()?;
+                Some(Breakpoint::Offset(spec[..colon].to_owned(), offset))
+            },
+            None => spec.parse().ok().map(Breakpoint::Line),
+        }
+    }
+
+    fn hit(&self, location: &Location) -> bool {
+        match *self {
+            Breakpoint::Line(line) => location.line_start && location.line == line,
+            Breakpoint::Offset(ref function, offset) => location.function == *function && location.offset == offset,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Breakpoint::Line(line) => write!(f, "{}", line),
+            Breakpoint::Offset(ref function, offset) => write!(f, "{}:{}", function, offset),
+        }
+    }
+}
+
+/// Steps through `program` in `vm`, reading commands from `input` and
+/// writing what they show to `out`, until the user quits or `input` ends.
+pub fn debug<R: BufRead, W: Write>(vm: &mut VM, program: &Program, mut input: R, out: W) -> io::Result<()> {
+    let mut session = Session {
+        vm,
+        out,
+        breakpoints: Vec::new(),
+        running: true,
+    };
+    if let Err(traceback) = session.vm.start(program) {
+        return writeln!(session.out, "[error]: {}", traceback);
+    }
+    session.show_location()?;
+    let mut line = String::new();
+    loop {
+        write!(session.out, "{}", PROMPT)?;
+        session.out.flush()?;
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+        if !session.command(&words)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+struct Session<'a, W> {
+    vm: &'a mut VM,
+    out: W,
+    breakpoints: Vec<Breakpoint>,
+    /// Whether the script hasn't yet finished.
+    running: bool,
+}
+
+impl<'a, W: Write> Session<'a, W> {
+    /// Runs a command, returning false if it was to quit.
+    fn command(&mut self, words: &[&str]) -> io::Result<bool> {
+        let arg = words.get(1).cloned();
+        match (words[0], arg) {
+            ("quit", _) | ("q", _) => return Ok(false),
+            ("help", _) => writeln!(self.out, "{}", HELP)?,
+            ("break", Some(spec)) | ("b", Some(spec)) => match Breakpoint::parse(spec) {
+                Some(breakpoint) => {
+                    writeln!(self.out, "Breakpoint at {}.", breakpoint)?;
+                    self.breakpoints.push(breakpoint);
+                },
+                None => writeln!(self.out, "Expected a line or <function>:<offset>, not '{}'.", spec)?,
+            },
+            ("delete", Some(spec)) => {
+                let count = self.breakpoints.len();
+                self.breakpoints.retain(|breakpoint| breakpoint.to_string() != spec);
+                if self.breakpoints.len() == count {
+                    writeln!(self.out, "No breakpoint at {}.", spec)?;
+                }
+            },
+            ("breakpoints", _) => for breakpoint in &self.breakpoints {
+                writeln!(self.out, "{}", breakpoint)?;
+            },
+            (_, _) if !self.running => writeln!(self.out, "The script has finished.")?,
+            ("step", count) | ("s", count) => {
+                let count = match count.map(str::parse::<usize>) {
+                    None => 1,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => {
+                        writeln!(self.out, "Expected a number of instructions.")?;
+                        return Ok(true);
+                    },
+                };
+                for _ in 0..count {
+                    if !self.step()? {
+                        return Ok(true);
+                    }
+                }
+                self.show_location()?;
+            },
+            ("continue", _) | ("c", _) => {
+                loop {
+                    if !self.step()? {
+                        return Ok(true);
+                    }
+                    let location = self.vm.location().expect("a running script");
+                    if self.breakpoints.iter().any(|breakpoint| breakpoint.hit(&location)) {
+                        break;
+                    }
+                }
+                self.show_location()?;
+            },
+            ("stack", _) => for (i, &value) in self.vm.stack().iter().enumerate() {
+                writeln!(self.out, "{:4} {}", i, describe(self.vm, value))?;
+            },
+            ("frames", _) | ("bt", _) => for (i, (line, name)) in self.vm.backtrace().into_iter().enumerate() {
+                writeln!(self.out, "#{} {} at line {}", i, name, line)?;
+            },
+            ("locals", frame) => {
+                let frame = frame.map_or(Ok(0), str::parse::<usize>);
+                match frame {
+                    Ok(frame) if frame < self.vm.depth() => {
+                        for (name, value) in self.vm.frame_locals(frame) {
+                            // The callee's own slot has no name.
+                            if !name.is_empty() {
+                                writeln!(self.out, "{} = {}", name, describe(self.vm, value))?;
+                            }
+                        }
+                    },
+                    _ => writeln!(self.out, "Expected a frame from 0 to {}.", self.vm.depth() - 1)?,
+                }
+            },
+            ("globals", _) => for (name, value) in self.vm.defined_globals() {
+                if self.vm.object(value).map_or(true, |o| o.as_native().is_none()) {
+                    writeln!(self.out, "{} = {}", name, describe(self.vm, value))?;
+                }
+            },
+            (command, _) => writeln!(self.out, "Unknown command '{}'. Try 'help'.", command)?,
+        }
+        Ok(true)
+    }
+
+    /// Runs the next instruction, returning false if the script finished.
+    fn step(&mut self) -> io::Result<bool> {
+        let res = self.vm.step();
+        self.vm.flush()?;
+        match res {
+            Ok(StepResult::Running) => return Ok(true),
+            Ok(StepResult::Finished(value)) => writeln!(self.out, "The script returned {}.", describe(self.vm, value))?,
+            Err(traceback) => writeln!(self.out, "[error]: {}", traceback)?,
+        }
+        self.running = false;
+        Ok(false)
+    }
+
+    fn show_location(&mut self) -> io::Result<()> {
+        let location = self.vm.location().expect("a running script");
+        writeln!(self.out, "{} at line {}:", location.function, location.line)?;
+        self.vm.disassemble_next(&mut self.out);
+        Ok(())
+    }
+}
+
+/// Shows strings quoted, so that they can be told apart from other values.
+fn describe(vm: &VM, value: Value) -> String {
+    match vm.object(value) {
+        Some(&Object::String(ref s)) => format!("{:?}", s),
+        _ => vm.stringify(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn session(source: &str, commands: &str) -> String {
+        let program = Program::compile(source).unwrap();
+        let output = Output::default();
+        let mut vm = VM::new();
+        vm.set_stdout(output.clone());
+        debug(&mut vm, &program, commands.as_bytes(), output.clone()).unwrap();
+        let output = output.0.borrow();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    const SOURCE: &str = "var a = \"one\";\nfun f(x) {\n    var y = x + 1;\n    return y;\n}\nprint f(2);\nprint a;\n";
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let output = session(SOURCE, "b 4\nc\nlocals\nbt\nglobals\nc\nc\n");
+        let expected = [
+            "Breakpoint at 4.",
+            "f at line 4:",
+            "x = 2",
+            "y = 3",
+            "#0 f at line 4",
+            "#1 <top> at line 6",
+            "a = \"one\"",
+            "f = <fn f>",
+            "3",
+            "one",
+            "The script returned nil.",
+            "The script has finished.",
+        ];
+        let lines = output.lines().map(|line| line.trim_start_matches(PROMPT)).collect::<Vec<_>>();
+        let mut rest = &lines[..];
+        for line in &expected {
+            let found = rest.iter().position(|l| l == line).unwrap_or_else(|| panic!("{:?} not in:\n{}", line, output));
+            rest = &rest[found + 1..];
+        }
+    }
+
+    #[test]
+    fn steps_through_instructions() {
+        let output = session("print 1 + 2;", "s\nstack\ns 2\nstack\nq\ns\n");
+        let lines = output.lines().map(|line| line.trim_start_matches(PROMPT)).collect::<Vec<_>>();
+        assert_eq!(lines[0], "<top> at line 1:");
+        assert!(lines.contains(&"   1 1"), "{}", output);
+        assert!(lines.contains(&"   1 3"), "{}", output);
+        assert!(!output.contains("The script"), "{}", output);
+    }
+
+    #[test]
+    fn parses_breakpoints() {
+        assert_eq!(Breakpoint::parse("12"), Some(Breakpoint::Line(12)));
+        assert_eq!(Breakpoint::parse("f:3"), Some(Breakpoint::Offset("f".into(), 3)));
+        assert_eq!(Breakpoint::parse("<top>:0"), Some(Breakpoint::Offset("<top>".into(), 0)));
+        assert_eq!(Breakpoint::parse("f"), None);
+        assert_eq!(Breakpoint::parse("f:x"), None);
+    }
+}
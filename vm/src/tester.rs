@@ -0,0 +1,241 @@
+//! The `test` command, which runs scripts and checks what they do against
+//! comments in them, in the format of the tests in `integration/lox-tests`:
+//!
+//! ```text
+//! print 1 + 2;       // expect: 3
+//! var a = readLine(); // input: some text
+//! nil.field;         // expect runtime error: Only instances have properties.
+//! return 3;          // expect exit: 3
+//! // [line 1] Error at 'x': Expect ';' after expression.
+//! ```
+//!
+//! Scripts run in this process, with the same flags as `run`, so a script
+//! that calls `exit` ends the whole run.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use vm::gc::value::Variant;
+use vm::{Config, Program};
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_ERR: &str = "// expect runtime error: ";
+const EXPECT_EXIT: &str = "// expect exit: ";
+const INPUT: &str = "// input: ";
+const EXPECT_PARSE_ERR: &str = "// Error at";
+const LINE_PARSE_ERR: &str = "// [line";
+
+/// Runs every `.lox` file in `paths`, searching directories recursively, and
+/// prints whether each passed along with a summary. Returns whether they all
+/// passed.
+pub fn test(paths: &[String], config: &Config) -> io::Result<bool> {
+    let mut scripts = Vec::new();
+    if paths.is_empty() {
+        collect(Path::new("."), &mut scripts)?;
+    }
+    for path in paths {
+        collect(Path::new(path), &mut scripts)?;
+    }
+
+    let mut failed = 0;
+    for script in &scripts {
+        let res = fs::read_to_string(script).map_err(|e| e.to_string()).and_then(|source| {
+            // A bug in the compiler or the VM fails only the script that found it.
+            panic::catch_unwind(AssertUnwindSafe(|| check(&source, config)))
+                .unwrap_or_else(|_| Err("rlox panicked".to_owned()))
+        });
+        match res {
+            Ok(()) => println!("ok   {}", script.display()),
+            Err(reason) => {
+                failed += 1;
+                println!("FAIL {}", script.display());
+                for line in reason.lines() {
+                    println!("     {}", line);
+                }
+            },
+        }
+    }
+    println!();
+    println!("{} passed, {} failed.", scripts.len() - failed, failed);
+    Ok(failed == 0)
+}
+
+/// Adds `path` to `scripts` if it is a file, or the `.lox` files under it in
+/// order if it is a directory.
+fn collect(path: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        scripts.push(path.to_owned());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect(&entry, scripts)?;
+        } else if entry.extension().map_or(false, |ext| ext == "lox") {
+            scripts.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// What the comments in a script say it should do.
+#[derive(Debug, Default, PartialEq)]
+struct Expectations {
+    output: String,
+    /// What the script reads from stdin.
+    input: String,
+    runtime_error: Option<String>,
+    compile_errors: Vec<String>,
+    exit: Option<i32>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Result<Expectations, String> {
+        let mut expected = Expectations::default();
+        for (i, line) in source.lines().enumerate() {
+            let comment = match line.find("// ") {
+                Some(idx) => &line[idx..],
+                None => continue,
+            };
+            if comment.starts_with(EXPECT) {
+                expected.output.push_str(&comment[EXPECT.len()..].trim().replace("\\n", "\n"));
+                expected.output.push('\n');
+            } else if comment.starts_with(EXPECT_ERR) {
+                expected.runtime_error = Some(comment[EXPECT_ERR.len()..].trim().to_owned());
+            } else if comment.starts_with(EXPECT_EXIT) {
+                let code = comment[EXPECT_EXIT.len()..].trim().parse();
+                expected.exit = Some(code.map_err(|_| format!("line {}: exit code must be an integer", i + 1))?);
+            } else if comment.starts_with(INPUT) {
+                expected.input.push_str(&comment[INPUT.len()..]);
+                expected.input.push('\n');
+            } else if comment.starts_with(EXPECT_PARSE_ERR) || comment.starts_with(LINE_PARSE_ERR) {
+                // Only the message is checked, since errors report their
+                // position differently.
+                let message = comment.find(": ").map(|idx| &comment[idx + 2..]);
+                let message = message.ok_or_else(|| format!("line {}: error has no message", i + 1))?;
+                expected.compile_errors.push(message.trim().to_owned());
+            }
+        }
+        Ok(expected)
+    }
+}
+
+/// Runs a script, returning why it failed if it didn't do what its comments
+/// say it should.
+fn check(source: &str, config: &Config) -> Result<(), String> {
+    let expected = Expectations::parse(source)?;
+    let program = match Program::compile_with(source, config.optimize) {
+        Ok(program) => program,
+        Err(errors) => {
+            let errors = errors.iter().map(|e| strip_position(&e.to_string())).collect::<Vec<_>>();
+            if expected.compile_errors.is_empty() {
+                return Err(format!("unexpected compile errors: {:?}", errors));
+            }
+            if !errors.starts_with(&expected.compile_errors) {
+                return Err(format!("expected compile errors {:?}\n but got {:?}", expected.compile_errors, errors));
+            }
+            return Ok(());
+        },
+    };
+    if !expected.compile_errors.is_empty() {
+        return Err(format!("expected compile errors {:?}\n but it compiled", expected.compile_errors));
+    }
+
+    let output = Capture::default();
+    let mut vm = config.vm();
+    vm.set_stdout(output.clone());
+    vm.set_stdin(Cursor::new(expected.input.clone().into_bytes()));
+    let res = vm.run_program(&program);
+    let _ = vm.flush();
+    match (res, expected.runtime_error.as_ref()) {
+        (Ok(_), Some(error)) => return Err(format!("expected runtime error {:?}\n but it finished", error)),
+        (Err(traceback), None) => return Err(format!("unexpected runtime error: {}", traceback)),
+        (Err(traceback), Some(error)) => {
+            let got = format!("{}.", traceback.error());
+            if got != *error {
+                return Err(format!("expected runtime error {:?}\n but got {:?}", error, got));
+            }
+        },
+        (Ok(value), None) => if let Some(exit) = expected.exit {
+            // A script that returns a number exits with it, as with `run`.
+            let code = match value.decode() {
+                Variant::Float(n) => n as i32,
+                _ => 0,
+            };
+            if code != exit {
+                return Err(format!("expected exit code {}\n but got {}", exit, code));
+            }
+        },
+    }
+
+    let printed = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+    if printed != expected.output {
+        return Err(format!("expected output {:?}\n but got {:?}", expected.output, printed));
+    }
+    Ok(())
+}
+
+/// Removes the `[line 12:8]` that ends a compile error.
+fn strip_position(error: &str) -> String {
+    match error.rfind(" [line ") {
+        Some(idx) if error.ends_with(']') => error[..idx].to_owned(),
+        _ => error.to_owned(),
+    }
+}
+
+/// Collects the output of a VM.
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_expectations() {
+        let source = "print readLine(); // input:  two\nprint \"a\\nb\"; // expect: a\\nb\n\
+                      nil.x; // expect runtime error: Only instances have properties.\n\
+                      // [line 4] Error at 'x': Expect ';' after expression.\nreturn 2; // expect exit: 2\n";
+        assert_eq!(Expectations::parse(source), Ok(Expectations {
+            output: "a\nb\n".to_owned(),
+            input: " two\n".to_owned(),
+            runtime_error: Some("Only instances have properties.".to_owned()),
+            compile_errors: vec!["Expect ';' after expression.".to_owned()],
+            exit: Some(2),
+        }));
+        assert_eq!(Expectations::parse("// expect exit: x"), Err("line 1: exit code must be an integer".to_owned()));
+    }
+
+    #[test]
+    fn checks_scripts() {
+        let config = Config::default();
+        assert_eq!(check("print 1 + 2; // expect: 3\n", &config), Ok(()));
+        assert_eq!(check("print readLine(); // input: hi\n// expect: hi\n", &config), Ok(()));
+        assert_eq!(check("print 1; return 4; // expect: 1\n// expect exit: 4\n", &config), Ok(()));
+        assert_eq!(check("class A with {} // Error at '{': Expect mixin name.\n", &config), Ok(()));
+        assert_eq!(check("\"str\".foo = 1; // expect runtime error: Only instances have fields.\n", &config), Ok(()));
+
+        assert!(check("print 1; // expect: 2\n", &config).is_err());
+        assert!(check("print x;\n", &config).is_err());
+        assert!(check("print 1; // expect runtime error: Oops.\n// expect: 1\n", &config).is_err());
+        assert!(check("print 1; // Error at '1': Expect expression.\n", &config).is_err());
+        assert!(check("return 1; // expect exit: 2\n", &config).is_err());
+    }
+}
@@ -0,0 +1,568 @@
+//! Checks that bytecode is safe to run before the VM trusts it.
+//!
+//! The VM doesn't check its bytecode as it runs, so a bad jump or constant
+//! index in a corrupt `.loxc` file would make it read garbage or panic. The
+//! verifier decodes every instruction of a function and follows every path
+//! through it, checking that jumps land on instructions, that operands refer
+//! to constants, globals, upvalues and caches that exist, and that the stack
+//! holds the same number of values whichever way an instruction is reached.
+
+use broom::Heap;
+
+use chunk::{Chunk, OP_COUNT};
+use gc::object::{LoxFunction, Object};
+use gc::value::Value;
+
+#[derive(Debug, Fail, PartialEq)]
+#[fail(display = "Invalid bytecode at {} in '{}': {}", offset, function, problem)]
+pub struct VerifyError {
+    pub function: String,
+    /// The offset of the instruction with the problem.
+    pub offset: usize,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum Problem {
+    #[fail(display = "the line table does not start with the first instruction.")]
+    MissingLines,
+    #[fail(display = "unknown opcode {:#04x}.", _0)]
+    UnknownOp(u8),
+    #[fail(display = "the instruction runs past the end of the chunk.")]
+    Truncated,
+    #[fail(display = "execution runs past the end of the chunk.")]
+    FallsOffEnd,
+    #[fail(display = "jump to {}, which is not the start of an instruction.", _0)]
+    BadJump(usize),
+    #[fail(display = "constant {} does not exist.", _0)]
+    MissingConstant(usize),
+    #[fail(display = "constant {} is not a {}.", _0, _1)]
+    WrongConstant(usize, &'static str),
+    #[fail(display = "the operand {:#018x} is not a number.", _0)]
+    NotANumber(u64),
+    #[fail(display = "global {} does not exist.", _0)]
+    MissingGlobal(usize),
+    #[fail(display = "upvalue {} does not exist.", _0)]
+    MissingUpValue(u8),
+    #[fail(display = "cache {} does not exist.", _0)]
+    MissingCache(u16),
+    #[fail(display = "it needs {} values on the stack but there are {}.", needs, depth)]
+    StackUnderflow {
+        needs: usize,
+        depth: usize,
+    },
+    #[fail(display = "the stack holds {} values on one path to it and {} on another.", _0, _1)]
+    StackMismatch(usize, usize),
+}
+
+type Result<T> = ::std::result::Result<T, Problem>;
+
+/// Verifies `function` and every function nested within it, in a program
+/// with `globals` global names.
+pub fn verify_all(function: &LoxFunction, heap: &Heap<Object>, globals: usize) -> ::std::result::Result<(), VerifyError> {
+    verify(function, heap, globals)?;
+    for constant in function.chunk().constants() {
+        let nested = constant.as_object()
+            .and_then(|o| heap.get(o))
+            .and_then(|o| o.as_function());
+        if let Some(nested) = nested {
+            verify_all(nested, heap, globals)?;
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the bytecode of `function`, but not of the functions nested
+/// within it.
+pub fn verify(function: &LoxFunction, heap: &Heap<Object>, globals: usize) -> ::std::result::Result<(), VerifyError> {
+    layout(function, heap, globals).map(|_| ())
+}
+
+/// Where a verified function's instructions start and how deep its frame's
+/// stack is at each of them, for checking where a saved frame can resume.
+pub struct Layout {
+    depths: Vec<Option<usize>>,
+    after_call: Vec<bool>,
+}
+
+impl Layout {
+    /// How many values the frame holds when the instruction at `ip` starts,
+    /// if it is one that some path reaches straight after a call returns.
+    pub fn depth_after_call(&self, ip: usize) -> Option<usize> {
+        match self.after_call.get(ip) {
+            Some(&true) => self.depths[ip],
+            _ => None,
+        }
+    }
+}
+
+/// Verifies the bytecode of `function` like `verify`, and returns its
+/// layout.
+pub fn layout(function: &LoxFunction, heap: &Heap<Object>, globals: usize) -> ::std::result::Result<Layout, VerifyError> {
+    let error = |offset, problem| VerifyError {
+        function: function.name().to_owned(),
+        offset,
+        problem,
+    };
+    let chunk = function.chunk();
+    let mut verifier = Verifier {
+        offset: 0,
+        chunk,
+        heap,
+        globals,
+        upvalue_count: function.upvalue_count(),
+    };
+
+    if chunk.len() > 0 && chunk.lines().first().map_or(true, |info| info.start != 0) {
+        return Err(error(0, Problem::MissingLines));
+    }
+
+    // Decode every instruction, so that the targets of jumps can be checked
+    // against where instructions start.
+    let mut effects = vec![None; chunk.len()];
+    while verifier.offset < chunk.len() {
+        let offset = verifier.offset;
+        let effect = verifier.decode().map_err(|problem| error(offset, problem))?;
+        effects[offset] = Some((effect, verifier.offset));
+    }
+    for (offset, effect) in effects.iter().enumerate() {
+        if let Some(target) = effect.and_then(|(effect, _)| effect.flow.target()) {
+            if target >= chunk.len() || effects[target].is_none() {
+                return Err(error(offset, Problem::BadJump(target)));
+            }
+        }
+    }
+
+    // Then follow every path from the start, keeping track of how many
+    // values are on the stack. A frame starts with the function being called
+    // and its arguments.
+    let mut depths = vec![None; chunk.len()];
+    let mut after_call = vec![false; chunk.len()];
+    let mut pending = vec![(0, function.arity() as usize + 1)];
+    while let Some((offset, depth)) = pending.pop() {
+        match depths[offset] {
+            Some(seen) if seen == depth => continue,
+            Some(seen) => return Err(error(offset, Problem::StackMismatch(seen, depth))),
+            None => depths[offset] = Some(depth),
+        }
+        let (effect, next) = effects[offset].expect("paths to only reach the start of instructions");
+        if depth < effect.needs {
+            return Err(error(offset, Problem::StackUnderflow { needs: effect.needs, depth }));
+        }
+        let after = depth - effect.pops + effect.pushes;
+        match effect.flow {
+            Flow::Next if next >= chunk.len() => return Err(error(offset, Problem::FallsOffEnd)),
+            Flow::Next => {
+                after_call[next] |= effect.call;
+                pending.push((next, after));
+            },
+            Flow::Jump(target) => pending.push((target, after)),
+            Flow::Branch(target) => {
+                pending.push((target, after));
+                pending.push((next, after));
+            },
+            Flow::Stop => {},
+        }
+    }
+    Ok(Layout { depths, after_call })
+}
+
+/// What an instruction does to the stack, and where control goes after it.
+#[derive(Debug, Clone, Copy)]
+struct Effect {
+    /// How many values must be on the stack, which is more than it pops for
+    /// instructions that look below the top.
+    needs: usize,
+    pops: usize,
+    pushes: usize,
+    flow: Flow,
+    /// Whether the instruction calls something, so that a frame can be
+    /// saved while the next instruction waits for the call to return.
+    call: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Stop,
+}
+
+impl Effect {
+    fn new(pops: usize, pushes: usize) -> Self {
+        Effect { needs: pops, pops, pushes, flow: Flow::Next, call: false }
+    }
+
+    fn calling(mut self) -> Self {
+        self.call = true;
+        self
+    }
+
+    fn needing(mut self, needs: usize) -> Self {
+        self.needs = self.needs.max(needs);
+        self
+    }
+
+    fn going(mut self, flow: Flow) -> Self {
+        self.flow = flow;
+        self
+    }
+}
+
+impl Flow {
+    fn target(self) -> Option<usize> {
+        match self {
+            Flow::Jump(target) | Flow::Branch(target) => Some(target),
+            Flow::Next | Flow::Stop => None,
+        }
+    }
+}
+
+struct Verifier<'c> {
+    offset: usize,
+    chunk: &'c Chunk,
+    heap: &'c Heap<Object>,
+    globals: usize,
+    upvalue_count: usize,
+}
+
+impl<'c> Verifier<'c> {
+    fn decode(&mut self) -> Result<Effect> {
+        let inst = self.read_byte();
+        if inst as usize >= OP_COUNT {
+            return Err(Problem::UnknownOp(inst));
+        }
+        let effect = decode_op!(inst, self);
+        // Reading past the end gives zeroes, so the operands are only
+        // meaningful if the instruction fit.
+        if self.offset > self.chunk.len() {
+            return Err(Problem::Truncated);
+        }
+        effect
+    }
+
+    fn constant(&mut self, idx: u8) -> Result<Effect> {
+        self.check_constant(idx as usize)?;
+        Ok(Effect::new(0, 1))
+    }
+
+    fn constant_long(&mut self, idx: usize) -> Result<Effect> {
+        self.check_constant(idx)?;
+        Ok(Effect::new(0, 1))
+    }
+
+    fn ret(&self) -> Result<Effect> { Ok(Effect::new(1, 0).going(Flow::Stop)) }
+    fn halt(&self) -> Result<Effect> { Ok(Effect::new(1, 0).going(Flow::Stop)) }
+    fn print(&self) -> Result<Effect> { Ok(Effect::new(1, 0)) }
+    fn add(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn sub(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn mul(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn div(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn eq(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn gt(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn lt(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn add_number(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn sub_number(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn mul_number(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn div_number(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn lt_number(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn gt_number(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn is(&self) -> Result<Effect> { Ok(Effect::new(2, 1)) }
+    fn neg(&self) -> Result<Effect> { Ok(Effect::new(1, 1)) }
+    fn not(&self) -> Result<Effect> { Ok(Effect::new(1, 1)) }
+    fn op_pop(&self) -> Result<Effect> { Ok(Effect::new(1, 0)) }
+    fn pop_n(&self, count: u8) -> Result<Effect> { Ok(Effect::new(count as usize, 0)) }
+    fn imm_nil(&self) -> Result<Effect> { Ok(Effect::new(0, 1)) }
+    fn imm_true(&self) -> Result<Effect> { Ok(Effect::new(0, 1)) }
+    fn imm_false(&self) -> Result<Effect> { Ok(Effect::new(0, 1)) }
+    fn close_upvalue(&self) -> Result<Effect> { Ok(Effect::new(1, 0)) }
+
+    fn immediate(&mut self) -> Result<Effect> {
+        let start = self.offset;
+        self.offset += 8;
+        // The VM pushes the operand as it is, so it has to be a number rather
+        // than a tag or a handle.
+        if self.offset <= self.chunk.len() {
+            let raw = self.chunk.read_u64(start);
+            if !unsafe { Value::from_raw(raw) }.is_float() {
+                return Err(Problem::NotANumber(raw));
+            }
+        }
+        Ok(Effect::new(0, 1))
+    }
+
+    fn jmp(&mut self) -> Result<Effect> {
+        let target = self.read_u16() as usize + self.offset;
+        Ok(Effect::new(0, 0).going(Flow::Jump(target)))
+    }
+
+    fn jze(&mut self) -> Result<Effect> {
+        let target = self.read_u16() as usize + self.offset;
+        Ok(Effect::new(0, 0).needing(1).going(Flow::Branch(target)))
+    }
+
+    fn jump_if_less(&mut self) -> Result<Effect> { self.compare_and_jump() }
+    fn jump_if_not_less(&mut self) -> Result<Effect> { self.compare_and_jump() }
+    fn jump_if_greater(&mut self) -> Result<Effect> { self.compare_and_jump() }
+    fn jump_if_not_greater(&mut self) -> Result<Effect> { self.compare_and_jump() }
+
+    fn compare_and_jump(&mut self) -> Result<Effect> {
+        let target = self.read_u16() as usize + self.offset;
+        Ok(Effect::new(2, 0).going(Flow::Branch(target)))
+    }
+
+    fn op_loop(&mut self) -> Result<Effect> {
+        let target = self.loop_target();
+        Ok(Effect::new(0, 0).going(Flow::Jump(target)))
+    }
+
+    fn loop_if_true(&mut self) -> Result<Effect> {
+        let target = self.loop_target();
+        Ok(Effect::new(1, 0).going(Flow::Branch(target)))
+    }
+
+    fn loop_if_less(&mut self) -> Result<Effect> { self.compare_and_loop() }
+    fn loop_if_not_less(&mut self) -> Result<Effect> { self.compare_and_loop() }
+    fn loop_if_greater(&mut self) -> Result<Effect> { self.compare_and_loop() }
+    fn loop_if_not_greater(&mut self) -> Result<Effect> { self.compare_and_loop() }
+
+    fn compare_and_loop(&mut self) -> Result<Effect> {
+        let target = self.loop_target();
+        Ok(Effect::new(2, 0).going(Flow::Branch(target)))
+    }
+
+    fn loop_target(&mut self) -> usize {
+        let sub = self.read_u16() as usize;
+        // A loop past the start of the chunk wraps around to an offset that
+        // can't be an instruction.
+        self.offset.checked_sub(sub).unwrap_or(usize::max_value())
+    }
+
+    fn get_global(&mut self) -> Result<Effect> {
+        self.check_global()?;
+        Ok(Effect::new(0, 1))
+    }
+
+    fn set_global(&mut self) -> Result<Effect> {
+        self.check_global()?;
+        Ok(Effect::new(0, 0).needing(1))
+    }
+
+    fn define_global(&mut self) -> Result<Effect> {
+        self.check_global()?;
+        Ok(Effect::new(1, 0))
+    }
+
+    fn get_local(&mut self) -> Result<Effect> {
+        let slot = self.read_byte() as usize;
+        Ok(Effect::new(0, 1).needing(slot + 1))
+    }
+
+    fn set_local(&mut self) -> Result<Effect> {
+        let slot = self.read_byte() as usize;
+        Ok(Effect::new(0, 0).needing(slot + 1))
+    }
+
+    fn get_upvalue(&mut self) -> Result<Effect> {
+        self.check_upvalue()?;
+        Ok(Effect::new(0, 1))
+    }
+
+    fn set_upvalue(&mut self) -> Result<Effect> {
+        self.check_upvalue()?;
+        Ok(Effect::new(0, 0).needing(1))
+    }
+
+    fn call(&self, arity: u8) -> Result<Effect> {
+        Ok(Effect::new(arity as usize + 1, 1).calling())
+    }
+
+    fn tail_call(&self, arity: u8) -> Result<Effect> {
+        Ok(Effect::new(arity as usize + 1, 1).calling())
+    }
+
+    fn invoke(&mut self, arity: u8) -> Result<Effect> {
+        let idx = self.read_byte();
+        self.check_string(idx as usize)?;
+        self.check_cache()?;
+        Ok(Effect::new(arity as usize + 1, 1).calling())
+    }
+
+    fn closure(&mut self) -> Result<Effect> {
+        let idx = self.read_byte() as usize;
+        self.check_constant(idx)?;
+        let count = self.chunk.get_constant(idx)
+            .and_then(|val| val.as_object())
+            .and_then(|o| self.heap.get(o))
+            .and_then(|o| o.as_function())
+            .ok_or(Problem::WrongConstant(idx, "function"))?
+            .upvalue_count();
+        let mut needs = 0;
+        for _ in 0..count {
+            if self.offset >= self.chunk.len() {
+                break;
+            }
+            let is_local = self.read_byte() > 0;
+            let index = self.read_byte();
+            if is_local {
+                // A local function can capture itself, in the slot that the
+                // closure is about to be pushed into.
+                needs = needs.max(index as usize);
+            } else if index as usize >= self.upvalue_count {
+                return Err(Problem::MissingUpValue(index));
+            }
+        }
+        Ok(Effect::new(0, 1).needing(needs))
+    }
+
+    fn class(&mut self, idx: u8) -> Result<Effect> {
+        self.check_string(idx as usize)?;
+        let methods = self.read_byte();
+        Ok(Effect::new(methods as usize, 1))
+    }
+
+    fn inherit(&self) -> Result<Effect> {
+        Ok(Effect::new(0, 0).needing(2))
+    }
+
+    fn mixin(&self, count: u8) -> Result<Effect> {
+        // The class the mixins are copied into stays below them.
+        Ok(Effect::new(count as usize, 0).needing(count as usize + 1))
+    }
+
+    fn get_super(&mut self, idx: u8) -> Result<Effect> {
+        self.check_string(idx as usize)?;
+        Ok(Effect::new(2, 1))
+    }
+
+    fn get_property(&mut self) -> Result<Effect> {
+        let idx = self.read_byte();
+        self.check_string(idx as usize)?;
+        self.check_cache()?;
+        Ok(Effect::new(1, 1))
+    }
+
+    fn set_property(&mut self) -> Result<Effect> {
+        let idx = self.read_byte();
+        self.check_string(idx as usize)?;
+        Ok(Effect::new(2, 1))
+    }
+
+    fn check_constant(&self, idx: usize) -> Result<()> {
+        match self.chunk.get_constant(idx) {
+            Some(_) => Ok(()),
+            None => Err(Problem::MissingConstant(idx)),
+        }
+    }
+
+    fn check_string(&self, idx: usize) -> Result<()> {
+        self.check_constant(idx)?;
+        let string = self.chunk.get_constant(idx)
+            .and_then(|val| val.as_object())
+            .and_then(|o| self.heap.get(o))
+            .and_then(|o| o.as_string());
+        match string {
+            Some(_) => Ok(()),
+            None => Err(Problem::WrongConstant(idx, "string")),
+        }
+    }
+
+    fn check_global(&mut self) -> Result<()> {
+        let slot = self.read_u16() as usize;
+        if slot >= self.globals {
+            return Err(Problem::MissingGlobal(slot));
+        }
+        Ok(())
+    }
+
+    fn check_upvalue(&mut self) -> Result<()> {
+        let index = self.read_byte();
+        if index as usize >= self.upvalue_count {
+            return Err(Problem::MissingUpValue(index));
+        }
+        Ok(())
+    }
+
+    fn check_cache(&mut self) -> Result<()> {
+        let cache = self.read_u16();
+        if cache as usize >= self.chunk.cache_count() {
+            return Err(Problem::MissingCache(cache));
+        }
+        Ok(())
+    }
+
+    /// Reads the next byte, or zero past the end of the chunk.
+    fn read_byte(&mut self) -> u8 {
+        self.offset += 1;
+        if self.offset > self.chunk.len() {
+            return 0;
+        }
+        self.chunk.get(self.offset - 1)
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let lo = self.read_byte() as u16;
+        let hi = self.read_byte() as u16;
+        lo + (hi << 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    use chunk::{op_name, GlobalPool, LineInfo};
+    use gc::value::Value;
+
+    fn check(code: &[u8], arity: u8) -> ::std::result::Result<(), (usize, Problem)> {
+        let constants = vec![Value::float(1.0)];
+        let lines = vec![LineInfo { start: 0, line: 1 }];
+        let chunk = Chunk::from_parts("test".into(), code.to_vec(), constants, lines, vec![], 0, Rc::new(GlobalPool::default()));
+        let function = LoxFunction::from_parts("test".into(), arity, 0, chunk);
+        verify(&function, &Heap::default(), 0).map_err(|err| (err.offset, err.problem))
+    }
+
+    #[test]
+    fn accepts_valid_code() {
+        // nil; print; true; jump if false over the next two; nil; pop; nil; return
+        assert_eq!(check(&[0x14, 0x02, 0x15, 0x0d, 0x02, 0x00, 0x14, 0x0e, 0x14, 0x00], 0), Ok(()));
+        assert_eq!(check(&[0x11, 0x01, 0x01, 0x00, 0x03, 0x00], 1), Ok(()));
+    }
+
+    #[test]
+    fn rejects_malformed_instructions() {
+        assert_eq!(check(&[0xff], 0), Err((0, Problem::UnknownOp(0xff))));
+        assert_eq!(check(&[OP_COUNT as u8], 0), Err((0, Problem::UnknownOp(OP_COUNT as u8))));
+        for op in 0..OP_COUNT {
+            assert!(!op_name(op as u8).starts_with("OP_UNKNOWN"), "{:#04x} has no name", op);
+        }
+        let chunk = Chunk::from_parts("test".into(), vec![0x14, 0x00], vec![], vec![], vec![], 0, Rc::new(GlobalPool::default()));
+        let function = LoxFunction::from_parts("test".into(), 0, 0, chunk);
+        assert_eq!(verify(&function, &Heap::default(), 0).unwrap_err().problem, Problem::MissingLines);
+        assert_eq!(check(&[0x14, 0x00, 0x0c, 0x01], 0), Err((2, Problem::Truncated)));
+        assert_eq!(check(&[0x01, 0x05, 0x00], 0), Err((0, Problem::MissingConstant(5))));
+        assert_eq!(check(&[0x0f, 0x00, 0x00, 0x00], 0), Err((0, Problem::MissingGlobal(0))));
+        assert_eq!(check(&[0x22, 0x00, 0x00], 0), Err((0, Problem::MissingUpValue(0))));
+        let tag = [0x13, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfc, 0x7f, 0x00];
+        assert_eq!(check(&tag, 0), Err((0, Problem::NotANumber(0x7ffc_0000_0000_0007))));
+        assert_eq!(check(&[0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f, 0x00], 0), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_jumps() {
+        // A jump into the operand of a constant, and a loop before the start.
+        assert_eq!(check(&[0x0c, 0x01, 0x00, 0x01, 0x00, 0x00], 0), Err((0, Problem::BadJump(4))));
+        assert_eq!(check(&[0x20, 0x09, 0x00, 0x00], 0), Err((0, Problem::BadJump(usize::max_value()))));
+        assert_eq!(check(&[0x14], 0), Err((0, Problem::FallsOffEnd)));
+    }
+
+    #[test]
+    fn rejects_unbalanced_stacks() {
+        assert_eq!(check(&[0x03, 0x00], 0), Err((0, Problem::StackUnderflow { needs: 2, depth: 1 })));
+        assert_eq!(check(&[0x11, 0x02, 0x00], 1), Err((0, Problem::StackUnderflow { needs: 3, depth: 2 })));
+        // true; jump if false over nil; return
+        assert_eq!(check(&[0x15, 0x0d, 0x01, 0x00, 0x14, 0x00], 0), Err((5, Problem::StackMismatch(3, 2))));
+    }
+}
@@ -1,37 +1,255 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use broom::Heap;
 use broom::Handle;
 use fnv::FnvBuildHasher;
 
-use chunk::Chunk;
-use compile::Compiler;
+use chunk::{Chunk, Op, OP_COUNT};
+use convert::ConversionError;
+use debug::Disassembler;
 
 use gc::object::Object;
 use gc::object::LoxClass;
 use gc::object::LoxClosure;
+use gc::object::LoxFunction;
 use gc::object::LoxUpValue;
 use gc::object::LoxInstance;
 use gc::object::BoundMethod;
+use gc::object::UnboundMethod;
+use gc::object::NativeFn;
+use gc::dump;
+use gc::stats::GcStats;
 use gc::value::Value;
 use gc::value::Variant;
-use parser::ast::Stmt;
 use native;
+use native::Capabilities;
+#[cfg(feature = "stats")]
+use op_stats::OpStats;
+use hook::{Arg, HookRegistry};
+use random::Rng;
+use program::Program;
+use snapshot::{self, SnapshotError};
+use stack::Stack;
 
 const STACK_SIZE: usize = 4096;
-const HEAP_GROWTH: usize = 2;
+const FRAMES_SIZE: usize = 1024;
+/// How many instructions run between checks of the clock and for
+/// cancellation, since either is far slower than an instruction.
+const LIMIT_CHECK_INTERVAL: usize = 1024;
+
+pub const GC_TRIGGER_COUNT: usize = 1024;
+
+/// When the garbage collector runs, and how large the heap may get.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    /// Bytes the heap may grow to before the first collection.
+    pub initial_heap: usize,
+    /// After a collection, the next one happens once the heap has grown to
+    /// this multiple of what survived.
+    pub growth_factor: usize,
+    /// Allocating past this many bytes, even after collecting, is a runtime
+    /// error.
+    pub max_heap: Option<usize>,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            initial_heap: GC_TRIGGER_COUNT,
+            growth_factor: 2,
+            max_heap: None,
+        }
+    }
+}
+
+/// Bounds on how much memory and time a script may take up, so that a
+/// runaway script fails rather than exhausting the host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Maximum length in bytes of any string produced at runtime.
+    pub max_string_len: usize,
+    /// Maximum number of values on the stack.
+    pub max_stack: usize,
+    /// Maximum depth of nested calls.
+    pub max_frames: usize,
+    /// Maximum number of instructions that each script, or each call into
+    /// the VM from the host, may execute.
+    pub max_instructions: Option<usize>,
+    /// How long each script, or each call into the VM from the host, may
+    /// run for.
+    pub timeout: Option<Duration>,
+}
 
-const GC_TRIGGER_COUNT: usize = 1024;
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_string_len: 1 << 28,
+            max_stack: STACK_SIZE,
+            max_frames: FRAMES_SIZE,
+            max_instructions: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Stops a script running on another thread. Cancelling stops the script
+/// that the VM is running, or the next one it runs, with
+/// `RuntimeError::Cancelled`. Globals keep whatever values the script had
+/// given them.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the VM has yet to stop a script after a cancellation.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Which of the limits on running time a script ran past.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionLimit {
+    Instructions,
+    Time,
+}
+
+/// The values of global variables, by slot. Names are only looked up when a
+/// program is loaded, after which its ops index straight into `values`.
+#[derive(Default)]
+struct Globals {
+    values: Vec<Option<Value>>,
+    names: Vec<String>,
+    slots: HashMap<String, usize, FnvBuildHasher>,
+}
+
+impl Globals {
+    /// The slot for `name`, which is undefined until something is stored in
+    /// it.
+    fn slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.slots.get(name) {
+            return slot;
+        }
+        let slot = self.values.len();
+        self.values.push(None);
+        self.names.push(name.to_owned());
+        self.slots.insert(name.to_owned(), slot);
+        slot
+    }
+
+    fn get(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+
+    fn set(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flat_map(|v| v.as_ref())
+    }
+}
+
+/// Watches a script run line by line, such as to stop it at breakpoints.
+pub trait Debugger {
+    /// Called when a frame is about to run the first instruction of a line,
+    /// which is whenever it moves to a different line than the one it was
+    /// on. A call returning to the middle of a line doesn't count. Returning
+    /// an error stops the script with it.
+    fn line(&mut self, vm: &VM, line: usize) -> Result<()>;
+}
+
+/// Where a script that is being stepped through carries on from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// The name of the function that is running, as in tracebacks.
+    pub function: String,
+    /// The offset of the next instruction in the function's chunk.
+    pub offset: usize,
+    pub line: usize,
+    /// Whether the next instruction is the first one of its line.
+    pub line_start: bool,
+}
+
+/// What running an instruction with `step` did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    /// The script has more instructions to run.
+    Running,
+    /// The script finished, returning this value.
+    Finished(Value),
+}
+
+/// A call on the stack of a running script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    /// The name of the function that was called, as in tracebacks.
+    pub function: String,
+    /// The offset in the function's chunk of the instruction that the frame
+    /// is running: the next one for the innermost frame, and the call for
+    /// the others.
+    pub ip: usize,
+    pub line: usize,
+    /// Where the callee is on the stack, with its arguments and locals above
+    /// it.
+    pub stack_start: usize,
+}
 
 pub struct VM {
-    // FIXME: Local variables are not currently rooted properly, we will need
-    // to scan the stack to address this at this point.
     heap: Heap<Object>,
+    gc_config: GcConfig,
     next_gc: usize,
-    globals: HashMap<String, Value, FnvBuildHasher>,
+    globals: Globals,
     open_upvalues: Vec<LoxUpValue>,
-
-    stack: Vec<Value>,
+    args: Vec<Value>,
+    limits: Limits,
+    trace: bool,
+    gc_stress: bool,
+    gc_stats: GcStats,
+    instructions: usize,
+    #[cfg(feature = "stats")]
+    op_stats: OpStats,
+    /// The instruction count at which the limits on running time are next
+    /// checked.
+    next_limit_check: usize,
+    max_instruction_count: Option<usize>,
+    deadline: Option<Instant>,
+    cancel: Option<CancelHandle>,
+    /// Where the callee is on the stack for each native that is running.
+    natives_running: Vec<usize>,
+    /// Whether the built in natives that the capabilities and hooks allow
+    /// have been defined since they were last changed.
+    natives_defined: bool,
+    capabilities: Capabilities,
+    hooks: HookRegistry,
+    debugger: Option<Box<dyn Debugger>>,
+    /// Whether the frames belong to a script started by `start`, which runs
+    /// an instruction at a time.
+    stepping: bool,
+    stdout: RefCell<Box<dyn Write>>,
+    stderr: RefCell<Box<dyn Write>>,
+    stdin: RefCell<Box<dyn BufRead>>,
+    start_time: Instant,
+    rng: Rng,
+
+    stack: Stack,
     frames: Vec<CallFrame>,
 }
 
@@ -39,6 +257,9 @@ pub struct CallFrame {
     closure: Handle<Object>,
     ip: usize,
     stack_start: usize,
+    /// The line the debugger last saw this frame on, or zero if it hasn't
+    /// seen it yet.
+    line: usize,
 }
 
 impl CallFrame {
@@ -47,6 +268,7 @@ impl CallFrame {
             closure,
             ip: 0,
             stack_start,
+            line: 0,
         }
     }
 
@@ -68,13 +290,13 @@ impl CallFrame {
         self.with_chunk(|c| c.read_u64(ip))
     }
 
-    pub fn read_constant_at(&mut self, idx: u8) -> Value {
+    pub fn read_constant_at(&self, idx: usize) -> Value {
         self.with_chunk(|c| *c.get_constant(idx).expect("invalid constant index"))
     }
 
     pub fn read_constant(&mut self) -> Value {
         let idx = self.read_byte();
-        self.read_constant_at(idx)
+        self.read_constant_at(idx as usize)
     }
 
     pub fn with_chunk<F, T>(&self, fun: F) -> T
@@ -90,17 +312,37 @@ impl CallFrame {
     }
 }
 
+/// What running an instruction does, for each opcode.
+static HANDLERS: [fn(&mut VM) -> Result<()>; OP_COUNT] = op_table!(VM, Result<()>);
+
+/// A generic op on two numbers, which is quickened into `$quick` once it has
+/// run on them.
 macro_rules! binary_op {
-    ($self:ident, $op:tt) => {
+    ($self:ident, $op:tt, $quick:expr) => {{
         let b = $self.pop();
         let a = $self.pop();
         if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
             let c = a $op b;
-            $self.push(c.into());
-            return;
+            $self.push(c.into())?;
+            $self.quicken($quick);
+            return Ok(());
         }
-        $self.runtime_error(RuntimeError::BadArgument("Operands must be numbers"));
-    }
+        Err(RuntimeError::BadArgument("Operands must be numbers"))
+    }}
+}
+
+/// An op that expects two numbers. Anything else is handed back to the
+/// generic op, which is also put back in its place.
+macro_rules! number_op {
+    ($self:ident, $op:tt, $generic:ident, $slow:expr) => {{
+        let b = $self.pop();
+        let a = $self.pop();
+        if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
+            $self.push((a $op b).into())?;
+            return Ok(());
+        }
+        $self.deoptimize(a, b, $slow, VM::$generic)
+    }}
 }
 
 #[derive(Debug, Clone)]
@@ -113,8 +355,29 @@ pub enum RuntimeError {
     ArityMismatch(u8, u8),
     UndefinedVariable(String),
     UndefinedProperty(String),
+    AllocationLimit,
+    OutOfMemory,
+    BadReceiver(String),
+    Output(String),
+    Input(String),
+    /// Reading or writing the file at a path failed.
+    File(String, String),
+    SuperNotAClass,
+    StackOverflow,
+    UnknownEvent(String),
+    MixinNotAClass,
+    MixinConflict(String),
+    IsNotAClass,
+    ExecutionLimitExceeded(ExecutionLimit),
+    Cancelled,
+    NotSuspended,
+    NotStepping,
+    /// A native couldn't convert one of its arguments to a Rust value.
+    BadConversion(ConversionError),
 }
 
+pub type Result<T> = ::std::result::Result<T, RuntimeError>;
+
 impl ::std::fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match *self {
@@ -126,206 +389,1274 @@ impl ::std::fmt::Display for RuntimeError {
             RuntimeError::ArityMismatch(expected, got) => write!(f, "Expected {} arguments but got {}", expected, got),
             RuntimeError::UndefinedVariable(ref var) => write!(f, "Undefined variable '{}'", var),
             RuntimeError::UndefinedProperty(ref prop) => write!(f, "Undefined property '{}'", prop),
+            RuntimeError::AllocationLimit => write!(f, "Allocation limit exceeded"),
+            RuntimeError::OutOfMemory => write!(f, "Out of memory"),
+            RuntimeError::BadReceiver(ref class) => write!(f, "Expected a {} instance as the receiver", class),
+            RuntimeError::Output(ref err) => write!(f, "Failed to write output: {}", err),
+            RuntimeError::Input(ref err) => write!(f, "Failed to read input: {}", err),
+            RuntimeError::File(ref path, ref err) => write!(f, "Could not access '{}': {}", path, err),
+            RuntimeError::SuperNotAClass => write!(f, "Superclass must be a class"),
+            RuntimeError::StackOverflow => write!(f, "Stack overflow"),
+            RuntimeError::UnknownEvent(ref name) => write!(f, "Unknown event '{}'", name),
+            RuntimeError::MixinNotAClass => write!(f, "Mixin must be a class"),
+            RuntimeError::MixinConflict(ref name) => write!(f, "Method '{}' is defined by more than one mixin", name),
+            RuntimeError::IsNotAClass => write!(f, "Right operand of 'is' must be a class"),
+            RuntimeError::BadConversion(ref err) => write!(f, "{}", err),
+            RuntimeError::ExecutionLimitExceeded(ExecutionLimit::Instructions) => {
+                write!(f, "Instruction limit exceeded")
+            },
+            RuntimeError::ExecutionLimitExceeded(ExecutionLimit::Time) => write!(f, "Time limit exceeded"),
+            RuntimeError::Cancelled => write!(f, "Script cancelled"),
+            RuntimeError::NotSuspended => write!(f, "No suspended script to resume"),
+            RuntimeError::NotStepping => write!(f, "No script to step through"),
         }
     }
 }
 
+/// How many frames of a traceback are displayed before eliding the middle.
+const TRACEBACK_FRAMES: usize = 20;
+
+/// A runtime error along with the call stack at the point it occurred.
+#[derive(Debug)]
+pub struct Traceback {
+    error: RuntimeError,
+    frames: Vec<(usize, String)>,
+}
+
+impl Traceback {
+    /// A traceback for `error`, with the line and function name of each
+    /// call on the stack, innermost first.
+    pub fn new(error: RuntimeError, frames: Vec<(usize, String)>) -> Self {
+        Traceback { error, frames }
+    }
+
+    pub fn error(&self) -> &RuntimeError {
+        &self.error
+    }
+
+    /// The line that was executing when the error occurred, if it happened
+    /// inside the script rather than while setting it up.
+    pub fn line(&self) -> Option<usize> {
+        self.frames.first().map(|f| f.0)
+    }
+
+    /// The line and function name of each call on the stack, innermost
+    /// first.
+    pub fn frames(&self) -> &[(usize, String)] {
+        &self.frames
+    }
+}
+
+impl ::std::fmt::Display for Traceback {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}.", self.error)?;
+        // A runaway recursion would otherwise print every one of its frames.
+        let (shown, elided) = if self.frames.len() > TRACEBACK_FRAMES {
+            (TRACEBACK_FRAMES / 2, self.frames.len() - TRACEBACK_FRAMES)
+        } else {
+            (self.frames.len(), 0)
+        };
+        for &(line, ref name) in &self.frames[..shown] {
+            write!(f, "\n         at [line {}] in {}", line, name)?;
+        }
+        if elided > 0 {
+            write!(f, "\n         ... {} more", elided)?;
+            for &(line, ref name) in &self.frames[shown + elided..] {
+                write!(f, "\n         at [line {}] in {}", line, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
+        VM::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
         VM {
-            stack: Vec::with_capacity(STACK_SIZE),
+            stack: Stack::with_limit(limits.max_stack),
             heap: Heap::default(),
+            gc_config: GcConfig::default(),
             next_gc: GC_TRIGGER_COUNT,
-            globals: HashMap::with_hasher(FnvBuildHasher::default()),
-            frames: Vec::with_capacity(256),
+            globals: Globals::default(),
+            frames: Vec::with_capacity(limits.max_frames.min(256)),
             open_upvalues: Vec::with_capacity(16),
+            args: Vec::new(),
+            limits,
+            trace: false,
+            gc_stress: false,
+            gc_stats: GcStats::default(),
+            instructions: 0,
+            #[cfg(feature = "stats")]
+            op_stats: OpStats::default(),
+            next_limit_check: usize::max_value(),
+            max_instruction_count: None,
+            deadline: None,
+            cancel: None,
+            natives_running: Vec::new(),
+            natives_defined: false,
+            capabilities: Capabilities::default(),
+            hooks: HookRegistry::new(),
+            debugger: None,
+            stepping: false,
+            stdout: RefCell::new(Box::new(io::stdout())),
+            stderr: RefCell::new(Box::new(io::stderr())),
+            stdin: RefCell::new(Box::new(BufReader::new(io::stdin()))),
+            start_time: Instant::now(),
+            rng: Rng::from_time(),
         }
     }
 
+    /// Defines the built in natives, unless they already are. A global that
+    /// the script or the host has already defined keeps its value, even if
+    /// it has the name of a built in.
     fn define_natives(&mut self) {
-        let clock = self.allocate(Object::native_fn("clock", 0, native::clock));
-        self.globals.insert("clock".into(), clock.into());
+        if self.natives_defined {
+            return;
+        }
+        self.natives_defined = true;
+        // The natives belong to the VM rather than the script, so like the
+        // program's constants they don't count towards its allocations.
+        let stats = self.gc_stats;
+        self.define_builtin("printf", 1, native::native_print);
+        self.define_builtin("fieldCount", 1, native::field_count);
+        self.define_builtin("fieldName", 2, native::field_name);
+        self.define_builtin("deleteField", 2, native::delete_field);
+        self.define_builtin("getattr", 2, native::getattr);
+        self.define_builtin("setattr", 3, native::setattr);
+        self.define_builtin("hasattr", 2, native::hasattr);
+        self.define_builtin("len", 1, native::len);
+        self.define_builtin("substr", 3, native::substr);
+        self.define_builtin("indexOf", 2, native::index_of);
+        self.define_builtin("upper", 1, native::upper);
+        self.define_builtin("lower", 1, native::lower);
+        self.define_builtin("chr", 1, native::chr);
+        self.define_builtin("ord", 1, native::ord);
+        self.define_builtin("type", 1, native::type_of);
+        self.define_builtin("str", 1, native::str);
+        self.define_builtin("num", 1, native::num);
+        self.define_builtin("random", 0, native::random);
+        self.define_builtin("randomRange", 2, native::random_range);
+        self.define_builtin("seedRandom", 1, native::seed_random);
+
+        if self.capabilities.time {
+            self.define_builtin("clock", 0, native::clock);
+            self.define_builtin("now", 0, native::now);
+        }
+        if self.capabilities.args {
+            self.define_builtin("argc", 0, native::argc);
+            self.define_builtin("arg", 1, native::arg);
+        }
+        if self.capabilities.input {
+            self.define_builtin("readLine", 0, native::read_line);
+            self.define_builtin("readNumber", 0, native::read_number);
+        }
+        #[cfg(feature = "io")]
+        {
+            if self.capabilities.files {
+                self.define_builtin("readFile", 1, native::read_file);
+                self.define_builtin("writeFile", 2, native::write_file);
+                self.define_builtin("appendFile", 2, native::append_file);
+                self.define_builtin("heapDump", 1, native::heap_dump);
+            }
+        }
+        #[cfg(feature = "process")]
+        {
+            if self.capabilities.process {
+                self.define_builtin("env", 1, native::env);
+                self.define_builtin("exit", 1, native::exit);
+                self.define_builtin("cwd", 0, native::cwd);
+            }
+        }
+        if !self.hooks.is_empty() {
+            self.define_builtin("on", 2, native::on);
+        }
+        self.gc_stats.objects_allocated = stats.objects_allocated;
+        self.gc_stats.bytes_allocated = stats.bytes_allocated;
+    }
+
+    fn define_builtin(&mut self, name: &str, arity: u8, function: NativeFn) {
+        if self.global_value(name).is_none() {
+            self.define_native(name, arity, function);
+        }
+    }
+
+    /// Defines a global function `name` that calls `function`.
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let native = self.insert(Object::native_fn(name, arity, function));
+        let slot = self.globals.slot(name);
+        self.globals.set(slot, native.into());
+    }
+
+    /// Defines a global function `name` that calls a closure with the
+    /// arguments it was called with. The closure can return a string, or
+    /// anything else that has to be allocated, as an `Arg`, which the VM
+    /// allocates once the closure has returned. Nothing the closure allocates
+    /// itself is rooted until then, so it should allocate at most its result.
+    pub fn register_fn<F, R>(&mut self, name: &str, arity: u8, function: F)
+        where F: Fn(&mut VM, &[Value]) -> Result<R> + 'static,
+              R: Into<Arg>,
+    {
+        let function = Rc::new(move |vm: &mut VM, args: &[Value]| {
+            let result = function(vm, &args[1..])?;
+            vm.arg_value(result.into())
+        });
+        let native = self.insert(Object::native_closure(name, arity, function));
+        let slot = self.globals.slot(name);
+        self.globals.set(slot, native.into());
+    }
+
+    /// The value of the global variable `name`, if it has been defined.
+    pub fn global_value(&self, name: &str) -> Option<Value> {
+        self.globals.slots.get(name).and_then(|&slot| self.globals.get(slot))
+    }
+
+    /// Defines the global variable `name`, or assigns to it if it already
+    /// exists.
+    pub fn set_global_value(&mut self, name: &str, value: Arg) {
+        let value = match value {
+            Arg::Nil => Value::nil(),
+            Arg::Bool(b) => b.into(),
+            Arg::Number(n) => Value::float(n),
+            Arg::String(s) => self.insert(Object::String(s)).into(),
+            Arg::Value(value) => value,
+        };
+        let slot = self.globals.slot(name);
+        self.globals.set(slot, value);
+    }
+
+    /// Restricts which of the built in natives scripts can use.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.natives_defined = false;
+    }
+
+    /// The events that scripts can subscribe to with `on`.
+    pub fn set_hooks(&mut self, hooks: HookRegistry) {
+        self.hooks = hooks;
+        self.natives_defined = false;
+    }
+
+    pub fn hooks(&self) -> &HookRegistry {
+        &self.hooks
+    }
 
-        let print = self.allocate(Object::native_fn("printf", 1, native::native_print));
-        self.globals.insert("printf".into(), print.into());
+    /// Calls `debugger` as the scripts that the VM runs reach each line.
+    pub fn set_debugger<D: Debugger + 'static>(&mut self, debugger: D) {
+        self.debugger = Some(Box::new(debugger));
     }
 
-    pub fn interpret(&mut self, stmts: &[Stmt]) {
+    /// How many calls are on the stack, including the script itself.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The line and function name of each call on the stack of a running
+    /// script, innermost first. Unlike in a traceback, the innermost frame
+    /// is at the line it is about to run.
+    pub fn backtrace(&self) -> Vec<(usize, String)> {
+        self.frames().into_iter().map(|frame| (frame.line, frame.function)).collect()
+    }
+
+    /// The calls on the stack of a running script, innermost first.
+    pub fn frames(&self) -> Vec<FrameInfo> {
+        (0..self.frames.len()).map(|frame| {
+            let ip = self.frame_ip(frame);
+            let call = &self.frames[self.frames.len() - 1 - frame];
+            call.with_chunk(|chunk| FrameInfo {
+                function: chunk.name().to_owned(),
+                ip,
+                line: chunk.line(ip),
+                stack_start: call.stack_start,
+            })
+        }).collect()
+    }
+
+    /// The offset of the next instruction of the innermost call, if a script
+    /// is running.
+    pub fn ip(&self) -> Option<usize> {
+        self.frames.last().map(|frame| frame.ip)
+    }
+
+    /// The locals in scope in the `frame`th call of `backtrace`, along with
+    /// their values.
+    pub fn frame_locals(&self, frame: usize) -> Vec<(String, Value)> {
+        let ip = self.frame_ip(frame);
+        let frame = &self.frames[self.frames.len() - 1 - frame];
+        frame.with_chunk(|chunk| {
+            chunk.locals_at(ip).into_iter()
+                .map(|local| (local.name.clone(), self.stack.slot(frame.stack_start, local.slot as usize)))
+                .collect()
+        })
+    }
+
+    /// Every global that has a value, natives included, in the order that
+    /// they were first used.
+    pub fn defined_globals(&self) -> Vec<(String, Value)> {
+        self.globals.names.iter().zip(&self.globals.values)
+            .flat_map(|(name, value)| value.map(|value| (name.clone(), value)))
+            .collect()
+    }
+
+    /// Where the `frame`th call from the innermost is in its chunk. The
+    /// callers have already moved past the instruction that made the call.
+    fn frame_ip(&self, frame: usize) -> usize {
+        let ip = self.frames[self.frames.len() - 1 - frame].ip;
+        if frame == 0 { ip } else { ip.saturating_sub(1) }
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut HookRegistry {
+        &mut self.hooks
+    }
+
+    /// Redirects the output of `print` statements, which goes to stdout by
+    /// default.
+    pub fn set_stdout<W: Write + 'static>(&mut self, stdout: W) {
+        self.stdout = RefCell::new(Box::new(stdout));
+    }
+
+    /// Redirects the output of tracing, which goes to stderr by default.
+    pub fn set_stderr<W: Write + 'static>(&mut self, stderr: W) {
+        self.stderr = RefCell::new(Box::new(stderr));
+    }
+
+    /// Where `readLine` and `readNumber` read from, instead of stdin.
+    pub fn set_stdin<R: BufRead + 'static>(&mut self, stdin: R) {
+        self.stdin = RefCell::new(Box::new(stdin));
+    }
+
+    /// Reads a line from the VM's stdin without its line ending, or None at
+    /// the end of the input.
+    pub fn read_line(&self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.stdin.borrow_mut().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    /// The value as `print` would show it.
+    pub fn stringify(&self, value: Value) -> String {
+        value.with_heap(&self.heap).to_string()
+    }
+
+    /// Writes `value` followed by a newline to the VM's stdout.
+    pub fn println(&self, value: Value) -> io::Result<()> {
+        let mut stdout = self.stdout.borrow_mut();
+        writeln!(stdout, "{}", value.with_heap(&self.heap))
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.stdout.borrow_mut().flush()
+    }
+
+    /// Makes `args` available to the script through the `argc` and `arg`
+    /// natives.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args.clear();
+        for arg in args {
+            let value = self.insert(Object::String(arg)).into();
+            self.args.push(value);
+        }
+    }
+
+    /// When the VM was created, which `clock()` counts from.
+    pub fn start_time(&self) -> Instant {
+        self.start_time
+    }
+
+    /// The generator used by `random` and `randomRange`, which `seedRandom`
+    /// reseeds.
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn args(&self) -> &[Value] {
+        &self.args
+    }
+
+    /// The object that `value` refers to, for natives to inspect.
+    pub fn object(&self, value: Value) -> Option<&Object> {
+        value.as_object().map(move |handle| self.deref(handle))
+    }
+
+    pub fn object_mut(&mut self, value: Value) -> Option<&mut Object> {
+        value.as_object().map(move |handle| self.deref_mut(handle))
+    }
+
+    /// Looks up a property of an instance the way `instance.name` does, so a
+    /// method comes back bound to the instance. This is `None` if the
+    /// instance has no such field or method.
+    pub fn property(&mut self, value: Value, name: &str) -> Result<Option<Value>> {
+        let (instance, class) = match value.as_object() {
+            Some(handle) => match self.deref(handle).as_instance() {
+                Some(instance) => match instance.get_property(name) {
+                    Some(field) => return Ok(Some(field)),
+                    None => (handle, instance.class()),
+                },
+                None => return Err(RuntimeError::BadGet),
+            },
+            None => return Err(RuntimeError::BadGet),
+        };
+        self.bind_instance(name, &instance, class)
+    }
+
+    /// Allocates a string for a native to return. Like any allocation while
+    /// a script runs, this fails if the heap would grow past its maximum.
+    pub fn new_string(&mut self, string: String) -> Result<Value> {
+        self.allocate(Object::String(string)).map(Value::from)
+    }
+
+    /// When enabled, every instruction is disassembled to stderr along with
+    /// the contents of the stack just before it executes.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// When enabled, the heap is collected on every single allocation rather
+    /// than once it has grown past a threshold. This is very slow, but makes
+    /// any value that isn't properly rooted get freed straight away.
+    pub fn set_gc_stress(&mut self, gc_stress: bool) {
+        self.gc_stress = gc_stress;
+    }
+
+    pub fn set_gc_config(&mut self, gc_config: GcConfig) {
+        self.next_gc = gc_config.initial_heap.max(1);
+        self.gc_config = gc_config;
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        GcStats {
+            objects_live: self.heap.len(),
+            ..self.gc_stats
+        }
+    }
+
+    /// A handle that other threads can use to stop the running script. The
+    /// VM only checks for cancellation once a handle has been made.
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        self.cancel.get_or_insert_with(CancelHandle::default).clone()
+    }
+
+    /// How many times each opcode, and each pair of opcodes, has run.
+    #[cfg(feature = "stats")]
+    pub fn op_stats(&self) -> &OpStats {
+        &self.op_stats
+    }
+
+    /// Writes a list of every live object to `out`, along with what refers
+    /// to each of them and where the VM holds the roots they are reachable
+    /// from. See `gc::dump` for the format.
+    pub fn heap_dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut roots = Vec::new();
+        for (i, &value) in self.stack.iter().enumerate() {
+            roots.push((format!("stack {}", i), value));
+        }
+        for (i, frame) in self.frames.iter().enumerate() {
+            roots.push((format!("frame {}", i), frame.closure.into()));
+        }
+        for (name, value) in self.defined_globals() {
+            roots.push((format!("global {}", name), value));
+        }
+        for (i, &value) in self.args.iter().enumerate() {
+            roots.push((format!("arg {}", i), value));
+        }
+        let mut events = self.hooks.events().collect::<Vec<_>>();
+        events.sort_by_key(|event| event.0);
+        for (event, callbacks) in events {
+            for &callback in callbacks {
+                roots.push((format!("hook {}", event), callback));
+            }
+        }
+        dump::write(&self.heap, &roots, out)
+    }
+
+    /// The number of instructions executed so far, across every program run.
+    pub fn instruction_count(&self) -> usize {
+        self.instructions
+    }
+
+    pub fn heap(&self) -> &Heap<Object> {
+        &self.heap
+    }
+
+    pub fn run_program(&mut self, program: &Program) -> ::std::result::Result<Value, Traceback> {
+        self.abandon_suspended();
+        let globals = &mut self.globals;
+        let (function, _) = program.load(&mut self.heap, &mut |name| globals.slot(name));
+        self.execute(function)
+    }
+
+    /// Runs a script that has been loaded from a compiled program. Returns
+    /// the value that the script returned, or nil if it has no top-level
+    /// `return`. Its globals must have been resolved against this VM.
+    fn execute(&mut self, function: LoxFunction) -> ::std::result::Result<Value, Traceback> {
+        // The function's constants aren't rooted until its closure is on the
+        // stack, so that has to happen before anything else is allocated.
+        let closure = LoxClosure::new(function, Vec::new());
+        let value = self.insert(Object::LoxClosure(closure)).into();
+        if let Err(error) = self.stack.push(value) {
+            return Err(self.unwind(error));
+        }
+
         self.define_natives();
-        let function = {
-            let compiler = Compiler::new(&mut self.heap);
-            compiler.compile(stmts)
+        self.start_limits();
+        let res = self.call(0).and_then(|_| self.run(0));
+        res.map(|_| self.pop()).map_err(|error| self.unwind(error))
+    }
+
+    /// Calls each of the callbacks subscribed to `event` with `args`, and
+    /// returns what they returned in the order that they were subscribed.
+    /// Like the result of a script, the values are only safe to use until the
+    /// VM runs again.
+    pub fn fire(&mut self, event: &str, args: Vec<Arg>)
+        -> ::std::result::Result<Vec<Value>, Traceback>
+    {
+        self.abandon_suspended();
+        let callbacks = self.hooks.callbacks(event).to_vec();
+        self.start_limits();
+        self.fire_callbacks(&callbacks, args).map_err(|error| self.unwind(error))
+    }
+
+    /// Calls the global function `name` with `args` and returns its result,
+    /// which like the result of a script is only safe to use until the VM
+    /// runs again.
+    pub fn call_global(&mut self, name: &str, args: Vec<Arg>)
+        -> ::std::result::Result<Value, Traceback>
+    {
+        self.abandon_suspended();
+        let callee = match self.global_value(name) {
+            Some(callee) => callee,
+            None => return Err(self.unwind(RuntimeError::UndefinedVariable(name.to_owned()))),
+        };
+        self.start_limits();
+        self.fire_callbacks(&[callee], args)
+            .map(|mut results| results.pop().expect("a result for the callee"))
+            .map_err(|error| self.unwind(error))
+    }
+
+    /// Saves everything that scripts can reach, so that `restore` can bring
+    /// it back later, perhaps in another VM. A native can take a snapshot
+    /// while the script that called it is running, in which case restoring
+    /// the snapshot suspends that script until `resume`.
+    pub fn snapshot(&self) -> ::std::result::Result<Vec<u8>, SnapshotError> {
+        // The native that is taking the snapshot, along with its arguments,
+        // is replaced by its result when the script resumes.
+        let stack_len = match self.natives_running.len() {
+            0 => self.stack.len(),
+            1 => self.natives_running[0],
+            _ => return Err(SnapshotError::Nested),
+        };
+        let globals = self.defined_globals();
+        let mut hooks = self.hooks.events()
+            .map(|(event, callbacks)| (event.to_owned(), callbacks.to_vec()))
+            .collect::<Vec<_>>();
+        hooks.sort_by(|a, b| a.0.cmp(&b.0));
+        let state = snapshot::State {
+            globals,
+            hooks,
+            stack: self.stack.iter().take(stack_len).cloned().collect(),
+            frames: self.frames.iter().map(|f| (f.closure, f.ip, f.stack_start)).collect(),
+            open_upvalues: self.open_upvalues.clone(),
         };
+        Ok(snapshot::write(&state, &self.heap))
+    }
+
+    /// Replaces the globals, callbacks and any suspended script with those of
+    /// a snapshot. Natives and events are found by name in this VM, and
+    /// natives that the snapshot doesn't mention stay defined.
+    pub fn restore(&mut self, bytes: &[u8]) -> ::std::result::Result<(), SnapshotError> {
+        if !self.natives_running.is_empty() {
+            return Err(SnapshotError::Running);
+        }
+        self.define_natives();
+        let mut natives = HashMap::new();
+        for value in self.globals.values() {
+            if let Some(&Object::NativeFunction(ref native)) = self.object(*value) {
+                natives.insert(native.name.clone(), native.clone());
+            }
+        }
+        let globals = &mut self.globals;
+        let state = snapshot::read(
+            bytes,
+            &mut self.heap,
+            &mut |name| globals.slot(name),
+            &|name| natives.get(name).cloned(),
+        )?;
+        if let Some(&(ref event, _)) = state.hooks.iter().find(|hook| !self.hooks.has_event(&hook.0)) {
+            return Err(SnapshotError::UnknownEvent(event.clone()));
+        }
+
+        for slot in 0..self.globals.values.len() {
+            let keep = match self.globals.values[slot] {
+                Some(value) => self.object(value).map_or(false, |o| o.as_native().is_some()),
+                None => false,
+            };
+            if !keep {
+                self.globals.values[slot] = None;
+            }
+        }
+        for (name, value) in state.globals {
+            let slot = self.globals.slot(&name);
+            self.globals.set(slot, value);
+        }
+        // A built in that this VM's script had shadowed was cleared above,
+        // so put it back unless the snapshot shadows it too.
+        self.natives_defined = false;
+        self.define_natives();
+        self.hooks.clear();
+        for (event, callbacks) in state.hooks {
+            for callback in callbacks {
+                self.hooks.subscribe(&event, callback);
+            }
+        }
+        self.stack.clear();
+        for value in state.stack {
+            self.stack.push(value).map_err(|_| SnapshotError::Invalid)?;
+        }
+        self.stepping = false;
+        self.frames = state.frames.into_iter()
+            .map(|(closure, ip, stack_start)| CallFrame { ip, ..CallFrame::new(closure, stack_start) })
+            .collect();
+        self.open_upvalues = state.open_upvalues;
+        Ok(())
+    }
+
+    /// Whether a script from a restored snapshot is waiting to be resumed.
+    pub fn is_suspended(&self) -> bool {
+        !self.stepping && !self.frames.is_empty() && self.natives_running.is_empty()
+    }
+
+    /// Gets `program` ready to run an instruction at a time with `step`,
+    /// without running any of it.
+    pub fn start(&mut self, program: &Program) -> ::std::result::Result<(), Traceback> {
+        self.abandon_suspended();
+        let globals = &mut self.globals;
+        let (function, _) = program.load(&mut self.heap, &mut |name| globals.slot(name));
         let closure = LoxClosure::new(function, Vec::new());
-        let value = self.allocate(Object::LoxClosure(closure)).into();
+        let value = self.insert(Object::LoxClosure(closure)).into();
+        self.stepping = true;
+        if let Err(error) = self.stack.push(value) {
+            return Err(self.unwind(error));
+        }
+        self.define_natives();
+        self.start_limits();
+        self.call(0).map_err(|error| self.unwind(error))
+    }
 
-        self.push(value);
-        self.call(0);
-        self.run();
+    /// Runs the next instruction of the script from `start`. A call to a
+    /// native, and anything that it calls, counts as a single instruction.
+    pub fn step(&mut self) -> ::std::result::Result<StepResult, Traceback> {
+        if !self.stepping || self.frames.is_empty() {
+            return Err(self.traceback(RuntimeError::NotStepping));
+        }
+        if let Err(error) = self.run_instruction() {
+            return Err(self.unwind(error));
+        }
+        if self.frames.is_empty() {
+            self.stepping = false;
+            return Ok(StepResult::Finished(self.pop()));
+        }
+        Ok(StepResult::Running)
     }
 
-    fn run(&mut self) {
-        while !self.frames.is_empty() {
-            let inst = self.read_byte();
-            decode_op!(inst, self);
+    /// Where the script from `start` carries on from, or `None` if no script
+    /// is being stepped through.
+    pub fn location(&self) -> Option<Location> {
+        if !self.stepping {
+            return None;
         }
+        let frame = self.frames.last()?;
+        Some(frame.with_chunk(|chunk| {
+            let line = chunk.line(frame.ip);
+            Location {
+                function: chunk.name().to_owned(),
+                offset: frame.ip,
+                line,
+                line_start: frame.ip == 0 || chunk.line(frame.ip - 1) != line,
+            }
+        }))
+    }
+
+    /// Writes the disassembly of the next instruction of the script from
+    /// `start` to `out`.
+    pub fn disassemble_next(&self, out: &mut dyn Write) {
+        if let (true, Some(frame)) = (self.stepping, self.frames.last()) {
+            frame.with_chunk(|chunk| {
+                Disassembler::new(chunk, &self.heap, chunk.global_names())
+                    .output(out)
+                    .disassemble_at(frame.ip);
+            });
+        }
+    }
+
+    /// The values on the stack, from the bottom up.
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// Continues the suspended script as though the native that took the
+    /// snapshot had returned `result`, and returns what the script returns.
+    pub fn resume(&mut self, result: Arg) -> ::std::result::Result<Value, Traceback> {
+        if !self.is_suspended() {
+            return Err(self.traceback(RuntimeError::NotSuspended));
+        }
+        self.start_limits();
+        let res = self.arg_value(result)
+            .and_then(|value| self.push(value))
+            .and_then(|_| self.run(0));
+        res.map(|_| self.pop()).map_err(|error| self.unwind(error))
+    }
+
+    /// Running anything else abandons a suspended script, or one that is
+    /// being stepped through, since its frames would otherwise be resumed as
+    /// soon as the new code returned.
+    fn abandon_suspended(&mut self) {
+        if !self.frames.is_empty() && self.natives_running.is_empty() {
+            self.stepping = false;
+            self.frames.clear();
+            self.stack.clear();
+            self.open_upvalues.clear();
+        }
+    }
+
+    fn arg_value(&mut self, arg: Arg) -> Result<Value> {
+        Ok(match arg {
+            Arg::Nil => Value::nil(),
+            Arg::Bool(b) => b.into(),
+            Arg::Number(n) => Value::float(n),
+            Arg::String(s) => self.allocate(Object::String(s))?.into(),
+            Arg::Value(value) => value,
+        })
+    }
+
+    fn fire_callbacks(&mut self, callbacks: &[Value], args: Vec<Arg>) -> Result<Vec<Value>> {
+        // The arguments are kept below the calls on the stack so that each
+        // callback gets the same ones, and the results stay above them until
+        // every callback has run, so that neither is collected in between.
+        let args_start = self.stack.len();
+        let arity = args.len() as u8;
+        for arg in args {
+            let value = self.arg_value(arg)?;
+            self.push(value)?;
+        }
+        let results_start = self.stack.len();
+        for &callback in callbacks {
+            let depth = self.frames.len();
+            self.push(callback)?;
+            for i in args_start..results_start {
+                let arg = self.stack.get(i);
+                self.push(arg)?;
+            }
+            self.call(arity)?;
+            self.run(depth)?;
+        }
+        let results = self.stack.above(results_start).to_vec();
+        self.stack.truncate_to(args_start);
+        Ok(results)
+    }
+
+    /// Builds the traceback for `error`, then unwinds everything so that the
+    /// VM can be used again.
+    fn unwind(&mut self, error: RuntimeError) -> Traceback {
+        let traceback = self.traceback(error);
+        self.stepping = false;
+        self.frames.clear();
+        self.stack.clear();
+        self.open_upvalues.clear();
+        traceback
+    }
+
+    /// Executes instructions until the frames above `depth` have returned.
+    /// Each chunk ends in a return or a halt, so the ip never runs off the end
+    /// of a chunk.
+    fn run(&mut self, depth: usize) -> Result<()> {
+        while self.frames.len() > depth {
+            self.run_instruction()?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn run_instruction(&mut self) -> Result<()> {
+        if self.instructions >= self.next_limit_check {
+            self.check_limits()?;
+        }
+        if self.debugger.is_some() {
+            self.debug_line()?;
+        }
+        if self.trace {
+            self.trace_instruction();
+        }
+        let inst = self.read_byte();
+        self.instructions += 1;
+        #[cfg(feature = "stats")]
+        self.op_stats.record(inst);
+        match HANDLERS.get(inst as usize) {
+            Some(handler) => handler(self),
+            None => panic!("Unknown op {}", inst),
+        }
+    }
+
+    /// Starts counting towards the limits on running time, unless the VM is
+    /// already running a script that this is a part of.
+    fn start_limits(&mut self) {
+        if !self.natives_running.is_empty() {
+            return;
+        }
+        self.max_instruction_count = self.limits.max_instructions.map(|n| self.instructions.saturating_add(n));
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.next_limit_check = match (self.max_instruction_count, self.deadline, &self.cancel) {
+            (None, None, &None) => usize::max_value(),
+            _ => self.instructions,
+        };
+    }
+
+    fn check_limits(&mut self) -> Result<()> {
+        if let Some(max) = self.max_instruction_count {
+            if self.instructions >= max {
+                return Err(RuntimeError::ExecutionLimitExceeded(ExecutionLimit::Instructions));
+            }
+        }
+        let mut next = self.max_instruction_count.unwrap_or_else(usize::max_value);
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(RuntimeError::ExecutionLimitExceeded(ExecutionLimit::Time));
+            }
+            next = next.min(self.instructions.saturating_add(LIMIT_CHECK_INTERVAL));
+        }
+        if let Some(ref cancel) = self.cancel {
+            if cancel.take() {
+                return Err(RuntimeError::Cancelled);
+            }
+            next = next.min(self.instructions.saturating_add(LIMIT_CHECK_INTERVAL));
+        }
+        self.next_limit_check = next;
+        Ok(())
+    }
+
+    /// Tells the debugger when the current frame reaches a new line.
+    fn debug_line(&mut self) -> Result<()> {
+        let line = {
+            let frame = self.frame();
+            frame.with_chunk(|chunk| chunk.line(frame.ip))
+        };
+        if line == self.frame().line {
+            return Ok(());
+        }
+        self.frame_mut().line = line;
+        let mut debugger = self.debugger.take().expect("debugger to be attached");
+        let res = debugger.line(self, line);
+        self.debugger = Some(debugger);
+        res
+    }
+
+    fn trace_instruction(&self) {
+        // Tracing is only a debugging aid, so failing to write it doesn't
+        // stop the program.
+        let _ = self.write_trace();
+    }
+
+    fn write_trace(&self) -> io::Result<()> {
+        let mut stderr = self.stderr.borrow_mut();
+        write!(stderr, "          ")?;
+        for value in self.stack.iter() {
+            write!(stderr, "[ {} ]", value.with_heap(&self.heap))?;
+        }
+        writeln!(stderr)?;
+        let frame = self.frame();
+        frame.with_chunk(|chunk| {
+            let locals = chunk.locals_at(frame.ip);
+            if !locals.is_empty() {
+                write!(stderr, "          locals:")?;
+                for local in locals {
+                    let value = self.stack.slot(frame.stack_start, local.slot as usize);
+                    match value.as_object().map(|o| self.deref(o)) {
+                        Some(&Object::String(ref s)) => write!(stderr, " {}={:?}", local.name, s)?,
+                        _ => write!(stderr, " {}={}", local.name, value.with_heap(&self.heap))?,
+                    }
+                }
+                writeln!(stderr)?;
+            }
+            Disassembler::new(chunk, &self.heap, chunk.global_names())
+                .output(&mut **stderr)
+                .disassemble_at(frame.ip);
+            Ok(())
+        })
+    }
+
+    fn traceback(&self, error: RuntimeError) -> Traceback {
+        let frames = self.frames.iter().rev().map(|frame| {
+            // The ip is past the instruction that failed or made the call.
+            frame.with_chunk(|chunk| (chunk.line(frame.ip.saturating_sub(1)), chunk.name().to_owned()))
+        });
+        Traceback {
+            error,
+            frames: frames.collect(),
+        }
+    }
+
+    fn constant(&mut self, idx: u8) -> Result<()> {
+        self.constant_long(idx as usize)
     }
 
-    fn constant(&mut self, idx: u8) {
-        let val = self.frame_mut().read_constant_at(idx);
-        self.push(val);
+    fn constant_long(&mut self, idx: usize) -> Result<()> {
+        let val = self.frame().read_constant_at(idx);
+        self.push(val)?;
+        Ok(())
     }
 
-    fn print(&mut self) {
+    fn print(&mut self) -> Result<()> {
+        let value = self.peek();
+        if let Some(method) = self.to_string_method(value) {
+            // The instance is the receiver of the call, and is replaced by
+            // what the method returns once it has run.
+            let depth = self.frames.len();
+            self.call_closure(method, 0)?;
+            self.run(depth)?;
+        }
         let val = self.pop();
-        println!("{}", val.with_heap(&self.heap));
+        self.println(val).map_err(|e| RuntimeError::Output(e.to_string()))
     }
 
-    fn add(&mut self) {
+    /// The `toString` method of `value`'s class, if it is an instance of a
+    /// class that has one.
+    fn to_string_method(&self, value: Value) -> Option<Handle<Object>> {
+        let instance = value.as_object().and_then(|h| self.deref(h).as_instance())?;
+        self.deref(instance.class())
+            .as_class()
+            .expect("valid class reference")
+            .method("toString")
+    }
+
+    fn add(&mut self) -> Result<()> {
         let b = self.pop();
         let a = self.pop();
         match (a.decode(), b.decode()) {
-            (Variant::Float(a), Variant::Float(b)) => { return self.push((a + b).into()); }
+            (Variant::Float(a), Variant::Float(b)) => {
+                self.push((a + b).into())?;
+                self.quicken(Op::AddNumber);
+                return Ok(());
+            }
             (Variant::Obj(a), Variant::Obj(b)) => {
                 if let (&Object::String(ref a), &Object::String(ref b)) = (self.deref(a), self.deref(b)) {
+                    if a.len() + b.len() > self.limits.max_string_len {
+                        return Err(RuntimeError::AllocationLimit);
+                    }
                     let c = a.clone() + b;
-                    let val = self.allocate(Object::String(c)).into();
-                    self.push(val);
-                    return;
+                    let val = self.allocate(Object::String(c))?.into();
+                    self.push(val)?;
+                    return Ok(());
                 }
             }
             _ => {}
         }
-         self.runtime_error(RuntimeError::BadArgument("Operands must be two numbers or two strings"));
+        Err(RuntimeError::BadArgument("Operands must be two numbers or two strings"))
     }
 
-    fn sub(&mut self) {
-        binary_op!(self, -);
+    fn sub(&mut self) -> Result<()> {
+        binary_op!(self, -, Op::SubtractNumber)
     }
 
-    fn mul(&mut self) {
-        binary_op!(self, *);
+    fn mul(&mut self) -> Result<()> {
+        binary_op!(self, *, Op::MultiplyNumber)
     }
 
-    fn div(&mut self) {
+    fn div(&mut self) -> Result<()> {
         let b = self.pop();
         let a = self.pop();
         if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
             if b == 0.0 {
-                self.runtime_error(RuntimeError::DivideByZero);
+                return Err(RuntimeError::DivideByZero);
             }
-            self.push((a/b).into());
-            return;
+            self.push((a/b).into())?;
+            self.quicken(Op::DivideNumber);
+            return Ok(());
+        }
+        Err(RuntimeError::BadArgument("Operands must be numbers"))
+    }
+
+    fn add_number(&mut self) -> Result<()> {
+        number_op!(self, +, add, Op::Add)
+    }
+
+    fn sub_number(&mut self) -> Result<()> {
+        number_op!(self, -, sub, Op::Subtract)
+    }
+
+    fn mul_number(&mut self) -> Result<()> {
+        number_op!(self, *, mul, Op::Multiply)
+    }
+
+    fn div_number(&mut self) -> Result<()> {
+        let b = self.pop();
+        let a = self.pop();
+        match (a.decode(), b.decode()) {
+            (Variant::Float(a), Variant::Float(b)) if b != 0.0 => {
+                self.push((a / b).into())
+            },
+            // Dividing by zero is still an error, but doesn't mean that the
+            // operands aren't usually numbers.
+            (Variant::Float(_), Variant::Float(_)) => Err(RuntimeError::DivideByZero),
+            _ => self.deoptimize(a, b, Op::Divide, VM::div),
+        }
+    }
+
+    fn lt_number(&mut self) -> Result<()> {
+        number_op!(self, <, lt, Op::LessThan)
+    }
+
+    fn gt_number(&mut self) -> Result<()> {
+        number_op!(self, >, gt, Op::GreaterThan)
+    }
+
+    fn is(&mut self) -> Result<()> {
+        let class = self.pop().as_object()
+            .filter(|&h| self.deref(h).as_class().is_some())
+            .ok_or(RuntimeError::IsNotAClass)?;
+        let value = self.pop();
+        let is = self.is_instance_of(value, class);
+        self.push(is.into())
+    }
+
+    /// Whether `value` is an instance of `class` or of one of its
+    /// subclasses.
+    fn is_instance_of(&self, value: Value, class: Handle<Object>) -> bool {
+        let mut next = value.as_object()
+            .and_then(|h| self.deref(h).as_instance())
+            .map(|i| i.class());
+        while let Some(ancestor) = next {
+            if ancestor == class {
+                return true;
+            }
+            next = self.deref(ancestor).as_class().expect("class to be a class").superclass();
         }
-        self.runtime_error(RuntimeError::BadArgument("Operands must be numbers"))
+        false
+    }
+
+    /// Puts back the generic `op` in place of a number op whose operands `a`
+    /// and `b` weren't numbers, and runs it on them. This is kept out of line
+    /// so that the number ops stay small.
+    #[cold]
+    #[inline(never)]
+    fn deoptimize(&mut self, a: Value, b: Value, op: Op, generic: fn(&mut VM) -> Result<()>) -> Result<()> {
+        self.push(a)?;
+        self.push(b)?;
+        self.quicken(op);
+        generic(self)
     }
 
-    fn neg(&mut self) {
+    /// Rewrites the op that is running, which takes no operands, into `op`.
+    /// This happens once for each op, so it is kept out of line too.
+    #[inline(never)]
+    fn quicken(&mut self, op: Op) {
+        let offset = self.frame().ip - 1;
+        self.frame().with_chunk(|chunk| chunk.quicken(offset, op));
+    }
+
+    fn neg(&mut self) -> Result<()> {
         if let Variant::Float(a) = self.pop().decode() {
-            self.push((-a).into());
+            self.push((-a).into())?;
+            return Ok(());
         }
-        self.runtime_error(RuntimeError::BadArgument("Operand must be a number"));
+        Err(RuntimeError::BadArgument("Operand must be a number"))
     }
 
-    fn not(&mut self) {
+    fn not(&mut self) -> Result<()> {
         let a = self.pop();
         if a.truthy() {
-            self.push(Value::falselit());
+            self.push(Value::falselit())?;
         } else {
-            self.push(Value::truelit());
+            self.push(Value::truelit())?;
         }
+        Ok(())
     }
 
-    fn eq(&mut self) {
-        let a = self.pop();
+    fn eq(&mut self) -> Result<()> {
         let b = self.pop();
-        self.push((a == b).into());
+        let a = self.pop();
+        let equal = self.values_equal(a, b);
+        self.push(equal.into())?;
+        Ok(())
+    }
+
+    /// Strings built at runtime are separate objects from equal literals,
+    /// so they are compared by contents rather than identity.
+    fn values_equal(&self, a: Value, b: Value) -> bool {
+        match (a.decode(), b.decode()) {
+            // These compare as numbers, so that 0 equals -0 and NaN equals
+            // nothing, even though their bits say otherwise.
+            (Variant::Float(a), Variant::Float(b)) => a == b,
+            (Variant::Obj(x), Variant::Obj(y)) => {
+                if let (&Object::String(ref x), &Object::String(ref y)) = (self.deref(x), self.deref(y)) {
+                    return x == y;
+                }
+                a == b
+            },
+            _ => a == b,
+        }
+    }
+
+    fn gt(&mut self) -> Result<()> {
+        binary_op!(self, >, Op::GreaterNumber)
+    }
+
+    fn lt(&mut self) -> Result<()> {
+        binary_op!(self, <, Op::LessNumber)
+    }
+
+    fn jmp(&mut self) -> Result<()> {
+        self.frame_mut().ip += self.read_u16() as usize;
+        Ok(())
+    }
+
+    fn jump_if_less(&mut self) -> Result<()> {
+        self.compare_and_jump(|a, b| a < b)
     }
 
-    fn gt(&mut self) {
-        binary_op!(self, >);
+    fn jump_if_not_less(&mut self) -> Result<()> {
+        self.compare_and_jump(|a, b| !(a < b))
     }
 
-    fn lt(&mut self) {
-        binary_op!(self, <);
+    fn jump_if_greater(&mut self) -> Result<()> {
+        self.compare_and_jump(|a, b| a > b)
     }
 
-    fn jmp(&mut self) {
-        self.frame_mut().ip = self.read_u16() as usize;
+    fn jump_if_not_greater(&mut self) -> Result<()> {
+        self.compare_and_jump(|a, b| !(a > b))
     }
 
-    fn jze(&mut self) {
-        let ip = self.read_u16();
+    fn compare_and_jump(&mut self, jump: fn(f64, f64) -> bool) -> Result<()> {
+        let offset = self.read_u16();
+        let b = self.pop();
+        let a = self.pop();
+        if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
+            if jump(a, b) {
+                self.frame_mut().ip += offset as usize;
+            }
+            return Ok(());
+        }
+        Err(RuntimeError::BadArgument("Operands must be numbers"))
+    }
+
+    fn jze(&mut self) -> Result<()> {
+        let offset = self.read_u16();
         if self.peek().falsey() {
-            self.frame_mut().ip = ip as usize;
+            self.frame_mut().ip += offset as usize;
         }
+        Ok(())
     }
 
-    fn op_loop(&mut self) {
+    fn op_loop(&mut self) -> Result<()> {
         self.frame_mut().ip -= self.read_u16() as usize;
+        Ok(())
     }
 
-    fn get_global(&mut self) {
-        let global = self.frame_mut()
-            .read_constant()
-            .as_object()
-            .map(|o| self.deref(o))
-            .and_then(|o| o.as_string())
-            .expect("GET_GLOBAL constant was not a string");
+    fn loop_if_true(&mut self) -> Result<()> {
+        let offset = self.read_u16();
+        if !self.pop().falsey() {
+            self.frame_mut().ip -= offset as usize;
+        }
+        Ok(())
+    }
 
-        if let Some(val) = self.globals.get(global).cloned() {
-            self.push(val);
-            return;
-        } else {
-            self.runtime_error(RuntimeError::UndefinedVariable(global.clone()));
+    fn loop_if_less(&mut self) -> Result<()> {
+        self.compare_and_loop(|a, b| a < b)
+    }
+
+    fn loop_if_not_less(&mut self) -> Result<()> {
+        self.compare_and_loop(|a, b| !(a < b))
+    }
+
+    fn loop_if_greater(&mut self) -> Result<()> {
+        self.compare_and_loop(|a, b| a > b)
+    }
+
+    fn loop_if_not_greater(&mut self) -> Result<()> {
+        self.compare_and_loop(|a, b| !(a > b))
+    }
+
+    fn compare_and_loop(&mut self, jump: fn(f64, f64) -> bool) -> Result<()> {
+        let offset = self.read_u16();
+        let b = self.pop();
+        let a = self.pop();
+        if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
+            if jump(a, b) {
+                self.frame_mut().ip -= offset as usize;
+            }
+            return Ok(());
         }
+        Err(RuntimeError::BadArgument("Operands must be numbers"))
     }
 
-    fn define_global(&mut self) {
-        let var = self.frame_mut().read_constant()
-            .as_object()
-            .map(|o| self.deref(o))
-            .and_then(|o| o.as_string())
-            .cloned()
-            .expect("constant to be a string");
-        let lhs = self.stack.pop().unwrap();
-        self.globals.insert(var, lhs);
+    fn op_pop(&mut self) -> Result<()> {
+        self.pop();
+        Ok(())
     }
 
-    fn set_global(&mut self) {
-        let handle = self.frame_mut().read_constant()
-            .as_object()
-            .filter(|&o| self.deref(o).as_string().is_some())
-            .expect("constant to be a string");
+    fn pop_n(&mut self, count: u8) -> Result<()> {
+        self.stack.pop_n(count as usize);
+        Ok(())
+    }
 
-        let var = unsafe {
-            handle.get_unchecked()
-                .as_string()
-                .unwrap()
-        };
+    // Each program has its own pool of global names, which is mapped to the
+    // VM's slots when it is loaded, so they are looked up in the chunk of the
+    // function that is running rather than in whichever program was loaded
+    // last.
 
-        let val = *self.stack.last().unwrap();
-        if let Some(slot) = self.globals.get_mut(var) {
-            *slot = val;
-            return;
+    fn get_global(&mut self) -> Result<()> {
+        let slot = self.global_slot();
+        match self.globals.get(slot) {
+            Some(val) => self.push(val),
+            None => Err(RuntimeError::UndefinedVariable(self.globals.name(slot).to_owned())),
         }
-        self.globals.insert(var.clone(), val);
     }
 
-    fn get_local(&mut self) {
+    fn define_global(&mut self) -> Result<()> {
+        let slot = self.global_slot();
+        let lhs = self.pop();
+        self.globals.set(slot, lhs);
+        Ok(())
+    }
+
+    fn set_global(&mut self) -> Result<()> {
+        let slot = self.global_slot();
+        let val = self.peek();
+        self.globals.set(slot, val);
+        Ok(())
+    }
+
+    /// Reads the operand of a global variable op, which indexes the running
+    /// program's pool of names, and finds the VM's slot for it.
+    fn global_slot(&mut self) -> usize {
+        let idx = self.read_u16() as usize;
+        self.frame().with_chunk(|chunk| chunk.global_slot(idx))
+    }
+
+    fn get_local(&mut self) -> Result<()> {
         let start = self.frame().stack_start;
         let idx = self.read_byte() as usize;
-        let val = self.stack[start + idx];
-        self.push(val);
+        let val = self.stack.slot(start, idx);
+        self.push(val)?;
+        Ok(())
     }
 
-    fn set_local(&mut self) {
+    fn set_local(&mut self) -> Result<()> {
         // We peek because we would just push it back after
         // the assignment occurs.
         let val = self.peek();
         let start = self.frame().stack_start;
         let idx = self.read_byte() as usize;
-        self.stack[start + idx] = val;
+        self.stack.set_slot(start, idx, val);
+        Ok(())
     }
 
     fn frame(&self) -> &CallFrame {
@@ -336,162 +1667,241 @@ impl VM {
         self.frames.last_mut().expect("frames to be nonempty")
     }
 
-    fn immediate(&mut self) {
+    fn immediate(&mut self) -> Result<()> {
         let raw = self.frame_mut().read_u64();
         let val = unsafe { Value::from_raw(raw) };
-        self.push(val);
+        self.push(val)?;
+        Ok(())
     }
 
-    fn imm_nil(&mut self) {
-        self.push(Value::nil());
+    fn imm_nil(&mut self) -> Result<()> {
+        self.push(Value::nil())?;
+        Ok(())
     }
 
-    fn imm_true(&mut self) {
-        self.push(Value::truelit());
+    fn imm_true(&mut self) -> Result<()> {
+        self.push(Value::truelit())?;
+        Ok(())
     }
 
-    fn imm_false(&mut self) {
-        self.push(Value::falselit());
+    fn imm_false(&mut self) -> Result<()> {
+        self.push(Value::falselit())?;
+        Ok(())
     }
 
-    fn invoke(&mut self, arity: u8) {
+    fn invoke(&mut self, arity: u8) -> Result<()> {
         let idx = self.read_byte();
-        let method = self.frame_mut()
-            .read_constant_at(idx)
+        let cache = self.read_u16() as usize;
+        let method = self.frame()
+            .read_constant_at(idx as usize)
             .as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_string())
             .expect("class constant to be a string");
-        let last = self.stack.len();
-        let frame_start = last - (arity + 1) as usize;
-        let instance = self.stack[frame_start]
+        let frame_start = self.stack.below(arity as usize);
+        let instance = self.stack.get(frame_start)
             .as_object()
             .map(|h| self.deref(h))
             .and_then(|o| o.as_instance());
         if let Some(instance) = instance {
             if let Some(field) = instance.get_property(&method) {
-                self.stack[frame_start] = field;
-                self.call(arity);
-                return;
+                self.stack.set(frame_start, field);
+                return self.call(arity);
             }
-            let class = self.deref(instance.class())
-                .as_class()
-                .expect("valid class reference");
-            if let Some(method) = class.method(&method) {
-                self.call_closure(method, arity);
-                return
+            if let Some(method) = self.find_method(cache, instance.class(), &method) {
+                return self.call_closure(method, arity);
             }
-            self.runtime_error(RuntimeError::UndefinedProperty(method.into()))
+            return Err(RuntimeError::UndefinedProperty(method.into()));
         }
-        self.runtime_error(RuntimeError::BadCall)
+        let class = self.stack.get(frame_start)
+            .as_object()
+            .filter(|&h| self.deref(h).as_class().is_some());
+        if let Some(class) = class {
+            let closure = self.deref(class)
+                .as_class()
+                .and_then(|c| c.method(&method));
+            return match closure {
+                Some(closure) => self.call_unbound(class, closure, arity),
+                None => Err(RuntimeError::UndefinedProperty(method.into())),
+            };
+        }
+        Err(RuntimeError::BadCall)
     }
 
-    fn call(&mut self, arity: u8) {
-        let last = self.stack.len();
-        let frame_start = last - (arity + 1) as usize;
-        let callee = self.stack[frame_start].decode();
+    fn call(&mut self, arity: u8) -> Result<()> {
+        let frame_start = self.stack.below(arity as usize);
+        let callee = self.stack.get(frame_start).decode();
 
         // ensure callee is a callable
         if let Variant::Obj(handle) = callee {
             match unsafe { self.heap.get_unchecked(handle) } {
                 &Object::LoxClosure(_) => {
-                    self.call_closure(handle, arity);
-                    return;
+                    return self.call_closure(handle, arity);
                 },
                 &Object::BoundMethod(ref bound) => {
                     let closure = bound.closure.clone();
-                    self.stack[frame_start] = bound.receiver.into();
-                    self.call_closure(closure, arity);
-                    return;
+                    self.stack.set(frame_start, bound.receiver.into());
+                    return self.call_closure(closure, arity);
+                },
+                &Object::UnboundMethod(ref unbound) => {
+                    return self.call_unbound(unbound.class, unbound.closure, arity);
                 },
                 &Object::LoxClass(ref class) => {
                     // Allocate a fresh instance and replace the class reference on the stack
                     let method = class.method("init").clone();
-                    self.stack[frame_start] = self.allocate(
+                    let instance = self.allocate(
                         Object::LoxInstance(
                             LoxInstance::new(handle)
                         )
-                    ).into();
+                    )?;
+                    self.stack.set(frame_start, instance.into());
                     if let Some(init) = method {
-                        self.call_closure(init, arity);
-                        return;
+                        return self.call_closure(init, arity);
                     }
                     // Call to default constructor with arguments
                     if arity > 0 {
-                        self.runtime_error(RuntimeError::ArityMismatch(0, arity));
+                        return Err(RuntimeError::ArityMismatch(0, arity));
                     }
-                    return;
+                    return Ok(());
                 },
                 &Object::NativeFunction(ref native) => {
                     if native.arity != arity {
-                        self.runtime_error(RuntimeError::ArityMismatch(native.arity, arity));
+                        return Err(RuntimeError::ArityMismatch(native.arity, arity));
                     }
-                    let val = {
-                        (native.function)(&self.heap, &self.stack[frame_start..])
-                    };
-                    self.stack.pop(); // function
-                    self.stack.push(val);
-                    return;
+                    // The arguments stay on the stack, and so rooted, while the
+                    // native runs.
+                    let function = native.function.clone();
+                    let args = self.stack.above(frame_start).to_vec();
+                    self.natives_running.push(frame_start);
+                    let val = function(self, &args);
+                    self.natives_running.pop();
+                    let val = val?;
+                    // Pop the arguments as well as the function itself
+                    self.stack.truncate_to(frame_start);
+                    self.stack.push(val)?;
+                    return Ok(());
                 },
                 _ => {},
             }
         }
-        self.runtime_error(RuntimeError::BadCall)
+        Err(RuntimeError::BadCall)
+    }
+
+    /// Calls a closure in place of the running function, so that deep tail
+    /// recursion runs in constant space. Other callees are called normally.
+    fn tail_call(&mut self, arity: u8) -> Result<()> {
+        let frame_start = self.stack.below(arity as usize);
+        let callee = match self.stack.get(frame_start).decode() {
+            Variant::Obj(handle) => match *self.deref(handle) {
+                Object::LoxClosure(_) => Some((handle, None)),
+                Object::BoundMethod(ref bound) => Some((bound.closure, Some(bound.receiver))),
+                _ => None,
+            },
+            _ => None,
+        };
+        let closure = match callee {
+            Some((closure, receiver)) => {
+                if let Some(receiver) = receiver {
+                    self.stack.set(frame_start, receiver.into());
+                }
+                closure
+            },
+            None => return self.call(arity),
+        };
+        let expected = self.deref(closure).as_closure().expect("closure").arity();
+        if expected != arity {
+            return Err(RuntimeError::ArityMismatch(expected, arity));
+        }
+        // Nothing can refer to the running function's locals once it is
+        // replaced, so they're closed over and the callee moved down.
+        let start = self.frame().stack_start;
+        self.close_upvalues(start);
+        self.stack.remove(start..frame_start);
+        *self.frame_mut() = CallFrame::new(closure, start);
+        Ok(())
+    }
+
+    /// Calls a method of `class` with the first argument as the receiver,
+    /// which takes the place of the callee on the stack.
+    fn call_unbound(&mut self, class: Handle<Object>, closure: Handle<Object>, arity: u8) -> Result<()> {
+        let expected = self.deref(closure)
+            .as_closure()
+            .expect("method to be a closure")
+            .arity() + 1;
+        if arity != expected {
+            return Err(RuntimeError::ArityMismatch(expected, arity));
+        }
+        let frame_start = self.stack.below(arity as usize);
+        if !self.is_instance_of(self.stack.get(frame_start + 1), class) {
+            let name = self.deref(class).as_class().expect("class").name().to_owned();
+            return Err(RuntimeError::BadReceiver(name));
+        }
+        self.stack.remove(frame_start..frame_start + 1);
+        self.call_closure(closure, arity - 1)
     }
 
-    fn call_closure(&mut self, handle: Handle<Object>, arity: u8) {
+    fn call_closure(&mut self, handle: Handle<Object>, arity: u8) -> Result<()> {
         let closure = self.deref(handle)
             .as_closure()
             .expect("redundant cast to succeed");
-        let last = self.stack.len();
-        let frame_start = last - (arity + 1) as usize;
+        let frame_start = self.stack.below(arity as usize);
         if closure.arity() != arity {
-            self.runtime_error(RuntimeError::ArityMismatch(closure.arity(), arity));
+            return Err(RuntimeError::ArityMismatch(closure.arity(), arity));
+        }
+        if self.frames.len() == self.limits.max_frames {
+            return Err(RuntimeError::StackOverflow);
         }
         let frame = CallFrame::new(handle, frame_start);
         self.frames.push(frame);
-        return;
+        Ok(())
     }
 
-    fn ret(&mut self) {
-        if let Some(frame) = self.frames.pop() {
-            let retval = self.pop(); // return value
-            if frame.stack_start < self.stack.len() {
-                self.close_upvalues(frame.stack_start);
-            }
-            self.stack.truncate(frame.stack_start);
-            self.push(retval);
-            return;
-        }
-        panic!("Cannot return from top-level.");
+    /// Replaces the running function's callee, arguments and locals with the
+    /// value it returned. Returning from the frame that `run` was started
+    /// above finishes the run, so the script's own frame is no different.
+    fn ret(&mut self) -> Result<()> {
+        let retval = self.pop();
+        let frame = self.frames.pop().expect("frames to be nonempty");
+        self.close_upvalues(frame.stack_start);
+        self.stack.truncate_to(frame.stack_start);
+        self.stack.push(retval)
+    }
+
+    /// Finishes the script, leaving whatever it returned as the only value on
+    /// the stack. Falling off the end of the script returns nil.
+    fn halt(&mut self) -> Result<()> {
+        self.ret()
     }
 
-    fn close_upvalue(&mut self) {
-        let end = self.stack.len() - 1;
+    fn close_upvalue(&mut self) -> Result<()> {
+        let end = self.stack.below(0);
         self.close_upvalues(end);
         self.pop();
+        Ok(())
     }
 
-    fn get_upvalue(&mut self) {
+    fn get_upvalue(&mut self) -> Result<()> {
         let idx = self.frame_mut().read_byte();
         let val = self.current_closure()
             .get(idx as usize)
             .get()
-            .unwrap_or_else(|i| self.stack[i]);
-        self.push(val);
+            .unwrap_or_else(|i| self.stack.get(i));
+        self.push(val)?;
+        Ok(())
     }
 
-    fn set_upvalue(&mut self) {
+    fn set_upvalue(&mut self) -> Result<()> {
         let val = self.peek();
         let idx = self.frame_mut().read_byte();
         let closure = self.current_closure();
         let res = closure.get(idx as usize).set(val);
         if let Err(i) = res {
-            self.stack[i] = val;
+            self.stack.set(i, val);
         }
+        Ok(())
     }
 
-    fn closure(&mut self) {
+    fn closure(&mut self) -> Result<()> {
         let val = self.frame_mut().read_constant();
         let function = val.as_object()
             .map(|o| self.deref(o))
@@ -513,13 +1923,14 @@ impl VM {
             upvalues.push(upvalue);
         }
         let closure = LoxClosure::new(function, upvalues);
-        let val = self.allocate(Object::LoxClosure(closure)).into();
-        self.push(val);
+        let val = self.allocate(Object::LoxClosure(closure))?.into();
+        self.push(val)?;
+        Ok(())
     }
 
-    fn class(&mut self, idx: u8) {
-        let name = self.frame_mut()
-            .read_constant_at(idx)
+    fn class(&mut self, idx: u8) -> Result<()> {
+        let name = self.frame()
+            .read_constant_at(idx as usize)
             .as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_string())
@@ -529,8 +1940,11 @@ impl VM {
         let mut methods = HashMap::<String, Handle<Object>, FnvBuildHasher>::with_hasher(
             FnvBuildHasher::default()
         );
-        for _ in 0..method_count {
-            if let Variant::Obj(handle) = self.pop().decode() {
+        // The methods stay on the stack until the class has been allocated so
+        // that they remain rooted.
+        let methods_start = self.stack.len() - method_count as usize;
+        for &value in self.stack.above(methods_start).iter().rev() {
+            if let Variant::Obj(handle) = value.decode() {
                 if let &Object::LoxClosure(ref method) = self.deref(handle) {
                     let name = method.name();
                     methods.insert(name.to_owned(), handle.clone());
@@ -539,19 +1953,102 @@ impl VM {
             }
             panic!("method was not a closure");
         }
-        let val = self.allocate(Object::LoxClass(LoxClass::new(name, methods))).into();
-        self.push(val);
+        let val = self.allocate(Object::LoxClass(LoxClass::new(name, methods)))?.into();
+        self.stack.truncate_to(methods_start);
+        self.push(val)?;
+        Ok(())
+    }
+
+    /// Copies the methods of the superclass below the class on the stack
+    /// into it and remembers it as the class's superclass, leaving both
+    /// where they are.
+    fn inherit(&mut self) -> Result<()> {
+        let (superclass, class) = (self.stack.peek(1), self.stack.peek(0));
+        let methods = superclass.as_object()
+            .and_then(|h| self.deref(h).as_class())
+            .map(|c| c.methods().map(|(k, v)| (k.clone(), *v)).collect::<Vec<_>>())
+            .ok_or(RuntimeError::SuperNotAClass)?;
+        let superclass = superclass.as_object().expect("superclass to be an object");
+        let class = class.as_object().expect("class to be an object");
+        let class = self.deref_mut(class)
+            .as_class_mut()
+            .expect("class to be a class");
+        class.set_superclass(superclass);
+        class.inherit(methods);
+        Ok(())
+    }
+
+    /// Copies the methods of the mixins on top of the stack into the class
+    /// below them, which is left on the stack. Only the class's own methods
+    /// are present at this point, so they are the ones that win over a
+    /// mixin's.
+    fn mixin(&mut self, count: u8) -> Result<()> {
+        let mixins_start = self.stack.len() - count as usize;
+        let class = self.stack.get(mixins_start - 1)
+            .as_object()
+            .expect("class to be an object");
+        let mut methods = Vec::new();
+        {
+            let own = self.deref(class).as_class().expect("class to be a class");
+            for &mixin in self.stack.above(mixins_start) {
+                let mixin = mixin.as_object()
+                    .and_then(|h| self.deref(h).as_class())
+                    .ok_or(RuntimeError::MixinNotAClass)?;
+                for (name, &method) in mixin.methods() {
+                    if own.method(name).is_some() {
+                        continue;
+                    }
+                    if methods.iter().any(|&(ref n, _)| n == name) {
+                        return Err(RuntimeError::MixinConflict(name.clone()));
+                    }
+                    methods.push((name.clone(), method));
+                }
+            }
+        }
+        self.deref_mut(class)
+            .as_class_mut()
+            .expect("class to be a class")
+            .inherit(methods);
+        self.stack.truncate_to(mixins_start);
+        Ok(())
+    }
+
+    /// Binds the superclass's method to the receiver below it on the stack.
+    fn get_super(&mut self, idx: u8) -> Result<()> {
+        let name = self.frame()
+            .read_constant_at(idx as usize)
+            .as_object()
+            .and_then(|o| self.deref(o).as_string())
+            .cloned()
+            .expect("method name to be a string");
+        let superclass = self.pop()
+            .as_object()
+            .expect("superclass to be an object");
+        let receiver = self.peek()
+            .as_object()
+            .expect("receiver to be an instance");
+        match self.bind_instance(&name, &receiver, superclass)? {
+            Some(method) => {
+                self.pop();
+                self.push(method)?;
+                Ok(())
+            },
+            None => Err(RuntimeError::UndefinedProperty(name)),
+        }
     }
 
-    fn get_property(&mut self) {
+    fn get_property(&mut self) -> Result<()> {
         let idx = self.read_byte();
+        let cache = self.read_u16() as usize;
         // FIXME: Don't clone this string.
-        let name = self.frame_mut()
-            .read_constant_at(idx)
+        let name = self.frame()
+            .read_constant_at(idx as usize)
             .as_object()
             .filter(|o| self.deref(*o).as_string().is_some())
             .expect("property name to be a string");
-        let val = self.pop();
+        // The instance is left on the stack while binding a method, since
+        // that allocates.
+        let val = self.peek();
         if let Variant::Obj(ref handle) = val.decode() {
             if let &Object::LoxInstance(ref inst) = self.deref(*handle) {
                 let name = unsafe {
@@ -567,38 +2064,74 @@ impl VM {
                         .unwrap()
                 };
                 if let Some(prop) = inst.get_property(&name) {
-                    self.push(prop);
-                    return
+                    self.pop();
+                    self.push(prop)?;
+                    return Ok(());
                 }
-                let class_handle = inst.class();
-                if let Some(method) = self.bind_instance(&name, handle, class_handle) {
-                    self.push(method);
-                    return
+                if let Some(method) = self.find_method(cache, inst.class(), &name) {
+                    let bound = self.allocate(Object::BoundMethod(BoundMethod::new(*handle, method)))?;
+                    self.pop();
+                    self.push(bound.into())?;
+                    return Ok(());
                 }
-                self.runtime_error(RuntimeError::UndefinedProperty(name.clone()));
+                return Err(RuntimeError::UndefinedProperty(name.clone()));
+            }
+            if let &Object::LoxClass(ref class) = self.deref(*handle) {
+                let name = self.deref(name).as_string().expect("property name to be a string");
+                return match class.method(name) {
+                    Some(closure) => {
+                        let unbound = UnboundMethod::new(*handle, closure);
+                        let val = self.allocate(Object::UnboundMethod(unbound))?.into();
+                        self.pop();
+                        self.push(val)?;
+                        Ok(())
+                    },
+                    None => Err(RuntimeError::UndefinedProperty(name.clone())),
+                };
             }
         }
-        self.runtime_error(RuntimeError::BadGet);
+        Err(RuntimeError::BadGet)
+    }
+
+    /// Looks up the method `name` on `class`, going through the inline cache
+    /// at `cache` in the running chunk. A class's methods never change once
+    /// it has been created, so a cached method is always current.
+    fn find_method(&self, cache: usize, class: Handle<Object>, name: &str) -> Option<Handle<Object>> {
+        let frame = self.frame();
+        if let Some(method) = frame.with_chunk(|chunk| chunk.cached_method(cache, class)) {
+            return Some(method);
+        }
+        let method = self.deref(class)
+            .as_class()
+            .expect("valid class reference")
+            .method(name)?;
+        frame.with_chunk(|chunk| chunk.cache_method(cache, class, method));
+        Some(method)
     }
 
     fn bind_instance(&mut self, name: &str, instance: &Handle<Object>, class: Handle<Object>)
-        -> Option<Value>
+        -> Result<Option<Value>>
     {
-        let class = self.deref(class)
+        let method = self.deref(class)
             .as_class()
-            .expect("class instance");
-        class.method(name).map(|method| {
-            #[cfg(debug_assertions)]
-            {
-                self.deref(method).as_closure().expect("valid instance");
-            }
-            self.allocate(Object::BoundMethod(BoundMethod::new(*instance, method))).into()
-        })
+            .expect("class instance")
+            .method(name);
+        match method {
+            Some(method) => {
+                #[cfg(debug_assertions)]
+                {
+                    self.deref(method).as_closure().expect("valid instance");
+                }
+                let bound = self.allocate(Object::BoundMethod(BoundMethod::new(*instance, method)))?;
+                Ok(Some(bound.into()))
+            },
+            None => Ok(None),
+        }
     }
 
-    fn set_property(&mut self) {
+    fn set_property(&mut self) -> Result<()> {
         let idx = self.read_byte();
-        let name_val = self.frame_mut().read_constant_at(idx);
+        let name_val = self.frame().read_constant_at(idx as usize);
 
         // Current stack looks like:
         //
@@ -629,10 +2162,10 @@ impl VM {
                     .unwrap()
             };
             inst.set_property(name, val);
-            self.push(val);
-            return
+            self.push(val)?;
+            return Ok(());
         }
-        self.runtime_error(RuntimeError::BadSet);
+        Err(RuntimeError::BadSet)
     }
 
     fn current_closure(&mut self) -> &mut LoxClosure {
@@ -661,10 +2194,10 @@ impl VM {
         let mut open_upvalues = Vec::new();
         ::std::mem::swap(&mut self.open_upvalues, &mut open_upvalues);
         for mut up in open_upvalues {
-            // will this blow up?
-            if up.get().map_err(|i| i >= stack_end).is_err() {
-                up.close(|i| self.stack[i]);
-                self.open_upvalues.push(up);
+            match up.as_local() {
+                Some(slot) if slot >= stack_end => up.close(|i| self.stack.get(i)),
+                Some(_) => self.open_upvalues.push(up),
+                None => {},
             }
         }
     }
@@ -677,62 +2210,92 @@ impl VM {
         self.frame_mut().read_u16()
     }
 
-    fn push(&mut self, value: Value) {
-        // It is important the stack never allocate so that open upvalues don't hold
-        // dangling local references.
-        if self.stack.len() == STACK_SIZE {
-            panic!("stack overflow.");
-        }
-        self.stack.push(value);
+    fn push(&mut self, value: Value) -> Result<()> {
+        self.stack.push(value)
     }
 
     fn pop(&mut self) -> Value {
-        self.stack.pop().expect("stack to be nonempty")
+        self.stack.pop()
     }
 
     fn peek(&mut self) -> Value {
-        *self.stack.last().expect("stack to be nonempty")
-    }
-
-    fn runtime_error(&self, err: RuntimeError) {
-        eprintln!("[error]: {}.", err);
-        for frame in self.frames.iter().rev() {
-            let ip = frame.ip;
-            frame.with_chunk(|chunk| {
-                let name = chunk.name();
-                let line = chunk.line(ip);
-                eprintln!("         at [line {}] in {}", line, name);
-            });
-        }
-        ::std::process::exit(1);
+        self.stack.peek(0)
     }
 
     ///
     /// GC wrapper that handles rooting.
     ///
-    fn allocate(&mut self, object: Object) -> Handle<Object> {
+    /// A collection may run on any call, so every live object must be
+    /// reachable from one of the roots in `collect_garbage` beforehand. Ops
+    /// keep their operands on the stack until after they have allocated.
+    ///
+    /// Fails if the heap is still larger than the configured maximum after
+    /// collecting.
+    ///
+    fn allocate(&mut self, object: Object) -> Result<Handle<Object>> {
+        let handle = self.insert(object);
+        if let Some(max_heap) = self.gc_config.max_heap {
+            if self.heap_size() > max_heap {
+                self.collect_garbage(handle);
+                if self.heap_size() > max_heap {
+                    return Err(RuntimeError::OutOfMemory);
+                }
+            }
+        }
+        Ok(handle)
+    }
+
+    /// Allocates without checking the heap limit, for objects that the host
+    /// rather than the script asked for.
+    fn insert(&mut self, object: Object) -> Handle<Object> {
         let handle = self.heap.insert(object).into_handle();
-        if self.heap.len() * ::std::mem::size_of::<Object>() >= self.next_gc {
-            self.next_gc *= HEAP_GROWTH;
-            // Root everything on the stack as well as all closures in the current
-            // set of callframes, upvalues in scope, and globals.
-            // let frame_iter = self.frames.iter().map(|f| f.closure.into());
-            let upvalue_iter = self.open_upvalues.iter()
-                .flat_map(|u| u.get().ok())
-                .flat_map(|v| v.as_object());
-            let globals_iter = self.globals.values().flat_map(Value::as_object);
-            let stack_iter = self.stack.iter().flat_map(Value::as_object);
-
-            let exclude = stack_iter
-                .chain(Some(handle))
-                .chain(globals_iter)
-                .chain(upvalue_iter);
-
-            self.heap.clean_excluding(exclude);
+        self.gc_stats.record_allocation(::std::mem::size_of::<Object>());
+        if self.heap_size() >= self.next_gc {
+            self.collect_garbage(handle);
+            let target = self.heap_size().saturating_mul(self.gc_config.growth_factor);
+            self.next_gc = target.max(self.gc_config.initial_heap).max(1);
+        } else if self.gc_stress {
+            self.collect_garbage(handle);
         }
         handle
     }
 
+    fn heap_size(&self) -> usize {
+        self.heap.len() * ::std::mem::size_of::<Object>()
+    }
+
+    /// Frees everything that isn't reachable from the VM's roots, or from
+    /// `allocated`, which has yet to be stored anywhere.
+    fn collect_garbage(&mut self, allocated: Handle<Object>) {
+        // The value stack holds locals and temporaries, but a frame's closure
+        // isn't always on it: calling a bound method replaces it with the
+        // receiver.
+        let stack_iter = self.stack.iter().flat_map(Value::as_object);
+        let frame_iter = self.frames.iter().map(|f| f.closure);
+        let globals_iter = self.globals.values().flat_map(Value::as_object);
+        // Open upvalues point into the stack, but closed ones hold their own
+        // value.
+        let upvalue_iter = self.open_upvalues.iter()
+            .flat_map(|u| u.get().ok())
+            .flat_map(|v| v.as_object());
+        let args_iter = self.args.iter().flat_map(Value::as_object);
+        let hooks_iter = self.hooks.values().flat_map(Value::as_object);
+
+        let roots = stack_iter
+            .chain(Some(allocated))
+            .chain(frame_iter)
+            .chain(globals_iter)
+            .chain(upvalue_iter)
+            .chain(args_iter)
+            .chain(hooks_iter);
+
+        let start = Instant::now();
+        let before = self.heap.len();
+        self.heap.clean_excluding(roots);
+        let freed = before - self.heap.len();
+        self.gc_stats.record_collection(freed, start.elapsed());
+    }
+
     fn deref(&self, o: Handle<Object>) -> &Object {
         unsafe { self.heap.get_unchecked(o) }
     }
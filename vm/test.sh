@@ -3,11 +3,9 @@
 # Wrapper around cargo test to avoid testing features that are not
 # implemented in the VM.
 
+# Assigning to an undefined global defines it, and constants past the
+# 256th are loaded with OP_CONSTANT_LONG rather than being an error.
 cargo test --\
-    --skip inheritance\
-    --skip limit\
-    --skip super_keyword\
-    --skip call::object\
-    --skip if_statement::class_in_else\
-    --skip if_statement::class_in_then\
+    --skip assignment::undefined\
+    --skip limit::too_many_constants\
     $@
@@ -0,0 +1,118 @@
+extern crate integration;
+extern crate vm;
+
+use std::fs;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use vm::register;
+use vm::{LoxBuilder, LoxError, RuntimeError};
+
+#[cfg(debug_assertions)]
+const BINARY: &str = "../target/debug/vm";
+#[cfg(debug_assertions)]
+const INTERPRETER: &str = "../target/debug/rlox";
+
+#[cfg(not(debug_assertions))]
+const BINARY: &str = "../target/release/vm";
+#[cfg(not(debug_assertions))]
+const INTERPRETER: &str = "../target/release/rlox";
+
+#[test]
+fn matches_the_interpreter() {
+    integration::differential::check(INTERPRETER, BINARY, 0..200);
+}
+
+/// Collects what a program prints.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The register machine only supports part of the language, so the
+/// programs that use the rest are skipped.
+#[test]
+fn register_backend_matches_the_stack_vm() {
+    let mut compared = 0;
+    for seed in 0..1000 {
+        let source = integration::differential::program(seed);
+        let program = match register::Program::compile(&source) {
+            Ok(program) => program,
+            Err(_) => continue,
+        };
+        let expected = Output::default();
+        let mut lox = LoxBuilder::new().stdout(expected.clone()).build();
+        lox.run(&source).unwrap_or_else(|err| panic!("{} on seed {}:\n{}", err, seed, source));
+        let got = Output::default();
+        let mut machine = register::Machine::default();
+        machine.set_stdout(got.clone());
+        machine.run(&program).unwrap_or_else(|err| panic!("{} on seed {}:\n{}", err, seed, source));
+        assert_eq!(expected.contents(), got.contents(), "seed {}:\n{}", seed, source);
+        compared += 1;
+    }
+    assert!(compared > 10, "only {} programs were compared", compared);
+}
+
+/// Runs every fixture that the register machine can compile on both
+/// backends, and checks that they print the same thing and fail with the
+/// same error. Fixtures that call natives which only the stack VM has are
+/// skipped.
+#[test]
+fn register_backend_runs_fixtures_like_the_stack_vm() {
+    let mut compared = 0;
+    for dir in fs::read_dir("../integration/lox-tests").expect("Could not read fixtures") {
+        let dir = dir.unwrap().path();
+        // The limits are those of the stack VM, and some of those fixtures
+        // are too deeply nested to parse on a test thread's stack.
+        if !dir.is_dir() || dir.ends_with("benchmark") || dir.ends_with("limit") {
+            continue;
+        }
+        for file in fs::read_dir(&dir).unwrap() {
+            let path = file.unwrap().path();
+            let source = fs::read_to_string(&path).unwrap();
+            let program = match register::Program::compile(&source) {
+                Ok(program) => program,
+                Err(_) => continue,
+            };
+            let got = Output::default();
+            let mut machine = register::Machine::default();
+            machine.set_stdout(got.clone());
+            let got_error = machine.run(&program).err();
+            let expected = Output::default();
+            let mut lox = LoxBuilder::new().stdout(expected.clone()).build();
+            // Natives are defined by the first script that runs.
+            lox.run("").unwrap();
+            if let Some(RuntimeError::UndefinedVariable(ref name)) = got_error.as_ref().map(|tb| tb.error()) {
+                if lox.global(name).is_some() {
+                    continue;
+                }
+            }
+            // The backends use the stack differently, so a deep recursion
+            // can overflow at a different depth. They also have different
+            // limits on the size of functions.
+            let expected_error = match lox.run(&source) {
+                Ok(_) => None,
+                Err(LoxError::Runtime(traceback)) => Some(traceback.error().to_string()),
+                Err(LoxError::Compile(_)) => continue,
+            };
+            assert_eq!(expected.contents(), got.contents(), "{}", path.display());
+            assert_eq!(expected_error, got_error.map(|tb| tb.error().to_string()), "{}", path.display());
+            compared += 1;
+        }
+    }
+    assert!(compared > 50, "only {} fixtures were compared", compared);
+}
@@ -0,0 +1,937 @@
+extern crate vm;
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use vm::{Capabilities, ConversionError, FromArgs, FromLox, GcConfig, HookRegistry, Limits, LoxBuilder, LoxError, Operand,
+         Program, RuntimeError, SnapshotError, StepResult, Value, VM};
+use vm::gc::value::Variant;
+
+/// Collects everything written to it so tests can inspect program output.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn double(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[1].decode() {
+        Variant::Float(n) => Ok(Value::float(n * 2.0)),
+        _ => Err(RuntimeError::BadArgument("Can only double numbers")),
+    }
+}
+
+fn repeat(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (text, times): (String, f64) = FromArgs::from_args(&args[1..], vm)?;
+    Ok(Value::from(text.len() as f64 * times))
+}
+
+#[test]
+fn globals_persist_between_runs() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new().stdout(output.clone()).build();
+    lox.run("var greeting = \"hello\";").unwrap();
+    lox.run("print greeting + \" world\";").unwrap();
+    assert_eq!(output.contents(), "hello world\n");
+}
+
+#[test]
+fn globals_shadowing_natives_persist_between_runs() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new().stdout(output.clone()).build();
+    lox.run("var len = 42;").unwrap();
+    lox.run("print len;").unwrap();
+    assert_eq!(output.contents(), "42\n");
+}
+
+#[test]
+fn host_natives_replace_builtins() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new()
+        .native("len", 1, double)
+        .stdout(output.clone())
+        .build();
+    lox.run("print len(21);").unwrap();
+    lox.run("print len(4);").unwrap();
+    assert_eq!(output.contents(), "42\n8\n");
+}
+
+#[test]
+fn natives_and_capabilities() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new()
+        .capabilities(Capabilities::none())
+        .native("double", 1, double)
+        .stdout(output.clone())
+        .build();
+    lox.run("print double(21);").unwrap();
+    assert_eq!(output.contents(), "42\n");
+
+    match lox.run("fun f() {\n  return double(\"x\");\n}\nf();") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Can only double numbers");
+            assert_eq!(traceback.line(), Some(2));
+        },
+        _ => panic!("double should only accept numbers"),
+    }
+
+    match lox.run("clock();") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Undefined variable 'clock'");
+        },
+        _ => panic!("clock should not be defined"),
+    }
+}
+
+#[test]
+fn stdin() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new()
+        .stdin(io::Cursor::new("6\nworld\n"))
+        .stdout(output.clone())
+        .build();
+    lox.run("print readNumber() * 7; print \"hello \" + readLine(); print readLine();").unwrap();
+    assert_eq!(output.contents(), "42\nhello world\nnil\n");
+}
+
+#[cfg(feature = "io")]
+#[test]
+fn files() {
+    let path = std::env::temp_dir().join(format!("rlox-files-{}.txt", std::process::id()));
+    let path = path.to_str().unwrap().replace('\\', "/");
+    let output = Output::default();
+    let mut lox = LoxBuilder::new().stdout(output.clone()).build();
+    let source = format!("
+        var path = \"{}\";
+        writeFile(path, \"hello\");
+        appendFile(path, \" world\");
+        print readFile(path);
+    ", path);
+    lox.run(&source).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.contents(), "hello world\n");
+
+    match lox.run("readFile(path);") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert!(traceback.error().to_string().starts_with(&format!("Could not access '{}'", path)));
+        },
+        _ => panic!("reading a missing file should fail"),
+    }
+    match lox.run("writeFile(path, 1);") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Contents must be a string");
+        },
+        _ => panic!("contents should have to be a string"),
+    }
+
+    let mut lox = LoxBuilder::new().capabilities(Capabilities::none()).build();
+    assert!(lox.run("readFile(\"x\");").is_err());
+}
+
+#[cfg(feature = "process")]
+#[test]
+fn process() {
+    std::env::set_var("RLOX_EMBED_TEST", "set");
+    let output = Output::default();
+    let mut lox = LoxBuilder::new().stdout(output.clone()).build();
+    lox.run("print env(\"RLOX_EMBED_TEST\"); print env(\"RLOX_EMBED_TEST_UNSET\"); print cwd();").unwrap();
+    let cwd = std::env::current_dir().unwrap();
+    assert_eq!(output.contents(), format!("set\nnil\n{}\n", cwd.display()));
+
+    match lox.run("exit(1.5);") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Exit code must be a whole number");
+        },
+        _ => panic!("exit code should have to be a whole number"),
+    }
+
+    let mut lox = LoxBuilder::new().capabilities(Capabilities::none()).build();
+    assert!(lox.run("cwd();").is_err());
+}
+
+#[test]
+fn compile_errors() {
+    let mut lox = LoxBuilder::new().build();
+    match lox.run("print ;") {
+        Err(LoxError::Compile(errors)) => assert_eq!(errors.len(), 1),
+        _ => panic!("expected a compile error"),
+    }
+
+    // Jumps are limited to 16 bits.
+    let source = format!("if (false) {{ {} }} print \"unreachable\";", "nil;".repeat(40000));
+    match lox.run(&source) {
+        Err(LoxError::Compile(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].to_string(), "Too much code to jump over.");
+        },
+        _ => panic!("expected a compile error"),
+    }
+
+    // Each property lookup has a cache, and a chunk has room for 65536.
+    let source = format!("var o; {}", "o.x;".repeat(65537));
+    match lox.run(&source) {
+        Err(LoxError::Compile(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].to_string(), "Too many method lookups in one chunk.");
+        },
+        _ => panic!("expected a compile error"),
+    }
+}
+
+#[test]
+fn max_heap() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new()
+        .gc(GcConfig {
+            max_heap: Some(64 * 1024),
+            ..GcConfig::default()
+        })
+        .stdout(output.clone())
+        .build();
+    let source = "
+        class Node {}
+        var list = nil;
+        for (var i = 0; i < 100000; i = i + 1) {
+            var node = Node();
+            node.next = list;
+            list = node;
+        }
+    ";
+    match lox.run(source) {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Out of memory");
+        },
+        _ => panic!("expected to run out of memory"),
+    }
+
+    // Garbage doesn't count towards the limit, and the VM is still usable
+    // once the script has failed.
+    lox.run("list = nil; for (var i = 0; i < 100000; i = i + 1) { var s = \"a\" + \"b\"; } print \"ok\";").unwrap();
+    assert_eq!(output.contents(), "ok\n");
+}
+
+#[test]
+fn max_heap_counts_native_strings() {
+    let mut lox = LoxBuilder::new()
+        .gc(GcConfig {
+            max_heap: Some(16 * 1024),
+            ..GcConfig::default()
+        })
+        .build();
+    // Each frame keeps a string from a native alive.
+    let source = "fun keep(n) { var s = upper(\"a\"); return keep(n + 1) + s; } keep(0);";
+    assert_eq!(runtime_error(lox.run(source)), "Out of memory");
+}
+
+struct Closed;
+
+impl Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn runtime_errors() {
+    let mut lox = LoxBuilder::new().build();
+    let source = "fun inner() {\n  return 1 - \"a\";\n}\nfun outer() {\n  inner();\n}\nouter();";
+    let traceback = match lox.run(source) {
+        Err(LoxError::Runtime(traceback)) => traceback,
+        _ => panic!("expected a runtime error"),
+    };
+    assert_eq!(traceback.error().to_string(), "Operands must be numbers");
+    assert_eq!(traceback.line(), Some(2));
+    let lines = traceback.frames().iter().map(|f| f.0).collect::<Vec<_>>();
+    assert_eq!(lines, vec![2, 5, 7]);
+
+    // Operators report their own line rather than their last operand's, and
+    // code after a multi-line expression goes back to the statement's line.
+    let source = "var a = 1;\nfun f(x) {\n  return x -\n    \"a\";\n}\nprint (\n  a)\n  + f(\n  a);";
+    let traceback = match lox.run(source) {
+        Err(LoxError::Runtime(traceback)) => traceback,
+        _ => panic!("expected a runtime error"),
+    };
+    let lines = traceback.frames().iter().map(|f| f.0).collect::<Vec<_>>();
+    assert_eq!(lines, vec![3, 8]);
+
+    let mut lox = LoxBuilder::new().stdout(Closed).build();
+    match lox.run("print 1;") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Failed to write output: closed");
+        },
+        _ => panic!("expected a runtime error"),
+    }
+}
+
+#[test]
+fn stack_overflow() {
+    let mut lox = LoxBuilder::new()
+        .limits(Limits {
+            max_frames: 64,
+            ..Limits::default()
+        })
+        .build();
+    let traceback = match lox.run("fun recurse(n) {\n  return 1 + recurse(n + 1);\n}\nrecurse(0);") {
+        Err(LoxError::Runtime(traceback)) => traceback,
+        _ => panic!("expected a stack overflow"),
+    };
+    assert_eq!(traceback.error().to_string(), "Stack overflow");
+    assert_eq!(traceback.frames().len(), 64);
+    assert!(traceback.to_string().contains("... 44 more"));
+
+    // The stack is unwound so later scripts can still call functions.
+    lox.run("recurse = nil; fun one() { return 1; } one();").unwrap();
+
+    // Tail calls reuse the caller's frame, so they can recurse past the
+    // limit.
+    lox.run("fun loop(n) {\n  if (n == 0) return \"done\";\n  return loop(n - 1);\n}\nloop(1000);").unwrap();
+}
+
+fn runtime_error(result: Result<Value, LoxError>) -> String {
+    match result {
+        Err(LoxError::Runtime(traceback)) => traceback.error().to_string(),
+        _ => panic!("expected a runtime error"),
+    }
+}
+
+#[test]
+fn execution_limits() {
+    let mut lox = LoxBuilder::new()
+        .limits(Limits {
+            max_instructions: Some(1000),
+            ..Limits::default()
+        })
+        .build();
+    assert_eq!(runtime_error(lox.run("while (true) {}")), "Instruction limit exceeded");
+    // Each script gets a budget of its own.
+    lox.run("var i = 0; while (i < 10) i = i + 1;").unwrap();
+    lox.run("fun spin() { while (true) {} }").unwrap();
+    assert_eq!(runtime_error(lox.call("spin", ())), "Instruction limit exceeded");
+    assert_eq!(lox.eval("i").unwrap().as_float(), 10.0);
+
+    let mut lox = LoxBuilder::new()
+        .limits(Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        })
+        .build();
+    assert_eq!(runtime_error(lox.run("while (true) {}")), "Time limit exceeded");
+    lox.run("print 1;").unwrap();
+}
+
+#[test]
+fn cancellation() {
+    let mut lox = LoxBuilder::new().build();
+    let cancel = lox.cancel_handle();
+    let canceller = {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            cancel.cancel();
+        })
+    };
+    assert_eq!(runtime_error(lox.run("var n = 0; while (true) n = n + 1;")), "Script cancelled");
+    canceller.join().unwrap();
+    assert!(!cancel.is_cancelled());
+    // The script's globals are left as they were when it stopped.
+    assert_eq!(bool::try_from(lox.eval("n > 0").unwrap()), Ok(true));
+
+    // Cancelling between scripts stops the next one.
+    cancel.cancel();
+    assert_eq!(runtime_error(lox.run("while (true) {}")), "Script cancelled");
+    lox.run("print 1;").unwrap();
+}
+
+#[test]
+fn worker_threads() {
+    let program = Program::compile("
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        print fib(worker + 10);
+        tick(worker);
+    ").unwrap();
+    let workers = (0..4).map(|worker| {
+        let output = Output::default();
+        let builder = LoxBuilder::new()
+            .native("tick", 1, double)
+            .hooks(HookRegistry::new().event("done"))
+            .stdout(output.clone());
+        let program = program.clone();
+        let handle = thread::spawn(move || {
+            let mut lox = builder.build();
+            lox.set_global("worker", worker as f64);
+            lox.run_program(&program).unwrap();
+            lox.run("on(\"done\", tick);").unwrap();
+        });
+        (handle, output)
+    }).collect::<Vec<_>>();
+    let outputs = workers.into_iter().map(|(handle, output)| {
+        handle.join().unwrap();
+        output.contents()
+    }).collect::<Vec<_>>();
+    assert_eq!(outputs, ["55\n", "89\n", "144\n", "233\n"]);
+}
+
+#[test]
+fn hooks() {
+    let output = Output::default();
+    let mut lox = LoxBuilder::new()
+        .hooks(HookRegistry::new().event("greet").event("idle"))
+        .gc_stress(true)
+        .stdout(output.clone())
+        .build();
+    lox.run("var greeting = \"Hello\";").unwrap();
+    lox.run("
+        fun welcome(name, times) {
+            print greeting + \", \" + name + \"!\";
+            return times * 2;
+        }
+        on(\"greet\", welcome);
+        class Counter {
+            init() { this.count = 0; }
+            greet(name, times) { this.count = this.count + times; return this.count; }
+        }
+        var counter = Counter();
+        on(\"greet\", counter.greet);
+    ").unwrap();
+
+    let results = lox.fire("greet", ("Ada", 3.0)).unwrap();
+    let results = results.iter().map(|v| v.as_float()).collect::<Vec<_>>();
+    assert_eq!(results, vec![6.0, 3.0]);
+    // Callbacks still find their globals after other scripts have run.
+    lox.run("var unrelated = 1; unrelated = unrelated + 1;").unwrap();
+    lox.fire("greet", ("Grace", 1.0)).unwrap();
+    assert_eq!(output.contents(), "Hello, Ada!\nHello, Grace!\n");
+    assert!(lox.fire("idle", ()).unwrap().is_empty());
+
+    match lox.fire("greet", ("Ada",)) {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Expected 2 arguments but got 1");
+        },
+        _ => panic!("expected an arity mismatch"),
+    }
+    match lox.run("on(\"missing\", clock);") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Unknown event 'missing'");
+        },
+        _ => panic!("expected an unknown event"),
+    }
+
+    // Without any events, scripts can't subscribe to anything.
+    let mut lox = LoxBuilder::new().build();
+    assert!(lox.run("on;").is_err());
+}
+
+#[test]
+fn globals_and_calls() {
+    let mut lox = LoxBuilder::new().gc_stress(true).build();
+    lox.set_global("name", "Ada");
+    lox.set_global("count", 2.0);
+    lox.run("
+        fun greet(greeting) {
+            count = count + 1;
+            return greeting + \", \" + name;
+        }
+    ").unwrap();
+
+    let greeting = lox.call("greet", ("Hello",)).unwrap();
+    assert_eq!(lox.vm().stringify(greeting), "Hello, Ada");
+    assert_eq!(lox.global("count").map(|v| v.as_float()), Some(3.0));
+    assert!(lox.global("missing").is_none());
+
+    assert_eq!(lox.eval("count * 2").unwrap().as_float(), 6.0);
+    let greeting = lox.eval("greet(\"Hi\")").unwrap();
+    assert_eq!(lox.vm().stringify(greeting), "Hi, Ada");
+    assert!(lox.eval("1 + 2; print 3;").is_err());
+    assert!(lox.eval("var x = 1").is_err());
+
+    match lox.call("missing", ()) {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Undefined variable 'missing'");
+        },
+        _ => panic!("expected an undefined function"),
+    }
+    match lox.call("greet", ()) {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Expected 1 arguments but got 0");
+        },
+        _ => panic!("expected an arity mismatch"),
+    }
+    // A failed call leaves the VM ready to use again.
+    assert_eq!(lox.eval("count").unwrap().as_float(), 4.0);
+}
+
+#[test]
+fn conversions() {
+    let mut lox = LoxBuilder::new().native("repeat", 2, repeat).build();
+    assert_eq!(f64::try_from(lox.eval("1 + 2").unwrap()), Ok(3.0));
+    assert_eq!(bool::try_from(lox.eval("!nil").unwrap()), Ok(true));
+    assert_eq!(f64::try_from(Value::from(())), Err(ConversionError::WrongType { expected: "number", found: "nil" }));
+
+    let value = lox.eval("\"a\" + \"b\"").unwrap();
+    assert_eq!(String::from_lox(value, lox.vm()), Ok("ab".to_owned()));
+    assert_eq!(f64::from_lox(value, lox.vm()), Err(ConversionError::WrongType { expected: "number", found: "string" }));
+    assert_eq!(Option::<f64>::from_lox(Value::from(()), lox.vm()), Ok(None));
+    assert_eq!(Option::<f64>::from_lox(Value::from(2.0), lox.vm()), Ok(Some(2.0)));
+
+    let args = [Value::from(1.0), Value::from(true)];
+    assert_eq!(<(f64, bool)>::from_args(&args, lox.vm()), Ok((1.0, true)));
+    assert_eq!(<(f64,)>::from_args(&args, lox.vm()), Err(ConversionError::WrongCount { expected: 1, found: 2 }));
+    assert_eq!(Vec::<Value>::from_args(&args, lox.vm()).map(|args| args.len()), Ok(2));
+
+    lox.set_global("maybe", None::<&str>);
+    assert_eq!(bool::try_from(lox.eval("maybe == nil").unwrap()), Ok(true));
+    lox.run("fun sum(a, b, c) { return a + b + c; }").unwrap();
+    assert_eq!(lox.call("sum", vec![1.0, 2.0, 3.0]).unwrap().as_float(), 6.0);
+
+    assert_eq!(lox.eval("repeat(\"abc\", 2)").unwrap().as_float(), 6.0);
+    match lox.eval("repeat(2, \"abc\")") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Expected string but got number");
+        },
+        _ => panic!("expected a bad conversion"),
+    }
+}
+
+#[test]
+fn closure_natives() {
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let mut lox = LoxBuilder::new().gc_stress(true).build();
+    let log = calls.clone();
+    lox.register_fn("shout", 2, move |vm, args| {
+        let (text, times): (String, f64) = FromArgs::from_args(args, vm)?;
+        log.borrow_mut().push(text.clone());
+        Ok(text.to_uppercase().repeat(times as usize))
+    });
+    let prefix = String::from("> ");
+    lox.register_fn("prompt", 1, move |vm, args| Ok(format!("{}{}", prefix, vm.stringify(args[0]))));
+
+    let value = lox.eval("shout(\"hey\", 2) + \"!\"").unwrap();
+    assert_eq!(lox.vm().stringify(value), "HEYHEY!");
+    let value = lox.eval("prompt(1 + 2)").unwrap();
+    assert_eq!(lox.vm().stringify(value), "> 3");
+    assert_eq!(*calls.borrow(), vec!["hey".to_owned()]);
+
+    match lox.eval("shout(1, 2)") {
+        Err(LoxError::Runtime(traceback)) => {
+            assert_eq!(traceback.error().to_string(), "Expected string but got number");
+        },
+        _ => panic!("expected a bad conversion"),
+    }
+    assert!(lox.eval("shout(\"hey\")").is_err());
+}
+
+#[test]
+fn trace_output() {
+    let (stdout, stderr) = (Output::default(), Output::default());
+    let mut lox = LoxBuilder::new().trace(true).stdout(stdout.clone()).stderr(stderr.clone()).build();
+    lox.run("{ var a = 1; print a + 2; }").unwrap();
+    assert_eq!(stdout.contents(), "3\n");
+    let trace = stderr.contents();
+    assert!(trace.contains("OP_ADD"), "trace: {}", trace);
+    assert!(trace.contains("locals: a=1"), "trace: {}", trace);
+}
+
+#[test]
+fn arithmetic_is_quickened_for_numbers() {
+    let (stdout, stderr) = (Output::default(), Output::default());
+    let mut lox = LoxBuilder::new().trace(true).stdout(stdout.clone()).stderr(stderr.clone()).build();
+    lox.run("
+        fun add(a, b) { return a + b; }
+        print add(1, 2);
+        print add(3, 4);
+        print add(\"a\", \"b\");
+        print add(5, 6);
+        print -add(1, 2) * 2;
+    ").unwrap();
+    assert_eq!(stdout.contents(), "3\n7\nab\n11\n-6\n");
+    let trace = stderr.contents();
+    let ops = trace.lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .filter(|op| op.starts_with("OP_ADD") || op.starts_with("OP_MUL"))
+        .collect::<Vec<_>>();
+    // The add is quickened once it has seen numbers, and put back when it
+    // sees strings. The multiply is known to get numbers from the start.
+    assert_eq!(ops, ["OP_ADD", "OP_ADD_NUMBER", "OP_ADD_NUMBER", "OP_ADD", "OP_ADD_NUMBER", "OP_MUL_NUMBER"]);
+}
+
+#[test]
+fn snapshots() {
+    let hooks = || HookRegistry::new().event("tick");
+    let mut lox = LoxBuilder::new().hooks(hooks()).build();
+    lox.run("
+        class Node {
+            init(name) { this.name = name; }
+            describe() { return this.name + \" -> \" + this.next.name; }
+        }
+        var a = Node(\"a\");
+        var b = Node(\"b\");
+        a.next = b;
+        b.next = a;
+        var describe = a.describe;
+        class Leaf < Node {}
+        var leaf = Leaf(\"c\");
+
+        var inc;
+        var get;
+        {
+            var n = 0;
+            fun i() { n = n + 1; return n; }
+            fun g() { return n; }
+            inc = i;
+            get = g;
+        }
+        inc();
+        inc();
+        fun tick(x) { return x + inc(); }
+        on(\"tick\", tick);
+    ").unwrap();
+    let bytes = lox.snapshot().unwrap();
+
+    let mut restored = LoxBuilder::new().hooks(hooks()).gc_stress(true).build();
+    restored.run("var extra = 1;").unwrap();
+    restored.restore(&bytes).unwrap();
+    assert!(restored.global("extra").is_none());
+    assert_eq!(restored.eval("inc()").unwrap().as_float(), 3.0);
+    assert_eq!(restored.eval("get()").unwrap().as_float(), 3.0);
+    let description = restored.eval("describe()").unwrap();
+    assert_eq!(restored.vm().stringify(description), "a -> b");
+    assert_eq!(bool::try_from(restored.eval("a.next.next == a").unwrap()), Ok(true));
+    assert_eq!(bool::try_from(restored.eval("leaf is Node").unwrap()), Ok(true));
+    assert_eq!(restored.eval("len(a.name + b.name)").unwrap().as_float(), 2.0);
+    let results = restored.fire("tick", (10.0,)).unwrap();
+    assert_eq!(results.iter().map(|v| v.as_float()).collect::<Vec<_>>(), vec![14.0]);
+
+    // The original carries on from where the snapshot was taken.
+    assert_eq!(lox.eval("get()").unwrap().as_float(), 2.0);
+
+    // Events have to exist in the VM that the snapshot is restored into.
+    match LoxBuilder::new().hooks(HookRegistry::new().event("other")).build().restore(&bytes) {
+        Err(SnapshotError::UnknownEvent(ref event)) if event == "tick" => {},
+        other => panic!("expected an unknown event, got {:?}", other.err()),
+    }
+    // Any damage to a snapshot is an error rather than a crash.
+    for len in 0..bytes.len() {
+        assert!(LoxBuilder::new().hooks(hooks()).build().restore(&bytes[..len]).is_err());
+    }
+}
+
+#[test]
+fn resume_from_snapshot() {
+    let build = |saved: Rc<RefCell<Option<Vec<u8>>>>, output: Output| {
+        let mut lox = LoxBuilder::new().stdout(output).gc_stress(true).build();
+        lox.register_fn("checkpoint", 0, move |vm, _| {
+            *saved.borrow_mut() = Some(vm.snapshot().unwrap());
+            Ok(())
+        });
+        lox
+    };
+    let source = "
+        fun work(limit) {
+            var total = 0;
+            fun add(n) { total = total + n; }
+            for (var i = 1; i <= limit; i = i + 1) {
+                add(i);
+                if (i == 2) print checkpoint();
+            }
+            return total;
+        }
+        print work(4);
+        return \"done\";
+    ";
+    let saved = Rc::new(RefCell::new(None));
+    let output = Output::default();
+    let mut lox = build(saved.clone(), output.clone());
+    lox.run(source).unwrap();
+    assert_eq!(output.contents(), "nil\n10\n");
+    let bytes = saved.borrow_mut().take().unwrap();
+
+    let output = Output::default();
+    let mut restored = build(saved.clone(), output.clone());
+    restored.restore(&bytes).unwrap();
+    assert!(restored.vm().is_suspended());
+    let result = restored.resume("resumed").unwrap();
+    assert_eq!(restored.vm().stringify(result), "done");
+    assert_eq!(output.contents(), "resumed\n10\n");
+    assert!(!restored.vm().is_suspended());
+    assert_eq!(runtime_error(restored.resume(())), "No suspended script to resume");
+
+    // Running anything else abandons the suspended script.
+    restored.restore(&bytes).unwrap();
+    restored.run("print 1;").unwrap();
+    assert_eq!(runtime_error(restored.resume(())), "No suspended script to resume");
+
+    // The natives that the snapshot uses have to exist.
+    match LoxBuilder::new().build().restore(&bytes) {
+        Err(SnapshotError::UnknownNative(ref name)) if name == "checkpoint" => {},
+        other => panic!("expected an unknown native, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn suspended_frames_resume_after_a_call() {
+    let saved = Rc::new(RefCell::new(None));
+    let mut lox = LoxBuilder::new().build();
+    let save = saved.clone();
+    lox.register_fn("checkpoint", 0, move |vm, _| {
+        *save.borrow_mut() = Some(vm.snapshot().unwrap());
+        Ok(())
+    });
+    lox.run("fun wait() {\n  checkpoint();\n  return 1;\n}\nreturn wait();").unwrap();
+    let mut bytes = saved.borrow_mut().take().unwrap();
+    lox.restore(&bytes).unwrap();
+    assert_eq!(lox.resume(()).unwrap().as_float(), 1.0);
+
+    // Nothing is captured, so the snapshot ends with the frame of `wait` and
+    // no open upvalues. Moving its ip back onto the call itself leaves it at
+    // the start of an instruction, but not one that a call returns to.
+    let ip = bytes.len() - 12;
+    let moved = u32::from_le_bytes([bytes[ip], bytes[ip + 1], bytes[ip + 2], bytes[ip + 3]]) - 1;
+    bytes[ip..ip + 4].copy_from_slice(&moved.to_le_bytes());
+    match lox.restore(&bytes) {
+        Err(SnapshotError::Invalid) => {},
+        other => panic!("expected an invalid snapshot, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn step_through_instructions() {
+    let program = Program::compile("fun f(x) {\n  return x * 2;\n}\nprint f(1);\nreturn f(2);").unwrap();
+    let output = Output::default();
+    let mut vm = VM::new();
+    vm.set_stdout(output.clone());
+    assert_eq!(vm.step().unwrap_err().error().to_string(), "No script to step through");
+    assert_eq!(vm.location(), None);
+
+    vm.start(&program).unwrap();
+    let start = vm.location().unwrap();
+    assert_eq!((&start.function[..], start.offset, start.line_start), ("<top>", 0, true));
+    let mut lines = Vec::new();
+    let mut frames = None;
+    let result = loop {
+        let location = vm.location().unwrap();
+        assert_eq!(vm.ip(), Some(location.offset));
+        if location.function == "f" && frames.is_none() {
+            frames = Some(vm.frames());
+        }
+        if location.line_start {
+            lines.push((location.function, location.line));
+        }
+        if let StepResult::Finished(result) = vm.step().unwrap() {
+            break result;
+        }
+    };
+    assert_eq!(vm.stringify(result), "4");
+    assert_eq!(output.contents(), "2\n");
+    assert_eq!(lines, [
+        ("<top>".to_owned(), 1),
+        ("<top>".to_owned(), 4),
+        ("f".to_owned(), 2),
+        ("<top>".to_owned(), 5),
+        ("f".to_owned(), 2),
+    ]);
+    assert_eq!(vm.location(), None);
+    assert_eq!(vm.ip(), None);
+
+    let frames = frames.unwrap();
+    let calls = frames.iter().map(|frame| (&frame.function[..], frame.line, frame.stack_start)).collect::<Vec<_>>();
+    assert_eq!(calls, [("f", 2, 1), ("<top>", 4, 0)]);
+    assert_eq!(frames[0].ip, 0);
+
+    // Running anything else abandons the script being stepped through.
+    vm.start(&program).unwrap();
+    vm.step().unwrap();
+    vm.run_program(&Program::compile("print 3;").unwrap()).unwrap();
+    assert!(vm.step().is_err());
+    assert_eq!(output.contents(), "2\n3\n");
+}
+
+#[test]
+fn heap_dump() {
+    let mut lox = LoxBuilder::new().capabilities(Capabilities::none()).build();
+    lox.run("
+        class Node { init(next) { this.next = next; } }
+        var list = Node(Node(nil));
+        var name = \"a string that is long enough to be cut short in the dump\";
+    ").unwrap();
+    let mut out = Vec::new();
+    lox.vm().heap_dump(&mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    let lines = dump.lines().collect::<Vec<_>>();
+
+    let line = |prefix: &str| *lines.iter().find(|line| line.starts_with(prefix))
+        .unwrap_or_else(|| panic!("no line starting with {:?} in:\n{}", prefix, dump));
+    let id = |line: &str| line.rsplit('#').next().unwrap().to_owned();
+    let list = id(line("global list -> "));
+    let head = line(&format!("#{} instance Node", list));
+    assert!(head.contains(" bytes: next=#"), "{}", head);
+    let next = head.split("next=#").nth(1).unwrap().split(' ').next().unwrap();
+    let tail = line(&format!("#{} instance Node", next));
+    assert!(!tail.contains("next="), "{}", tail);
+    assert!(head.ends_with(&tail[tail.find("class=").unwrap()..]), "{}\n{}", head, tail);
+
+    let name = line(&format!("#{} string", id(line("global name -> "))));
+    assert!(name.contains("\"a string that is long enough to be cut s\"..."), "{}", name);
+    assert!(lines.iter().any(|line| line.trim_start().starts_with("2 ") && line.ends_with("bytes  instance Node")), "{}", dump);
+}
+
+#[cfg(feature = "io")]
+#[test]
+fn heap_dump_native() {
+    let path = std::env::temp_dir().join(format!("rlox-heap-{}.txt", std::process::id()));
+    let path = path.to_str().unwrap().replace('\\', "/");
+    let mut lox = LoxBuilder::new().build();
+    lox.run(&format!("var kept = \"kept\"; heapDump(\"{}\");", path)).unwrap();
+    let dump = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(dump.lines().any(|line| line.starts_with("global kept -> #")), "{}", dump);
+}
+
+#[test]
+fn compile_and_check_reports_errors() {
+    let warnings = vm::compile_and_check(b"{ var unused = 1; }").unwrap();
+    assert_eq!(warnings.len(), 1);
+    let message = |input: &[u8]| vm::compile_and_check(input).unwrap_err()[0].to_string();
+    assert!(message(b"print \"\xff\";").starts_with("Source is not valid UTF-8."));
+    assert!(message(b"print (;").starts_with("Expect expression."));
+    assert!(message(b"return this;").starts_with("Cannot use 'this' outside of a class."));
+    let locals = (0..256).map(|i| format!("var v{};", i)).collect::<String>();
+    assert_eq!(message(format!("fun f() {{ {} v255; }}", locals).as_bytes()), "Too many local variables in function.");
+}
+
+#[test]
+fn local_functions_and_function_expressions() {
+    let source = "
+        {
+            fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            class Point { copy() { return Point(); } }
+            var add = fun (a, b) { return a + b; };
+            print fib(10);
+            print Point().copy();
+            print add(1, 2);
+            print add;
+        }
+    ";
+    let output = Output::default();
+    let mut vm = VM::new();
+    vm.set_stdout(output.clone());
+    vm.run_program(&Program::compile(source).unwrap()).unwrap();
+    assert_eq!(output.contents(), "55\nPoint instance\n3\n<fn>\n");
+}
+
+#[test]
+fn listings_decode_every_function() {
+    let program = Program::compile("var a = 1;\nfun add(x) {\n  return x + a;\n}\nprint add(2);").unwrap();
+    let listings = program.listings();
+    assert_eq!(listings.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), vec!["<top>", "add"]);
+
+    let add = &listings[1].instructions;
+    let ops = add.iter().map(|i| i.op).collect::<Vec<_>>();
+    assert_eq!(ops[..4], ["OP_GET_LOCAL", "OP_GET_GLOBAL", "OP_ADD", "OP_RETURN"]);
+    assert_eq!(add[0].operands, vec![Operand::Local(1, "x".into())]);
+    assert_eq!(add[1].operands, vec![Operand::Global(0, "a".into())]);
+    assert_eq!(add[1].offset, 2);
+    assert_eq!(add[2].line, 3);
+    assert_eq!(add[1].to_string(), "OP_GET_GLOBAL\t0\ta");
+}
+
+#[test]
+fn stripped_programs_have_no_local_names() {
+    let program = Program::compile("fun add(x) {\n  var y = 2;\n  return x + y;\n}\nprint add(1);").unwrap();
+    let stripped = program.stripped();
+    assert!(stripped.as_bytes().len() < program.as_bytes().len());
+    assert!(Program::from_bytes(stripped.as_bytes().to_vec()).is_ok());
+
+    let add = &stripped.listings()[1].instructions;
+    assert_eq!(add.iter().find(|i| i.op == "OP_GET_LOCAL").unwrap().operands, vec![Operand::Local(1, "?".into())]);
+
+    let output = Output::default();
+    let mut vm = VM::new();
+    vm.set_stdout(output.clone());
+    vm.run_program(&stripped).unwrap();
+    assert_eq!(output.contents(), "3\n");
+}
+
+#[test]
+fn huge_counts_in_truncated_bytecode_are_rejected() {
+    let header = Program::compile("").unwrap().as_bytes()[..6].to_vec();
+    // No globals, then a function named "f" with no arity, upvalues or code.
+    let function = [&0u32.to_le_bytes()[..], &1u32.to_le_bytes(), b"f", &[0], &0u32.to_le_bytes(), &0u32.to_le_bytes()]
+        .concat();
+    let lines = [&header[..], &function, &u32::max_value().to_le_bytes()].concat();
+    assert!(Program::from_bytes(lines).is_err());
+    let constants = [&header[..], &function, &0u32.to_le_bytes(), &u32::max_value().to_le_bytes()].concat();
+    assert!(Program::from_bytes(constants).is_err());
+}
+
+#[test]
+fn immediates_that_are_not_numbers_are_rejected() {
+    let bytes = Program::compile("").unwrap().as_bytes().to_vec();
+    // The top level is just nil and return, so replace the nil with an
+    // immediate that holds an unknown tag.
+    let code = [0x02, 0x00, 0x00, 0x00, 0x14];
+    let at = bytes.windows(code.len()).position(|window| window == code).unwrap();
+    let immediate = [0x0a, 0x00, 0x00, 0x00, 0x13, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfc, 0x7f];
+    let crafted = [&bytes[..at], &immediate[..], &bytes[at + code.len()..]].concat();
+    let error = Program::from_bytes(crafted).err().expect("the immediate to be rejected");
+    assert!(error.to_string().contains("is not a number"), "{}", error);
+}
+
+#[test]
+fn nans_in_bytecode_lose_their_payload() {
+    let bytes = Program::compile("print 1.5;").unwrap().as_bytes().to_vec();
+    let constant = 1.5f64.to_bits().to_le_bytes();
+    let at = bytes.windows(constant.len()).position(|window| window == constant).unwrap();
+    // The bits of a NaN that would otherwise decode as an unknown tag.
+    let tag = 0x7ffc_0000_0000_0007u64.to_le_bytes();
+    let crafted = [&bytes[..at], &tag[..], &bytes[at + constant.len()..]].concat();
+    let output = Output::default();
+    let mut vm = VM::new();
+    vm.set_stdout(output.clone());
+    vm.run_program(&Program::from_bytes(crafted).unwrap()).unwrap();
+    assert_eq!(output.contents(), "NaN\n");
+}
+
+#[test]
+fn while_loops_test_their_condition_at_the_bottom() {
+    let source = "var i = 0;\nwhile (i < 3) i = i + 1;\nwhile (i) { print i; i = nil; }\nwhile (false) print i;";
+    let program = Program::compile(source).unwrap();
+    let ops = program.listings()[0].instructions.iter().map(|i| i.op).collect::<Vec<_>>();
+    assert_eq!(ops.iter().filter(|&&op| op == "OP_JUMP").count(), 3);
+    assert_eq!(ops.iter().filter(|&&op| op == "OP_LOOP_IF_TRUE").count(), 2);
+    assert!(ops.contains(&"OP_LOOP_IF_LESS"));
+    assert!(!ops.contains(&"OP_JUMP_IF_FALSE"));
+
+    let output = Output::default();
+    let mut vm = VM::new();
+    vm.set_stdout(output.clone());
+    vm.run_program(&program).unwrap();
+    assert_eq!(output.contents(), "3\n");
+
+    // Once optimized, a constant condition isn't tested at all.
+    let (optimized, _) = Program::compile_with_warnings("while (true) break;\nwhile (false) print 1;", true);
+    let ops = optimized.unwrap().listings()[0].instructions.iter().map(|i| i.op).collect::<Vec<_>>();
+    assert!(ops.contains(&"OP_LOOP"));
+    assert!(!ops.iter().any(|op| op.starts_with("OP_LOOP_IF")));
+}